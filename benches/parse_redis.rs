@@ -79,17 +79,18 @@ fn criterion_benchmark(c: &mut Criterion) {
             || {
                 let mut manager = Manager::try_from(&config::Redis::default()).expect("bench");
                 for i in 1..=6 {
-                    manager.redis_conn.add(&input_msg(i));
+                    manager.source.redis_conn.add(&input_msg(i));
                 }
                 manager
             },
             |mut m| {
                 black_box({
                     let mut i = 1;
-                    while let Ok(Async::Ready(Some(len))) = m.redis_conn.poll_redis(m.unread_idx.1)
+                    let s = &mut m.source;
+                    while let Ok(Async::Ready(Some(len))) = s.redis_conn.poll_redis(s.unread_idx.1)
                     {
-                        m.unread_idx = (0, m.unread_idx.1 + len);
-                        while let Ok(Async::Ready(Some((_tl, event)))) = m.poll() {
+                        s.unread_idx = (0, s.unread_idx.1 + len);
+                        while let Ok(Async::Ready(Some((_tl, event)))) = s.poll() {
                             //                  println!("Parsing Event #{:03}", i + 1);
                             //        assert_eq!(event, output(i));
                             i += 1;
@@ -2,11 +2,15 @@ pub use self::deployment_cfg::Deployment;
 pub use self::postgres_cfg::Postgres;
 pub use self::redis_cfg::Redis;
 
+use self::deployment_cfg_types::{DeniedTokens, LogLevel, WsKeepalive};
 use self::environmental_variables::EnvVar;
 
 use hashbrown::HashMap;
 use std::env;
 use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
 mod deployment_cfg;
 mod deployment_cfg_types;
 mod environmental_variables;
@@ -14,15 +18,22 @@ mod postgres_cfg;
 mod postgres_cfg_types;
 mod redis_cfg;
 mod redis_cfg_types;
+#[cfg(test)]
+mod test;
 
 type Result<T> = std::result::Result<T, Error>;
 
-pub fn merge_dotenv() -> Result<()> {
-    let env_file = match env::var("ENV").ok().as_deref() {
+/// The environmental file to read, for the value of the `ENV` variable
+fn env_file(env: Option<&str>) -> Result<&'static str> {
+    Ok(match env {
         Some("production") => ".env.production",
         Some("development") | None => ".env",
         Some(v) => Err(Error::config("ENV", v, "`production` or `development`"))?,
-    };
+    })
+}
+
+pub fn merge_dotenv() -> Result<()> {
+    let env_file = env_file(env::var("ENV").ok().as_deref())?;
     let res = dotenv::from_filename(env_file);
 
     if let Ok(log_level) = env::var("RUST_LOG") {
@@ -70,6 +81,111 @@ pub fn from_env<'a>(
     Ok((pg_cfg, redis_cfg, deployment_cfg))
 }
 
+/// Start logging at the level set by RUST_LOG.  The logger itself passes every level through,
+/// so that a reload can later raise the level as well as lower it.
+pub fn init_logger() -> std::result::Result<(), log::SetLoggerError> {
+    pretty_env_logger::formatted_timed_builder()
+        .filter_level(log::LevelFilter::Trace)
+        .try_init()?;
+    let log_level = LogLevel::default().maybe_update(env::var("RUST_LOG").ok().as_ref());
+    log::set_max_level(log_level.unwrap_or_default().filter());
+    Ok(())
+}
+
+/// The settings that can change while Flodgatt is running (on `SIGHUP`), without dropping any
+/// connections.  A new WebSocket keepalive only applies to connections opened after the reload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reloadable {
+    /// Release builds are compiled with `release_max_level_info`, which discards the `debug`
+    /// and `trace` logs entirely, so raising the level above `info` has no effect in them.
+    pub log_level: log::LevelFilter,
+    pub denied_tokens: Vec<String>,
+    pub ws_keepalive: Option<Duration>,
+}
+
+impl Reloadable {
+    const ENV_VARS: [&'static str; 3] = ["RUST_LOG", "DENIED_TOKENS", "WS_KEEPALIVE"];
+
+    /// Make the reloaded log level the current one (as far as this build can log; see
+    /// `log_level`)
+    pub fn set_log_level(&self) {
+        if self.log_level > log::STATIC_MAX_LEVEL {
+            log::warn!(
+                "This build can't log above {}; logging at that level instead of {}",
+                log::STATIC_MAX_LEVEL,
+                self.log_level
+            );
+        }
+        log::set_max_level(self.log_level);
+    }
+}
+
+/// The environmental variables to reload from: the environment Flodgatt was started with
+/// (`process_env`), plus the current contents of the environmental file.
+///
+/// The file is read into the returned map without touching the process's environment, which
+/// can't safely be changed while other threads may be reading it.
+#[allow(clippy::implicit_hasher)]
+pub fn reload_vars(process_env: &HashMap<String, String>) -> Result<HashMap<String, String>> {
+    let env_file = env_file(process_env.get("ENV").map(String::as_str))?;
+    with_env_file(process_env.clone(), Path::new(env_file))
+}
+
+/// `env_vars`, plus the variables set in `env_file` that they don't already set (as `dotenv`
+/// would merge them).  A missing file adds nothing.
+fn with_env_file(
+    mut env_vars: HashMap<String, String>,
+    env_file: &Path,
+) -> Result<HashMap<String, String>> {
+    let file = match File::open(env_file) {
+        Ok(file) => file,
+        Err(e) => {
+            log::warn!("Could not read {}: {}", env_file.display(), e);
+            return Ok(env_vars);
+        }
+    };
+    for var in dotenv::Iter::new(file) {
+        let (var, val) = var
+            .map_err(|e| Error::Config(format!("could not parse {}: {}", env_file.display(), e)))?;
+        env_vars.entry(var).or_insert(val);
+    }
+    Ok(env_vars)
+}
+
+/// Read the `Reloadable` settings from `env_vars`.  Every other setting keeps the value read
+/// from `startup_vars` (including the ones that control which addresses Flodgatt listens on and
+/// which Redis server it connects to); changes to them are logged and ignored.
+#[allow(clippy::implicit_hasher)]
+pub fn reload(
+    startup_vars: &HashMap<String, String>,
+    env_vars: HashMap<String, String>,
+) -> Result<Reloadable> {
+    let removed = startup_vars
+        .keys()
+        .filter(|var| !env_vars.contains_key(*var));
+    let changed = env_vars
+        .iter()
+        .filter(|(var, val)| startup_vars.get(*var) != Some(*val))
+        .map(|(var, _)| var)
+        .chain(removed);
+    for var in changed.filter(|var| !Reloadable::ENV_VARS.contains(&var.as_str())) {
+        log::warn!("{} changed, but requires a restart; ignoring it", var);
+    }
+
+    let env = EnvVar::new(env_vars);
+    let reloaded = Reloadable {
+        log_level: LogLevel::default()
+            .maybe_update(env.get("RUST_LOG"))?
+            .filter(),
+        denied_tokens: DeniedTokens::default()
+            .maybe_update(env.get("DENIED_TOKENS"))?
+            .0,
+        ws_keepalive: *WsKeepalive::default().maybe_update(env.get("WS_KEEPALIVE"))?,
+    };
+    log::info!("Reloaded {:?}", reloaded);
+    Ok(reloaded)
+}
+
 #[derive(Debug)]
 pub enum Error {
     Config(String),
@@ -5,11 +5,43 @@ use super::{EnvVar, Error};
 pub struct Deployment<'a> {
     pub(crate) env: Env,
     pub(crate) log_level: LogLevel,
+    pub log_headers: LogHeaders,
     pub address: FlodgattAddr,
     pub port: Port,
     pub unix_socket: Socket,
+    pub client_send_buffer: ClientSendBufferBytes,
+    pub listen_backlog: ListenBacklog,
+    pub worker_threads: WorkerThreads,
+    pub local_subscribers: LocalSubscribers,
     pub cors: Cors<'a>,
     pub whitelist_mode: WhitelistMode,
+    pub strict_list_membership: StrictListMembership,
+    pub reject_conflicting_params: RejectConflictingParams,
+    pub reject_during_outage: RejectDuringOutage,
+    pub sse_event_type_paths: SseEventTypePaths,
+    pub setup_timeout: SetupTimeout,
+    pub require_user_agent: RequireUserAgent,
+    pub max_query_length: MaxQueryLength,
+    pub probe_ok: ProbeOk,
+    pub metrics: Metrics,
+    pub admin_tokens: AdminTokens,
+    pub denied_tokens: DeniedTokens,
+    pub always_allow_langs: AlwaysAllowLangs,
+    pub session_cookie: SessionCookie,
+    pub sse_gzip: SseGzip,
+    pub sse_retry: SseRetry,
+    pub sse_retry_jitter: SseRetryJitter,
+    pub ws_allowed_origins: WsAllowedOrigins,
+    pub ws_keepalive: WsKeepalive,
+    pub presence_interval: PresenceInterval,
+    pub event_ids: SendEventIds,
+    pub node_name: NodeName,
+    pub sequence_numbers: SequenceNumbers,
+    pub retain_internal_fields: RetainInternalFields,
+    pub trace_events: TraceEvents,
+    pub connection_summaries: ConnectionSummaries,
+    pub tag_revalidation_interval: TagRevalidationInterval,
+    pub prewarm_hashtag_count: PrewarmHashtagCount,
 }
 
 impl Deployment<'_> {
@@ -17,13 +49,71 @@ impl Deployment<'_> {
         let mut cfg = Self {
             env: Env::default().maybe_update(env.get("NODE_ENV"))?,
             log_level: LogLevel::default().maybe_update(env.get("RUST_LOG"))?,
+            log_headers: LogHeaders::default().maybe_update(env.get("LOG_HEADERS"))?,
             address: FlodgattAddr::default().maybe_update(env.get("BIND"))?,
             port: Port::default().maybe_update(env.get("PORT"))?,
             unix_socket: Socket::default().maybe_update(env.get("SOCKET"))?,
+            client_send_buffer: ClientSendBufferBytes::default()
+                .maybe_update(env.get("CLIENT_SEND_BUFFER_BYTES"))?,
+            listen_backlog: ListenBacklog::default().maybe_update(env.get("LISTEN_BACKLOG"))?,
+            worker_threads: WorkerThreads::default().maybe_update(env.get("WORKER_THREADS"))?,
+            local_subscribers: LocalSubscribers::default()
+                .maybe_update(env.get("LOCAL_SUBSCRIBERS"))?,
             whitelist_mode: WhitelistMode::default().maybe_update(env.get("WHITELIST_MODE"))?,
+            strict_list_membership: StrictListMembership::default()
+                .maybe_update(env.get("STRICT_LIST_MEMBERSHIP"))?,
+            reject_conflicting_params: RejectConflictingParams::default()
+                .maybe_update(env.get("REJECT_CONFLICTING_PARAMS"))?,
+            reject_during_outage: RejectDuringOutage::default()
+                .maybe_update(env.get("REJECT_DURING_OUTAGE"))?,
+            sse_event_type_paths: SseEventTypePaths::default()
+                .maybe_update(env.get("SSE_EVENT_TYPE_PATHS"))?,
+            setup_timeout: SetupTimeout::default().maybe_update(env.get("SETUP_TIMEOUT"))?,
+            require_user_agent: RequireUserAgent::default()
+                .maybe_update(env.get("REQUIRE_USER_AGENT"))?,
+            max_query_length: MaxQueryLength::default()
+                .maybe_update(env.get("MAX_QUERY_LENGTH"))?,
+            probe_ok: ProbeOk::default().maybe_update(env.get("PROBE_OK"))?,
+            metrics: Metrics::default().maybe_update(env.get("METRICS"))?,
+            admin_tokens: AdminTokens::default().maybe_update(env.get("ADMIN_TOKENS"))?,
+            denied_tokens: DeniedTokens::default().maybe_update(env.get("DENIED_TOKENS"))?,
+            always_allow_langs: AlwaysAllowLangs::default()
+                .maybe_update(env.get("ALWAYS_ALLOW_LANGS"))?,
+            session_cookie: SessionCookie::default().maybe_update(env.get("SESSION_COOKIE"))?,
+            sse_gzip: SseGzip::default().maybe_update(env.get("SSE_GZIP"))?,
+            sse_retry: SseRetry::default().maybe_update(env.get("SSE_RETRY"))?,
+            sse_retry_jitter: SseRetryJitter::default()
+                .maybe_update(env.get("SSE_RETRY_JITTER"))?,
+            ws_allowed_origins: WsAllowedOrigins::default()
+                .maybe_update(env.get("WS_ALLOWED_ORIGINS"))?,
+            ws_keepalive: WsKeepalive::default().maybe_update(env.get("WS_KEEPALIVE"))?,
+            presence_interval: PresenceInterval::default()
+                .maybe_update(env.get("PRESENCE_INTERVAL"))?,
+            event_ids: SendEventIds::default().maybe_update(env.get("EVENT_IDS"))?,
+            node_name: NodeName::default().maybe_update(env.get("NODE_NAME"))?,
+            sequence_numbers: SequenceNumbers::default()
+                .maybe_update(env.get("SEQUENCE_NUMBERS"))?,
+            retain_internal_fields: RetainInternalFields::default()
+                .maybe_update(env.get("RETAIN_INTERNAL_FIELDS"))?,
+            trace_events: TraceEvents::default().maybe_update(env.get("TRACE_EVENTS"))?,
+            connection_summaries: ConnectionSummaries::default()
+                .maybe_update(env.get("CONNECTION_SUMMARIES"))?,
+            tag_revalidation_interval: TagRevalidationInterval::default()
+                .maybe_update(env.get("TAG_REVALIDATION_INTERVAL"))?,
+            prewarm_hashtag_count: PrewarmHashtagCount::default()
+                .maybe_update(env.get("PREWARM_HASHTAG_COUNT"))?,
             cors: Cors::default(),
         };
         cfg.env = cfg.env.maybe_update(env.get("RUST_ENV"))?;
         Ok(cfg)
     }
+
+    /// A builder for the runtime the server runs on, with `WORKER_THREADS` workers (if set)
+    pub fn runtime_builder(&self) -> tokio::runtime::Builder {
+        let mut builder = tokio::runtime::Builder::new();
+        if let Some(threads) = *self.worker_threads {
+            builder.core_threads(threads);
+        }
+        builder
+    }
 }
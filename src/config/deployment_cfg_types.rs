@@ -1,7 +1,11 @@
 use crate::from_env_var;
+use crate::request::Timeline;
+use lru::LruCache;
 use std::fmt;
 use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 use strum_macros::{EnumString, EnumVariantNames};
 
 from_env_var!(
@@ -28,6 +32,49 @@ from_env_var!(
     let (env_var, allowed_values) = ("RUST_LOG",  &format!("one of: {:?}", LogLevelInner::variants())); 
     let from_str = |s| LogLevelInner::from_str(s).ok();
 );
+from_env_var!(
+    /// Request headers to include when logging incoming requests (e.g., a proxy's request id)
+    let name = LogHeaders;
+    let default: Vec<String> = Vec::new();
+    let (env_var, allowed_values) = ("LOG_HEADERS", "a comma-separated list of header names");
+    let from_str = |s| Some(
+        s.split(',')
+            .map(|header| header.trim().to_lowercase())
+            .filter(|header| !header.is_empty())
+            .collect()
+    );
+);
+from_env_var!(
+    /// Static access tokens that grant access to the administrative (status) endpoints
+    ///
+    /// These are checked without querying Postgres, so admin access keeps working during a
+    /// Postgres outage.  If none are set, the read-only administrative endpoints are
+    /// unauthenticated, and the ones that change anything (flush, drain and denied tokens) are
+    /// not served.
+    let name = AdminTokens;
+    let default: Vec<String> = Vec::new();
+    let (env_var, allowed_values) = ("ADMIN_TOKENS", "a comma-separated list of tokens");
+    let from_str = |s| Some(
+        s.split(',')
+            .map(|token| token.trim().to_string())
+            .filter(|token| !token.is_empty())
+            .collect()
+    );
+);
+from_env_var!(
+    /// Access tokens to reject outright, without querying Postgres (e.g., abused tokens whose
+    /// revocation hasn't reached every node yet).  These can be replaced at runtime through
+    /// the `status/denied_tokens` endpoint.
+    let name = DeniedTokens;
+    let default: Vec<String> = Vec::new();
+    let (env_var, allowed_values) = ("DENIED_TOKENS", "a comma-separated list of tokens");
+    let from_str = |s| Some(
+        s.split(',')
+            .map(|token| token.trim().to_string())
+            .filter(|token| !token.is_empty())
+            .collect()
+    );
+);
 from_env_var!(
     /// A Unix Socket to use in place of a local address
     let name = Socket;
@@ -35,6 +82,73 @@ from_env_var!(
     let (env_var, allowed_values) = ("SOCKET", "any string");
     let from_str = |s| Some(Some(s.to_string()));
 );
+from_env_var!(
+    /// Local Unix sockets to write a timeline's events to (as newline-delimited JSON), as
+    /// comma-separated `timeline=path` pairs
+    ///
+    /// Each timeline is named as its Redis channel is, without the channel root (such as
+    /// `public:local` or `list:5`).  Hashtag timelines aren't supported.
+    let name = LocalSubscribers;
+    let default: Vec<(Timeline, PathBuf)> = Vec::new();
+    let (env_var, allowed_values) = (
+        "LOCAL_SUBSCRIBERS",
+        "comma-separated pairs such as `public:local=/run/indexer.sock`, naming any timeline \
+         other than a hashtag"
+    );
+    let from_str = |s| s
+        .split(',')
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=').map(str::trim);
+            let timeline = Timeline::from_redis_text(parts.next()?, &mut LruCache::new(1)).ok()?;
+            match parts.next() {
+                Some(path) if !path.is_empty() && timeline.tag().is_none() => {
+                    Some((timeline, PathBuf::from(path)))
+                }
+                _ => None,
+            }
+        })
+        .collect();
+);
+from_env_var!(
+    /// The length of the queue of connections waiting to be accepted on the listening socket
+    ///
+    /// Unset by default, which leaves the standard library's default (128).  The OS caps this
+    /// at its own limit (`net.core.somaxconn` on Linux).  Not used with a Unix socket.
+    let name = ListenBacklog;
+    let default: Option<i32> = None;
+    let (env_var, allowed_values) = ("LISTEN_BACKLOG", "a positive number of connections");
+    let from_str = |s| match s.parse() {
+        Ok(backlog) if backlog > 0 => Some(Some(backlog)),
+        _ => None,
+    };
+);
+from_env_var!(
+    /// The number of worker threads the server runs on
+    ///
+    /// Unset by default, which starts one per CPU core.
+    let name = WorkerThreads;
+    let default: Option<usize> = None;
+    let (env_var, allowed_values) = ("WORKER_THREADS", "a positive number of threads");
+    let from_str = |s| match s.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(threads) => Some(Some(threads)),
+    };
+);
+from_env_var!(
+    /// The size, in bytes, to request for the OS send buffer of each client's socket (`0`
+    /// disables this)
+    ///
+    /// Unset by default, which leaves the OS default.  Linux doubles the requested size and
+    /// enforces its own minimum.
+    let name = ClientSendBufferBytes;
+    let default: Option<usize> = None;
+    let (env_var, allowed_values) = ("CLIENT_SEND_BUFFER_BYTES", "a number of bytes");
+    let from_str = |s| match s.parse() {
+        Ok(0) => Some(None),
+        Ok(bytes) => Some(Some(bytes)),
+        Err(_) => None,
+    };
+);
 from_env_var!(
     /// The port to run Flodgatt on
     let name = Port;
@@ -52,6 +166,243 @@ from_env_var!(
     let (env_var, allowed_values) = ("WHITELIST_MODE", "true or false");
     let from_str = |s| s.parse().ok();
 );
+from_env_var!(
+    /// Whether to check that statuses sent to `list` timelines are from members of the list
+    ///
+    /// Guards against Redis publishing statuses too broadly, at the cost of a Postgres query
+    /// when each list subscription is created.
+    let name = StrictListMembership;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("STRICT_LIST_MEMBERSHIP", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Whether to reject requests whose parameters contradict each other or the stream (e.g.,
+    /// a `list` with a `public` stream) with a 400, rather than ignoring the extra parameters
+    let name = RejectConflictingParams;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("REJECT_CONFLICTING_PARAMS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Languages to deliver to every user who has limited their languages, in addition to the
+    /// languages they chose (so that, e.g., statuses in the instance's own language still arrive)
+    let name = AlwaysAllowLangs;
+    let default: Vec<String> = Vec::new();
+    let (env_var, allowed_values) = ("ALWAYS_ALLOW_LANGS", "a comma-separated list of language codes");
+    let from_str = |s| Some(
+        s.split(',')
+            .map(|lang| lang.trim().to_string())
+            .filter(|lang| !lang.is_empty())
+            .collect()
+    );
+);
+from_env_var!(
+    /// The name of a cookie holding the user's access token, which whitelist-mode SSE requests
+    /// may authenticate with instead of a header or query parameter (as a browser's
+    /// `EventSource` with `withCredentials` does).  Its value is URL-decoded.
+    ///
+    /// This only works when Flodgatt is served from the same origin as Mastodon: its CORS
+    /// responses don't allow credentials, so browsers won't send cookies cross-origin.
+    let name = SessionCookie;
+    let default: Option<String> = None;
+    let (env_var, allowed_values) = ("SESSION_COOKIE", "any cookie name");
+    let from_str = |s| Some(Some(s.to_string()));
+);
+from_env_var!(
+    /// Whether to reject new streaming requests with a 503 while Flodgatt can't reach Redis,
+    /// rather than accepting clients that won't receive any events until Redis is back
+    let name = RejectDuringOutage;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("REJECT_DURING_OUTAGE", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Whether SSE paths may end with an event type (e.g., `/api/v1/streaming/public/update`)
+    /// to receive only events of that type.  Mastodon doesn't support this, but some clients
+    /// expect it.  When disabled, any trailing segment is ignored.
+    let name = SseEventTypePaths;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("SSE_EVENT_TYPE_PATHS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// How long setting up a subscription (including its Postgres queries) may take before the
+    /// request is answered with a 504
+    ///
+    /// Unset by default, in which case setup is never cut short.
+    let name = SetupTimeout;
+    let default: Option<Duration> = None;
+    let (env_var, allowed_values) = ("SETUP_TIMEOUT", "a number of milliseconds");
+    let from_str = |s| s.parse().map(|ms| Some(Duration::from_millis(ms))).ok();
+);
+from_env_var!(
+    /// Whether to reject streaming requests that don't send a `User-Agent` header (with a 400)
+    let name = RequireUserAgent;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("REQUIRE_USER_AGENT", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// The longest query string to accept on streaming requests (longer ones get a 414 before
+    /// any of their parameters are parsed or looked up in Postgres)
+    let name = MaxQueryLength;
+    let default: usize = 4096;
+    let (env_var, allowed_values) = ("MAX_QUERY_LENGTH", "a positive number of bytes");
+    let from_str = |s| s.parse().ok().filter(|len| *len > 0);
+);
+from_env_var!(
+    /// Whether to answer requests to the streaming root that aren't valid streaming requests
+    /// (such as those from uptime monitors) with `{"status":"online"}` rather than an error
+    let name = ProbeOk;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("PROBE_OK", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Whether to serve Prometheus metrics at `/metrics`
+    ///
+    /// These need no token (so that scrapers can reach them) but name the subscribed timelines,
+    /// so only enable this where `/metrics` isn't reachable from outside.
+    let name = Metrics;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("METRICS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Whether to gzip SSE responses for clients that send `Accept-Encoding: gzip`
+    ///
+    /// Each event is flushed as soon as it's written, so compression doesn't delay delivery.
+    let name = SseGzip;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("SSE_GZIP", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// How many of the most-used hashtags to load into the hashtag cache on startup
+    ///
+    /// Defaults to 0, which disables prewarming the cache.
+    let name = PrewarmHashtagCount;
+    let default: usize = 0;
+    let (env_var, allowed_values) = ("PREWARM_HASHTAG_COUNT", "a non-negative integer");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// How long SSE clients should wait before reconnecting (sent as the `retry:` field)
+    ///
+    /// Unset by default, in which case no `retry:` field is sent.
+    let name = SseRetry;
+    let default: Option<Duration> = None;
+    let (env_var, allowed_values) = ("SSE_RETRY", "a number of milliseconds");
+    let from_str = |s| s.parse().map(|ms| Some(Duration::from_millis(ms))).ok();
+);
+from_env_var!(
+    /// The maximum random delay added to `SSE_RETRY` for each connection
+    ///
+    /// Spreads out reconnections so clients don't all return at the same instant.
+    let name = SseRetryJitter;
+    let default: Duration = Duration::from_millis(0);
+    let (env_var, allowed_values) = ("SSE_RETRY_JITTER", "a number of milliseconds");
+    let from_str = |s| s.parse().map(Duration::from_millis).ok();
+);
+from_env_var!(
+    /// Origins allowed to open WebSocket connections, independently of CORS (which only governs
+    /// SSE and the other HTTP endpoints).  Empty by default, which allows any origin.
+    let name = WsAllowedOrigins;
+    let default: Vec<String> = Vec::new();
+    let (env_var, allowed_values) = ("WS_ALLOWED_ORIGINS", "a comma-separated list of origins");
+    let from_str = |s| Some(
+        s.split(',')
+            .map(|origin| origin.trim().to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect()
+    );
+);
+from_env_var!(
+    /// How often to send WebSocket clients a `{"event":"keepalive"}` text frame (`0` disables it)
+    ///
+    /// Unset by default.  Unlike a Ping, this survives proxies that strip WebSocket control frames.
+    let name = WsKeepalive;
+    let default: Option<Duration> = None;
+    let (env_var, allowed_values) = ("WS_KEEPALIVE", "a number of seconds");
+    let from_str = |s| match s.parse() {
+        Ok(0) => Some(None),
+        Ok(secs) => Some(Some(Duration::from_secs(secs))),
+        Err(_) => None,
+    };
+);
+from_env_var!(
+    /// How often to send a `presence` event to clients of a `user` timeline that asked for one
+    /// (with `presence=true`), which some clients use to keep the account shown as online
+    let name = PresenceInterval;
+    let default: Duration = Duration::from_secs(60);
+    let (env_var, allowed_values) = ("PRESENCE_INTERVAL", "a positive number of seconds");
+    let from_str = |s| match s.parse() {
+        Ok(0) => None,
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => None,
+    };
+);
+from_env_var!(
+    /// Whether to tag each status-related event with an `id` (its type and status id), which
+    /// clients connected to several Flodgatt nodes can use to discard duplicates
+    let name = SendEventIds;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("EVENT_IDS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// A name for this Flodgatt node, appended to event ids when `EVENT_IDS` is enabled
+    let name = NodeName;
+    let default: Option<String> = None;
+    let (env_var, allowed_values) = ("NODE_NAME", "any string");
+    let from_str = |s| Some(Some(s.to_string()));
+);
+from_env_var!(
+    /// Whether to number the events sent to each WebSocket client, so that it can tell when
+    /// events meant for it were dropped
+    let name = SequenceNumbers;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("SEQUENCE_NUMBERS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Whether to send fields Mastodon only uses internally (such as `queued_at`) to WebSocket
+    /// clients.  They reveal server timing, so this is only meant for debugging.
+    let name = RetainInternalFields;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("RETAIN_INTERNAL_FIELDS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Whether to log each event under a short trace id as it's parsed, routed and delivered
+    /// to (or filtered from) each client.  This is very verbose, so it's only meant for debugging.
+    let name = TraceEvents;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("TRACE_EVENTS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Whether to send each SSE client a `summary` event (with the number of events delivered
+    /// and filtered, and how long it was connected) just before its stream ends, for debugging.
+    /// WebSocket clients aren't sent one: their streams end when they disconnect, after which
+    /// nothing can reach them.
+    let name = ConnectionSummaries;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("CONNECTION_SUMMARIES", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// How often to check cached hashtag ids against Postgres (`0` disables the check)
+    let name = TagRevalidationInterval;
+    let default: Option<Duration> = Some(Duration::from_secs(600));
+    let (env_var, allowed_values) = ("TAG_REVALIDATION_INTERVAL", "a number of seconds");
+    let from_str = |s| match s.parse() {
+        Ok(0) => Some(None),
+        Ok(secs) => Some(Some(Duration::from_secs(secs))),
+        Err(_) => None,
+    };
+);
 /// Permissions for Cross Origin Resource Sharing (CORS)
 pub struct Cors<'a> {
     pub allowed_headers: Vec<&'a str>,
@@ -85,6 +436,19 @@ pub enum LogLevelInner {
     Error,
 }
 
+impl LogLevelInner {
+    /// The `log` crate's filter for this level
+    pub fn filter(&self) -> log::LevelFilter {
+        match self {
+            Self::Trace => log::LevelFilter::Trace,
+            Self::Debug => log::LevelFilter::Debug,
+            Self::Info => log::LevelFilter::Info,
+            Self::Warn => log::LevelFilter::Warn,
+            Self::Error => log::LevelFilter::Error,
+        }
+    }
+}
+
 #[derive(EnumString, EnumVariantNames, Debug, Clone)]
 #[strum(serialize_all = "snake_case")]
 pub enum EnvInner {
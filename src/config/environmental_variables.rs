@@ -32,12 +32,43 @@ impl fmt::Display for EnvVar {
         for env_var in &[
             "NODE_ENV",
             "RUST_LOG",
+            "LOG_HEADERS",
             "BIND",
             "PORT",
             "SOCKET",
+            "CLIENT_SEND_BUFFER_BYTES",
+            "LISTEN_BACKLOG",
+            "WORKER_THREADS",
+            "LOCAL_SUBSCRIBERS",
+            "STRICT_LIST_MEMBERSHIP",
+            "REJECT_CONFLICTING_PARAMS",
+            "REJECT_DURING_OUTAGE",
+            "SSE_EVENT_TYPE_PATHS",
+            "SETUP_TIMEOUT",
+            "REQUIRE_USER_AGENT",
+            "MAX_QUERY_LENGTH",
+            "PROBE_OK",
+            "METRICS",
+            "ALWAYS_ALLOW_LANGS",
+            "SESSION_COOKIE",
             "SSE_FREQ",
             "WS_FREQ",
+            "SSE_GZIP",
+            "SSE_RETRY",
+            "SSE_RETRY_JITTER",
+            "WS_ALLOWED_ORIGINS",
+            "WS_KEEPALIVE",
+            "PRESENCE_INTERVAL",
+            "EVENT_IDS",
+            "NODE_NAME",
+            "SEQUENCE_NUMBERS",
+            "RETAIN_INTERNAL_FIELDS",
+            "TRACE_EVENTS",
+            "CONNECTION_SUMMARIES",
+            "TAG_REVALIDATION_INTERVAL",
+            "PREWARM_HASHTAG_COUNT",
             "DATABASE_URL",
+            "DB_REPLICA_URL",
             "DB_USER",
             "USER",
             "DB_PORT",
@@ -46,12 +77,39 @@ impl fmt::Display for EnvVar {
             "DB_NAME",
             "DB_SSLMODE",
             "REDIS_HOST",
+            "REDIS_SOCKET",
+            "REDIS_TLS",
+            "REDIS_CA_CERT",
+            "REDIS_RESP3",
             "REDIS_USER",
             "REDIS_PORT",
             "REDIS_PASSWORD",
             "REDIS_USER",
             "REDIS_DB",
+            "REDIS_CHANNEL_ROOT",
             "REDIS_FREQ",
+            "REDIS_FREQ_OVERRIDES",
+            "REDIS_HELD_EVENTS_MAX",
+            "REDIS_SLOW_START",
+            "REDIS_STATS_KEY",
+            "REDIS_STATS_INTERVAL",
+            "REDIS_WATCHDOG_TIMEOUT",
+            "REDIS_AUTH_TIMEOUT",
+            "REDIS_CONFIRM_TIMEOUT",
+            "REDIS_MAX_MESSAGE_BYTES",
+            "REDIS_MAX_LINE_LENGTH",
+            "REDIS_UNSUBSCRIBE_LINGER",
+            "REDIS_BACKFILL_MAX",
+            "REDIS_NARROW_CHANNELS",
+            "REDIS_ADVERTISE_SUBSCRIPTIONS",
+            "REDIS_PASSTHROUGH_UNKNOWN_EVENTS",
+            "REDIS_STRINGIFY_IDS",
+            "REDIS_CHECK_NAMESPACE",
+            "REDIS_DEAD_LETTERS",
+            "REDIS_TAG_CACHE_SIZE",
+            "REDIS_KEEPALIVE_IDLE",
+            "REDIS_KEEPALIVE_INTERVAL",
+            "REDIS_KEEPALIVE_PROBES",
         ] {
             if let Some(value) = self.get(&(*env_var).to_string()) {
                 result = format!("{}\n    {}: {}", result, env_var, value)
@@ -16,6 +16,9 @@ pub struct Postgres {
     pub database: PgDatabase,
     pub(crate) port: PgPort,
     pub(crate) ssl_mode: PgSslMode,
+    /// A read replica to send queries to instead (any settings it doesn't specify are the same
+    /// as for the primary)
+    pub(crate) replica: Option<Box<Postgres>>,
 }
 
 impl EnvVar {
@@ -59,15 +62,24 @@ impl Postgres {
             None => env,
         };
 
-        let cfg = Self {
+        let mut cfg = Self::from_vars(&env)?;
+        if let Some(url_str) = env.get("DB_REPLICA_URL").cloned() {
+            let replica_env = env.update_with_postgres_url(&url_str)?;
+            cfg.replica = Some(Box::new(Self::from_vars(&replica_env)?));
+        }
+        Ok(cfg)
+    }
+
+    fn from_vars(env: &EnvVar) -> Result<Self> {
+        Ok(Self {
             user: PgUser::default().maybe_update(env.get("DB_USER"))?,
             host: PgHost::default().maybe_update(env.get("DB_HOST"))?,
             password: PgPass::default().maybe_update(env.get("DB_PASS"))?,
             database: PgDatabase::default().maybe_update(env.get("DB_NAME"))?,
             port: PgPort::default().maybe_update(env.get("DB_PORT"))?,
             ssl_mode: PgSslMode::default().maybe_update(env.get("DB_SSLMODE"))?,
-        };
-        Ok(cfg)
+            replica: None,
+        })
     }
 
     //     // use openssl::ssl::{SslConnector, SslMethod};
@@ -5,29 +5,63 @@ use url::Url;
 
 type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Redis {
     pub(crate) user: RedisUser,
     pub(crate) password: RedisPass,
     pub(crate) port: RedisPort,
     pub(crate) host: RedisHost,
+    pub(crate) socket: RedisSocket,
+    pub(crate) use_tls: RedisUseTls,
+    pub(crate) ca_cert: RedisCaCert,
+    pub(crate) resp3: RedisResp3,
     pub(crate) db: RedisDb,
     pub(crate) namespace: RedisNamespace,
+    pub(crate) channel_root: RedisChannelRoot,
     // **NOTE**:  Polling Redis is much more time consuming than polling the `Receiver` (~1ms
     // compared to ~50μs).  Thus, changing this setting with REDIS_POLL_INTERVAL may be a good
     // place to start for performance improvements at the cost of delaying all updates.
     pub polling_interval: RedisInterval,
+    pub(crate) polling_overrides: RedisIntervalOverrides,
+    pub(crate) held_events_max: RedisHeldEventsMax,
+    pub(crate) slow_start: RedisSlowStart,
+    pub(crate) stats_key: RedisStatsKey,
+    pub(crate) stats_interval: RedisStatsInterval,
+    pub(crate) watchdog_timeout: RedisWatchdogTimeout,
+    pub(crate) auth_timeout: RedisAuthTimeout,
+    pub(crate) confirm_timeout: RedisConfirmTimeout,
+    pub(crate) max_message_bytes: RedisMaxMessageBytes,
+    pub(crate) max_line_length: RedisMaxLineLength,
+    pub(crate) unsubscribe_linger: RedisUnsubscribeLinger,
+    pub(crate) backfill_max: RedisBackfillMax,
+    pub(crate) narrow_channels: RedisNarrowChannels,
+    pub(crate) advertise_subscriptions: RedisAdvertiseSubscriptions,
+    pub(crate) passthrough_unknown_events: RedisPassthroughUnknownEvents,
+    pub(crate) stringify_ids: RedisStringifyIds,
+    pub check_namespace: RedisCheckNamespace,
+    pub(crate) dead_letters: RedisDeadLetters,
+    pub(crate) tag_cache_size: RedisTagCacheSize,
+    pub(crate) keepalive_idle: RedisKeepaliveIdle,
+    pub(crate) keepalive_interval: RedisKeepaliveInterval,
+    pub(crate) keepalive_probes: RedisKeepaliveProbes,
 }
 
 impl EnvVar {
     fn update_with_redis_url(mut self, url_str: &str) -> Result<Self> {
         let url = Url::parse(url_str)?;
         let none_if_empty = |s: String| if s.is_empty() { None } else { Some(s) };
-
-        self.maybe_add_env_var("REDIS_PORT", url.port());
-        self.maybe_add_env_var("REDIS_PASSWORD", url.password());
-        self.maybe_add_env_var("REDIS_USERNAME", none_if_empty(url.username().to_string()));
-        self.maybe_add_env_var("REDIS_DB", none_if_empty(url.path()[1..].to_string()));
+        if url.scheme() == "unix" {
+            // `unix:///run/redis.sock` names a socket; its path isn't a database
+            self.maybe_add_env_var("REDIS_SOCKET", Some(url.path()));
+        } else {
+            if url.scheme() == "rediss" {
+                self.maybe_add_env_var("REDIS_TLS", Some(true));
+            }
+            self.maybe_add_env_var("REDIS_PORT", url.port());
+            self.maybe_add_env_var("REDIS_PASSWORD", url.password());
+            self.maybe_add_env_var("REDIS_USERNAME", none_if_empty(url.username().to_string()));
+            self.maybe_add_env_var("REDIS_DB", none_if_empty(url.path()[1..].to_string()));
+        }
         for (k, v) in url.query_pairs().into_owned() {
             match k.to_string().as_str() {
                 "password" => self.maybe_add_env_var("REDIS_PASSWORD", Some(v.to_string())),
@@ -60,9 +94,57 @@ For similar functionality, you may wish to set a REDIS_NAMESPACE";
             password: RedisPass::default().maybe_update(env.get("REDIS_PASSWORD"))?,
             port: RedisPort::default().maybe_update(env.get("REDIS_PORT"))?,
             host: RedisHost::default().maybe_update(env.get("REDIS_HOST"))?,
+            socket: RedisSocket::default().maybe_update(env.get("REDIS_SOCKET"))?,
+            use_tls: RedisUseTls::default().maybe_update(env.get("REDIS_TLS"))?,
+            ca_cert: RedisCaCert::default().maybe_update(env.get("REDIS_CA_CERT"))?,
+            resp3: RedisResp3::default().maybe_update(env.get("REDIS_RESP3"))?,
             db: RedisDb::default().maybe_update(env.get("REDIS_DB"))?,
             namespace: RedisNamespace::default().maybe_update(env.get("REDIS_NAMESPACE"))?,
+            channel_root: RedisChannelRoot::default()
+                .maybe_update(env.get("REDIS_CHANNEL_ROOT"))?,
             polling_interval: RedisInterval::default().maybe_update(env.get("REDIS_FREQ"))?,
+            polling_overrides: RedisIntervalOverrides::default()
+                .maybe_update(env.get("REDIS_FREQ_OVERRIDES"))?,
+            held_events_max: RedisHeldEventsMax::default()
+                .maybe_update(env.get("REDIS_HELD_EVENTS_MAX"))?,
+            slow_start: RedisSlowStart::default().maybe_update(env.get("REDIS_SLOW_START"))?,
+            stats_key: RedisStatsKey::default().maybe_update(env.get("REDIS_STATS_KEY"))?,
+            stats_interval: RedisStatsInterval::default()
+                .maybe_update(env.get("REDIS_STATS_INTERVAL"))?,
+            watchdog_timeout: RedisWatchdogTimeout::default()
+                .maybe_update(env.get("REDIS_WATCHDOG_TIMEOUT"))?,
+            auth_timeout: RedisAuthTimeout::default()
+                .maybe_update(env.get("REDIS_AUTH_TIMEOUT"))?,
+            confirm_timeout: RedisConfirmTimeout::default()
+                .maybe_update(env.get("REDIS_CONFIRM_TIMEOUT"))?,
+            max_message_bytes: RedisMaxMessageBytes::default()
+                .maybe_update(env.get("REDIS_MAX_MESSAGE_BYTES"))?,
+            max_line_length: RedisMaxLineLength::default()
+                .maybe_update(env.get("REDIS_MAX_LINE_LENGTH"))?,
+            unsubscribe_linger: RedisUnsubscribeLinger::default()
+                .maybe_update(env.get("REDIS_UNSUBSCRIBE_LINGER"))?,
+            backfill_max: RedisBackfillMax::default()
+                .maybe_update(env.get("REDIS_BACKFILL_MAX"))?,
+            narrow_channels: RedisNarrowChannels::default()
+                .maybe_update(env.get("REDIS_NARROW_CHANNELS"))?,
+            advertise_subscriptions: RedisAdvertiseSubscriptions::default()
+                .maybe_update(env.get("REDIS_ADVERTISE_SUBSCRIPTIONS"))?,
+            passthrough_unknown_events: RedisPassthroughUnknownEvents::default()
+                .maybe_update(env.get("REDIS_PASSTHROUGH_UNKNOWN_EVENTS"))?,
+            stringify_ids: RedisStringifyIds::default()
+                .maybe_update(env.get("REDIS_STRINGIFY_IDS"))?,
+            check_namespace: RedisCheckNamespace::default()
+                .maybe_update(env.get("REDIS_CHECK_NAMESPACE"))?,
+            dead_letters: RedisDeadLetters::default()
+                .maybe_update(env.get("REDIS_DEAD_LETTERS"))?,
+            tag_cache_size: RedisTagCacheSize::default()
+                .maybe_update(env.get("REDIS_TAG_CACHE_SIZE"))?,
+            keepalive_idle: RedisKeepaliveIdle::default()
+                .maybe_update(env.get("REDIS_KEEPALIVE_IDLE"))?,
+            keepalive_interval: RedisKeepaliveInterval::default()
+                .maybe_update(env.get("REDIS_KEEPALIVE_INTERVAL"))?,
+            keepalive_probes: RedisKeepaliveProbes::default()
+                .maybe_update(env.get("REDIS_KEEPALIVE_PROBES"))?,
         };
 
         if cfg.db.is_some() {
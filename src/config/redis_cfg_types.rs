@@ -1,4 +1,7 @@
 use crate::from_env_var; //macro
+use crate::request::Timeline;
+use hashbrown::HashMap;
+use std::path::PathBuf;
 use std::time::Duration;
 //use std::{fmt, net::IpAddr, os::unix::net::UnixListener, str::FromStr, time::Duration};
 //use strum_macros::{EnumString, EnumVariantNames};
@@ -10,6 +13,37 @@ from_env_var!(
     let (env_var, allowed_values) = ("REDIS_HOST", "any string");
     let from_str = |s| Some(s.to_string());
 );
+from_env_var!(
+    /// The path of a Unix socket to connect to Redis through, in place of `REDIS_HOST` and
+    /// `REDIS_PORT` (for a Redis running on the same host)
+    let name = RedisSocket;
+    let default: Option<String> = None;
+    let (env_var, allowed_values) = ("REDIS_SOCKET", "the path of a Unix socket");
+    let from_str = |s| Some(Some(s.to_string()));
+);
+from_env_var!(
+    /// Whether to connect to Redis over TLS (as many hosted Redis providers require)
+    let name = RedisUseTls;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("REDIS_TLS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// A CA certificate (in PEM format) to trust when connecting to Redis over TLS, in addition
+    /// to the system's (for providers that sign their certificates with a private CA)
+    let name = RedisCaCert;
+    let default: Option<PathBuf> = None;
+    let (env_var, allowed_values) = ("REDIS_CA_CERT", "the path of a PEM file");
+    let from_str = |s| Some(Some(PathBuf::from(s)));
+);
+from_env_var!(
+    /// Whether to speak RESP3 to Redis (which requires Redis 6 or later), so that events and
+    /// other commands can share one connection instead of needing a second
+    let name = RedisResp3;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("REDIS_RESP3", "true or false");
+    let from_str = |s| s.parse().ok();
+);
 from_env_var!(
     /// The port Redis is running on
     let name = RedisPort;
@@ -24,6 +58,39 @@ from_env_var!(
     let (env_var, allowed_values) = ("REDIS_FREQ", "a number of milliseconds");
     let from_str = |s| s.parse().map(Duration::from_millis).ok();
 );
+from_env_var!(
+    /// Per-category overrides of `REDIS_FREQ`, as comma-separated `category=milliseconds` pairs
+    ///
+    /// Events for an overridden category are held and delivered on that category's own
+    /// cadence, which lets busy timelines be delivered more eagerly than quiet ones.
+    let name = RedisIntervalOverrides;
+    let default: HashMap<String, Duration> = HashMap::new();
+    let (env_var, allowed_values) = (
+        "REDIS_FREQ_OVERRIDES",
+        "comma-separated pairs such as `public=50,hashtag=250`; the categories are `public`, \
+         `hashtag`, `user`, `list`, and `direct`"
+    );
+    let from_str = |s| s
+        .split(',')
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=').map(str::trim);
+            match (parts.next(), parts.next().and_then(|ms| ms.parse().ok())) {
+                (Some(category), Some(ms)) if Timeline::CATEGORIES.contains(&category) => {
+                    Some((category.to_string(), Duration::from_millis(ms)))
+                }
+                _ => None,
+            }
+        })
+        .collect();
+);
+from_env_var!(
+    /// The most events to hold for each timeline while its category waits to be polled (see
+    /// `REDIS_FREQ_OVERRIDES`).  Once a timeline's queue is full, its oldest event is dropped.
+    let name = RedisHeldEventsMax;
+    let default: usize = 1_000;
+    let (env_var, allowed_values) = ("REDIS_HELD_EVENTS_MAX", "a number of events");
+    let from_str = |s| s.parse().ok();
+);
 from_env_var!(
     /// The password to use for Redis
     let name = RedisPass;
@@ -38,6 +105,207 @@ from_env_var!(
     let (env_var, allowed_values) = ("REDIS_NAMESPACE", "any string");
     let from_str = |s| Some(Some(s.to_string()));
 );
+from_env_var!(
+    /// The root of the Redis channels Mastodon publishes timelines to (after the namespace,
+    /// if any)
+    let name = RedisChannelRoot;
+    let default: String = "timeline".to_string();
+    let (env_var, allowed_values) = ("REDIS_CHANNEL_ROOT", "any string");
+    let from_str = |s| Some(s.to_string());
+);
+from_env_var!(
+    /// A Redis key to periodically store Flodgatt's stats in (as JSON)
+    ///
+    /// Unset by default, in which case stats are not written to Redis.
+    let name = RedisStatsKey;
+    let default: Option<String> = None;
+    let (env_var, allowed_values) = ("REDIS_STATS_KEY", "any string");
+    let from_str = |s| Some(Some(s.to_string()));
+);
+from_env_var!(
+    /// How frequently to write stats to `REDIS_STATS_KEY`
+    let name = RedisStatsInterval;
+    let default: Duration = Duration::from_secs(10);
+    let (env_var, allowed_values) = ("REDIS_STATS_INTERVAL", "a number of milliseconds");
+    let from_str = |s| s.parse().map(Duration::from_millis).ok();
+);
+from_env_var!(
+    /// How long to go without receiving anything from Redis (while clients are connected)
+    /// before assuming the connection is stuck and reconnecting.  Redis is sent a `PING` once
+    /// half of this passes without any input, so a quiet connection still has a reply to show.
+    ///
+    /// Unset by default.
+    let name = RedisWatchdogTimeout;
+    let default: Option<Duration> = None;
+    let (env_var, allowed_values) = ("REDIS_WATCHDOG_TIMEOUT", "a number of seconds");
+    let from_str = |s| s.parse().map(|secs| Some(Duration::from_secs(secs))).ok();
+);
+from_env_var!(
+    /// How long to wait for each step of connecting to Redis (the TCP connect, the TLS
+    /// handshake, and the replies to AUTH, HELLO and PING) before giving up
+    ///
+    /// This also bounds how long a reconnect can hold up delivering events to every client.
+    let name = RedisAuthTimeout;
+    let default: Duration = Duration::from_secs(5);
+    let (env_var, allowed_values) = ("REDIS_AUTH_TIMEOUT", "a positive number of seconds");
+    let from_str = |s| match s.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(secs) => Some(Duration::from_secs(secs)),
+    };
+);
+from_env_var!(
+    /// How long to wait for Redis to confirm a SUBSCRIBE or UNSUBSCRIBE before assuming the
+    /// connection is hung and reconnecting
+    ///
+    /// Unset by default; Redis confirms commands almost immediately on a healthy connection.
+    let name = RedisConfirmTimeout;
+    let default: Option<Duration> = None;
+    let (env_var, allowed_values) = ("REDIS_CONFIRM_TIMEOUT", "a number of seconds");
+    let from_str = |s| s.parse().map(|secs| Some(Duration::from_secs(secs))).ok();
+);
+from_env_var!(
+    /// The most unparsed input to buffer from Redis while waiting for a message to complete
+    ///
+    /// If a malformed stream exceeds this, the input is discarded and Redis is reconnected
+    /// rather than letting the buffer grow without bound.
+    let name = RedisMaxMessageBytes;
+    let default: usize = 16 * 1024 * 1024;
+    let (env_var, allowed_values) = ("REDIS_MAX_MESSAGE_BYTES", "a number of bytes");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// The longest a RESP type-and-length line (such as `$1386`) may be before its `\r\n`
+    ///
+    /// These lines are only ever a few bytes long, so a longer one means the framing is
+    /// corrupt; the input is discarded and Redis is reconnected instead of waiting for a line
+    /// end that may never come.
+    let name = RedisMaxLineLength;
+    let default: usize = 32;
+    let (env_var, allowed_values) = ("REDIS_MAX_LINE_LENGTH", "a number of bytes greater than 2");
+    let from_str = |s| s.parse().ok().filter(|len| *len > 2);
+);
+from_env_var!(
+    /// The most recent events to send clients that ask for a `backfill` when they connect
+    /// (`0` disables backfill).  This many events are kept for each subscribed channel, so only
+    /// events received while the timeline already had subscribers are available.  These share
+    /// the client's channel with live events, so this shouldn't exceed its capacity of 10.
+    let name = RedisBackfillMax;
+    let default: usize = 10;
+    let (env_var, allowed_values) = ("REDIS_BACKFILL_MAX", "a number of events");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Whether Mastodon publishes the narrow (media-only and notification-only) Redis channels.
+    /// If not, those timelines are served by filtering the events on the broader channel.
+    let name = RedisNarrowChannels;
+    let default: bool = true;
+    let (env_var, allowed_values) = ("REDIS_NARROW_CHANNELS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Whether to set a `subscribed:` key for each channel we subscribe to (and clear it when we
+    /// unsubscribe).  Mastodon stops publishing to channels it thinks no one is subscribed to,
+    /// so only disable this if your Mastodon doesn't check these keys.
+    let name = RedisAdvertiseSubscriptions;
+    let default: bool = true;
+    let (env_var, allowed_values) = ("REDIS_ADVERTISE_SUBSCRIPTIONS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Whether to forward events of types Flodgatt doesn't recognize (and can't otherwise parse)
+    /// to subscribers as is, rather than dropping them.  Filters can't apply to such events.
+    let name = RedisPassthroughUnknownEvents;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("REDIS_PASSTHROUGH_UNKNOWN_EVENTS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Whether to convert status and account ids that arrive as JSON numbers into strings (as
+    /// Mastodon itself sends them) before parsing and delivering events.  Some forks send
+    /// numeric ids, which many clients can't handle.
+    let name = RedisStringifyIds;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("REDIS_STRINGIFY_IDS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// How long to listen at startup for messages that show whether `REDIS_NAMESPACE` matches
+    /// Mastodon's (`0`, the default, skips the check)
+    let name = RedisCheckNamespace;
+    let default: Option<Duration> = None;
+    let (env_var, allowed_values) = ("REDIS_CHECK_NAMESPACE", "a number of seconds");
+    let from_str = |s| match s.parse() {
+        Ok(0) => Some(None),
+        Ok(secs) => Some(Some(Duration::from_secs(secs))),
+        Err(_) => None,
+    };
+);
+from_env_var!(
+    /// How long after reconnecting to Redis to poll it more slowly than `REDIS_FREQ`
+    ///
+    /// Unset by default.  Right after a reconnect, Redis may deliver a burst of backed-up
+    /// events; during this window, the poll interval starts at several times `REDIS_FREQ` and
+    /// ramps back down to it, so that the burst reaches clients gradually.  The cap on held
+    /// events (`REDIS_HELD_EVENTS_MAX`) is cut by the same factor for the window.
+    let name = RedisSlowStart;
+    let default: Option<Duration> = None;
+    let (env_var, allowed_values) = ("REDIS_SLOW_START", "a number of milliseconds");
+    let from_str = |s| s.parse().map(|ms| Some(Duration::from_millis(ms))).ok();
+);
+from_env_var!(
+    /// How long a timeline must go without any clients before unsubscribing from it
+    ///
+    /// Unset by default (unsubscribe immediately).  A short linger lets clients that briefly
+    /// drop their connection reconnect without Flodgatt unsubscribing and resubscribing.
+    let name = RedisUnsubscribeLinger;
+    let default: Option<Duration> = None;
+    let (env_var, allowed_values) = ("REDIS_UNSUBSCRIBE_LINGER", "a number of milliseconds");
+    let from_str = |s| s.parse().map(|ms| Some(Duration::from_millis(ms))).ok();
+);
+from_env_var!(
+    /// How long a Redis connection can be idle before the OS starts sending TCP keepalive
+    /// probes (`0` disables TCP keepalive)
+    let name = RedisKeepaliveIdle;
+    let default: Option<Duration> = Some(Duration::from_secs(60));
+    let (env_var, allowed_values) = ("REDIS_KEEPALIVE_IDLE", "a number of seconds");
+    let from_str = |s| match s.parse() {
+        Ok(0) => Some(None),
+        Ok(secs) => Some(Some(Duration::from_secs(secs))),
+        Err(_) => None,
+    };
+);
+from_env_var!(
+    /// How many hashtags to keep in each of the caches that translate between hashtag names and
+    /// ids.  Each miss when subscribing costs a Postgres lookup, so instances with many active
+    /// hashtags may want more than the default.
+    let name = RedisTagCacheSize;
+    let default: usize = 1000;
+    let (env_var, allowed_values) = ("REDIS_TAG_CACHE_SIZE", "a positive number of hashtags");
+    let from_str = |s| s.parse().ok().filter(|size| *size > 0);
+);
+from_env_var!(
+    /// How many of the most recent messages from Redis that couldn't be parsed to keep for
+    /// inspection through the admin status endpoint (`0` disables this).  Each is truncated
+    /// to 1 KiB, so this bounds the memory used.
+    let name = RedisDeadLetters;
+    let default: usize = 20;
+    let (env_var, allowed_values) = ("REDIS_DEAD_LETTERS", "a number of messages");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// How long to wait between unanswered TCP keepalive probes
+    let name = RedisKeepaliveInterval;
+    let default: Duration = Duration::from_secs(10);
+    let (env_var, allowed_values) = ("REDIS_KEEPALIVE_INTERVAL", "a number of seconds");
+    let from_str = |s| s.parse().map(Duration::from_secs).ok();
+);
+from_env_var!(
+    /// How many unanswered TCP keepalive probes to send before the connection is dropped
+    let name = RedisKeepaliveProbes;
+    let default: u32 = 3;
+    let (env_var, allowed_values) = ("REDIS_KEEPALIVE_PROBES", "a number of probes");
+    let from_str = |s| s.parse().ok();
+);
 from_env_var!(
     /// A user for Redis (not supported)
     let name = RedisUser;
@@ -0,0 +1,116 @@
+use super::*;
+use futures::future::{lazy, Future};
+use log::LevelFilter;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs
+        .iter()
+        .map(|(var, val)| (var.to_string(), val.to_string()))
+        .collect()
+}
+
+#[test]
+fn reload_updates_the_log_level() -> Result<()> {
+    let startup_vars = vars(&[("RUST_LOG", "warn")]);
+    let reloaded = reload(&startup_vars, vars(&[("RUST_LOG", "debug")]))?;
+    assert_eq!(reloaded.log_level, LevelFilter::Debug);
+    Ok(())
+}
+
+#[test]
+fn env_file_adds_the_variables_the_environment_does_not_set() -> Result<()> {
+    let env_file = env::temp_dir().join(format!("flodgatt-{}.env", std::process::id()));
+    std::fs::write(&env_file, "RUST_LOG=debug\nWS_KEEPALIVE=15\n")
+        .map_err(|e| Error::Config(e.to_string()))?;
+
+    let env_vars = with_env_file(vars(&[("RUST_LOG", "warn")]), &env_file);
+    std::fs::remove_file(&env_file).unwrap_or_default();
+
+    assert_eq!(
+        env_vars?,
+        vars(&[("RUST_LOG", "warn"), ("WS_KEEPALIVE", "15")])
+    );
+    assert_eq!(env::var("WS_KEEPALIVE").ok(), None);
+    Ok(())
+}
+
+#[test]
+fn missing_env_file_adds_nothing() -> Result<()> {
+    let missing = env::temp_dir().join("flodgatt-missing.env");
+    let env_vars = with_env_file(vars(&[("PORT", "4000")]), &missing)?;
+    assert_eq!(env_vars, vars(&[("PORT", "4000")]));
+    Ok(())
+}
+
+#[test]
+fn reload_reads_the_denied_tokens_and_keepalive() -> Result<()> {
+    let startup_vars = vars(&[("PORT", "4000")]);
+    let env_vars = vars(&[
+        ("PORT", "4001"),
+        ("DENIED_TOKENS", "abc, def"),
+        ("WS_KEEPALIVE", "15"),
+    ]);
+
+    let reloaded = reload(&startup_vars, env_vars)?;
+
+    assert_eq!(reloaded.denied_tokens, vec!["abc", "def"]);
+    assert_eq!(reloaded.ws_keepalive, Some(Duration::from_secs(15)));
+    Ok(())
+}
+
+#[test]
+fn runtime_uses_the_configured_workers() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (_, _, cfg) = from_env(vars(&[("WORKER_THREADS", "1")]))?;
+    let started = Arc::new(AtomicUsize::new(0));
+    let counter = started.clone();
+    let mut builder = cfg.runtime_builder();
+    let mut runtime = builder
+        .after_start(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+        .build()?;
+
+    // Busy workers would otherwise lead the runtime to start more of them
+    for _ in 0..4 {
+        runtime.spawn(lazy(|| {
+            std::thread::sleep(Duration::from_millis(10));
+            Ok(())
+        }));
+    }
+    runtime.shutdown_on_idle().wait().unwrap_or_default();
+    assert_eq!(started.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[test]
+fn zero_workers_or_backlog_are_rejected() {
+    assert!(from_env(vars(&[("WORKER_THREADS", "0")])).is_err());
+    assert!(from_env(vars(&[("LISTEN_BACKLOG", "0")])).is_err());
+}
+
+#[test]
+fn unix_redis_url_sets_the_socket() -> Result<()> {
+    let (_, redis, _) = from_env(vars(&[("REDIS_URL", "unix:///run/redis/redis.sock")]))?;
+    assert_eq!(*redis.socket, Some("/run/redis/redis.sock".to_string()));
+    assert_eq!(*redis.db, None);
+    Ok(())
+}
+
+#[test]
+fn resp3_is_opt_in() -> Result<()> {
+    let (_, redis, _) = from_env(vars(&[]))?;
+    assert!(!*redis.resp3);
+    let (_, redis, _) = from_env(vars(&[("REDIS_RESP3", "true")]))?;
+    assert!(*redis.resp3);
+    Ok(())
+}
+
+#[test]
+fn rediss_url_enables_tls() -> Result<()> {
+    let (_, redis, _) = from_env(vars(&[("REDIS_URL", "rediss://redis.example:6380")]))?;
+    assert!(*redis.use_tls);
+    assert_eq!(*redis.port, 6380);
+    Ok(())
+}
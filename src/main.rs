@@ -1,85 +1,273 @@
 use flodgatt::config;
-use flodgatt::request::{Handler, Subscription};
-use flodgatt::response::{RedisManager, SseStream, WsStream};
+use flodgatt::request::{Handler, HandlerConfig, Subscription};
+use flodgatt::response::{bind_with_backlog, with_send_buffer, LocalSubscriber};
+use flodgatt::response::{EventIds, RedisManager, RedisPing, SseOptions, SseStream};
+use flodgatt::response::{WsOptions, WsStream};
 use flodgatt::Error;
 
 use futures::future::lazy;
 use futures::stream::Stream as _;
+use futures::Future as _;
+use hashbrown::HashMap;
+use std::env;
 use std::fs;
 use std::net::SocketAddr;
 use std::os::unix::fs::PermissionsExt;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 use std::time::Instant;
-use tokio::net::UnixListener;
+use tokio::net::{TcpListener, UnixListener};
 use tokio::sync::mpsc;
 use tokio::timer::Interval;
+use tokio_signal::unix::{Signal, SIGHUP};
 use warp::ws::Ws2;
 use warp::Filter;
 
 fn main() -> Result<(), Error> {
+    let process_env: HashMap<String, String> = env::vars().collect();
     config::merge_dotenv()?;
-    pretty_env_logger::try_init_timed()?;
-    let (postgres_cfg, redis_cfg, cfg) = config::from_env(dotenv::vars().collect())?;
-    let poll_freq = *redis_cfg.polling_interval;
+    config::init_logger()?;
+    let startup_vars: HashMap<String, String> = dotenv::vars().collect();
+    let (postgres_cfg, redis_cfg, cfg) = config::from_env(startup_vars.clone())?;
 
-    let request = Handler::new(&postgres_cfg, *cfg.whitelist_mode)?;
-    let shared_manager = RedisManager::try_from(&redis_cfg)?.into_arc();
+    let handler_cfg = HandlerConfig {
+        whitelist_mode: *cfg.whitelist_mode,
+        strict_list_membership: *cfg.strict_list_membership,
+        reject_conflicting_params: *cfg.reject_conflicting_params,
+        require_user_agent: *cfg.require_user_agent,
+        sse_event_type_paths: *cfg.sse_event_type_paths,
+        setup_timeout: *cfg.setup_timeout,
+        denied_tokens: cfg.denied_tokens.clone().0,
+        always_allow_langs: cfg.always_allow_langs.clone().0,
+        session_cookie: cfg.session_cookie.clone().0,
+    };
+    let request = Handler::new(&postgres_cfg, handler_cfg)?;
+    let mut manager = RedisManager::try_from(&redis_cfg)?;
+    if let Some(wait) = *redis_cfg.check_namespace {
+        manager.check_namespace(wait)?;
+    }
+    if *cfg.prewarm_hashtag_count > 0 {
+        let tags = request.select_popular_hashtags(*cfg.prewarm_hashtag_count);
+        log::info!("Prewarming the hashtag cache with {} hashtags", tags.len());
+        manager.prewarm_tags(&tags);
+    }
+    let trace_events = *cfg.trace_events;
+    let connection_summaries = *cfg.connection_summaries;
+    manager.trace_events(trace_events);
+    let local_subscribers: Vec<_> = cfg
+        .local_subscribers
+        .iter()
+        .map(|(timeline, path)| {
+            log::info!("Writing events for {:?} to {}", timeline, path.display());
+            let subscription = Subscription {
+                timeline: *timeline,
+                ..Subscription::default()
+            };
+            let (event_tx, event_rx) = mpsc::channel(10);
+            manager.subscribe(&subscription, event_tx);
+            LocalSubscriber::new(path).send_events(event_rx)
+        })
+        .collect();
+    let poll_freq = manager.poll_interval();
+    let write_failures = manager.write_failures();
+    let shared_manager = manager.into_arc();
+    let logged_headers = Handler::logged_headers(cfg.log_headers.clone());
+    let event_ids = if *cfg.event_ids {
+        Some(EventIds::new((*cfg.node_name).clone()))
+    } else {
+        None
+    };
+    let query_length = Handler::query_length(*cfg.max_query_length);
+    let probe = Handler::probe(*cfg.probe_ok);
+    let version = Handler::version();
+    let health_manager = shared_manager.clone();
+    let available = Handler::available(*cfg.reject_during_outage, move || {
+        let manager = health_manager.lock().unwrap_or_else(RedisManager::recover);
+        manager.is_healthy()
+    });
+    let redis_ping = Arc::new(Mutex::new(RedisPing::new(&redis_cfg)));
+    let redis_ok = move |timeout| {
+        let mut redis_ping = redis_ping.lock().unwrap_or_else(PoisonError::into_inner);
+        redis_ping
+            .ping(timeout)
+            .map_err(|e| log::error!("Health check could not ping Redis: {}", e))
+            .is_ok()
+    };
 
     // Server Sent Events
     let sse_manager = shared_manager.clone();
-    let sse = request
-        .sse_subscription()
+    let sse_options = SseOptions {
+        retry: *cfg.sse_retry,
+        retry_jitter: *cfg.sse_retry_jitter,
+        event_ids: event_ids.clone(),
+        trace_events,
+    };
+    let presence_interval = *cfg.presence_interval;
+    // `available` comes first, so that no request reaches Postgres during an outage
+    let sse = available
+        .clone()
+        .and(request.sse_subscription())
+        .and(logged_headers.clone())
+        .and(SseStream::gzip_accepted(*cfg.sse_gzip))
         .and(warp::sse())
-        .map(move |subscription: Subscription, sse: warp::sse::Sse| {
-            log::info!("Incoming SSE request for {:?}", subscription.timeline);
-            let mut manager = sse_manager.lock().unwrap_or_else(RedisManager::recover);
-            let (event_tx, event_rx) = mpsc::channel(10);
-            manager.subscribe(&subscription, event_tx);
-            let sse_stream = SseStream::new(subscription);
-            sse_stream.send_events(sse, event_rx)
-        })
-        .with(warp::reply::with::header("Connection", "keep-alive"));
+        .map(
+            move |subscription: Subscription, headers: String, gzip: bool, sse: warp::sse::Sse| {
+                let timeline = subscription.timeline;
+                log::info!("Incoming SSE request for {:?}{}", timeline, headers);
+                let mut manager = sse_manager.lock().unwrap_or_else(RedisManager::recover);
+                let (event_tx, event_rx) = mpsc::channel(10);
+                manager.subscribe(&subscription, event_tx);
+                SseStream::new(subscription, sse_options.clone())
+                    .with_presence(presence_interval)
+                    .with_summary(connection_summaries)
+                    .respond(sse, gzip, event_rx)
+            },
+        );
 
     // WebSocket
     let ws_manager = shared_manager.clone();
-    let ws = request
-        .ws_subscription()
+    let ws_keepalive = Arc::new(RwLock::new(*cfg.ws_keepalive));
+    let reloaded_ws_keepalive = ws_keepalive.clone();
+    let ws_options = WsOptions {
+        keepalive: None, // set per connection, since it can be reloaded
+        event_ids,
+        retain_internal_fields: *cfg.retain_internal_fields,
+        trace_events,
+        write_failures,
+    };
+    let sequence_numbers = *cfg.sequence_numbers;
+    let ws = available
+        .and(Handler::ws_origin(cfg.ws_allowed_origins.clone().0))
+        .and(request.ws_subscription())
+        .and(logged_headers)
         .and(warp::ws::ws2())
-        .map(move |subscription: Subscription, ws: Ws2| {
-            log::info!("Incoming websocket request for {:?}", subscription.timeline);
-            let mut manager = ws_manager.lock().unwrap_or_else(RedisManager::recover);
-            let (event_tx, event_rx) = mpsc::channel(10);
-            manager.subscribe(&subscription, event_tx);
-            let token = subscription.access_token.clone().unwrap_or_default(); // token sent for security
-            let ws_stream = WsStream::new(subscription);
+        .map(
+            move |subscription: Subscription, headers: String, ws: Ws2| {
+                let timeline = subscription.timeline;
+                log::info!("Incoming websocket request for {:?}{}", timeline, headers);
+                let mut manager = ws_manager.lock().unwrap_or_else(RedisManager::recover);
+                let (event_tx, event_rx) = mpsc::channel(10);
+                let dropped = manager.subscribe(&subscription, event_tx);
+                let token = subscription.access_token.clone().unwrap_or_default(); // token sent for security
+                let keepalive = *ws_keepalive.read().unwrap_or_else(PoisonError::into_inner);
+                let options = WsOptions {
+                    keepalive,
+                    ..ws_options.clone()
+                };
+                let mut ws_stream =
+                    WsStream::new(subscription, options).with_presence(presence_interval);
+                if sequence_numbers {
+                    ws_stream = ws_stream.numbered(dropped);
+                }
 
-            (
-                ws.on_upgrade(move |ws| ws_stream.send_to(ws, event_rx)),
-                token,
-            )
-        })
+                (
+                    ws.on_upgrade(move |ws| ws_stream.send_to(ws, event_rx)),
+                    token,
+                )
+            },
+        )
         .map(|(reply, token)| warp::reply::with_header(reply, "sec-websocket-protocol", token));
 
     #[cfg(feature = "stub_status")]
     #[rustfmt::skip]
     let status = {
         let (r1, r2, r3) = (shared_manager.clone(), shared_manager.clone(), shared_manager.clone());
-        request.health().map(|| "OK")
-            .or(request.status()
+        let (r4, r5, r6) = (shared_manager.clone(), shared_manager.clone(), shared_manager.clone());
+        let r7 = request.clone();
+        let admin = Handler::admin_only(cfg.admin_tokens.clone());
+        let admin_required = Handler::admin_required(cfg.admin_tokens.clone());
+        request.health(redis_ok)
+            .or(request.status().and(admin.clone())
                 .map(move || r1.lock().unwrap_or_else(RedisManager::recover).count()))
-            .or(request.status_backpresure()
+            .or(request.status_backpresure().and(admin.clone())
                 .map(move || r2.lock().unwrap_or_else(RedisManager::recover).backpresure()))
-            .or(request.status_per_timeline()
+            .or(request.status_per_timeline().and(admin.clone())
                 .map(move || r3.lock().unwrap_or_else(RedisManager::recover).list()))
+            .or(request.status_dead_letters().and(admin.clone())
+                .map(move || r4.lock().unwrap_or_else(RedisManager::recover).dead_letters()))
+            .or(request.status_subscriptions().and(admin)
+                .map(move || {
+                    let graph = r5.lock().unwrap_or_else(RedisManager::recover).subscription_graph();
+                    warp::reply::json(&graph)
+                }))
+            .or(request.status_flush().and(admin_required.clone())
+                .map(move || r6.lock().unwrap_or_else(RedisManager::recover).flush()))
+            .or(request.status_denied_tokens().and(admin_required)
+                .map(move |tokens| r7.deny_tokens(tokens)))
     };
     #[cfg(not(feature = "stub_status"))]
-    let status = request.health().map(|| "OK");
+    let status = request.health(redis_ok);
+
+    let metrics_manager = shared_manager.clone();
+    let metrics = Handler::metrics(*cfg.metrics).map(move || {
+        metrics_manager
+            .lock()
+            .unwrap_or_else(RedisManager::recover)
+            .metrics()
+    });
+
+    let drain_manager = shared_manager.clone();
+    let drain = request
+        .status_drain()
+        .and(Handler::admin_required(cfg.admin_tokens.clone()))
+        .map(move |tl: String| {
+            let mut manager = drain_manager.lock().unwrap_or_else(RedisManager::recover);
+            manager.drain_named(&tl)
+        });
 
     let cors = warp::cors()
         .allow_any_origin()
         .allow_methods(cfg.cors.allowed_methods)
         .allow_headers(cfg.cors.allowed_headers);
 
+    let tag_revalidation_interval = *cfg.tag_revalidation_interval;
+    let reload_request = request.clone();
     let streaming_server = move || {
+        let (handler, keepalive) = (reload_request.clone(), reloaded_ws_keepalive.clone());
+        let (process_env, startup_vars) = (process_env.clone(), startup_vars.clone());
+        let reload = Signal::new(SIGHUP)
+            .flatten_stream()
+            .map_err(|e| log::error!("{}", e))
+            .for_each(move |_| {
+                log::warn!("Received SIGHUP; reloading configuration");
+                let reloaded = config::reload_vars(&process_env)
+                    .and_then(|env_vars| config::reload(&startup_vars, env_vars));
+                match reloaded {
+                    Ok(reloaded) => {
+                        reloaded.set_log_level();
+                        handler.deny_tokens(reloaded.denied_tokens);
+                        *keepalive.write().unwrap_or_else(PoisonError::into_inner) =
+                            reloaded.ws_keepalive;
+                    }
+                    Err(e) => log::error!("Could not reload configuration: {}", e),
+                }
+                Ok(())
+            });
+        warp::spawn(lazy(move || reload));
+
+        if let Some(interval) = tag_revalidation_interval {
+            let (manager, request) = (shared_manager.clone(), request.clone());
+            let revalidation = Interval::new_interval(interval)
+                .map_err(|e| log::error!("{}", e))
+                .for_each(move |_| {
+                    // Query Postgres without holding the lock, since these queries can be slow
+                    let tags = manager
+                        .lock()
+                        .unwrap_or_else(RedisManager::recover)
+                        .subscribed_tags();
+                    let manager = manager.clone();
+                    request.select_hashtag_ids(tags).then(move |current_ids| {
+                        if let Ok(current_ids) = current_ids {
+                            manager
+                                .lock()
+                                .unwrap_or_else(RedisManager::recover)
+                                .revalidate_tags(&current_ids);
+                        }
+                        Ok(())
+                    })
+                });
+            warp::spawn(lazy(move || revalidation));
+        }
+
         let manager = shared_manager.clone();
         let stream = Interval::new(Instant::now(), poll_freq)
             .map_err(|e| log::error!("{}", e))
@@ -95,18 +283,41 @@ fn main() -> Result<(), Error> {
             });
 
         warp::spawn(lazy(move || stream));
-        warp::serve(ws.or(sse).with(cors).or(status).recover(Handler::err))
+        let streams = query_length.and(ws.or(sse)).with(cors);
+        warp::serve(
+            streams
+                .or(status)
+                .or(drain)
+                .or(metrics)
+                .or(probe)
+                .or(version)
+                .recover(Handler::err),
+        )
     };
 
+    let (send_buffer, backlog) = (*cfg.client_send_buffer, *cfg.listen_backlog);
+    let mut runtime = cfg.runtime_builder().build()?;
+    for subscriber in local_subscribers {
+        runtime.spawn(subscriber);
+    }
     if let Some(socket) = &*cfg.unix_socket {
         log::info!("Using Unix socket {}", socket);
         fs::remove_file(socket).unwrap_or_default();
-        let incoming = UnixListener::bind(socket)?.incoming();
+        let incoming = with_send_buffer(UnixListener::bind(socket)?.incoming(), send_buffer);
         fs::set_permissions(socket, PermissionsExt::from_mode(0o666))?;
-        tokio::run(lazy(|| streaming_server().serve_incoming(incoming)));
+        runtime.spawn(lazy(|| streaming_server().serve_incoming(incoming)));
+    } else if send_buffer.is_some() || backlog.is_some() {
+        let server_addr = SocketAddr::new(*cfg.address, *cfg.port);
+        let listener = match backlog {
+            Some(backlog) => bind_with_backlog(&server_addr, backlog)?,
+            None => TcpListener::bind(&server_addr)?,
+        };
+        let incoming = with_send_buffer(listener.incoming(), send_buffer);
+        runtime.spawn(lazy(|| streaming_server().serve_incoming(incoming)));
     } else {
         let server_addr = SocketAddr::new(*cfg.address, *cfg.port);
-        tokio::run(lazy(move || streaming_server().bind(server_addr)));
+        runtime.spawn(lazy(move || streaming_server().bind(server_addr)));
     }
+    runtime.shutdown_on_idle().wait().unwrap_or_default();
     Err(Error::Unrecoverable) // only reached if poll_broadcast encounters an unrecoverable error
 }
@@ -4,11 +4,12 @@ mod query;
 mod timeline;
 
 mod err;
+mod setup_pool;
 mod subscription;
 
 pub use err::{Error, Timeline as TimelineErr};
-pub use subscription::{Blocks, Subscription};
-pub use timeline::Timeline;
+pub use subscription::{Blocks, Filters, Subscription};
+pub use timeline::{TagIds, Timeline};
 
 #[cfg(feature = "bench")]
 pub use timeline::{Content, Reach, Stream};
@@ -18,16 +19,30 @@ use timeline::{Content, Reach, Stream};
 
 pub use self::postgres::PgPool;
 use self::query::Query;
+use self::setup_pool::SetupPool;
 use crate::config::Postgres;
+use crate::response::Event;
+
+use futures::future::{self, Either, Future};
+use futures::sync::oneshot;
+use hashbrown::HashSet;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+use tokio::timer::Timeout;
 use warp::filters::BoxedFilter;
-use warp::http::StatusCode;
+use warp::http::header::{HeaderValue, RETRY_AFTER};
+use warp::http::{HeaderMap, StatusCode};
 use warp::path;
+#[allow(deprecated)] // one fn is deprecated, not whole module
+use warp::reject;
 use warp::reply;
-use warp::{Filter, Rejection};
+use warp::{Filter, Rejection, Reply};
 
 #[cfg(test)]
 mod sse_test;
 #[cfg(test)]
+mod test;
+#[cfg(test)]
 mod ws_test;
 
 type Result<T> = std::result::Result<T, err::Error>;
@@ -46,13 +61,57 @@ macro_rules! parse_sse_query {
             .and(query::Media::to_filter())
             .and(query::Hashtag::to_filter())
             .and(query::List::to_filter())
-            .map(|auth: query::Auth, media: query::Media, hashtag: query::Hashtag, list: query::List| {
+            .and(query::ExcludeSelf::to_filter())
+            .and(query::OnlyRelationships::to_filter())
+            .and(query::NewestFirst::to_filter())
+            .and(query::Backfill::to_filter())
+            .and(query::Presence::to_filter())
+            .and(query::DebugTimeline::to_filter())
+            .and(query::MarkLocal::to_filter())
+            .and(query::MinAccountAge::to_filter())
+            .and(query::MinFollowers::to_filter())
+            .and(query::Accounts::to_filter())
+            .and(query::Limit::to_filter())
+            // grouped, since warp can only combine so many extracted values
+            .and(query::BatchWindow::to_filter()
+                .and(query::MaxRate::to_filter())
+                .map(|batch, max_rate| (batch, max_rate)))
+            .map(|auth: query::Auth,
+                  media: query::Media,
+                  hashtag: query::Hashtag,
+                  list: query::List,
+                  exclude_self: query::ExcludeSelf,
+                  relationships: query::OnlyRelationships,
+                  newest_first: query::NewestFirst,
+                  backfill: query::Backfill,
+                  presence: query::Presence,
+                  debug_timeline: query::DebugTimeline,
+                  mark_local: query::MarkLocal,
+                  min_age: query::MinAccountAge,
+                  min_followers: query::MinFollowers,
+                  accounts: query::Accounts,
+                  limit: query::Limit,
+                  (batch, max_rate): (query::BatchWindow, query::MaxRate)| {
                 Query {
                     access_token: auth.access_token,
                     stream: $endpoint.to_string(),
                     media: media.is_truthy(),
                     hashtag: hashtag.tag,
                     list: list.list,
+                    exclude_self: exclude_self.is_truthy(),
+                    only_relationships: relationships.is_truthy(),
+                    newest_first: newest_first.is_truthy(),
+                    backfill: backfill.is_truthy(),
+                    presence: presence.is_truthy(),
+                    debug_timeline: debug_timeline.is_truthy(),
+                    mark_local: mark_local.is_truthy(),
+                    min_account_age_days: min_age.min_account_age_days,
+                    min_followers: min_followers.min_followers,
+                    accounts: accounts.ids(),
+                    limit: limit.limit,
+                    batch_ms: batch.batch_ms,
+                    max_events_per_sec: max_rate.max_events_per_sec,
+                    event_type: None, // set from the path, if at all
                 }
             },
         )
@@ -60,27 +119,175 @@ macro_rules! parse_sse_query {
     };
 }
 
+/// How a `Handler` screens and sets up subscriptions (see `Handler::new`)
+#[derive(Debug, Clone, Default)]
+pub struct HandlerConfig {
+    pub whitelist_mode: bool,
+    pub strict_list_membership: bool,
+    pub reject_conflicting_params: bool,
+    pub require_user_agent: bool,
+    pub sse_event_type_paths: bool,
+    /// How long subscription setup may take, if it's limited
+    pub setup_timeout: Option<Duration>,
+    pub denied_tokens: Vec<String>,
+    pub always_allow_langs: Vec<String>,
+    /// Ignored unless in `whitelist_mode`
+    pub session_cookie: Option<String>,
+}
+
 #[derive(Clone)]
 pub struct Handler {
     pg_conn: PgPool,
+    reject_conflicting_params: bool,
+    require_user_agent: bool,
+    sse_event_type_paths: bool,
+    /// How long subscription setup may take, and the threads it runs on when it's limited
+    setup: Option<(Duration, SetupPool)>,
+    /// The thread hashtag ids are looked up on when revalidating the ones subscribed to
+    tag_lookups: SetupPool,
+    denied_tokens: Arc<RwLock<HashSet<String>>>,
+    always_allow_langs: Arc<HashSet<String>>,
+    whitelist_mode: bool,
+    /// The cookie SSE requests may carry their access token in (only used in whitelist mode)
+    session_cookie: Option<String>,
 }
 
 impl Handler {
-    pub fn new(postgres_cfg: &Postgres, whitelist_mode: bool) -> Result<Self> {
+    pub(crate) const ADMIN_ONLY: &'static str = "Error: Admin token required";
+    pub(crate) const ADMIN_SCOPE: &'static str = "Error: Stream requires admin rights";
+    pub(crate) const SETUP_TIMEOUT: &'static str = "Error: Timed out setting up subscription";
+    pub(crate) const MISSING_USER_AGENT: &'static str = "Error: Missing User-Agent header";
+    pub(crate) const QUERY_TOO_LONG: &'static str = "Error: Query string too long";
+    pub(crate) const FORBIDDEN_ORIGIN: &'static str = "Error: Origin not allowed";
+    pub(crate) const INVALID_PARAMETER: &'static str = "Invalid parameter: ";
+    pub(crate) const DENIED_TOKEN: &'static str = "Error: Access token denied";
+    pub(crate) const UNAVAILABLE: &'static str = "Error: Streaming is temporarily unavailable";
+    /// How many seconds clients rejected as `UNAVAILABLE` are asked to wait before retrying
+    const RETRY_AFTER_SECS: u64 = 5;
+    /// How long the health check waits on each backend before reporting it as down
+    const HEALTH_TIMEOUT: Duration = Duration::from_secs(1);
+    /// Threads for health checks, one per backend checked
+    const HEALTH_THREADS: usize = 2;
+    /// How many subscriptions can be set up at once (when setup is limited by a timeout); as many
+    /// as the Postgres pool has connections
+    const SETUP_THREADS: usize = 10;
+    /// How many more subscriptions can wait to be set up before requests are rejected as
+    /// `UNAVAILABLE`
+    const SETUP_QUEUE: usize = 1_000;
+
+    pub fn new(postgres_cfg: &Postgres, cfg: HandlerConfig) -> Result<Self> {
+        let whitelist_mode = cfg.whitelist_mode;
         Ok(Self {
-            pg_conn: PgPool::new(postgres_cfg, whitelist_mode)?,
+            pg_conn: PgPool::new(postgres_cfg, whitelist_mode, cfg.strict_list_membership)?,
+            reject_conflicting_params: cfg.reject_conflicting_params,
+            require_user_agent: cfg.require_user_agent,
+            sse_event_type_paths: cfg.sse_event_type_paths,
+            setup: cfg.setup_timeout.map(|budget| {
+                (
+                    budget,
+                    SetupPool::new(Self::SETUP_THREADS, Self::SETUP_QUEUE),
+                )
+            }),
+            tag_lookups: SetupPool::new(1, 1),
+            denied_tokens: Arc::new(RwLock::new(cfg.denied_tokens.into_iter().collect())),
+            always_allow_langs: Arc::new(cfg.always_allow_langs.into_iter().collect()),
+            whitelist_mode,
+            session_cookie: cfg.session_cookie.filter(|_| whitelist_mode),
         })
     }
 
+    /// Replace the access tokens to reject (for all new subscriptions)
+    pub fn deny_tokens(&self, tokens: Vec<String>) -> String {
+        let mut denied_tokens = self
+            .denied_tokens
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        *denied_tokens = tokens.into_iter().collect();
+        log::warn!("Now denying {} access tokens", denied_tokens.len());
+        format!("Denied tokens: {}", denied_tokens.len())
+    }
+
+    /// Reject a `Query` with a denied access token.  This happens before the token is looked
+    /// up, so a denied token is rejected even if Postgres still considers it valid.
+    fn screen_token(
+        denied_tokens: &RwLock<HashSet<String>>,
+        q: Query,
+    ) -> std::result::Result<Query, Rejection> {
+        let denied_tokens = denied_tokens.read().unwrap_or_else(PoisonError::into_inner);
+        match &q.access_token {
+            Some(token) if denied_tokens.contains(token) => Err(reject::custom(Self::DENIED_TOKEN)),
+            _ => Ok(q),
+        }
+    }
+
+    /// The `query`, with the access token from its `Authorization` header, its `access_token`
+    /// parameter or the `session_cookie` (if configured), in that order of preference.
+    fn with_sse_token(
+        query: BoxedFilter<(Query,)>,
+        session_cookie: Option<String>,
+    ) -> BoxedFilter<(Query,)> {
+        // because SSE requests place their `access_token` in the header instead of in a query
+        // parameter, we need to update our Query if the header has a token
+        query
+            .and(query::OptionalAccessToken::from_sse_header())
+            .and_then(Query::update_access_token)
+            .and(query::OptionalAccessToken::from_cookie(session_cookie))
+            .map(Query::or_session_token)
+            .boxed()
+    }
+
+    /// Reject a `Query` without any access token in whitelist mode.  (Postgres would reject it
+    /// too, but there's no need to check out a connection to find that out.)
+    fn screen_anonymous(whitelist_mode: bool, q: Query) -> std::result::Result<Query, Rejection> {
+        if whitelist_mode && q.access_token.is_none() {
+            Err(reject::custom(PgPool::BAD_TOKEN))
+        } else {
+            Ok(q)
+        }
+    }
+
+    /// Reject a `Query` with parameters that conflict (see `Query::conflict`), if `enabled`
+    fn screen_params(enabled: bool, q: Query) -> std::result::Result<Query, Rejection> {
+        match q.conflict() {
+            Some(conflict) if enabled => {
+                Err(reject::custom([Self::INVALID_PARAMETER, conflict].concat()))
+            }
+            _ => Ok(q),
+        }
+    }
+
+    /// The event type named by the rest of the path (such as `update`, in
+    /// `/api/v1/streaming/public/update`), if `enabled`.  When enabled, a path that continues
+    /// with anything other than a known event type is not found; otherwise, it's ignored.
+    fn event_type_segment(enabled: bool) -> BoxedFilter<(Option<String>,)> {
+        path::tail()
+            .and_then(move |tail: path::Tail| match tail.as_str() {
+                "" => Ok(None),
+                _ignored if !enabled => Ok(None),
+                event_type if Event::KNOWN_TYPES.contains(&event_type) => {
+                    Ok(Some(event_type.to_string()))
+                }
+                _unknown => Err(reject::not_found()),
+            })
+            .boxed()
+    }
+
     pub fn sse_subscription(&self) -> BoxedFilter<(Subscription,)> {
-        let pg_conn = self.pg_conn.clone();
-        any_of!(
+        let (pg_conn, setup) = (self.pg_conn.clone(), self.setup.clone());
+        let (denied_tokens, strict) = (self.denied_tokens.clone(), self.reject_conflicting_params);
+        let (event_type_paths, require_user_agent) =
+            (self.sse_event_type_paths, self.require_user_agent);
+        let langs = self.always_allow_langs.clone();
+        let (whitelist_mode, session_cookie) = (self.whitelist_mode, self.session_cookie.clone());
+        let query = any_of!(
             parse_sse_query!( path => "api" / "v1" / "streaming" / "user" / "notification"
                               endpoint => "user:notification" ),
             parse_sse_query!( path => "api" / "v1" / "streaming" / "user"
                               endpoint => "user"),
             parse_sse_query!( path => "api" / "v1" / "streaming" / "public" / "local"
                               endpoint => "public:local"),
+            parse_sse_query!( path => "api" / "v1" / "streaming" / "public" / "remote"
+                              endpoint => "public:remote"),
             parse_sse_query!( path => "api" / "v1" / "streaming" / "public"
                               endpoint => "public"),
             parse_sse_query!( path => "api" / "v1" / "streaming" / "direct"
@@ -92,25 +299,346 @@ impl Handler {
             parse_sse_query!( path => "api" / "v1" / "streaming" / "list"
                               endpoint => "list")
         )
-        // because SSE requests place their `access_token` in the header instead of in a query
-        // parameter, we need to update our Query if the header has a token
-        .and(query::OptionalAccessToken::from_sse_header())
-        .and_then(Query::update_access_token)
-        .and_then(move |q| Subscription::query_postgres(q, pg_conn.clone()))
-        .boxed()
+        .and(Self::event_type_segment(event_type_paths))
+        .map(|q: Query, event_type: Option<String>| Query { event_type, ..q })
+        .boxed();
+
+        Self::with_sse_token(query, session_cookie)
+            .and(Self::user_agent(require_user_agent))
+            .and_then(move |q| Self::screen_anonymous(whitelist_mode, q))
+            .and_then(move |q| Self::screen_params(strict, q))
+            .and_then(move |q| Self::screen_token(&denied_tokens, q))
+            .and_then(move |q| {
+                let (pg_conn, langs) = (pg_conn.clone(), langs.clone());
+                Self::within(setup.clone(), move |deadline| {
+                    let pg_conn = pg_conn.until(deadline);
+                    Ok(Subscription::query_postgres(q, pg_conn)?.always_allowing(&langs))
+                })
+            })
+            .boxed()
     }
 
     pub fn ws_subscription(&self) -> BoxedFilter<(Subscription,)> {
-        let pg_conn = self.pg_conn.clone();
+        let (pg_conn, setup) = (self.pg_conn.clone(), self.setup.clone());
+        let (denied_tokens, strict) = (self.denied_tokens.clone(), self.reject_conflicting_params);
+        let langs = self.always_allow_langs.clone();
         parse_ws_query()
+            .and(Self::user_agent(self.require_user_agent))
             .and(query::OptionalAccessToken::from_ws_header())
             .and_then(Query::update_access_token)
-            .and_then(move |q| Subscription::query_postgres(q, pg_conn.clone()))
+            .and_then(move |q| Self::screen_params(strict, q))
+            .and_then(move |q| Self::screen_token(&denied_tokens, q))
+            .and_then(move |q| {
+                let (pg_conn, langs) = (pg_conn.clone(), langs.clone());
+                Self::within(setup.clone(), move |deadline| {
+                    let pg_conn = pg_conn.until(deadline);
+                    Ok(Subscription::query_postgres(q, pg_conn)?.always_allowing(&langs))
+                })
+            })
             .boxed()
     }
 
-    pub fn health(&self) -> BoxedFilter<()> {
-        warp::path!("api" / "v1" / "streaming" / "health").boxed()
+    /// Run the (blocking) `setup` work, giving up with a 504 if it takes longer than `budget`.
+    ///
+    /// With a budget, `setup` runs on one of the `SetupPool`'s threads so that the request can be
+    /// answered as soon as the budget runs out, or is rejected as `UNAVAILABLE` if too much setup
+    /// is already waiting for a thread.  `setup` is passed the deadline, so that work abandoned
+    /// at the deadline can stop there (see `PgPool::until`) rather than finish for nothing.
+    fn within<T: Send + 'static>(
+        budget: Option<(Duration, SetupPool)>,
+        setup: impl FnOnce(Option<Instant>) -> std::result::Result<T, Rejection> + Send + 'static,
+    ) -> impl Future<Item = T, Error = Rejection> {
+        let (budget, setup_pool) = match budget {
+            Some(budget) => budget,
+            None => return Either::A(future::result(setup(None))),
+        };
+
+        let deadline = Instant::now() + budget;
+        let (tx, rx) = oneshot::channel();
+        let queued = setup_pool.run(move || {
+            if tx.send(setup(Some(deadline))).is_err() {
+                log::info!("Discarding a subscription that was set up after its timeout");
+            }
+        });
+        if !queued {
+            log::warn!("Rejecting a subscription: too many are already waiting to be set up");
+            return Either::A(future::err(reject::custom(Self::UNAVAILABLE)));
+        }
+        Either::B(Timeout::new(rx, budget).then(|result| match result {
+            Ok(setup_result) => setup_result,
+            Err(e) if e.is_elapsed() => Err(reject::custom(Self::SETUP_TIMEOUT)),
+            Err(e) => {
+                log::error!("Subscription setup failed: {}", e);
+                Err(reject::custom(PgPool::SERVER_ERR))
+            }
+        }))
+    }
+
+    /// Look up the current ids of the hashtags `tag_names` in Postgres, leaving out any that
+    /// can't be found.  The lookups block, so (like subscription setup) they run on a thread of
+    /// their own rather than stall the streams sharing the caller's thread.  Fails without
+    /// looking anything up if the previous lookups are still running.
+    pub fn select_hashtag_ids(
+        &self,
+        tag_names: Vec<String>,
+    ) -> impl Future<Item = Vec<(String, i64)>, Error = ()> {
+        let pg_conn = self.pg_conn.clone();
+        let (tx, rx) = oneshot::channel();
+        let queued = self.tag_lookups.run(move || {
+            let ids = tag_names
+                .into_iter()
+                .filter_map(|tag| {
+                    let id = pg_conn.clone().select_hashtag_id(&tag).ok()?;
+                    Some((tag, id))
+                })
+                .collect();
+            if tx.send(ids).is_err() {
+                log::info!("Discarding hashtag ids that were looked up for nothing");
+            }
+        });
+        if !queued {
+            log::warn!("Not looking up hashtag ids: the previous lookups are still running");
+            return Either::A(future::err(()));
+        }
+        Either::B(rx.map_err(|e| log::error!("Hashtag id lookups failed: {}", e)))
+    }
+
+    /// Look up the names and ids of the `count` most-used hashtags in Postgres
+    pub fn select_popular_hashtags(&self, count: usize) -> Vec<(String, i64)> {
+        self.pg_conn
+            .clone()
+            .select_popular_hashtags(count)
+            .unwrap_or_else(|e| {
+                log::error!("Could not load popular hashtags: {:?}", e);
+                Vec::new()
+            })
+    }
+
+    /// The values of the configured `headers` (formatted as ` name=value` pairs) for inclusion
+    /// in the request's log line.  Headers that aren't present on the request are omitted.
+    pub fn logged_headers(headers: Vec<String>) -> BoxedFilter<(String,)> {
+        warp::header::headers_cloned()
+            .map(move |request_headers: HeaderMap| {
+                headers
+                    .iter()
+                    .filter_map(|name| {
+                        let value = request_headers.get(name.as_str())?.to_str().ok()?;
+                        Some(format!(" {}={}", name, value))
+                    })
+                    .collect()
+            })
+            .boxed()
+    }
+
+    /// Only allow requests that present one of the `admin_tokens` as a bearer token.  These are
+    /// checked directly (rather than through Postgres), and no tokens means no restriction.
+    pub fn admin_only(admin_tokens: Vec<String>) -> BoxedFilter<()> {
+        query::OptionalAccessToken::from_sse_header()
+            .and_then(move |token: Option<String>| match token {
+                _ if admin_tokens.is_empty() => Ok(()),
+                Some(token) if admin_tokens.contains(&token) => Ok(()),
+                _ => Err(reject::custom(Self::ADMIN_ONLY)),
+            })
+            .untuple_one()
+            .boxed()
+    }
+
+    /// Like `admin_only`, but with no `admin_tokens` the endpoint is not found at all.  This
+    /// is for endpoints (such as `status_drain`) that are too disruptive to leave open.
+    pub fn admin_required(admin_tokens: Vec<String>) -> BoxedFilter<()> {
+        if admin_tokens.is_empty() {
+            return warp::any()
+                .and_then(|| Err::<(), _>(reject::not_found()))
+                .untuple_one()
+                .boxed();
+        }
+        Self::admin_only(admin_tokens)
+    }
+
+    /// Reject requests without a `User-Agent` header (if `required`), as a crude defense against
+    /// bots.  This is checked from the headers alone, before any Postgres queries, but only once
+    /// the path has matched a stream, so that unknown paths are still not found.
+    fn user_agent(required: bool) -> BoxedFilter<()> {
+        warp::header::optional::<String>("user-agent")
+            .and_then(move |user_agent: Option<String>| match user_agent {
+                None if required => Err(reject::custom(Self::MISSING_USER_AGENT)),
+                _ => Ok(()),
+            })
+            .untuple_one()
+            .boxed()
+    }
+
+    /// Reject WebSocket upgrades whose `Origin` isn't one of the `allowed_origins` (no origins
+    /// means no restriction).  Other requests, and upgrades without an `Origin` (which browsers
+    /// always send), pass through, so this can sit in front of the WebSocket routes without
+    /// affecting SSE.
+    pub fn ws_origin(allowed_origins: Vec<String>) -> BoxedFilter<()> {
+        warp::header::optional::<String>("upgrade")
+            .and(warp::header::optional::<String>("origin"))
+            .and_then(move |upgrade: Option<String>, origin: Option<String>| {
+                let is_ws = upgrade.map_or(false, |u| u.eq_ignore_ascii_case("websocket"));
+                match origin {
+                    Some(origin) if is_ws && !allowed_origins.is_empty() => {
+                        if allowed_origins.contains(&origin) {
+                            Ok(())
+                        } else {
+                            Err(reject::custom(Self::FORBIDDEN_ORIGIN))
+                        }
+                    }
+                    _ => Ok(()),
+                }
+            })
+            .untuple_one()
+            .boxed()
+    }
+
+    /// Reject requests with a query string longer than `max_len` bytes.  This runs before any
+    /// parameters are parsed, so an oversized `access_token` or `tag` costs nothing more than
+    /// reading the request line.
+    pub fn query_length(max_len: usize) -> BoxedFilter<()> {
+        warp::query::raw()
+            .or(warp::any().map(String::new))
+            .unify()
+            .and_then(move |query: String| {
+                if query.len() > max_len {
+                    Err(reject::custom(Self::QUERY_TOO_LONG))
+                } else {
+                    Ok(())
+                }
+            })
+            .untuple_one()
+            .boxed()
+    }
+
+    /// Reject new subscriptions while the event source is down (if `enabled`), as reported by
+    /// `healthy`, so that clients retry later instead of waiting on a stream with no events.
+    /// It goes before `sse_subscription` and `ws_subscription`, so rejected requests never
+    /// query Postgres.
+    pub fn available<F>(enabled: bool, healthy: F) -> BoxedFilter<()>
+    where
+        F: Fn() -> bool + Clone + Send + Sync + 'static,
+    {
+        warp::any()
+            .and_then(move || {
+                if enabled && !healthy() {
+                    Err(reject::custom(Self::UNAVAILABLE))
+                } else {
+                    Ok(())
+                }
+            })
+            .untuple_one()
+            .boxed()
+    }
+
+    /// Answer `GET /api/v1/streaming/health` for readiness probes: 200 if both Redis (as
+    /// checked by `redis_ok`) and Postgres answer within `HEALTH_TIMEOUT`, or else 503 naming
+    /// the backends that didn't (e.g., `{"status":"unavailable","failed":["redis"]}`).  Both
+    /// checks reuse open connections, so this is cheap enough to probe every few seconds.
+    pub fn health<F>(&self, redis_ok: F) -> BoxedFilter<(reply::WithStatus<reply::Json>,)>
+    where
+        F: Fn(Duration) -> bool + Clone + Send + Sync + 'static,
+    {
+        let pg_conn = self.pg_conn.clone();
+        let postgres_ok = move |timeout| {
+            pg_conn
+                .ping(timeout)
+                .map_err(|e| log::error!("Health check could not query Postgres: {}", e))
+                .is_ok()
+        };
+        let checks = SetupPool::new(Self::HEALTH_THREADS, Self::HEALTH_THREADS);
+        warp::path!("api" / "v1" / "streaming" / "health")
+            .and_then(move || {
+                let (redis_ok, postgres_ok) = (redis_ok.clone(), postgres_ok.clone());
+                Self::check_health(&checks, Self::HEALTH_TIMEOUT, redis_ok, postgres_ok)
+            })
+            .boxed()
+    }
+
+    /// Check Redis and Postgres with `redis_ok` and `postgres_ok`, each on one of the `checks`
+    /// threads (since both block), and reply naming any that failed or took over `timeout`
+    fn check_health(
+        checks: &SetupPool,
+        timeout: Duration,
+        redis_ok: impl FnOnce(Duration) -> bool + Send + 'static,
+        postgres_ok: impl FnOnce(Duration) -> bool + Send + 'static,
+    ) -> impl Future<Item = reply::WithStatus<reply::Json>, Error = Rejection> {
+        let redis = Self::check(checks, timeout, "redis", redis_ok);
+        let postgres = Self::check(checks, timeout, "postgres", postgres_ok);
+        redis.join(postgres).map(|(redis, postgres)| {
+            let failed: Vec<&str> = redis.into_iter().chain(postgres).collect();
+            Self::health_reply(&failed)
+        })
+    }
+
+    /// Run the check `ok` on one of the `checks` threads, resolving to the `backend` it checks
+    /// if that fails or doesn't finish in time (allowing `ok` its `timeout` plus as long again)
+    fn check(
+        checks: &SetupPool,
+        timeout: Duration,
+        backend: &'static str,
+        ok: impl FnOnce(Duration) -> bool + Send + 'static,
+    ) -> impl Future<Item = Option<&'static str>, Error = Rejection> {
+        let (tx, rx) = oneshot::channel();
+        let queued = checks.run(move || {
+            if tx.send(ok(timeout)).is_err() {
+                log::info!("Discarding a health check that finished after its timeout");
+            }
+        });
+        if !queued {
+            log::warn!(
+                "Could not check {}: earlier health checks are still running",
+                backend
+            );
+            return Either::A(future::ok::<_, Rejection>(Some(backend)));
+        }
+        Either::B(
+            Timeout::new(rx, timeout * 2).then(move |result| match result {
+                Ok(true) => Ok(None),
+                Ok(false) => Ok(Some(backend)),
+                Err(e) => {
+                    log::error!("Health check of {} did not finish: {}", backend, e);
+                    Ok(Some(backend))
+                }
+            }),
+        )
+    }
+
+    fn health_reply(failed: &[&str]) -> reply::WithStatus<reply::Json> {
+        if failed.is_empty() {
+            let ok = reply::json(&serde_json::json!({ "status": "ok" }));
+            reply::with_status(ok, StatusCode::OK)
+        } else {
+            let body = serde_json::json!({ "status": "unavailable", "failed": failed });
+            reply::with_status(reply::json(&body), StatusCode::SERVICE_UNAVAILABLE)
+        }
+    }
+
+    /// Answer `GET /api/v1/streaming/version` with this build's version and the git commit it
+    /// was built from (e.g., `{"version":"0.9.9","commit":"2092b91"}`), for fleet management
+    pub fn version() -> BoxedFilter<(reply::Json,)> {
+        warp::path!("api" / "v1" / "streaming" / "version")
+            .and(warp::path::end())
+            .and(warp::get2())
+            .map(|| {
+                let version = env!("CARGO_PKG_VERSION");
+                let commit = env!("FLODGATT_GIT_COMMIT");
+                reply::json(&serde_json::json!({ "version": version, "commit": commit }))
+            })
+            .boxed()
+    }
+
+    /// Answer `GET /api/v1/streaming` with `{"status":"online"}` (if `enabled`), so that uptime
+    /// monitors probing the streaming root see it as up.  Valid streaming requests are matched
+    /// before this, so it only answers requests that would otherwise be rejected.
+    pub fn probe(enabled: bool) -> BoxedFilter<(reply::Json,)> {
+        warp::path!("api" / "v1" / "streaming")
+            .and(warp::path::end())
+            .and(warp::get2())
+            .and_then(move || match enabled {
+                true => Ok(reply::json(&serde_json::json!({ "status": "online" }))),
+                false => Err(warp::reject::not_found()),
+            })
+            .boxed()
     }
 
     pub fn status(&self) -> BoxedFilter<()> {
@@ -119,6 +647,20 @@ impl Handler {
             .boxed()
     }
 
+    /// `GET /metrics`, for Prometheus (see `RedisManager::metrics`), if `enabled`.  Like
+    /// `probe`, this is served in every build and needs no token, so that scrapers can reach it.
+    pub fn metrics(enabled: bool) -> BoxedFilter<()> {
+        warp::path!("metrics")
+            .and(warp::path::end())
+            .and(warp::get2())
+            .and_then(move || match enabled {
+                true => Ok(()),
+                false => Err(warp::reject::not_found()),
+            })
+            .untuple_one()
+            .boxed()
+    }
+
     pub fn status_per_timeline(&self) -> BoxedFilter<()> {
         warp::path!("api" / "v1" / "streaming" / "status" / "per_timeline").boxed()
     }
@@ -127,12 +669,62 @@ impl Handler {
         warp::path!("api" / "v1" / "streaming" / "status" / "backpresure").boxed()
     }
 
+    pub fn status_subscriptions(&self) -> BoxedFilter<()> {
+        warp::path!("api" / "v1" / "streaming" / "status" / "subscriptions").boxed()
+    }
+
+    pub fn status_dead_letters(&self) -> BoxedFilter<()> {
+        warp::path!("api" / "v1" / "streaming" / "status" / "dead_letters").boxed()
+    }
+
+    /// Discard the held events and poll Redis straight away.  This is only served when
+    /// `ADMIN_TOKENS` is set (see `admin_required`).
+    pub fn status_flush(&self) -> BoxedFilter<()> {
+        warp::path!("api" / "v1" / "streaming" / "status" / "flush")
+            .and(warp::post2())
+            .boxed()
+    }
+
+    /// Disconnect the clients of the timeline named in the path (by its Redis channel, such as
+    /// `hashtag:spam`).  This is served with or without the `stub_status` feature, but only
+    /// when `ADMIN_TOKENS` is set (see `admin_required`).
+    pub fn status_drain(&self) -> BoxedFilter<(String,)> {
+        warp::path!("api" / "v1" / "streaming" / "status" / "drain" / String)
+            .and(warp::post2())
+            .boxed()
+    }
+
+    /// Replace the denied access tokens with the JSON array of tokens in the request body.  This
+    /// is only served when `ADMIN_TOKENS` is set (see `admin_required`).
+    pub fn status_denied_tokens(&self) -> BoxedFilter<(Vec<String>,)> {
+        warp::path!("api" / "v1" / "streaming" / "status" / "denied_tokens")
+            .and(warp::post2())
+            .and(warp::body::json())
+            .boxed()
+    }
+
     pub fn err(r: Rejection) -> std::result::Result<impl warp::Reply, warp::Rejection> {
         use StatusCode as Code;
-        let (msg, code) = match &r.cause().map(|cause| cause.to_string()).as_deref() {
+        let cause = r.cause().map(|cause| cause.to_string());
+        let is_invalid_parameter = |msg: &&String| msg.starts_with(Self::INVALID_PARAMETER);
+        if let Some(msg) = cause.as_ref().filter(is_invalid_parameter) {
+            log::info!("Request rejected: {} - {:?}", Code::BAD_REQUEST, &r);
+            let error = serde_json::json!({ "error": msg });
+            return Ok(reply::with_status(reply::json(&error), Code::BAD_REQUEST).into_response());
+        }
+
+        let (msg, code) = match &cause.as_deref() {
             Some(PgPool::BAD_TOKEN) => (PgPool::BAD_TOKEN, Code::UNAUTHORIZED),
             Some(PgPool::PG_NULL) => (PgPool::PG_NULL, Code::BAD_REQUEST),
             Some(PgPool::MISSING_HASHTAG) => (PgPool::MISSING_HASHTAG, Code::BAD_REQUEST),
+            Some(Self::ADMIN_ONLY) => (Self::ADMIN_ONLY, Code::FORBIDDEN),
+            Some(Self::ADMIN_SCOPE) => (Self::ADMIN_SCOPE, Code::FORBIDDEN),
+            Some(Self::DENIED_TOKEN) => (Self::DENIED_TOKEN, Code::FORBIDDEN),
+            Some(Self::SETUP_TIMEOUT) => (Self::SETUP_TIMEOUT, Code::GATEWAY_TIMEOUT),
+            Some(Self::MISSING_USER_AGENT) => (Self::MISSING_USER_AGENT, Code::BAD_REQUEST),
+            Some(Self::QUERY_TOO_LONG) => (Self::QUERY_TOO_LONG, Code::URI_TOO_LONG),
+            Some(Self::FORBIDDEN_ORIGIN) => (Self::FORBIDDEN_ORIGIN, Code::FORBIDDEN),
+            Some(Self::UNAVAILABLE) => (Self::UNAVAILABLE, Code::SERVICE_UNAVAILABLE),
             Some(PgPool::SERVER_ERR) | Some(_) => (PgPool::SERVER_ERR, Code::INTERNAL_SERVER_ERROR),
             None if r.is_not_found() => return Err(r),
 
@@ -144,7 +736,12 @@ impl Handler {
         } else {
             log::info!("Request rejected: {} - {:?}", code, &r);
         };
-        Ok(reply::with_status(reply::json(&msg), code))
+        let mut response = reply::with_status(reply::json(&msg), code).into_response();
+        if code == Code::SERVICE_UNAVAILABLE {
+            let retry_after = HeaderValue::from(Self::RETRY_AFTER_SECS);
+            response.headers_mut().insert(RETRY_AFTER, retry_after);
+        }
+        Ok(response)
     }
 }
 
@@ -157,12 +754,56 @@ fn parse_ws_query() -> BoxedFilter<(Query,)> {
         .and(Media::to_filter())
         .and(Hashtag::to_filter())
         .and(List::to_filter())
-        .map(|s: Stream, a: Auth, m: Media, h: Hashtag, l: List| Query {
-            access_token: a.access_token,
-            stream: s.stream,
-            media: m.is_truthy(),
-            hashtag: h.tag,
-            list: l.list,
-        })
+        .and(ExcludeSelf::to_filter())
+        .and(OnlyRelationships::to_filter())
+        .and(NewestFirst::to_filter())
+        .and(Backfill::to_filter())
+        .and(Presence::to_filter())
+        .and(DebugTimeline::to_filter())
+        .and(MarkLocal::to_filter())
+        .and(MinAccountAge::to_filter())
+        .and(MinFollowers::to_filter())
+        .and(Accounts::to_filter())
+        .and(MaxRate::to_filter())
+        .map(
+            |s: Stream,
+             a: Auth,
+             m: Media,
+             h: Hashtag,
+             l: List,
+             e: ExcludeSelf,
+             r: OnlyRelationships,
+             n: NewestFirst,
+             b: Backfill,
+             p: Presence,
+             d: DebugTimeline,
+             local: MarkLocal,
+             age: MinAccountAge,
+             f: MinFollowers,
+             accounts: Accounts,
+             max_rate: MaxRate| {
+                Query {
+                    access_token: a.access_token,
+                    stream: s.stream,
+                    media: m.is_truthy(),
+                    hashtag: h.tag,
+                    list: l.list,
+                    exclude_self: e.is_truthy(),
+                    only_relationships: r.is_truthy(),
+                    newest_first: n.is_truthy(),
+                    backfill: b.is_truthy(),
+                    presence: p.is_truthy(),
+                    debug_timeline: d.is_truthy(),
+                    mark_local: local.is_truthy(),
+                    min_account_age_days: age.min_account_age_days,
+                    min_followers: f.min_followers,
+                    accounts: accounts.ids(),
+                    limit: 0,    // only SSE streams can be limited
+                    batch_ms: 0, // or batched
+                    max_events_per_sec: max_rate.max_events_per_sec,
+                    event_type: None,
+                }
+            },
+        )
         .boxed()
 }
@@ -8,13 +8,22 @@ use ::postgres::{self, SimpleQueryMessage};
 use hashbrown::HashSet;
 use r2d2_postgres::PostgresConnectionManager;
 use std::convert::TryFrom;
+use std::time::{Duration, Instant};
 #[allow(deprecated)] // one fn is deprecated, not whole module
 use warp::reject;
 
+type Pool = r2d2::Pool<PostgresConnectionManager<postgres::NoTls>>;
+type Conn = r2d2::PooledConnection<PostgresConnectionManager<postgres::NoTls>>;
+
 #[derive(Clone)]
 pub struct PgPool {
-    conn: r2d2::Pool<PostgresConnectionManager<postgres::NoTls>>,
+    /// The pool for the database queries run on (see `read_cfg`)
+    pool: Pool,
     whitelist_mode: bool,
+    /// Whether `list` subscriptions should only receive statuses from the list's members
+    pub(super) strict_list_membership: bool,
+    /// When to stop waiting for a connection (see `until`)
+    deadline: Option<Instant>,
 }
 
 type Result<T> = std::result::Result<T, err::Error>;
@@ -26,7 +35,20 @@ impl PgPool {
     pub(crate) const PG_NULL: &'static str = "Error: Unexpected null from Postgres";
     pub(crate) const MISSING_HASHTAG: &'static str = "Error: Hashtag does not exist";
 
-    pub(crate) fn new(pg_cfg: &config::Postgres, whitelist_mode: bool) -> Result<Self> {
+    pub(crate) fn new(
+        pg_cfg: &config::Postgres,
+        whitelist_mode: bool,
+        strict_list_membership: bool,
+    ) -> Result<Self> {
+        Ok(Self {
+            pool: Self::pool(Self::read_cfg(pg_cfg))?,
+            whitelist_mode,
+            strict_list_membership,
+            deadline: None,
+        })
+    }
+
+    fn pool(pg_cfg: &config::Postgres) -> Result<Pool> {
         let mut cfg = postgres::Config::new();
         cfg.user(&pg_cfg.user)
             .host(&*pg_cfg.host.to_string())
@@ -39,11 +61,42 @@ impl PgPool {
         cfg.connect(postgres::NoTls)?; // Test connection, letting us immediately exit with an error
                                        // when Postgres isn't running instead of timing out below
         let manager = PostgresConnectionManager::new(cfg, postgres::NoTls);
+        Ok(r2d2::Pool::builder().max_size(10).build(manager)?)
+    }
 
-        Ok(Self {
-            conn: r2d2::Pool::builder().max_size(10).build(manager)?,
-            whitelist_mode,
-        })
+    /// The database to run queries on: the read replica, if there is one, or else the primary.
+    /// Flodgatt never writes to Postgres, so with a replica it doesn't connect to the primary.
+    fn read_cfg(pg_cfg: &config::Postgres) -> &config::Postgres {
+        pg_cfg.replica.as_deref().unwrap_or(pg_cfg)
+    }
+
+    /// This pool, giving up on checking out a connection once `deadline` has passed.  Setup that
+    /// has been abandoned at its deadline then fails at its next query, instead of holding on to
+    /// (or waiting for) a connection that nothing will use.
+    pub(crate) fn until(self, deadline: Option<Instant>) -> Self {
+        Self { deadline, ..self }
+    }
+
+    /// Check out a connection from the pool, waiting no later than the `deadline`
+    fn conn(&self) -> Rejectable<Conn> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return self.pool.get().map_err(reject::custom),
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(reject::custom(super::Handler::SETUP_TIMEOUT));
+        }
+        self.pool
+            .get_timeout(deadline - now)
+            .map_err(|_| reject::custom(super::Handler::SETUP_TIMEOUT))
+    }
+
+    /// Check that Postgres can run a query, waiting at most `timeout` for a connection
+    pub(crate) fn ping(&self, timeout: Duration) -> Result<()> {
+        let mut conn = self.pool.get_timeout(timeout)?;
+        conn.simple_query("SELECT 1")?;
+        Ok(())
     }
 
     fn is_safe(txt: &str) -> bool {
@@ -52,7 +105,7 @@ impl PgPool {
     }
 
     pub(crate) fn select_user(self, token: &Option<String>) -> Rejectable<UserData> {
-        let mut conn = self.conn.get().map_err(reject::custom)?;
+        let mut conn = self.conn()?;
 
         if let Some(token) = token {
             if !Self::is_safe(token) {
@@ -91,10 +144,8 @@ LIMIT 1", &token.to_owned())
                 .filter_map(|scope| Scope::try_from(scope).ok())
                 .collect();
             // We don't need to separately track read auth - it's just all three others
-            if scopes.contains(&Scope::Read) {
-                scopes = vec![Scope::Statuses, Scope::Notifications, Scope::Lists]
-                    .into_iter()
-                    .collect()
+            if scopes.remove(&Scope::Read) {
+                scopes.extend(vec![Scope::Statuses, Scope::Notifications, Scope::Lists]);
             }
 
             Ok(UserData {
@@ -114,7 +165,7 @@ LIMIT 1", &token.to_owned())
             Err(reject::custom(Self::MISSING_HASHTAG))?;
         };
 
-        let mut conn = self.conn.get().map_err(reject::custom)?;
+        let mut conn = self.conn()?;
         let rows = conn
             .simple_query(&format!(
                 "SELECT id FROM tags WHERE name='{}' LIMIT 1",
@@ -128,12 +179,34 @@ LIMIT 1", &token.to_owned())
         .map(|s| s.parse().map_err(reject::custom))?
     }
 
+    /// Query Postgres for the names and ids of the `limit` most-used hashtags
+    pub(crate) fn select_popular_hashtags(self, limit: usize) -> Rejectable<Vec<(String, i64)>> {
+        let mut conn = self.conn()?;
+        conn.simple_query(&format!(
+            "SELECT tags.name, tags.id FROM tags
+                 JOIN statuses_tags ON statuses_tags.tag_id = tags.id
+                 GROUP BY tags.id ORDER BY COUNT(*) DESC LIMIT {}",
+            limit
+        ))
+        .map_err(reject::custom)?
+        .iter()
+        .try_fold(Vec::new(), |mut tags, row| match row {
+            SimpleQueryMessage::Row(row) => {
+                let name = get_col_or_reject(row, 0)?.to_string();
+                let id = get_col_or_reject(row, 1)?.parse().map_err(reject::custom)?;
+                tags.push((name, id));
+                Ok(tags)
+            }
+            _ => Ok(tags),
+        })
+    }
+
     /// Query Postgres for everyone the user has blocked or muted
     ///
     /// **NOTE**: because we check this when the user connects, it will not include any blocks
     /// the user adds until they refresh/reconnect.
     pub(crate) fn select_blocked_users(self, user_id: Id) -> Rejectable<HashSet<Id>> {
-        let mut conn = self.conn.get().map_err(reject::custom)?;
+        let mut conn = self.conn()?;
         conn.simple_query(&format!(
             "SELECT target_account_id FROM blocks WHERE account_id = {0}
                  UNION SELECT target_account_id FROM mutes WHERE account_id = {0}",
@@ -155,7 +228,7 @@ LIMIT 1", &token.to_owned())
     /// **NOTE**: because we check this when the user connects, it will not include any blocks
     /// the user adds until they refresh/reconnect.
     pub(crate) fn select_blocking_users(self, user_id: Id) -> Rejectable<HashSet<Id>> {
-        let mut conn = self.conn.get().map_err(reject::custom)?;
+        let mut conn = self.conn()?;
         conn.simple_query(&format!(
             "SELECT account_id FROM blocks WHERE target_account_id = {}",
             &*user_id
@@ -176,7 +249,7 @@ LIMIT 1", &token.to_owned())
     /// **NOTE**: because we check this when the user connects, it will not include any blocks
     /// the user adds until they refresh/reconnect.
     pub(crate) fn select_blocked_domains(self, user_id: Id) -> Rejectable<HashSet<String>> {
-        let mut conn = self.conn.get().map_err(reject::custom)?;
+        let mut conn = self.conn()?;
         conn.simple_query(&format!(
             "SELECT domain FROM account_domain_blocks WHERE account_id = {}",
             &*user_id,
@@ -192,10 +265,61 @@ LIMIT 1", &token.to_owned())
         })
     }
 
+    /// Query Postgres for the phrases the user has filtered from the `context` (`home`,
+    /// `notifications` or `public`) and not yet expired, with whether each should only match
+    /// whole words
+    ///
+    /// Only irreversible filters are returned: the client hides what the others match itself,
+    /// so the user can still choose to see it.
+    pub(crate) fn select_filters(
+        self,
+        user_id: Id,
+        context: &str,
+    ) -> Rejectable<Vec<(String, bool)>> {
+        let mut conn = self.conn()?;
+        conn.simple_query(&format!(
+            "SELECT phrase, whole_word FROM custom_filters WHERE account_id = {} \
+             AND '{}' = ANY(context) AND irreversible \
+             AND (expires_at IS NULL OR expires_at > NOW())",
+            &*user_id, context,
+        ))
+        .map_err(reject::custom)?
+        .iter()
+        .try_fold(Vec::new(), |mut filters, row| match row {
+            SimpleQueryMessage::Row(row) => {
+                let phrase = get_col_or_reject(row, 0)?.to_string();
+                filters.push((phrase, get_col_or_reject(row, 1)? == "t"));
+                Ok(filters)
+            }
+            _ => Ok(filters),
+        })
+    }
+
+    /// Query Postgres for the accounts that are members of a list
+    ///
+    /// **NOTE**: because we check this when the user connects, it will not include any members
+    /// added to the list until they refresh/reconnect.
+    pub(crate) fn select_list_members(self, list_id: i64) -> Rejectable<HashSet<Id>> {
+        let mut conn = self.conn()?;
+        conn.simple_query(&format!(
+            "SELECT account_id FROM list_accounts WHERE list_id = {}",
+            list_id
+        ))
+        .map_err(reject::custom)?
+        .iter()
+        .try_fold(HashSet::new(), |mut set, row| match row {
+            SimpleQueryMessage::Row(row) => {
+                set.insert(get_col_or_reject(row, 0)?.parse().map_err(reject::custom)?);
+                Ok(set)
+            }
+            _ => Ok(set),
+        })
+    }
+
     /// Test whether a user owns a list
     pub(crate) fn user_owns_list(self, user_id: Id, list_id: i64) -> Rejectable<bool> {
         // For the Postgres query, `id` = list number; `account_id` = user.id
-        let mut conn = self.conn.get().map_err(reject::custom)?;
+        let mut conn = self.conn()?;
         let rows = conn
             .simple_query(&format!(
                 "SELECT id, account_id FROM lists WHERE id={} LIMIT 1",
@@ -218,3 +342,38 @@ fn get_col_or_reject(row: &postgres::row::SimpleQueryRow, col: usize) -> Rejecta
         .map_err(reject::custom)?
         .ok_or_else(|| reject::custom(PgPool::PG_NULL))?)
 }
+
+/// Counts the failed attempts to connect to Postgres
+#[cfg(test)]
+#[derive(Debug)]
+struct CountedErrors(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+#[cfg(test)]
+impl r2d2::HandleError<postgres::Error> for CountedErrors {
+    fn handle_error(&self, _: postgres::Error) {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl PgPool {
+    /// A pool that never connects (there's no Postgres for it to connect to), counting each
+    /// attempt to in `attempts`
+    pub(crate) fn unconnected(attempts: std::sync::Arc<std::sync::atomic::AtomicUsize>) -> Self {
+        let manager = PostgresConnectionManager::new(postgres::Config::new(), postgres::NoTls);
+        let pool = r2d2::Pool::builder()
+            .min_idle(Some(0))
+            .connection_timeout(Duration::from_millis(100))
+            .error_handler(Box::new(CountedErrors(attempts)))
+            .build_unchecked(manager);
+        Self {
+            pool,
+            whitelist_mode: false,
+            strict_list_membership: false,
+            deadline: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,74 @@
+use super::*;
+
+/// A pool that doesn't connect to Postgres until it's used
+fn unconnected_pool(max_size: u32) -> Pool {
+    let manager = PostgresConnectionManager::new(postgres::Config::new(), postgres::NoTls);
+    r2d2::Pool::builder()
+        .max_size(max_size)
+        .min_idle(Some(0))
+        .build_unchecked(manager)
+}
+
+/// A `PgPool` whose connections never succeed (there's no Postgres for them to connect to)
+fn unconnected_pg_pool() -> PgPool {
+    PgPool {
+        pool: unconnected_pool(1),
+        whitelist_mode: false,
+        strict_list_membership: false,
+        deadline: None,
+    }
+}
+
+#[test]
+fn queries_use_the_replica_only_when_one_is_configured() {
+    let pg_cfg = |vars: &[(&str, &str)]| {
+        let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        config::from_env(vars.collect()).expect("valid config").0
+    };
+
+    let primary_only = pg_cfg(&[("DB_HOST", "primary.example")]);
+    let read_host = PgPool::read_cfg(&primary_only).host.to_string();
+    assert_eq!(read_host, "primary.example");
+
+    let with_replica = pg_cfg(&[
+        ("DB_HOST", "primary.example"),
+        ("DB_REPLICA_URL", "postgres://replica.example/mastodon"),
+    ]);
+    let read_host = PgPool::read_cfg(&with_replica).host.to_string();
+    assert_eq!(read_host, "replica.example");
+}
+
+#[test]
+fn ping_fails_without_postgres() {
+    let pg_pool = unconnected_pg_pool();
+    assert!(pg_pool.ping(Duration::from_millis(50)).is_err());
+}
+
+#[test]
+fn queries_stop_waiting_for_a_connection_at_the_deadline() {
+    // Without Postgres, checking out a connection never succeeds; r2d2 would keep trying for 30s
+    let pg_pool = unconnected_pg_pool().until(Some(Instant::now() + Duration::from_millis(50)));
+    let cause = |rejection: warp::Rejection| rejection.cause().map(|cause| cause.to_string());
+
+    let start = Instant::now();
+    let rejection = pg_pool
+        .clone()
+        .select_hashtag_id("rust")
+        .expect_err("no Postgres");
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(
+        cause(rejection).as_deref(),
+        Some(super::super::Handler::SETUP_TIMEOUT)
+    );
+
+    // Once the deadline has passed, queries fail without waiting at all
+    let start = Instant::now();
+    let rejection = pg_pool
+        .select_hashtag_id("rust")
+        .expect_err("past the deadline");
+    assert!(start.elapsed() < Duration::from_millis(20));
+    assert_eq!(
+        cause(rejection).as_deref(),
+        Some(super::super::Handler::SETUP_TIMEOUT)
+    );
+}
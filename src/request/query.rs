@@ -1,6 +1,13 @@
 //! Validate query prarams with type checking
+use super::Handler;
+use crate::Id;
+
+use hashbrown::HashSet;
 use serde_derive::Deserialize;
+use std::collections::HashMap;
 use warp::filters::BoxedFilter;
+#[allow(deprecated)] // one fn is deprecated, not whole module
+use warp::reject;
 use warp::Filter as WarpFilter;
 
 #[derive(Debug)]
@@ -10,19 +17,64 @@ pub(crate) struct Query {
     pub(crate) media: bool,
     pub(crate) hashtag: String,
     pub(crate) list: i64,
+    pub(crate) exclude_self: bool,
+    pub(crate) only_relationships: bool,
+    pub(crate) newest_first: bool,
+    pub(crate) backfill: bool,
+    pub(crate) presence: bool,
+    pub(crate) debug_timeline: bool,
+    pub(crate) mark_local: bool,
+    pub(crate) min_account_age_days: u32,
+    pub(crate) min_followers: u32,
+    pub(crate) accounts: Option<HashSet<Id>>,
+    pub(crate) limit: u32,
+    pub(crate) batch_ms: u32,
+    pub(crate) max_events_per_sec: u32,
+    pub(crate) event_type: Option<String>,
 }
 
 impl Query {
+    /// Use the `token` from the request header (if any) in place of the query's `access_token`.
+    ///
+    /// Empty or whitespace-only tokens are treated as absent, so that they're handled like any
+    /// other anonymous request rather than sent to Postgres for a lookup that can't succeed.
     pub(crate) fn update_access_token(
         self,
         token: Option<String>,
     ) -> Result<Self, warp::reject::Rejection> {
-        match token {
-            Some(token) => Ok(Self {
-                access_token: Some(token),
-                ..self
-            }),
-            None => Ok(self),
+        let non_empty = |token: Option<String>| token.filter(|t| !t.trim().is_empty());
+        let access_token = non_empty(token).or_else(|| non_empty(self.access_token));
+        Ok(Self {
+            access_token,
+            ..self
+        })
+    }
+
+    /// Use the `token` from the session cookie (if any), but only if the request doesn't
+    /// already have an access token from its header or query.
+    pub(crate) fn or_session_token(self, token: Option<String>) -> Self {
+        let token = token.filter(|t| !t.trim().is_empty());
+        Self {
+            access_token: self.access_token.or(token),
+            ..self
+        }
+    }
+
+    /// The first parameter that contradicts another parameter or the stream, if any (as the
+    /// parameter's name and the reason it doesn't belong)
+    pub(crate) fn conflict(&self) -> Option<&'static str> {
+        let (has_tag, has_list) = (!self.hashtag.is_empty(), self.list != 0);
+        let stream = self.stream.as_str();
+        if has_tag && has_list {
+            Some("tag (conflicts with list)")
+        } else if has_tag && !stream.starts_with("hashtag") {
+            Some("tag (only allowed for hashtag streams)")
+        } else if has_list && stream != "list" {
+            Some("list (only allowed for the list stream)")
+        } else if self.media && !stream.starts_with("public") {
+            Some("only_media (only allowed for public streams)")
+        } else {
+            None
         }
     }
 }
@@ -34,29 +86,116 @@ macro_rules! make_query_type {
             pub(crate) $parameter: $type,
         }
     };
-    ($name:tt => $parameter:tt:$type:ty) => {
+    ($name:tt => $parameter:tt:$type:ty $(, valid if $is_valid:expr)?) => {
         #[derive(Deserialize, Debug, Default)]
         pub(crate) struct $name {
             pub(crate) $parameter: $type,
         }
         impl $name {
+            /// The parameter, if it's present and valid; the default, if it's absent; and a
+            /// rejection naming the parameter otherwise.
             pub(crate) fn to_filter() -> BoxedFilter<(Self,)> {
+                let invalid = || {
+                    let msg = [Handler::INVALID_PARAMETER, stringify!($parameter)].concat();
+                    reject::custom(msg)
+                };
+                let all_params = warp::query::<HashMap<String, String>>()
+                    .or(warp::any().map(HashMap::new))
+                    .unify();
+
                 warp::query()
-                    .or(warp::any().map(Self::default))
+                    .and_then(move |query: Self| {
+                        $(if !$is_valid(&query.$parameter) {
+                            return Err(invalid());
+                        })?
+                        Ok(query)
+                    })
+                    .or(all_params.and_then(move |params: HashMap<String, String>| {
+                        if params.contains_key(stringify!($parameter)) {
+                            Err(invalid())
+                        } else {
+                            Ok(Self::default())
+                        }
+                    }))
                     .unify()
                     .boxed()
             }
         }
     };
 }
-make_query_type!(Media => only_media:String);
+make_query_type!(Media => only_media: String, valid if is_flag);
 impl Media {
     pub(crate) fn is_truthy(&self) -> bool {
         self.only_media == "true" || self.only_media == "1"
     }
 }
-make_query_type!(Hashtag => tag: String);
-make_query_type!(List => list: i64);
+make_query_type!(ExcludeSelf => exclude_self: String, valid if is_flag);
+impl ExcludeSelf {
+    pub(crate) fn is_truthy(&self) -> bool {
+        self.exclude_self == "true" || self.exclude_self == "1"
+    }
+}
+make_query_type!(OnlyRelationships => only_relationships: String, valid if is_flag);
+impl OnlyRelationships {
+    pub(crate) fn is_truthy(&self) -> bool {
+        self.only_relationships == "true" || self.only_relationships == "1"
+    }
+}
+make_query_type!(NewestFirst => newest_first: String, valid if is_flag);
+impl NewestFirst {
+    pub(crate) fn is_truthy(&self) -> bool {
+        self.newest_first == "true" || self.newest_first == "1"
+    }
+}
+make_query_type!(Backfill => backfill: String, valid if is_flag);
+impl Backfill {
+    pub(crate) fn is_truthy(&self) -> bool {
+        self.backfill == "true" || self.backfill == "1"
+    }
+}
+make_query_type!(Presence => presence: String, valid if is_flag);
+impl Presence {
+    pub(crate) fn is_truthy(&self) -> bool {
+        self.presence == "true" || self.presence == "1"
+    }
+}
+make_query_type!(DebugTimeline => debug_timeline: String, valid if is_flag);
+impl DebugTimeline {
+    pub(crate) fn is_truthy(&self) -> bool {
+        self.debug_timeline == "true" || self.debug_timeline == "1"
+    }
+}
+make_query_type!(MarkLocal => mark_local: String, valid if is_flag);
+impl MarkLocal {
+    pub(crate) fn is_truthy(&self) -> bool {
+        self.mark_local == "true" || self.mark_local == "1"
+    }
+}
+make_query_type!(MinAccountAge => min_account_age_days: u32);
+make_query_type!(MinFollowers => min_followers: u32);
+make_query_type!(Accounts => accounts: String, valid if is_account_list);
+impl Accounts {
+    /// The most accounts a single request can limit its statuses to
+    pub(crate) const MAX: usize = 100;
+
+    /// The ids of the accounts to limit statuses to, if any were given
+    pub(crate) fn ids(&self) -> Option<HashSet<Id>> {
+        match self.accounts.as_str() {
+            "" => None,
+            list => Some(
+                list.split(',')
+                    .filter_map(|id| id.parse().ok())
+                    .map(Id)
+                    .collect(),
+            ),
+        }
+    }
+}
+make_query_type!(Limit => limit: u32);
+make_query_type!(BatchWindow => batch_ms: u32);
+make_query_type!(MaxRate => max_events_per_sec: u32);
+make_query_type!(Hashtag => tag: String, valid if |tag: &String| !tag.is_empty());
+make_query_type!(List => list: i64, valid if |id: &i64| *id > 0);
 make_query_type!(Auth => access_token: Option<String>);
 make_query_type!(Stream => stream: String);
 impl ToString for Stream {
@@ -65,20 +204,55 @@ impl ToString for Stream {
     }
 }
 
+fn is_flag(value: &str) -> bool {
+    ["true", "false", "1", "0"].contains(&value)
+}
+
+fn is_account_list(value: &str) -> bool {
+    let ids: Vec<_> = value.split(',').collect();
+    ids.len() <= Accounts::MAX && ids.iter().all(|id| id.parse::<i64>().is_ok())
+}
+
 pub(super) struct OptionalAccessToken;
 
 impl OptionalAccessToken {
+    /// The token from an `Authorization: Bearer <token>` header.  Headers using another scheme
+    /// or that aren't in that form are treated the same as no header at all.
     pub(super) fn from_sse_header() -> warp::filters::BoxedFilter<(Option<String>,)> {
-        let from_header = warp::header::header::<String>("authorization").map(|auth: String| {
-            match auth.split(' ').nth(1) {
-                Some(s) => Some(s.to_string()),
-                None => None,
-            }
-        });
+        let from_header = warp::header::header::<String>("authorization")
+            .map(|auth: String| Self::parse_bearer(&auth));
         let no_token = warp::any().map(|| None);
 
         from_header.or(no_token).unify().boxed()
     }
+
+    fn parse_bearer(auth: &str) -> Option<String> {
+        let mut parts = auth.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(scheme), Some(token), None) if scheme.eq_ignore_ascii_case("bearer") => {
+                Some(token.to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// The URL-decoded value of the cookie called `name` (if a name is given and the request
+    /// has that cookie, with a value that decodes)
+    pub(super) fn from_cookie(name: Option<String>) -> BoxedFilter<(Option<String>,)> {
+        warp::header::optional::<String>("cookie")
+            .map(move |cookies: Option<String>| {
+                let name = name.as_ref()?;
+                cookies?.split(';').find_map(|cookie| {
+                    let mut pair = cookie.trim().splitn(2, '=');
+                    match (pair.next(), pair.next()) {
+                        (Some(key), Some(value)) if key == name => urlencoding::decode(value).ok(),
+                        _ => None,
+                    }
+                })
+            })
+            .boxed()
+    }
+
     pub(super) fn from_ws_header() -> warp::filters::BoxedFilter<(Option<String>,)> {
         let from_header = warp::header::header::<String>("Sec-Websocket-Protocol").map(Some);
         let no_token = warp::any().map(|| None);
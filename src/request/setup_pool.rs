@@ -0,0 +1,47 @@
+//! A fixed set of threads for the blocking work of setting up subscriptions
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs jobs on `threads` threads, with at most `queue` more jobs waiting for one of them.
+/// Unlike a thread per job, this can't pile up threads (or Postgres checkouts) without bound
+/// while Postgres is slow.
+#[derive(Clone)]
+pub(crate) struct SetupPool(Arc<Mutex<SyncSender<Job>>>);
+
+impl SetupPool {
+    pub(crate) fn new(threads: usize, queue: usize) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<Job>(queue);
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads {
+            let rx = rx.clone();
+            let worker = move || loop {
+                let job = match rx.lock().unwrap_or_else(PoisonError::into_inner).recv() {
+                    Ok(job) => job,
+                    Err(_) => return, // every `SetupPool` handle has been dropped
+                };
+                job();
+            };
+            thread::Builder::new()
+                .name(format!("setup-{}", i))
+                .spawn(worker)
+                .expect("can start the subscription setup threads at startup");
+        }
+        Self(Arc::new(Mutex::new(tx)))
+    }
+
+    /// Queue `job` to run on the next free thread, or return `false` if the queue is full
+    pub(crate) fn run(&self, job: impl FnOnce() + Send + 'static) -> bool {
+        let tx = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        match tx.try_send(Box::new(job)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Disconnected(_)) => {
+                log::error!("Every subscription setup thread has stopped");
+                false
+            }
+        }
+    }
+}
@@ -8,9 +8,13 @@
 use super::postgres::PgPool;
 use super::query::Query;
 use super::{Content, Reach, Stream, Timeline};
+use crate::response::Event;
 use crate::Id;
 
 use hashbrown::HashSet;
+use regex::Regex;
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use warp::reject::Rejection;
 
@@ -20,8 +24,42 @@ pub struct Subscription {
     pub allowed_langs: HashSet<String>,
     /// [Blocks](./request/struct.Blocks.html)
     pub blocks: Blocks,
+    /// The user's keyword filters for this timeline (statuses that match are dropped)
+    pub filters: Filters,
     pub hashtag_name: Option<String>,
     pub access_token: Option<String>,
+    /// Whether this subscription is to the merged public timelines (admin only)
+    pub firehose: bool,
+    /// Whether to leave the user's own statuses out of their `user` timeline
+    pub exclude_self: bool,
+    /// The members of the subscribed list, if statuses from non-members should be dropped
+    pub list_members: Option<HashSet<Id>>,
+    /// Whether to send only follow and follow request notifications (`user:notification` only)
+    pub only_relationships: bool,
+    /// Whether to send the events waiting for this client newest-first (rather than in order)
+    pub newest_first: bool,
+    /// Whether to send the timeline's most recent events before streaming new ones
+    pub backfill: bool,
+    /// Whether to send periodic `presence` events (`user` timelines only)
+    pub presence: bool,
+    /// Whether to tell the client which `Timeline` its request resolved to, for debugging
+    pub debug_timeline: bool,
+    /// Whether to add a `_local` field to statuses, saying whether they were posted locally
+    pub mark_local: bool,
+    /// Drop statuses by accounts created fewer than this many days ago (public timelines only)
+    pub min_account_age_days: u32,
+    /// Drop statuses by accounts with fewer than this many followers (public timelines only)
+    pub min_followers: u32,
+    /// The only accounts whose statuses should be sent, if limited by the client
+    pub accounts: Option<HashSet<Id>>,
+    /// The number of events to send before closing the stream, for one-shot SSE requests
+    pub limit: Option<u32>,
+    /// How long to collect events for before sending them together, for batched SSE requests
+    pub batch: Option<Duration>,
+    /// The most events to send per second, for clients that can't keep up with busy timelines
+    pub max_events_per_sec: Option<u32>,
+    /// The only type of event to send, if the client named one in the path (SSE only)
+    pub event_type: Option<String>,
 }
 
 /// Blocked and muted users and domains
@@ -32,14 +70,92 @@ pub struct Blocks {
     pub blocking_users: HashSet<Id>,
 }
 
+impl Blocks {
+    /// Whether the user has blocked (or muted) the `user`, or been blocked by them
+    pub(crate) fn either_way(&self, user: &Id) -> bool {
+        self.blocked_users.contains(user) || self.blocking_users.contains(user)
+    }
+
+    /// Whether `event` notifies the user of something done by a user they've blocked (or who
+    /// has blocked them)
+    pub(crate) fn blocks_notification(&self, event: &Event) -> bool {
+        event
+            .notifying_user()
+            .map_or(false, |user| self.either_way(&user))
+    }
+}
+
+/// A user's filtered phrases, compiled once when they subscribe rather than for every status
+#[derive(Clone, Default, Debug)]
+pub struct Filters(Vec<Regex>);
+
+impl PartialEq for Filters {
+    fn eq(&self, other: &Self) -> bool {
+        self.0
+            .iter()
+            .map(Regex::as_str)
+            .eq(other.0.iter().map(Regex::as_str))
+    }
+}
+
+impl Filters {
+    /// Compile each filtered `phrase` (with whether it should only match `whole_word`s) the way
+    /// Mastodon matches them: literally, ignoring case, and with a word boundary at each end of
+    /// a whole-word phrase that starts or ends with a word character.  A phrase that won't
+    /// compile is logged and skipped, so that one bad filter can't break the connection.
+    pub fn new(phrases: &[(String, bool)]) -> Self {
+        let compiled = phrases.iter().filter_map(|(phrase, whole_word)| {
+            let boundary = |c: Option<char>| match c {
+                Some(c) if *whole_word && (c.is_alphanumeric() || c == '_') => r"\b",
+                _ => "",
+            };
+            let pattern = [
+                "(?i)",
+                boundary(phrase.chars().next()),
+                &regex::escape(phrase),
+                boundary(phrase.chars().last()),
+            ]
+            .concat();
+            Regex::new(&pattern)
+                .map_err(|e| log::warn!("Skipping the filter `{}`: {}", phrase, e))
+                .ok()
+        });
+        Self(compiled.collect())
+    }
+
+    /// Whether any of the filters matches any of the `text`
+    pub(crate) fn match_any<S: AsRef<str>>(&self, text: &[S]) -> bool {
+        self.0
+            .iter()
+            .any(|filter| text.iter().any(|text| filter.is_match(text.as_ref())))
+    }
+}
+
 impl Default for Subscription {
     fn default() -> Self {
         Self {
             timeline: Timeline(Stream::Unset, Reach::Local, Content::Notification),
             allowed_langs: HashSet::new(),
             blocks: Blocks::default(),
+            filters: Filters::default(),
             hashtag_name: None,
             access_token: None,
+            firehose: false,
+            exclude_self: false,
+            list_members: None,
+            only_relationships: false,
+            newest_first: false,
+            backfill: false,
+            presence: false,
+            debug_timeline: false,
+            mark_local: false,
+            min_account_age_days: 0,
+            min_followers: 0,
+            accounts: None,
+            limit: None,
+            batch: None,
+            max_events_per_sec: None,
+            event_type: None,
         }
     }
 }
@@ -63,6 +179,35 @@ impl Subscription {
             }
         };
 
+        let list_members = match timeline {
+            Timeline(Stream::List(list_id), _, _) if pool.strict_list_membership => {
+                Some(pool.clone().select_list_members(list_id)?)
+            }
+            _non_list_timeline => None,
+        };
+
+        let only_relationships = match timeline {
+            Timeline(Stream::User(_), _, Content::Notification) => q.only_relationships,
+            _non_notification_timeline => false,
+        };
+
+        let presence = match timeline {
+            Timeline(Stream::User(_), _, _) => q.presence,
+            _non_user_timeline => false,
+        };
+
+        let (min_account_age_days, min_followers) = match timeline {
+            Timeline(Stream::Public, _, _) => (q.min_account_age_days, q.min_followers),
+            _non_public_timeline => (0, 0),
+        };
+
+        // Mastodon's filter contexts (`thread` has no timeline to apply to)
+        let filter_context = match timeline {
+            Timeline(Stream::User(_), _, Content::Notification) => "notifications",
+            Timeline(Stream::Public, _, _) | Timeline(Stream::Hashtag(_), _, _) => "public",
+            _home_timeline => "home",
+        };
+
         let hashtag_name = match timeline {
             Timeline(Stream::Hashtag(_), _, _) => Some(q.hashtag),
             _non_hashtag_timeline => None,
@@ -74,10 +219,112 @@ impl Subscription {
             blocks: Blocks {
                 blocking_users: pool.clone().select_blocking_users(user.id)?,
                 blocked_users: pool.clone().select_blocked_users(user.id)?,
-                blocked_domains: pool.select_blocked_domains(user.id)?,
+                blocked_domains: pool.clone().select_blocked_domains(user.id)?,
             },
+            filters: Filters::new(&pool.select_filters(user.id, filter_context)?),
             hashtag_name,
             access_token: q.access_token,
+            firehose: q.stream == "firehose",
+            exclude_self: q.exclude_self,
+            list_members,
+            only_relationships,
+            newest_first: q.newest_first,
+            backfill: q.backfill,
+            presence,
+            debug_timeline: q.debug_timeline,
+            mark_local: q.mark_local,
+            min_account_age_days,
+            min_followers,
+            accounts: q.accounts,
+            limit: Some(q.limit).filter(|limit| *limit > 0),
+            batch: Some(q.batch_ms)
+                .filter(|ms| *ms > 0)
+                .map(|ms| Duration::from_millis(ms.into())),
+            max_events_per_sec: Some(q.max_events_per_sec).filter(|max| *max > 0),
+            event_type: q.event_type,
         })
     }
+
+    /// Whether a status by `author` should be dropped because they aren't a member of the
+    /// subscribed list (only ever `true` when the list's members were loaded)
+    pub(crate) fn is_from_non_member(&self, author: &Id) -> bool {
+        self.list_members
+            .as_ref()
+            .map_or(false, |members| !members.contains(author))
+    }
+
+    /// Whether a status in the `language` (a BCP 47 tag) is in one of the user's allowed
+    /// languages.  Tags are compared on their primary language subtag, ignoring case, so
+    /// allowing `en` allows `en-GB` and `EN` alike.  With no allowed languages, all are allowed.
+    pub(crate) fn allows_language(&self, language: &str) -> bool {
+        let primary = |tag: &str| {
+            let subtag = tag.split(|c| c == '-' || c == '_').next();
+            subtag.unwrap_or_default().to_ascii_lowercase()
+        };
+        let (language, allowed) = (primary(language), &self.allowed_langs);
+        allowed.is_empty() || allowed.iter().any(|tag| primary(tag) == language)
+    }
+
+    /// Add the `langs` that every user is allowed to this subscription's allowed languages.
+    /// With no allowed languages, all are already allowed, so that's left unchanged.
+    pub(crate) fn always_allowing(mut self, langs: &HashSet<String>) -> Self {
+        if !self.allowed_langs.is_empty() {
+            self.allowed_langs.extend(langs.iter().cloned());
+        }
+        self
+    }
+
+    /// Whether a status by `author` should be dropped because the client limited its statuses
+    /// to other accounts
+    pub(crate) fn is_from_unlisted_account(&self, author: &Id) -> bool {
+        self.accounts
+            .as_ref()
+            .map_or(false, |accounts| !accounts.contains(author))
+    }
+
+    /// Whether a status should be dropped because its author's account was created less than
+    /// `min_account_age_days` ago or has fewer than `min_followers` (unknown values never
+    /// cause a status to be dropped)
+    pub(crate) fn is_from_unestablished_account(
+        &self,
+        created_day: Option<i64>,
+        followers: Option<i64>,
+    ) -> bool {
+        let today = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .and_then(|since_epoch| i64::try_from(since_epoch.as_secs() / (24 * 60 * 60)).ok())
+            .unwrap_or_default();
+        self.is_unestablished_on(today, created_day, followers)
+    }
+
+    /// Like `is_from_unestablished_account`, as of `today` (in days since the Unix epoch)
+    pub(super) fn is_unestablished_on(
+        &self,
+        today: i64,
+        created_day: Option<i64>,
+        followers: Option<i64>,
+    ) -> bool {
+        let too_new = self.min_account_age_days > 0
+            && created_day.map_or(false, |day| {
+                today - day < i64::from(self.min_account_age_days)
+            });
+        let too_few = followers.map_or(false, |count| count < i64::from(self.min_followers));
+        too_new || too_few
+    }
+
+    /// All the `Timeline`s this subscription should receive events from
+    pub fn timelines(&self) -> Vec<Timeline> {
+        use {Content::*, Reach::*, Stream::*};
+        if self.firehose {
+            vec![
+                Timeline(Public, Federated, All),
+                Timeline(Public, Local, All),
+                Timeline(Public, Federated, Media),
+                Timeline(Public, Remote, All),
+            ]
+        } else {
+            vec![self.timeline]
+        }
+    }
 }
@@ -0,0 +1,995 @@
+use super::*;
+use crate::Id;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+#[test]
+fn logged_headers_include_configured_headers_that_are_present() {
+    let logged_headers = Handler::logged_headers(vec![
+        "x-request-id".to_string(),
+        "x-forwarded-for".to_string(),
+    ]);
+
+    let logged = warp::test::request()
+        .header("X-Request-Id", "7f3c9a")
+        .filter(&logged_headers)
+        .expect("infallible filter");
+
+    assert_eq!(logged, " x-request-id=7f3c9a");
+}
+
+#[test]
+fn logged_headers_are_empty_when_none_are_configured() {
+    let logged = warp::test::request()
+        .header("X-Request-Id", "7f3c9a")
+        .filter(&Handler::logged_headers(Vec::new()))
+        .expect("infallible filter");
+
+    assert_eq!(logged, "");
+}
+
+#[test]
+fn empty_access_token_is_treated_as_no_token() {
+    let query = |path: &str| {
+        warp::test::request()
+            .path(path)
+            .filter(&parse_ws_query())
+            .expect("valid query")
+            .update_access_token(None)
+            .expect("infallible")
+    };
+
+    let no_token = query("/api/v1/streaming?stream=public");
+    let empty = query("/api/v1/streaming?stream=public&access_token=");
+    let whitespace = query("/api/v1/streaming?stream=public&access_token=%20%20");
+
+    assert_eq!(no_token.access_token, None);
+    assert_eq!(empty.access_token, None);
+    assert_eq!(whitespace.access_token, None);
+}
+
+#[test]
+fn empty_header_token_does_not_replace_query_token() {
+    let query = warp::test::request()
+        .path("/api/v1/streaming?stream=public&access_token=TOKEN")
+        .filter(&parse_ws_query())
+        .expect("valid query");
+
+    let query = query
+        .update_access_token(Some(" ".to_string()))
+        .expect("infallible");
+
+    assert_eq!(query.access_token, Some("TOKEN".to_string()));
+}
+
+#[test]
+fn admin_only_accepts_configured_tokens_and_forbids_others() {
+    let admin = Handler::admin_only(vec!["s3cret".to_string()])
+        .map(|| "OK")
+        .recover(Handler::err);
+
+    let accepted = warp::test::request()
+        .header("authorization", "Bearer s3cret")
+        .reply(&admin);
+    let wrong_token = warp::test::request()
+        .header("authorization", "Bearer guess")
+        .reply(&admin);
+    let no_token = warp::test::request().reply(&admin);
+
+    assert_eq!(accepted.status(), StatusCode::OK);
+    assert_eq!(wrong_token.status(), StatusCode::FORBIDDEN);
+    assert_eq!(no_token.status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn admin_only_is_open_without_configured_tokens() {
+    let admin = Handler::admin_only(Vec::new())
+        .map(|| "OK")
+        .recover(Handler::err);
+
+    assert_eq!(warp::test::request().reply(&admin).status(), StatusCode::OK);
+}
+
+#[test]
+fn admin_required_is_not_found_without_configured_tokens() {
+    let admin = Handler::admin_required(Vec::new())
+        .map(|| "OK")
+        .recover(Handler::err);
+    let configured = Handler::admin_required(vec!["s3cret".to_string()])
+        .map(|| "OK")
+        .recover(Handler::err);
+
+    let unconfigured = warp::test::request()
+        .header("authorization", "Bearer s3cret")
+        .reply(&admin);
+    let no_token = warp::test::request().reply(&configured);
+
+    assert_eq!(unconfigured.status(), StatusCode::NOT_FOUND);
+    assert_eq!(no_token.status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn flush_is_not_found_without_configured_admin_tokens() {
+    let handler = unconnected_handler(Arc::default());
+    let flush = |admin_tokens: Vec<String>| {
+        let route = handler
+            .status_flush()
+            .and(Handler::admin_required(admin_tokens))
+            .map(|| "flushed")
+            .recover(Handler::err);
+        warp::test::request()
+            .method("POST")
+            .path("/api/v1/streaming/status/flush")
+            .header("authorization", "Bearer s3cret")
+            .reply(&route)
+            .status()
+    };
+
+    assert_eq!(flush(Vec::new()), StatusCode::NOT_FOUND);
+    assert_eq!(flush(vec!["s3cret".to_string()]), StatusCode::OK);
+}
+
+#[test]
+fn denied_tokens_cannot_be_replaced_without_configured_admin_tokens() {
+    let handler = unconnected_handler(Arc::default());
+    let replace = |admin_tokens: Vec<String>| {
+        let denying = handler.clone();
+        let route = handler
+            .status_denied_tokens()
+            .and(Handler::admin_required(admin_tokens))
+            .map(move |tokens: Vec<String>| denying.deny_tokens(tokens))
+            .recover(Handler::err);
+        warp::test::request()
+            .method("POST")
+            .path("/api/v1/streaming/status/denied_tokens")
+            .header("content-type", "application/json")
+            .body("[]")
+            .reply(&route)
+            .status()
+    };
+    handler.deny_tokens(vec!["abused".to_string()]);
+
+    // With no ADMIN_TOKENS, no one (not even an unauthenticated caller) can clear the list
+    assert_eq!(replace(Vec::new()), StatusCode::NOT_FOUND);
+    assert_eq!(replace(vec!["s3cret".to_string()]), StatusCode::FORBIDDEN);
+    assert!(handler
+        .denied_tokens
+        .read()
+        .expect("unpoisoned")
+        .contains("abused"));
+}
+
+#[test]
+fn exclude_self_is_parsed_from_the_query() {
+    let exclude_self = |path: &str| {
+        warp::test::request()
+            .path(path)
+            .filter(&parse_ws_query())
+            .expect("valid query")
+            .exclude_self
+    };
+
+    assert!(exclude_self(
+        "/api/v1/streaming?stream=user&exclude_self=true"
+    ));
+    assert!(!exclude_self("/api/v1/streaming?stream=user"));
+}
+
+#[test]
+fn presence_is_parsed_from_the_query() {
+    let presence = |path: &str| {
+        warp::test::request()
+            .path(path)
+            .filter(&parse_ws_query())
+            .expect("valid query")
+            .presence
+    };
+
+    assert!(presence("/api/v1/streaming?stream=user&presence=true"));
+    assert!(!presence("/api/v1/streaming?stream=user"));
+}
+
+#[test]
+fn only_relationships_is_parsed_from_the_query() {
+    let only_relationships = |path: &str| {
+        warp::test::request()
+            .path(path)
+            .filter(&parse_ws_query())
+            .expect("valid query")
+            .only_relationships
+    };
+
+    assert!(only_relationships(
+        "/api/v1/streaming?stream=user:notification&only_relationships=1"
+    ));
+    assert!(!only_relationships(
+        "/api/v1/streaming?stream=user:notification"
+    ));
+}
+
+#[test]
+fn account_thresholds_are_parsed_from_the_query() {
+    let query = |path: &str| {
+        warp::test::request()
+            .path(path)
+            .filter(&parse_ws_query())
+            .expect("valid query")
+    };
+
+    let q = query("/api/v1/streaming?stream=public&min_account_age_days=7&min_followers=10");
+    assert_eq!((q.min_account_age_days, q.min_followers), (7, 10));
+    let q = query("/api/v1/streaming?stream=public");
+    assert_eq!((q.min_account_age_days, q.min_followers), (0, 0));
+}
+
+#[test]
+fn account_thresholds_apply_as_of_today() {
+    let today = 18_200; // 2019-10-31
+    let (created_day, followers) = (Some(18_149), Some(100)); // 2019-09-10
+    let unestablished = |min_account_age_days, min_followers| {
+        let subscription = Subscription {
+            min_account_age_days,
+            min_followers,
+            ..Subscription::default()
+        };
+        subscription.is_unestablished_on(today, created_day, followers)
+    };
+
+    assert!(!unestablished(0, 0));
+    assert!(!unestablished(51, 0));
+    assert!(unestablished(52, 0));
+    assert!(!unestablished(0, 100));
+    assert!(unestablished(0, 101));
+}
+
+#[test]
+fn unknown_account_age_and_followers_are_never_filtered() {
+    let subscription = Subscription {
+        min_account_age_days: 30,
+        min_followers: 10,
+        ..Subscription::default()
+    };
+    assert!(!subscription.is_unestablished_on(18_200, None, None));
+}
+
+#[test]
+fn accounts_are_parsed_from_the_query_up_to_the_limit() {
+    let query = |path: &str| warp::test::request().path(path).filter(&parse_ws_query());
+
+    let q = query("/api/v1/streaming?stream=public&accounts=1,78").expect("valid query");
+    assert_eq!(q.accounts, Some(vec![Id(1), Id(78)].into_iter().collect()));
+    let q = query("/api/v1/streaming?stream=public").expect("valid query");
+    assert_eq!(q.accounts, None);
+
+    let too_many: Vec<_> = (0..=query::Accounts::MAX)
+        .map(|id| id.to_string())
+        .collect();
+    let path = format!(
+        "/api/v1/streaming?stream=public&accounts={}",
+        too_many.join(",")
+    );
+    assert!(query(&path).is_err());
+    assert!(query("/api/v1/streaming?stream=public&accounts=1,two").is_err());
+}
+
+/// The status and body of the reply to `path` when conflicting params are screened out
+fn screened_reply(enabled: bool, path: &str) -> (StatusCode, String) {
+    let screened = parse_ws_query()
+        .and_then(move |q| Handler::screen_params(enabled, q))
+        .map(|_| "OK")
+        .recover(Handler::err);
+    let reply = warp::test::request().path(path).reply(&screened);
+    let body = String::from_utf8_lossy(reply.body()).to_string();
+    (reply.status(), body)
+}
+
+#[test]
+fn conflicting_params_are_rejected_when_configured() {
+    let (status, body) = screened_reply(true, "/api/v1/streaming?stream=hashtag&tag=a&list=1");
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("tag (conflicts with list)"));
+
+    let (status, body) = screened_reply(true, "/api/v1/streaming?stream=public&list=1");
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("list (only allowed for the list stream)"));
+
+    for allowed in &[
+        "/api/v1/streaming?stream=hashtag&tag=rust",
+        "/api/v1/streaming?stream=list&list=1",
+        "/api/v1/streaming?stream=public&only_media=true",
+    ] {
+        assert_eq!(screened_reply(true, allowed).0, StatusCode::OK);
+    }
+}
+
+#[test]
+fn conflicting_params_are_ignored_by_default() {
+    let (status, _body) = screened_reply(false, "/api/v1/streaming?stream=public&list=1");
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[test]
+fn subscription_setup_is_abandoned_after_its_budget() {
+    let checkouts = Arc::new(AtomicUsize::new(0));
+    // Setup like `query_postgres`, which checks out a connection for each query until its deadline
+    let slow_setup = {
+        let checkouts = checkouts.clone();
+        move |deadline: Option<Instant>| {
+            while Instant::now() < deadline.expect("setup has a deadline") {
+                checkouts.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(10));
+            }
+            Ok(Subscription::default())
+        }
+    };
+
+    let mut rt = tokio::runtime::Runtime::new().expect("runtime");
+    let start = Instant::now();
+    let budget = (Duration::from_millis(20), SetupPool::new(1, 1));
+    let rejection = rt
+        .block_on(Handler::within(Some(budget), slow_setup))
+        .expect_err("setup timed out");
+
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert_eq!(
+        rejection.cause().map(|cause| cause.to_string()).as_deref(),
+        Some(Handler::SETUP_TIMEOUT)
+    );
+    // The abandoned setup stops at its deadline instead of holding on to the setup thread
+    thread::sleep(Duration::from_millis(50));
+    let abandoned_checkouts = checkouts.load(Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(checkouts.load(Ordering::SeqCst), abandoned_checkouts);
+}
+
+#[test]
+fn subscription_setup_beyond_the_queue_is_unavailable() {
+    // With no threads to run it, the first setup waits in the queue (until its timeout) and the
+    // queue is then full
+    let budget = Some((Duration::from_millis(20), SetupPool::new(0, 1)));
+    let cause = |rejection: Rejection| rejection.cause().map(|cause| cause.to_string());
+
+    let mut rt = tokio::runtime::Runtime::new().expect("runtime");
+    let queued = Handler::within(budget.clone(), |_| Ok(Subscription::default()));
+    let rejected = Handler::within(budget, |_| Ok(Subscription::default()));
+
+    let rejection = rt.block_on(rejected).expect_err("queue is full");
+    assert_eq!(cause(rejection).as_deref(), Some(Handler::UNAVAILABLE));
+    let rejection = rt.block_on(queued).expect_err("never set up");
+    assert_eq!(cause(rejection).as_deref(), Some(Handler::SETUP_TIMEOUT));
+}
+
+#[test]
+fn subscription_setup_within_its_budget_succeeds() {
+    let quick_setup = |_deadline| Ok(Subscription::default());
+
+    let mut rt = tokio::runtime::Runtime::new().expect("runtime");
+    let budget = (Duration::from_secs(5), SetupPool::new(1, 1));
+    let subscription = rt.block_on(Handler::within(Some(budget), quick_setup));
+
+    assert_eq!(
+        subscription.expect("set up in time"),
+        Subscription::default()
+    );
+}
+
+#[test]
+fn over_length_query_is_rejected_before_it_is_parsed() {
+    let token = "a".repeat(100);
+    let reply = |path: &str| {
+        // Nothing after the length check can run for a rejected request, including the
+        // Postgres lookup of its access token
+        let parsed = Handler::query_length(64)
+            .and(parse_ws_query())
+            .map(|_q: Query| "OK")
+            .recover(Handler::err);
+        warp::test::request().path(path).reply(&parsed).status()
+    };
+
+    let too_long = format!("/api/v1/streaming?stream=user&access_token={}", token);
+    assert_eq!(reply(&too_long), StatusCode::URI_TOO_LONG);
+    assert_eq!(reply("/api/v1/streaming?stream=public"), StatusCode::OK);
+}
+
+/// A `Handler` whose Postgres can't be reached, counting each attempt to connect to it in
+/// `attempts`
+fn unconnected_handler(attempts: Arc<AtomicUsize>) -> Handler {
+    Handler {
+        pg_conn: PgPool::unconnected(attempts),
+        reject_conflicting_params: false,
+        require_user_agent: false,
+        sse_event_type_paths: false,
+        setup: None,
+        tag_lookups: SetupPool::new(1, 1),
+        denied_tokens: Arc::default(),
+        always_allow_langs: Arc::default(),
+        whitelist_mode: false,
+        session_cookie: None,
+    }
+}
+
+#[test]
+fn over_length_query_never_reaches_postgres() {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let handler = unconnected_handler(attempts.clone());
+    let route = Handler::query_length(64)
+        .and(handler.sse_subscription())
+        .map(|_: Subscription| "OK")
+        .recover(Handler::err);
+    let reply = |path: &str| warp::test::request().path(path).reply(&route).status();
+
+    let too_long = format!("/api/v1/streaming/user?access_token={}", "a".repeat(100));
+    assert_eq!(reply(&too_long), StatusCode::URI_TOO_LONG);
+    assert_eq!(attempts.load(Ordering::SeqCst), 0);
+
+    // Within the limit, the same request does try to look its access token up
+    assert_ne!(
+        reply("/api/v1/streaming/user?access_token=abc"),
+        StatusCode::OK
+    );
+    assert!(attempts.load(Ordering::SeqCst) > 0);
+}
+
+#[test]
+fn missing_user_agent_is_rejected_when_required() {
+    let rejection = warp::test::request()
+        .filter(&Handler::user_agent(true))
+        .expect_err("no User-Agent");
+
+    assert_eq!(
+        rejection.cause().map(|cause| cause.to_string()).as_deref(),
+        Some(Handler::MISSING_USER_AGENT)
+    );
+}
+
+#[test]
+fn present_user_agent_is_accepted_when_required() {
+    let accepted = warp::test::request()
+        .header("User-Agent", "Tusky/12.0")
+        .filter(&Handler::user_agent(true));
+
+    assert!(accepted.is_ok());
+}
+
+#[test]
+fn missing_user_agent_is_accepted_when_not_required() {
+    assert!(warp::test::request()
+        .filter(&Handler::user_agent(false))
+        .is_ok());
+}
+
+#[test]
+fn unknown_path_without_user_agent_is_not_found() {
+    // As `ws_subscription` checks it: after the path has matched
+    let stream = parse_ws_query()
+        .and(Handler::user_agent(true))
+        .map(|_q: Query| "OK")
+        .recover(Handler::err);
+    let status = |path: &str| warp::test::request().path(path).reply(&stream).status();
+
+    assert_eq!(
+        status("/api/v1/streaming?stream=public"),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(status("/api/v1/unknown"), StatusCode::NOT_FOUND);
+}
+
+/// A WebSocket upgrade request from `origin`
+fn ws_upgrade(origin: &str) -> warp::test::RequestBuilder {
+    warp::test::request()
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-version", "13")
+        .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .header("origin", origin)
+}
+
+#[test]
+fn ws_upgrades_from_a_disallowed_origin_are_forbidden() {
+    let allowed = vec!["https://mastodon.example".to_string()];
+    let ws = Handler::ws_origin(allowed)
+        .map(|| "upgraded")
+        .recover(Handler::err);
+
+    let rejected = ws_upgrade("https://evil.example").reply(&ws);
+    assert_eq!(rejected.status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn ws_upgrades_from_an_allowed_origin_are_accepted() {
+    let allowed = vec!["https://mastodon.example".to_string()];
+    let ws = Handler::ws_origin(allowed).and(warp::ws::ws2());
+
+    assert!(ws_upgrade("https://mastodon.example").filter(&ws).is_ok());
+}
+
+#[test]
+fn any_origin_is_accepted_without_an_allowlist() {
+    assert!(ws_upgrade("https://evil.example")
+        .filter(&Handler::ws_origin(Vec::new()))
+        .is_ok());
+}
+
+#[test]
+fn version_endpoint_reports_the_crate_version() -> Result<(), serde_json::Error> {
+    let reply = warp::test::request()
+        .path("/api/v1/streaming/version")
+        .reply(&Handler::version());
+
+    assert_eq!(reply.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(reply.body())?;
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(body["commit"], env!("FLODGATT_GIT_COMMIT"));
+    Ok(())
+}
+
+#[test]
+fn health_reply_names_the_backends_that_failed() -> Result<(), serde_json::Error> {
+    let reply = |failed: &'static [&'static str]| {
+        let health = warp::any().map(move || Handler::health_reply(failed));
+        warp::test::request().reply(&health)
+    };
+
+    let healthy = reply(&[]);
+    assert_eq!(healthy.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(healthy.body())?;
+    assert_eq!(body, serde_json::json!({ "status": "ok" }));
+
+    let unhealthy = reply(&["redis", "postgres"]);
+    assert_eq!(unhealthy.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: serde_json::Value = serde_json::from_slice(unhealthy.body())?;
+    assert_eq!(body["failed"], serde_json::json!(["redis", "postgres"]));
+    Ok(())
+}
+
+/// Run the health checks with `redis_ok` and `postgres_ok`, returning the status and the
+/// backends reported as failed
+fn checked_health(
+    redis_ok: impl FnOnce(Duration) -> bool + Send + 'static,
+    postgres_ok: impl FnOnce(Duration) -> bool + Send + 'static,
+) -> (StatusCode, serde_json::Value) {
+    use futures::Stream as _;
+
+    let mut rt = tokio::runtime::Runtime::new().expect("runtime");
+    let checks = SetupPool::new(2, 2);
+    let timeout = Duration::from_millis(20);
+    let health = Handler::check_health(&checks, timeout, redis_ok, postgres_ok);
+    let response = rt.block_on(health).expect("infallible").into_response();
+    let status = response.status();
+    let body = rt.block_on(response.into_body().concat2()).expect("body");
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("JSON");
+    (status, body["failed"].clone())
+}
+
+#[test]
+fn health_check_names_the_backends_that_fail() {
+    let (status, _) = checked_health(|_| true, |_| true);
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, failed) = checked_health(|_| true, |_| false);
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(failed, serde_json::json!(["postgres"]));
+}
+
+#[test]
+fn health_check_does_not_wait_for_a_hung_backend() {
+    let hung_redis = |_| {
+        thread::sleep(Duration::from_millis(500));
+        true
+    };
+    let start = Instant::now();
+
+    let (status, failed) = checked_health(hung_redis, |_| true);
+
+    assert!(start.elapsed() < Duration::from_millis(400));
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(failed, serde_json::json!(["redis"]));
+}
+
+#[test]
+fn subscriptions_are_rejected_while_the_source_is_unhealthy() {
+    let healthy = Arc::new(AtomicBool::new(false));
+    let flag = healthy.clone();
+    let available = Handler::available(true, move || flag.load(Ordering::Relaxed))
+        .map(|| "OK")
+        .recover(Handler::err);
+
+    let rejected = warp::test::request().reply(&available);
+    assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(rejected.headers()["retry-after"], "5");
+
+    healthy.store(true, Ordering::Relaxed);
+    let accepted = warp::test::request().reply(&available);
+    assert_eq!(accepted.status(), StatusCode::OK);
+}
+
+#[test]
+fn subscriptions_are_accepted_during_an_outage_unless_configured() {
+    assert!(warp::test::request()
+        .filter(&Handler::available(false, || false))
+        .is_ok());
+}
+
+#[test]
+fn bearer_token_is_read_from_the_authorization_header() {
+    let token = |auth: &str| {
+        warp::test::request()
+            .header("Authorization", auth)
+            .filter(&query::OptionalAccessToken::from_sse_header())
+            .expect("infallible filter")
+    };
+
+    assert_eq!(token("Bearer abc"), Some("abc".to_string()));
+    assert_eq!(token("bearer abc"), Some("abc".to_string()));
+    assert_eq!(token("Bearer"), None);
+    assert_eq!(token("Basic dXNlcjpwYXNz"), None);
+    assert_eq!(token("Bearer abc def"), None);
+}
+
+fn whitelisted_sse(cookie: Option<&str>) -> std::result::Result<Query, Rejection> {
+    let request = warp::test::request().path("/api/v1/streaming?stream=user");
+    let request = match cookie {
+        Some(cookie) => request.header("Cookie", cookie),
+        None => request,
+    };
+    let session_cookie = Some("_session_token".to_string());
+    let authenticated = Handler::with_sse_token(parse_ws_query(), session_cookie)
+        .and_then(|q| Handler::screen_anonymous(true, q));
+    request.filter(&authenticated)
+}
+
+#[test]
+fn whitelisted_sse_request_is_authenticated_by_the_session_cookie() {
+    let query = whitelisted_sse(Some("theme=dark; _session_token=abc123"))
+        .expect("authenticated by cookie");
+
+    assert_eq!(query.access_token, Some("abc123".to_string()));
+}
+
+#[test]
+fn whitelisted_sse_request_without_any_token_is_rejected() {
+    let rejection = whitelisted_sse(None).expect_err("no token");
+    assert_eq!(
+        rejection.cause().map(|cause| cause.to_string()).as_deref(),
+        Some(PgPool::BAD_TOKEN)
+    );
+
+    let rejection = whitelisted_sse(Some("theme=dark")).expect_err("no session cookie");
+    assert_eq!(
+        rejection.cause().map(|cause| cause.to_string()).as_deref(),
+        Some(PgPool::BAD_TOKEN)
+    );
+}
+
+#[test]
+fn session_cookie_value_is_url_decoded() {
+    let query =
+        whitelisted_sse(Some("_session_token=abc%2B123%3D%3D")).expect("authenticated by cookie");
+
+    assert_eq!(query.access_token, Some("abc+123==".to_string()));
+}
+
+#[test]
+fn session_cookie_does_not_replace_a_header_token() {
+    let query = warp::test::request()
+        .path("/api/v1/streaming?stream=user")
+        .header("Authorization", "Bearer header_token")
+        .header("Cookie", "_session_token=cookie_token")
+        .filter(&Handler::with_sse_token(
+            parse_ws_query(),
+            Some("_session_token".to_string()),
+        ))
+        .expect("valid query");
+
+    assert_eq!(query.access_token, Some("header_token".to_string()));
+}
+
+fn invalid_parameter(query: &str) -> Option<String> {
+    warp::test::request()
+        .path(&format!("/api/v1/streaming?{}", query))
+        .filter(&parse_ws_query())
+        .expect_err("malformed parameter")
+        .cause()
+        .map(|cause| cause.to_string())
+}
+
+#[test]
+fn malformed_list_is_rejected_by_name() {
+    assert_eq!(
+        invalid_parameter("stream=list&list=notanumber").as_deref(),
+        Some("Invalid parameter: list")
+    );
+}
+
+#[test]
+fn boolean_parameters_that_are_not_flags_are_rejected_by_name() {
+    for param in &[
+        "exclude_self",
+        "only_relationships",
+        "newest_first",
+        "backfill",
+    ] {
+        let query = format!("stream=public&{}=yes", param);
+        assert_eq!(
+            invalid_parameter(&query),
+            Some(format!("Invalid parameter: {}", param))
+        );
+    }
+}
+
+#[test]
+fn non_positive_list_ids_are_rejected_by_name() {
+    for query in &["stream=list&list=0", "stream=list&list=-5"] {
+        assert_eq!(
+            invalid_parameter(query).as_deref(),
+            Some("Invalid parameter: list")
+        );
+    }
+}
+
+#[test]
+fn positive_list_id_is_accepted() {
+    let query = warp::test::request()
+        .path("/api/v1/streaming?stream=list&list=5")
+        .filter(&parse_ws_query())
+        .expect("valid query");
+
+    assert_eq!(query.list, 5);
+}
+
+#[test]
+fn max_events_per_sec_is_parsed() {
+    let query = warp::test::request()
+        .path("/api/v1/streaming?stream=public&max_events_per_sec=20")
+        .filter(&parse_ws_query())
+        .expect("valid query");
+    assert_eq!(query.max_events_per_sec, 20);
+
+    assert_eq!(
+        invalid_parameter("stream=public&max_events_per_sec=fast").as_deref(),
+        Some("Invalid parameter: max_events_per_sec")
+    );
+}
+
+#[test]
+fn malformed_media_is_rejected_by_name() {
+    assert_eq!(
+        invalid_parameter("stream=public&only_media=maybe").as_deref(),
+        Some("Invalid parameter: only_media")
+    );
+}
+
+#[test]
+fn malformed_tag_is_rejected_by_name() {
+    assert_eq!(
+        invalid_parameter("stream=hashtag&tag=").as_deref(),
+        Some("Invalid parameter: tag")
+    );
+}
+
+#[test]
+fn absent_parameters_still_use_their_defaults() {
+    let query = warp::test::request()
+        .path("/api/v1/streaming?stream=public&only_media=false")
+        .filter(&parse_ws_query())
+        .expect("valid query");
+
+    assert_eq!(
+        (query.list, query.hashtag.as_str(), query.media),
+        (0, "", false)
+    );
+}
+
+#[test]
+fn malformed_parameters_are_a_bad_request_naming_the_parameter() {
+    let streaming = parse_ws_query().map(|_| "ok").recover(Handler::err);
+    let response = warp::test::request()
+        .path("/api/v1/streaming?stream=list&list=notanumber")
+        .reply(&streaming);
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        response.body().as_ref(),
+        &br#"{"error":"Invalid parameter: list"}"#[..]
+    );
+}
+
+#[test]
+fn denied_token_is_rejected_before_postgres_is_queried() {
+    let denied_tokens = RwLock::new(vec!["abused".to_string()].into_iter().collect());
+    let screened = |path: &str| {
+        let query = warp::test::request()
+            .path(path)
+            .filter(&parse_ws_query())
+            .expect("valid query");
+        // Nothing here can reach Postgres, so a rejection can only come from the deny list
+        Handler::screen_token(&denied_tokens, query)
+    };
+
+    let rejection =
+        screened("/api/v1/streaming?stream=user&access_token=abused").expect_err("denied token");
+    assert_eq!(
+        rejection.cause().map(|cause| cause.to_string()).as_deref(),
+        Some(Handler::DENIED_TOKEN)
+    );
+    assert!(screened("/api/v1/streaming?stream=user&access_token=valid").is_ok());
+    assert!(screened("/api/v1/streaming?stream=public").is_ok());
+}
+
+#[test]
+fn firehose_without_admin_rights_is_forbidden() {
+    let user = timeline::UserData {
+        scopes: vec![timeline::Scope::Statuses].into_iter().collect(),
+        ..timeline::UserData::public()
+    };
+    let firehose = parse_ws_query()
+        .and_then(move |q: Query| Timeline::from_query_and_user(&q, &user))
+        .map(|_| "ok")
+        .recover(Handler::err);
+    let response = warp::test::request()
+        .path("/api/v1/streaming?stream=firehose&access_token=TOKEN")
+        .reply(&firehose);
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        response.body().as_ref(),
+        &br#""Error: Stream requires admin rights""#[..]
+    );
+}
+
+#[test]
+fn probe_of_the_streaming_root_is_ok_when_enabled() {
+    let response = warp::test::request()
+        .path("/api/v1/streaming")
+        .reply(&Handler::probe(true));
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.body().as_ref(), &br#"{"status":"online"}"#[..]);
+}
+
+#[test]
+fn probe_of_the_streaming_root_is_rejected_when_disabled() {
+    let rejection = warp::test::request()
+        .path("/api/v1/streaming")
+        .filter(&Handler::probe(false))
+        .expect_err("probes disabled");
+
+    assert!(rejection.is_not_found());
+}
+
+#[test]
+fn metrics_are_served_without_a_token_only_when_enabled() {
+    let enabled = warp::test::request()
+        .path("/metrics")
+        .filter(&Handler::metrics(true));
+    let disabled = warp::test::request()
+        .path("/metrics")
+        .filter(&Handler::metrics(false))
+        .expect_err("metrics disabled");
+
+    assert!(enabled.is_ok());
+    assert!(disabled.is_not_found());
+}
+
+fn allowing(langs: &[&str]) -> Subscription {
+    Subscription {
+        allowed_langs: langs.iter().map(|lang| lang.to_string()).collect(),
+        ..Subscription::default()
+    }
+}
+
+#[test]
+fn allowed_language_matches_regional_variants() {
+    assert!(allowing(&["en"]).allows_language("en-GB"));
+    assert!(allowing(&["en-US"]).allows_language("en"));
+    assert!(allowing(&["EN"]).allows_language("en-us"));
+}
+
+#[test]
+fn allowed_language_matches_exactly() {
+    assert!(allowing(&["en"]).allows_language("en"));
+    assert!(allowing(&["de", "ja"]).allows_language("ja"));
+}
+
+#[test]
+fn allowed_language_rejects_other_languages() {
+    assert!(!allowing(&["en"]).allows_language("de"));
+    assert!(!allowing(&["en-GB"]).allows_language("de-GB"));
+    assert!(allowing(&[]).allows_language("de"));
+}
+
+#[test]
+fn always_allowed_languages_are_added_to_a_limited_set() {
+    let always: HashSet<String> = vec!["ja".to_string()].into_iter().collect();
+
+    let limited = allowing(&["en"]).always_allowing(&always);
+    assert!(limited.allows_language("ja"));
+    assert!(limited.allows_language("en"));
+    assert!(!limited.allows_language("de"));
+
+    // allowing no languages allows them all, which adding a language would undo
+    assert!(allowing(&[]).always_allowing(&always).allows_language("de"));
+}
+
+/// The event type read from the end of `path` (`None` if the path was rejected)
+fn event_type_in(path: &str, enabled: bool) -> Option<Option<String>> {
+    let public = warp::path("public").and(Handler::event_type_segment(enabled));
+    warp::test::request().path(path).filter(&public).ok()
+}
+
+#[test]
+fn trailing_event_type_is_read_from_the_path_when_enabled() {
+    let update = Some("update".to_string());
+    assert_eq!(event_type_in("/public/update", true), Some(update));
+    assert_eq!(event_type_in("/public", true), Some(None));
+    assert_eq!(event_type_in("/public/nonsense", true), None);
+}
+
+#[test]
+fn trailing_event_type_is_ignored_when_disabled() {
+    assert_eq!(event_type_in("/public/update", false), Some(None));
+    assert_eq!(event_type_in("/public/nonsense", false), Some(None));
+}
+
+fn filtering(phrase: &str, whole_word: bool) -> Filters {
+    Filters::new(&[(phrase.to_string(), whole_word)])
+}
+
+#[test]
+fn whole_word_filters_only_match_whole_words() {
+    assert!(filtering("rust", true).match_any(&["I <3 Rust!"]));
+    assert!(!filtering("rust", true).match_any(&["trusty"]));
+    assert!(filtering("rust", false).match_any(&["trusty"]));
+}
+
+#[test]
+fn filters_match_their_phrase_literally() {
+    // No word boundary before a `#`, which isn't a word character
+    assert!(filtering("#rust", true).match_any(&["<p>#rust</p>"]));
+    assert!(!filtering("a.b", false).match_any(&["axb"]));
+    assert!(filtering("(", false).match_any(&["a (parenthetical)"]));
+    assert!(filtering("rust", false).match_any(&["", "RUST"]));
+    assert!(!Filters::default().match_any(&["anything"]));
+}
+
+#[test]
+fn blocks_only_apply_to_notifications_from_blocked_users() {
+    use crate::response::Event;
+    use std::convert::TryFrom;
+
+    let txt = std::fs::read_to_string("test_data/msg.event_txt_001.txt").expect("test input");
+    let update = Event::try_from(txt.clone()).expect("valid event"); // by account 78
+    let status: serde_json::Value = serde_json::from_str(&txt).expect("valid json");
+    let notification = serde_json::json!({
+        "event": "notification",
+        "payload": {
+            "id": "1",
+            "type": "mention",
+            "created_at": "2020-03-25T01:30:24.914Z",
+            "account": status["payload"]["account"],
+            "status": null,
+        },
+    });
+    let mention = Event::try_from(notification.to_string()).expect("valid event");
+    let blocking = |users: Vec<Id>| Blocks {
+        blocking_users: users.into_iter().collect(),
+        ..Blocks::default()
+    };
+
+    assert!(blocking(vec![Id(78)]).blocks_notification(&mention));
+    assert!(!blocking(vec![Id(1)]).blocks_notification(&mention));
+    assert!(!blocking(vec![Id(78)]).blocks_notification(&update));
+}
+
+#[test]
+fn hashtag_ids_that_cannot_be_looked_up_are_left_out() {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let handler = unconnected_handler(attempts.clone());
+
+    let ids = handler
+        .select_hashtag_ids(vec!["rust".to_string(), "mastodon".to_string()])
+        .wait();
+
+    assert_eq!(ids, Ok(Vec::new()));
+    assert!(attempts.load(Ordering::SeqCst) >= 2);
+}
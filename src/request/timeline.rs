@@ -1,19 +1,46 @@
 pub use self::inner::{Content, Reach, Scope, Stream};
 use super::err::Timeline as Error;
 use super::query::Query;
+use crate::Id;
 pub(crate) use inner::UserData;
 
 use lru::LruCache;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 use warp::reject::Rejection;
 
 mod err;
 mod inner;
+#[cfg(test)]
+mod test;
 
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Clone, Debug, Copy, Eq, Hash, PartialEq)]
 pub struct Timeline(pub Stream, pub Reach, pub Content);
 
+/// A cache of hashtag ids by name, for parsing the names of hashtag channels
+pub trait TagIds {
+    fn tag_id(&mut self, name: &str) -> Option<i64>;
+}
+
+/// Serializes as `{"stream":{"user":"78"},"reach":"federated","content":"all"}` (for example),
+/// for showing clients what their request resolved to
+impl Serialize for Timeline {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut timeline = serializer.serialize_struct("Timeline", 3)?;
+        timeline.serialize_field("stream", &self.0)?;
+        timeline.serialize_field("reach", &self.1)?;
+        timeline.serialize_field("content", &self.2)?;
+        timeline.end()
+    }
+}
+
+impl TagIds for LruCache<String, i64> {
+    fn tag_id(&mut self, name: &str) -> Option<i64> {
+        self.get(&name.to_string()).copied()
+    }
+}
+
 impl Timeline {
     pub fn empty() -> Self {
         Self(Stream::Unset, Reach::Local, Content::Notification)
@@ -27,6 +54,40 @@ impl Timeline {
         }
     }
 
+    /// Whether this is a public timeline that includes statuses from other instances
+    pub(crate) fn is_federated_public(&self) -> bool {
+        if let Self(Stream::Public, Reach::Federated, _) = self {
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The id of the user whose timeline this is, if it's a `user` timeline
+    pub(crate) fn user_id(&self) -> Option<Id> {
+        if let Self(Stream::User(id), _, _) = self {
+            Some(*id)
+        } else {
+            None
+        }
+    }
+
+    /// Every `category` of timeline that clients can stream
+    pub(crate) const CATEGORIES: [&'static str; 5] =
+        ["public", "hashtag", "user", "list", "direct"];
+
+    /// A short name for the kind of `Stream` this `Timeline` is for, ignoring any ids
+    pub(crate) fn category(&self) -> &'static str {
+        match self.0 {
+            Stream::Public => "public",
+            Stream::Hashtag(_) => "hashtag",
+            Stream::User(_) => "user",
+            Stream::List(_) => "list",
+            Stream::Direct(_) => "direct",
+            Stream::Unset => "unset",
+        }
+    }
+
     pub(crate) fn tag(&self) -> Option<i64> {
         if let Self(Stream::Hashtag(id), _, _) = self {
             Some(*id)
@@ -35,42 +96,121 @@ impl Timeline {
         }
     }
 
-    pub(crate) fn to_redis_raw_timeline(&self, hashtag: Option<&String>) -> Result<String> {
+    /// The same `Timeline`, but for the hashtag with the id `tag_id` (non-hashtag timelines
+    /// are returned unchanged)
+    pub(crate) fn with_tag(self, tag_id: i64) -> Self {
+        match self {
+            Self(Stream::Hashtag(_), reach, content) => {
+                Self(Stream::Hashtag(tag_id), reach, content)
+            }
+            other => other,
+        }
+    }
+
+    /// The broader `Timeline` whose events include all of this one's, if this is a narrow
+    /// (media-only or notification-only) `Timeline`
+    pub(crate) fn broader(&self) -> Option<Self> {
+        match self {
+            Self(stream, reach, Content::Media) | Self(stream, reach, Content::Notification) => {
+                Some(Self(*stream, *reach, Content::All))
+            }
+            Self(_, _, Content::All) => None,
+        }
+    }
+
+    /// The narrow `Timeline`s whose events are a subset of this one's (the inverse of `broader`)
+    pub(crate) fn narrower(&self) -> Vec<Self> {
+        match self {
+            Self(stream, reach, Content::All) => vec![
+                Self(*stream, *reach, Content::Media),
+                Self(*stream, *reach, Content::Notification),
+            ],
+            Self(_, _, Content::Media) | Self(_, _, Content::Notification) => Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_media_only(&self) -> bool {
+        self.2 == Content::Media
+    }
+
+    pub(crate) fn is_notification_only(&self) -> bool {
+        self.2 == Content::Notification
+    }
+
+    /// The name of the Redis channel for this `Timeline`, under the `channel_root` (which is
+    /// `timeline` for a standard Mastodon deployment)
+    pub(crate) fn to_redis_raw_timeline(
+        &self,
+        channel_root: &str,
+        hashtag: Option<&String>,
+    ) -> Result<String> {
+        Ok([channel_root, ":", &self.to_redis_text(hashtag)?].concat())
+    }
+
+    /// The name of the Redis channel for this `Timeline`, without the channel root (this is
+    /// also the stream name Mastodon uses for multiplexed WebSocket connections)
+    pub(crate) fn to_redis_text(&self, hashtag: Option<&String>) -> Result<String> {
         use {Content::*, Error::*, Reach::*, Stream::*};
 
         Ok(match self {
-            Timeline(Public, Federated, All) => "timeline:public".to_string(),
-            Timeline(Public, Local, All) => "timeline:public:local".to_string(),
-            Timeline(Public, Federated, Media) => "timeline:public:media".to_string(),
-            Timeline(Public, Local, Media) => "timeline:public:local:media".to_string(),
+            Timeline(Public, Federated, All) => "public".to_string(),
+            Timeline(Public, Local, All) => "public:local".to_string(),
+            Timeline(Public, Federated, Media) => "public:media".to_string(),
+            Timeline(Public, Local, Media) => "public:local:media".to_string(),
+            Timeline(Public, Remote, All) => "public:remote".to_string(),
+            Timeline(Public, Remote, Media) => "public:remote:media".to_string(),
             Timeline(Hashtag(_id), Federated, All) => {
-                ["timeline:hashtag:", hashtag.ok_or(MissingHashtag)?].concat()
+                ["hashtag:", hashtag.ok_or(MissingHashtag)?].concat()
+            }
+            Timeline(Hashtag(_id), Local, All) => {
+                ["hashtag:", hashtag.ok_or(MissingHashtag)?, ":local"].concat()
             }
-            Timeline(Hashtag(_id), Local, All) => [
-                "timeline:hashtag:",
-                hashtag.ok_or(MissingHashtag)?,
-                ":local",
-            ]
-            .concat(),
-            Timeline(User(id), Federated, All) => ["timeline:", &id.to_string()].concat(),
+            Timeline(User(id), Federated, All) => id.to_string(),
             Timeline(User(id), Federated, Notification) => {
-                ["timeline:", &id.to_string(), ":notification"].concat()
+                [&id.to_string(), ":notification"].concat()
             }
-            Timeline(List(id), Federated, All) => ["timeline:list:", &id.to_string()].concat(),
-            Timeline(Direct(id), Federated, All) => ["timeline:direct:", &id.to_string()].concat(),
+            Timeline(List(id), Federated, All) => ["list:", &id.to_string()].concat(),
+            Timeline(Direct(id), Federated, All) => ["direct:", &id.to_string()].concat(),
             Timeline(_one, _two, _three) => Err(Error::InvalidInput)?,
         })
     }
 
-    pub fn from_redis_text(timeline: &str, cache: &mut LruCache<String, i64>) -> Result<Self> {
+    /// Parse the full name of a Redis channel (including the `channel_root`, but not any
+    /// namespace) into a `Timeline`.
+    pub(crate) fn from_redis_raw_timeline(
+        raw_timeline: &str,
+        channel_root: &str,
+        cache: &mut impl TagIds,
+    ) -> Result<Self> {
+        let prefix_len = channel_root.len() + ":".len();
+        if raw_timeline.starts_with(channel_root)
+            && raw_timeline[channel_root.len()..].starts_with(':')
+        {
+            Self::from_redis_text(&raw_timeline[prefix_len..], cache)
+        } else {
+            Err(Error::InvalidInput)
+        }
+    }
+
+    /// Parse the text of a Redis channel (without the channel root) into a `Timeline`.
+    ///
+    /// Hashtag channels may identify the tag either by name (as Mastodon currently does) or by
+    /// its numeric id.  Mastodon doesn't allow all-numeric hashtags, so a segment that parses
+    /// as an integer is always an id and is used directly; names are looked up in the `cache`.
+    pub fn from_redis_text(timeline: &str, cache: &mut impl TagIds) -> Result<Self> {
         use {Content::*, Error::*, Reach::*, Stream::*};
-        let mut tag_id = |t: &str| cache.get(&t.to_string()).map_or(Err(BadTag), |id| Ok(*id));
+        let mut tag_id = |t: &str| match t.parse() {
+            Ok(id) => Ok(id),
+            Err(_) => cache.tag_id(t).ok_or(BadTag),
+        };
 
         Ok(match &timeline.split(':').collect::<Vec<&str>>()[..] {
             ["public"] => Timeline(Public, Federated, All),
             ["public", "local"] => Timeline(Public, Local, All),
             ["public", "media"] => Timeline(Public, Federated, Media),
             ["public", "local", "media"] => Timeline(Public, Local, Media),
+            ["public", "remote"] => Timeline(Public, Remote, All),
+            ["public", "remote", "media"] => Timeline(Public, Remote, Media),
             ["hashtag", tag] => Timeline(Hashtag(tag_id(tag)?), Federated, All),
             ["hashtag", tag, "local"] => Timeline(Hashtag(tag_id(tag)?), Local, All),
             [id] => Timeline(User(id.parse()?), Federated, All),
@@ -96,8 +236,18 @@ impl Timeline {
                 true => Timeline(Public, Local, Media),
                 false => Timeline(Public, Local, All),
             },
+            "public:remote" => match q.media {
+                true => Timeline(Public, Remote, Media),
+                false => Timeline(Public, Remote, All),
+            },
             "public:media" => Timeline(Public, Federated, Media),
             "public:local:media" => Timeline(Public, Local, Media),
+            "public:remote:media" => Timeline(Public, Remote, Media),
+            // all public timelines, merged (see `Subscription::timelines`)
+            "firehose" => match user.scopes.contains(&Admin) {
+                true => Timeline(Public, Federated, All),
+                false => Err(custom(super::Handler::ADMIN_SCOPE))?,
+            },
 
             "hashtag" => Timeline(Hashtag(0), Federated, All),
             "hashtag:local" => Timeline(Hashtag(0), Local, All),
@@ -2,9 +2,11 @@ use super::Error;
 use crate::Id;
 
 use hashbrown::HashSet;
+use serde::Serialize;
 use std::convert::TryFrom;
 
-#[derive(Clone, Debug, Copy, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Copy, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Stream {
     User(Id),
     List(i64),
@@ -14,13 +16,16 @@ pub enum Stream {
     Unset,
 }
 
-#[derive(Clone, Debug, Copy, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Copy, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Reach {
     Local,
+    Remote,
     Federated,
 }
 
-#[derive(Clone, Debug, Copy, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Copy, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Content {
     All,
     Media,
@@ -33,6 +38,7 @@ pub enum Scope {
     Statuses,
     Notifications,
     Lists,
+    Admin,
 }
 
 impl TryFrom<&str> for Scope {
@@ -44,6 +50,7 @@ impl TryFrom<&str> for Scope {
             "read:statuses" => Ok(Scope::Statuses),
             "read:notifications" => Ok(Scope::Notifications),
             "read:lists" => Ok(Scope::Lists),
+            "admin:read" => Ok(Scope::Admin),
             "write" | "follow" => Err(Error::InvalidInput), // ignore write scopes
             unexpected => {
                 log::warn!("Ignoring unknown scope `{}`", unexpected);
@@ -0,0 +1,106 @@
+use super::*;
+use {Content::*, Reach::*, Stream::*};
+
+#[test]
+fn hashtag_by_name_uses_the_cache() -> Result<()> {
+    let mut cache = LruCache::new(10);
+    cache.put("rust".to_string(), 42);
+
+    assert_eq!(
+        Timeline::from_redis_text("hashtag:rust", &mut cache)?,
+        Timeline(Hashtag(42), Federated, All)
+    );
+    assert_eq!(
+        Timeline::from_redis_text("hashtag:rust:local", &mut cache)?,
+        Timeline(Hashtag(42), Local, All)
+    );
+    Ok(())
+}
+
+#[test]
+fn hashtag_by_name_missing_from_cache_is_an_error() {
+    let mut cache = LruCache::new(10);
+    assert!(Timeline::from_redis_text("hashtag:rust", &mut cache).is_err());
+}
+
+#[test]
+fn hashtag_by_id_bypasses_the_cache() -> Result<()> {
+    let mut cache = LruCache::new(10);
+
+    assert_eq!(
+        Timeline::from_redis_text("hashtag:42", &mut cache)?,
+        Timeline(Hashtag(42), Federated, All)
+    );
+    assert_eq!(
+        Timeline::from_redis_text("hashtag:42:local", &mut cache)?,
+        Timeline(Hashtag(42), Local, All)
+    );
+    assert!(cache.is_empty());
+    Ok(())
+}
+
+#[test]
+fn raw_timelines_round_trip_with_a_custom_channel_root() -> Result<()> {
+    let mut cache = LruCache::new(10);
+    cache.put("rust".to_string(), 42);
+    let rust = Some("rust".to_string());
+
+    for tl in &[
+        Timeline(Public, Federated, All),
+        Timeline(Public, Local, Media),
+        Timeline(Hashtag(42), Local, All),
+        Timeline(User(1), Federated, Notification),
+        Timeline(List(3), Federated, All),
+        Timeline(Direct(4), Federated, All),
+    ] {
+        let raw = tl.to_redis_raw_timeline("streaming", rust.as_ref())?;
+        assert!(raw.starts_with("streaming:"));
+        assert_eq!(
+            Timeline::from_redis_raw_timeline(&raw, "streaming", &mut cache)?,
+            *tl
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn raw_timeline_with_a_different_channel_root_is_rejected() -> Result<()> {
+    let mut cache = LruCache::new(10);
+    assert_eq!(
+        Timeline(Public, Federated, All).to_redis_raw_timeline("timeline", None)?,
+        "timeline:public"
+    );
+    assert!(Timeline::from_redis_raw_timeline("timeline:public", "streaming", &mut cache).is_err());
+    assert!(Timeline::from_redis_raw_timeline("streamingpublic", "streaming", &mut cache).is_err());
+    Ok(())
+}
+
+#[test]
+fn narrow_timelines_fall_back_to_the_broad_channel() {
+    let notifications = Timeline(User(Id(1)), Federated, Notification);
+    let media = Timeline(Public, Local, Media);
+
+    assert_eq!(
+        notifications.broader(),
+        Some(Timeline(User(Id(1)), Federated, All))
+    );
+    assert_eq!(media.broader(), Some(Timeline(Public, Local, All)));
+    assert_eq!(Timeline(Public, Local, All).broader(), None);
+    assert!(Timeline(Public, Local, All).narrower().contains(&media));
+    assert!(media.narrower().is_empty());
+}
+
+#[test]
+fn timeline_serializes_its_stream_reach_and_content() {
+    let user = Timeline(User(Id(78)), Federated, Notification);
+    let hashtag = Timeline(Hashtag(42), Local, All);
+
+    assert_eq!(
+        serde_json::to_string(&user).expect("serializable"),
+        r#"{"stream":{"user":"78"},"reach":"federated","content":"notification"}"#
+    );
+    assert_eq!(
+        serde_json::to_string(&hashtag).expect("serializable"),
+        r#"{"stream":{"hashtag":42},"reach":"local","content":"all"}"#
+    );
+}
@@ -1,8 +1,10 @@
 //! Stream the updates appropriate for a given `User`/`timeline` pair from Redis.
 
-pub use event::Event;
+pub use event::{Event, EventIds, EventTransform};
 pub use redis::Manager as RedisManager;
-pub use stream::{Sse as SseStream, Ws as WsStream};
+pub use redis::{DroppedEvents, MessageSource, RedisPing, RedisSource};
+pub use stream::{bind_with_backlog, with_send_buffer, LocalSubscriber, WriteFailures};
+pub use stream::{Sse as SseStream, SseOptions, Ws as WsStream, WsOptions};
 
 pub(self) use event::err::Event as EventErr;
 pub(self) use event::Payload;
@@ -4,15 +4,24 @@ mod checked_event;
 pub mod checked_event;
 mod dynamic_event;
 pub mod err;
+mod transform;
+
+#[cfg(test)]
+mod test;
 
 pub use self::checked_event::CheckedEvent;
 pub use self::dynamic_event::{DynEvent, EventKind};
+pub use self::transform::EventTransform;
 use crate::Id;
 
 use hashbrown::HashSet;
 use serde::Serialize;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
 use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
 use std::string::String;
+use std::sync::Arc;
 use warp::sse::ServerSentEvent;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -28,22 +37,318 @@ pub(crate) trait Payload {
     fn involved_users(&self) -> HashSet<Id>;
     fn author(&self) -> &Id;
     fn sent_from(&self) -> &str;
+    /// The domains of the status's author and of the author of the status it boosts (if any),
+    /// leaving out local accounts, which have no domain
+    fn sent_from_domains(&self) -> Vec<&str>;
+    /// Whether the status must not be shown outside of the instance it was posted on
+    fn local_only(&self) -> bool;
+    /// The day the author's account was created, as days since the Unix epoch (if known)
+    fn author_created_day(&self) -> Option<i64>;
+    /// How many followers the author has (if known)
+    fn author_followers(&self) -> Option<i64>;
+    /// The text that keyword filters apply to: the content and content warning of the status,
+    /// and of the status it boosts (if any), as `plain_text`
+    fn filterable_text(&self) -> Vec<String>;
+}
+
+/// The text of the HTML `html` (such as a status's `content`), as Mastodon matches keyword
+/// filters against it: line breaks and ends of paragraphs become newlines, all other tags are
+/// dropped, and character references (such as `&amp;`) are decoded
+pub(crate) fn plain_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(len) => start + len,
+            None => break, // not a tag, so the rest is text
+        };
+        text.push_str(&decode_references(&rest[..start]));
+        let tag = rest[start + 1..end].to_ascii_lowercase();
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        if name == "br" || (closing && name == "p") {
+            text.push('\n');
+        }
+        rest = &rest[end + 1..];
+    }
+    text.push_str(&decode_references(rest));
+    text
+}
+
+/// `text` with its HTML character references (named ones Mastodon produces, and numeric ones)
+/// decoded.  Anything else that starts with `&` is left as is.
+fn decode_references(text: &str) -> String {
+    const LONGEST_REFERENCE: usize = "&#x10ffff;".len();
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest
+            .char_indices()
+            .take(LONGEST_REFERENCE)
+            .find(|(_, c)| *c == ';')
+            .and_then(|(end, _)| Some((end, decode_reference(&rest[1..end])?)));
+        match reference {
+            Some((end, c)) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// The character that the character reference `name` (between the `&` and `;`) stands for
+fn decode_reference(name: &str) -> Option<char> {
+    let code = match name {
+        "amp" => return Some('&'),
+        "lt" => return Some('<'),
+        "gt" => return Some('>'),
+        "quot" => return Some('"'),
+        "apos" => return Some('\''),
+        "nbsp" => return Some('\u{a0}'),
+        hex if hex.starts_with("#x") || hex.starts_with("#X") => u32::from_str_radix(&hex[2..], 16),
+        decimal if decimal.starts_with('#') => decimal[1..].parse(),
+        _ => return None,
+    };
+    std::char::from_u32(code.ok()?)
+}
+
+/// The number of days from the Unix epoch to the date at the start of an ISO 8601
+/// `timestamp` (such as Mastodon's `created_at`), if it starts with a valid date
+pub(crate) fn days_since_epoch(timestamp: &str) -> Option<i64> {
+    let date = timestamp.get(0..10)?;
+    let mut parts = date.split('-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Count from March, so that the leap day falls at the end of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146_097 + day_of_era - 719_468)
 }
 
 impl Event {
+    /// The types of event that Flodgatt parses (and so can check and filter)
+    pub(crate) const KNOWN_TYPES: [&'static str; 10] = [
+        "update",
+        "notification",
+        "delete",
+        "filters_changed",
+        "announcement",
+        "announcement.reaction",
+        "announcement.delete",
+        "conversation",
+        "status.counts",
+        "status.update",
+    ];
+
+    /// An event of a type Flodgatt doesn't recognize, which is forwarded with its payload (if
+    /// any) as is.  Events of known types that failed to parse aren't passed through, since
+    /// they couldn't be filtered.
+    pub(crate) fn passthrough(event_txt: &str) -> Option<Self> {
+        let value: Value = serde_json::from_str(event_txt).ok()?;
+        let event = value["event"].as_str()?;
+        if Self::KNOWN_TYPES.contains(&event) {
+            return None;
+        }
+        Some(Self::Dynamic(DynEvent {
+            kind: EventKind::NonUpdate,
+            event: event.to_string(),
+            payload: value["payload"].clone(),
+            queued_at: value["queued_at"].as_i64(),
+        }))
+    }
+
+    /// The `event_txt` with any of its payload's ids that are JSON numbers converted to strings,
+    /// or `None` if it has no numeric ids (or isn't JSON).  This covers the status's `id`,
+    /// `in_reply_to_id`, `in_reply_to_account_id` and `account.id`, and those of any boosted
+    /// status.
+    pub(crate) fn stringify_ids(event_txt: &str) -> Option<String> {
+        const IDS: [&str; 8] = [
+            "/payload/id",
+            "/payload/in_reply_to_id",
+            "/payload/in_reply_to_account_id",
+            "/payload/account/id",
+            "/payload/reblog/id",
+            "/payload/reblog/in_reply_to_id",
+            "/payload/reblog/in_reply_to_account_id",
+            "/payload/reblog/account/id",
+        ];
+        let mut value: Value = serde_json::from_str(event_txt).ok()?;
+        let mut stringified = false;
+        for pointer in &IDS {
+            if let Some(id) = value.pointer_mut(pointer).filter(|id| id.is_number()) {
+                *id = Value::String(id.to_string());
+                stringified = true;
+            }
+        }
+        if stringified {
+            Some(value.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// A single `batch` event carrying `events` as a JSON array (of events in the same format
+    /// as WebSocket messages), for clients that asked for their events in batches
+    pub(crate) fn batch(events: &[Arc<Event>]) -> Self {
+        let events = events
+            .iter()
+            .filter_map(|event| serde_json::from_str(&event.to_json_string()).ok())
+            .collect();
+        Self::Dynamic(DynEvent {
+            kind: EventKind::NonUpdate,
+            event: "batch".to_string(),
+            payload: Value::Array(events),
+            queued_at: None,
+        })
+    }
+
+    /// This event with a `_local` field added to its status, saying whether the status was
+    /// posted on this instance (judged by whether its author's `acct` names a domain), if
+    /// it's an `update`
+    pub(crate) fn marked_local(&self) -> Option<Self> {
+        let local = match (self.update_payload(), self.dyn_update_payload()) {
+            (Some(update), _) => update.sent_from().is_empty(),
+            (_, Some(update)) => update.sent_from().is_empty(),
+            (None, None) => return None,
+        };
+        let mut payload: Value = serde_json::from_str(&self.payload()?).ok()?;
+        payload
+            .as_object_mut()?
+            .insert("_local".to_string(), Value::Bool(local));
+        let event = DynEvent {
+            kind: EventKind::NonUpdate,
+            event: self.event_name(),
+            payload,
+            queued_at: self.queued_at(),
+        };
+        event.set_update().ok().map(Self::Dynamic)
+    }
+
     pub(crate) fn to_json_string(&self) -> String {
+        self.to_json_string_from(None, None, None, None)
+    }
+
+    /// Serialize the event, optionally tagged with a de-duplication `id` and the name of the
+    /// `stream` it was received from (in the same format Mastodon uses for multiplexed
+    /// WebSocket connections).  `queued_at` is internal to Mastodon, so it's only included
+    /// when explicitly passed in, as is the connection's `seq` number for the event.
+    pub(crate) fn to_json_string_from(
+        &self,
+        stream: Option<Vec<&str>>,
+        id: Option<&str>,
+        queued_at: Option<i64>,
+        seq: Option<u64>,
+    ) -> String {
         if let Event::Ping = self {
             "{}".to_string()
         } else {
             let event = &self.event_name();
             let sendable_event = match self.payload() {
-                Some(payload) => SendableEvent::WithPayload { event, payload },
-                None => SendableEvent::NoPayload { event },
+                Some(payload) => SendableEvent::WithPayload {
+                    stream,
+                    event,
+                    payload,
+                    id,
+                    queued_at,
+                    seq,
+                },
+                None => SendableEvent::NoPayload {
+                    stream,
+                    event,
+                    id,
+                    queued_at,
+                    seq,
+                },
             };
             serde_json::to_string(&sendable_event).expect("Guaranteed: SendableEvent is Serialize")
         }
     }
 
+    /// The id of the status, if this event is an `update`
+    pub(crate) fn status_id(&self) -> Option<Id> {
+        match self {
+            Self::TypeSafe(CheckedEvent::Update { payload, .. }) => Some(payload.id),
+            Self::Dynamic(DynEvent {
+                kind: EventKind::Update(_),
+                payload,
+                ..
+            }) => Id::try_from(&payload["id"]).ok(),
+            _ => None,
+        }
+    }
+
+    /// When Mastodon queued this event for streaming (in ms since the epoch), if it says
+    pub(crate) fn queued_at(&self) -> Option<i64> {
+        match self {
+            Self::TypeSafe(CheckedEvent::Update { queued_at, .. })
+            | Self::TypeSafe(CheckedEvent::StatusUpdate { queued_at, .. })
+            | Self::TypeSafe(CheckedEvent::Conversation { queued_at, .. })
+            | Self::Dynamic(DynEvent { queued_at, .. }) => *queued_at,
+            _ => None,
+        }
+    }
+
+    /// The id of the status this event is about (for updates, deletions and count changes)
+    fn subject_status_id(&self) -> Option<String> {
+        match self {
+            Self::TypeSafe(CheckedEvent::Delete { payload }) => Some(payload.clone()),
+            Self::TypeSafe(CheckedEvent::StatusCounts { payload }) => Some(payload.id.to_string()),
+            Self::TypeSafe(CheckedEvent::StatusUpdate { payload, .. }) => {
+                Some(payload.id.to_string())
+            }
+            Self::Dynamic(DynEvent { event, payload, .. }) if event == "delete" => {
+                payload.as_str().map(String::from)
+            }
+            _ => self.status_id().map(|id| id.to_string()),
+        }
+    }
+
+    /// A short id for following this event through the logs when `TRACE_EVENTS` is set.
+    ///
+    /// It's derived from the status the event is about (or, failing that, its payload) and the
+    /// time Mastodon queued it, rather than assigned on arrival, so every stage that logs the
+    /// event derives the same id without it needing to be passed along with the event.
+    pub(crate) fn trace_id(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.event_name().hash(&mut hasher);
+        match self.subject_status_id() {
+            Some(id) => id.hash(&mut hasher),
+            None => self.payload().hash(&mut hasher),
+        }
+        self.queued_at().hash(&mut hasher);
+        format!("{:08x}", hasher.finish() >> 32)
+    }
+
+    /// The log line recording that this event reached a `stage` of its handling
+    pub(crate) fn trace_line(&self, stage: &str) -> String {
+        format!("[event {}] {}", self.trace_id(), stage)
+    }
+
+    /// Log that this event reached a `stage` of its handling (pings aren't traced)
+    pub(crate) fn trace(&self, stage: &str) {
+        if !matches!(self, Self::Ping) {
+            log::info!("{}", self.trace_line(stage));
+        }
+    }
+
     pub(crate) fn to_warp_reply(&self) -> Option<(impl ServerSentEvent, impl ServerSentEvent)> {
         if let Event::Ping = self {
             None
@@ -55,11 +360,83 @@ impl Event {
         }
     }
 
-    pub(crate) fn update_payload(&self) -> Option<&checked_event::Status> {
-        if let Self::TypeSafe(CheckedEvent::Update { payload, .. }) = self {
-            Some(&payload)
-        } else {
+    /// The raw text of this event as a Server Sent Events frame (for responses that can't use
+    /// `warp::sse`, such as compressed ones)
+    pub(crate) fn to_sse_frame(&self) -> Option<String> {
+        if let Event::Ping = self {
             None
+        } else {
+            let data: String = self
+                .payload()
+                .unwrap_or_else(String::new)
+                .lines()
+                .map(|line| format!("data:{}\n", line))
+                .collect();
+            Some(format!("event:{}\n{}\n", self.event_name(), data))
+        }
+    }
+
+    /// Whether this is an event of the type named `event_type` (pings have no type)
+    pub(crate) fn is_of_type(&self, event_type: &str) -> bool {
+        !matches!(self, Self::Ping) && self.event_name() == event_type
+    }
+
+    /// Whether this is a notification of a relationship change (a follow or follow request)
+    pub(crate) fn is_relationship_notification(&self) -> bool {
+        match self {
+            Self::TypeSafe(CheckedEvent::Notification { payload }) => {
+                payload.is_relationship_change()
+            }
+            Self::Dynamic(DynEvent { event, payload, .. }) if event == "notification" => {
+                matches!(
+                    payload["type"].as_str(),
+                    Some("follow") | Some("follow_request")
+                )
+            }
+            _ => false,
+        }
+    }
+
+    /// The user whose action this event notifies the user of, if it's a notification
+    pub(crate) fn notifying_user(&self) -> Option<Id> {
+        match self {
+            Self::TypeSafe(CheckedEvent::Notification { payload }) => Some(*payload.from_user()),
+            Self::Dynamic(DynEvent { event, payload, .. }) if event == "notification" => {
+                Id::try_from(&payload["account"]["id"]).ok()
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_notification(&self) -> bool {
+        match self {
+            Self::TypeSafe(CheckedEvent::Notification { .. }) => true,
+            Self::Dynamic(DynEvent { event, .. }) => event == "notification",
+            _ => false,
+        }
+    }
+
+    /// Whether this is an `update` whose status has any media attachments
+    pub(crate) fn has_media(&self) -> bool {
+        match self {
+            Self::TypeSafe(_) => self
+                .update_payload()
+                .map_or(false, |status| !status.media_attachments.is_empty()),
+            Self::Dynamic(DynEvent { event, payload, .. }) if event == "update" => payload
+                ["media_attachments"]
+                .as_array()
+                .map_or(false, |media| !media.is_empty()),
+            _ => false,
+        }
+    }
+
+    /// The status carried by an `update` (or by a `status.update`, such as an edit or new poll
+    /// results, which is filtered the same way)
+    pub(crate) fn update_payload(&self) -> Option<&checked_event::Status> {
+        match self {
+            Self::TypeSafe(CheckedEvent::Update { payload, .. })
+            | Self::TypeSafe(CheckedEvent::StatusUpdate { payload, .. }) => Some(&payload),
+            _ => None,
         }
     }
 
@@ -86,6 +463,8 @@ impl Event {
                 CheckedEvent::AnnouncementDelete { .. } => "announcement.delete",
                 CheckedEvent::Conversation { .. } => "conversation",
                 CheckedEvent::FiltersChanged => "filters_changed",
+                CheckedEvent::StatusCounts { .. } => "status.counts",
+                CheckedEvent::StatusUpdate { .. } => "status.update",
             },
             Self::Dynamic(DynEvent {
                 kind: EventKind::Update(_),
@@ -106,10 +485,13 @@ impl Event {
                 Conversation         { payload, .. } => Some(escaped(payload)),
                 Announcement         { payload, .. } => Some(escaped(payload)),
                 AnnouncementReaction { payload, .. } => Some(escaped(payload)),
+                StatusCounts         { payload, .. } => Some(escaped(payload)),
+                StatusUpdate         { payload, .. } => Some(escaped(payload)),
                 AnnouncementDelete   { payload, .. } |
                 Delete               { payload, .. } => Some(payload.clone()),
                 FiltersChanged                       => None,
             },
+            Self::Dynamic(DynEvent { payload: Value::Null, .. }) => None,
             Self::Dynamic(DynEvent { payload, .. }) => Some(payload.to_string()),
             Self::Ping => unreachable!(), // private method only called above
         }
@@ -146,8 +528,53 @@ impl TryFrom<&str> for Event {
 #[derive(Serialize, Debug, Clone)]
 #[serde(untagged)]
 enum SendableEvent<'a> {
-    WithPayload { event: &'a str, payload: String },
-    NoPayload { event: &'a str },
+    WithPayload {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stream: Option<Vec<&'a str>>,
+        event: &'a str,
+        payload: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        queued_at: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    NoPayload {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stream: Option<Vec<&'a str>>,
+        event: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        queued_at: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+}
+
+/// Tags outgoing events with an identifier that clients connected to several nodes can use to
+/// recognize duplicates
+#[derive(Clone, Debug, Default)]
+pub struct EventIds {
+    node: Option<String>,
+}
+
+impl EventIds {
+    /// Identify events (as coming from `node`, if set)
+    pub fn new(node: Option<String>) -> Self {
+        Self { node }
+    }
+
+    /// The identifier for `event`: its type and the id of the status it's about (plus the node,
+    /// if set).  Events that aren't about a particular status don't get an identifier.
+    pub(crate) fn id_for(&self, event: &Event) -> Option<String> {
+        let status_id = event.subject_status_id()?;
+        Some(match &self.node {
+            Some(node) => format!("{}:{}@{}", event.event_name(), status_id, node),
+            None => format!("{}:{}", event.event_name(), status_id),
+        })
+    }
 }
 
 fn escaped<T: Serialize + std::fmt::Debug>(content: T) -> String {
@@ -7,10 +7,11 @@ mod id;
 mod mention;
 mod notification;
 pub(crate) mod status;
+mod status_counts;
 pub(crate) mod tag;
 pub(crate) mod visibility;
 
-pub(self) use super::Payload;
+pub(self) use super::{days_since_epoch, plain_text, Payload};
 pub(super) use announcement_reaction::AnnouncementReaction;
 pub(crate) use status::Status;
 
@@ -18,6 +19,7 @@ use announcement::Announcement;
 use conversation::Conversation;
 use notification::Notification;
 use serde::Deserialize;
+use status_counts::StatusCounts;
 
 #[serde(rename_all = "snake_case", tag = "event", deny_unknown_fields)]
 #[rustfmt::skip]
@@ -33,6 +35,10 @@ pub enum CheckedEvent {
     #[serde(rename(serialize = "announcement.delete", deserialize = "announcement.delete"))]
     AnnouncementDelete { payload: String },
     Conversation { payload: Conversation, queued_at: Option<i64> },
+    #[serde(rename(serialize = "status.counts", deserialize = "status.counts"))]
+    StatusCounts { payload: StatusCounts },
+    #[serde(rename(serialize = "status.update", deserialize = "status.update"))]
+    StatusUpdate { payload: Status, queued_at: Option<i64> },
 }
 
 #[cfg(test)]
@@ -1,6 +1,7 @@
 use super::{emoji::Emoji, visibility::Visibility};
 use crate::Id;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[serde(deny_unknown_fields)]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -22,7 +23,9 @@ pub(crate) struct Account {
     pub(crate) statuses_count: i64,
     pub(crate) followers_count: i64,
     pub(crate) following_count: i64,
-    pub(crate) moved: Option<String>,
+    /// The account this one moved to.  Mastodon has sent this as both an id and a full account
+    /// (which we don't need to inspect), so it's passed along as is.
+    pub(crate) moved: Option<Value>,
     pub(crate) fields: Option<Vec<Field>>,
     pub(crate) bot: Option<bool>,
     pub(crate) source: Option<Source>,
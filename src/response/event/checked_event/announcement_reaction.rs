@@ -1,10 +1,17 @@
 use serde::{Deserialize, Serialize};
 
+/// A reaction to an announcement, with its new count.  Mastodon streams these to each user's
+/// timeline whenever a reaction is added or removed, so that clients can update the count in
+/// place.  Reactions with a custom emoji also carry its image `url`s.
 #[serde(deny_unknown_fields)]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct AnnouncementReaction {
     #[serde(skip_serializing_if = "Option::is_none")]
-    announcement_id: Option<String>,
-    count: i64,
-    name: String,
+    pub(crate) announcement_id: Option<String>,
+    pub(crate) count: i64,
+    pub(crate) name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) static_url: Option<String>,
 }
@@ -1,5 +1,7 @@
 use super::{account::Account, status::Status};
+use crate::Id;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[serde(deny_unknown_fields)]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -9,6 +11,25 @@ pub struct Notification {
     created_at: String,
     account: Account,
     status: Option<Status>,
+    /// The warning a moderator issued (`moderation_warning` notifications only).  Its shape
+    /// has changed between Mastodon versions, so it's passed along without being checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    moderation_warning: Option<Value>,
+}
+
+impl Notification {
+    /// Whether this notification reports a change in who follows the user
+    pub(crate) fn is_relationship_change(&self) -> bool {
+        matches!(
+            self.r#type,
+            NotificationType::Follow | NotificationType::FollowRequest
+        )
+    }
+
+    /// The user whose action this notification reports
+    pub(crate) fn from_user(&self) -> &Id {
+        &self.account.id
+    }
 }
 
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -20,4 +41,9 @@ enum NotificationType {
     Reblog,
     Favourite,
     Poll,
+    /// An account the user follows moved to another account (see `Account::moved`)
+    Move,
+    /// A moderator took action against the user's account (or warned them)
+    #[serde(alias = "account_warning")]
+    ModerationWarning,
 }
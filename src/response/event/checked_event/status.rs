@@ -8,7 +8,7 @@ use super::emoji::Emoji;
 use super::mention::Mention;
 use super::tag::Tag;
 use super::visibility::Visibility;
-use super::Payload;
+use super::{days_since_epoch, plain_text, Payload};
 use crate::Id;
 use application::Application;
 use attachment::Attachment;
@@ -46,6 +46,9 @@ pub struct Status {
     pub(crate) card: Option<Card>,
     pub(crate) language: Option<String>,
     pub(crate) text: Option<String>,
+    // Not part of the Mastodon API, but published by forks that support local-only posts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) local_only: Option<bool>,
     // ↓↓↓ Only for authorized users
     pub(crate) favourited: Option<bool>,
     pub(crate) reblogged: Option<bool>,
@@ -95,8 +98,37 @@ impl Payload for Status {
         &self.account.id
     }
 
+    fn local_only(&self) -> bool {
+        self.local_only.unwrap_or(false)
+    }
+
+    fn author_created_day(&self) -> Option<i64> {
+        days_since_epoch(&self.account.created_at)
+    }
+
+    fn author_followers(&self) -> Option<i64> {
+        Some(self.account.followers_count)
+    }
+
     fn sent_from(&self) -> &str {
         let sender_username = &self.account.acct;
         sender_username.split('@').nth(1).unwrap_or_default() // default occurs when sent from local instance
     }
+
+    fn sent_from_domains(&self) -> Vec<&str> {
+        let mut domains = vec![self.sent_from()];
+        if let Some(boosted_status) = &self.reblog {
+            domains.push(boosted_status.sent_from());
+        }
+        domains.retain(|domain| !domain.is_empty());
+        domains
+    }
+
+    fn filterable_text(&self) -> Vec<String> {
+        let mut text = vec![plain_text(&self.spoiler_text), plain_text(&self.content)];
+        if let Some(boosted_status) = &self.reblog {
+            text.extend(boosted_status.filterable_text());
+        }
+        text
+    }
 }
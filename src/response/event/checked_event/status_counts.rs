@@ -0,0 +1,14 @@
+use crate::Id;
+use serde::{Deserialize, Serialize};
+
+/// New favourite and/or reblog counts for a status (streamed by some Mastodon forks so that
+/// clients can update counters without refetching the whole status)
+#[serde(deny_unknown_fields)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StatusCounts {
+    pub(crate) id: Id,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) favourites_count: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reblogs_count: Option<i64>,
+}
@@ -56,3 +56,220 @@ fn parse_redis_msg_to_event() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn parse_status_counts_event() -> Result<(), Box<dyn std::error::Error>> {
+    let input =
+        r#"{"event":"status.counts","payload":{"id":"104072557586605107","favourites_count":12}}"#;
+
+    let event = Event::try_from(input)?;
+
+    assert_eq!(
+        event,
+        Event::TypeSafe(CheckedEvent::StatusCounts {
+            payload: checked_event::status_counts::StatusCounts {
+                id: Id(104_072_557_586_605_107),
+                favourites_count: Some(12),
+                reblogs_count: None,
+            }
+        })
+    );
+    assert_eq!(
+        event.to_json_string(),
+        r#"{"event":"status.counts","payload":"{\"id\":\"104072557586605107\",\"favourites_count\":12}"}"#
+    );
+    Ok(())
+}
+
+#[test]
+fn parse_announcement_reaction_event() -> Result<(), Box<dyn std::error::Error>> {
+    let input = r#"{"event":"announcement.reaction","payload":{"name":"blobcat","count":3,"announcement_id":"8","url":"https://example.com/blobcat.png","static_url":"https://example.com/blobcat_static.png"}}"#;
+
+    let event = Event::try_from(input)?;
+
+    let reaction = match &event {
+        Event::TypeSafe(CheckedEvent::AnnouncementReaction { payload }) => payload,
+        other => panic!("Expected an announcement reaction, got {:?}", other),
+    };
+    assert_eq!(reaction.announcement_id.as_deref(), Some("8"));
+    assert_eq!(reaction.name, "blobcat");
+    assert_eq!(reaction.count, 3);
+    assert_eq!(
+        reaction.url.as_deref(),
+        Some("https://example.com/blobcat.png")
+    );
+    assert_eq!(
+        event.to_json_string(),
+        r#"{"event":"announcement.reaction","payload":"{\"announcement_id\":\"8\",\"count\":3,\"name\":\"blobcat\",\"url\":\"https://example.com/blobcat.png\",\"static_url\":\"https://example.com/blobcat_static.png\"}"}"#
+    );
+    Ok(())
+}
+
+#[test]
+fn parse_announcement_reaction_with_a_unicode_emoji() -> Result<(), Box<dyn std::error::Error>> {
+    let input = r#"{"event":"announcement.reaction","payload":{"name":"👍","count":1,"announcement_id":"8"}}"#;
+
+    match Event::try_from(input)? {
+        Event::TypeSafe(CheckedEvent::AnnouncementReaction { payload }) => {
+            assert_eq!(payload.name, "👍");
+            assert_eq!(payload.count, 1);
+            assert_eq!(payload.url, None);
+        }
+        other => panic!("Expected an announcement reaction, got {:?}", other),
+    }
+    Ok(())
+}
+
+/// An event with the status from `msg.event_txt_001.txt`, whose poll has new results
+fn poll_results_event(event: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut json: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    json["event"] = json!(event);
+    json["payload"]["poll"] = json!({
+        "id": "34830",
+        "expires_at": "2019-09-12T18:42:19.000Z",
+        "expired": false,
+        "multiple": false,
+        "votes_count": 10,
+        "voters_count": 10,
+        "options": [
+            { "title": "yes", "votes_count": 7 },
+            { "title": "no", "votes_count": 3 }
+        ],
+        "emojis": []
+    });
+    Ok(json.to_string())
+}
+
+#[test]
+fn parse_status_update_with_poll_results() -> Result<(), Box<dyn std::error::Error>> {
+    let event = Event::try_from(poll_results_event("status.update")?)?;
+
+    assert!(matches!(
+        event,
+        Event::TypeSafe(CheckedEvent::StatusUpdate { .. })
+    ));
+    let poll = event
+        .update_payload()
+        .and_then(|status| status.poll.as_ref())
+        .expect("poll");
+    assert_eq!(poll.votes_count, 10);
+    let tallies: Vec<_> = poll.options.iter().map(|o| o.votes_count).collect();
+    assert_eq!(tallies, vec![Some(7), Some(3)]);
+    assert!(event
+        .to_json_string()
+        .starts_with(r#"{"event":"status.update","payload":"#));
+    Ok(())
+}
+
+#[test]
+fn parse_poll_update_event_without_type_checking() -> Result<(), Box<dyn std::error::Error>> {
+    let input = r#"{"event":"poll.update","payload":{"id":"34830","votes_count":10}}"#;
+
+    let event = Event::try_from(input)?;
+
+    assert!(matches!(event, Event::Dynamic(_)));
+    assert_eq!(
+        event.to_json_string(),
+        r#"{"event":"poll.update","payload":"{\"id\":\"34830\",\"votes_count\":10}"}"#
+    );
+    Ok(())
+}
+
+/// A `move` notification about the account from `msg.event_txt_001.txt`, which has `moved`
+fn move_notification(moved: serde_json::Value) -> Result<String, Box<dyn std::error::Error>> {
+    let status: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    let mut account = status["payload"]["account"].clone();
+    account["moved"] = moved;
+    Ok(json!({
+        "event": "notification",
+        "payload": {
+            "id": "7",
+            "type": "move",
+            "created_at": "2020-03-01T12:00:00.000Z",
+            "account": account
+        }
+    })
+    .to_string())
+}
+
+#[test]
+fn parse_move_notification_with_either_moved_shape() -> Result<(), Box<dyn std::error::Error>> {
+    let status: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    let mut new_account = status["payload"]["account"].clone();
+    new_account["id"] = json!("99");
+
+    for moved in vec![json!("99"), new_account] {
+        let event = Event::try_from(move_notification(moved)?)?;
+
+        assert!(matches!(event, Event::TypeSafe(Notification { .. })));
+        assert!(event.is_notification());
+        assert!(!event.is_relationship_notification());
+    }
+    Ok(())
+}
+
+/// A `moderation_warning` notification with the `warning`, from the account in
+/// `msg.event_txt_001.txt`
+fn moderation_warning(warning: serde_json::Value) -> Result<String, Box<dyn std::error::Error>> {
+    let status: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    Ok(json!({
+        "event": "notification",
+        "payload": {
+            "id": "8",
+            "type": "moderation_warning",
+            "created_at": "2024-03-01T12:00:00.000Z",
+            "account": status["payload"]["account"],
+            "moderation_warning": warning
+        }
+    })
+    .to_string())
+}
+
+#[test]
+fn parse_moderation_warning_notification() -> Result<(), Box<dyn std::error::Error>> {
+    let warning = json!({
+        "id": "3",
+        "action": "disable",
+        "text": "Repeated spam",
+        "status_ids": ["102775370117886890"],
+        "target_account": { "id": "1" },
+        "appeal": null,
+        "created_at": "2024-03-01T12:00:00.000Z"
+    });
+    for warning in vec![warning, json!({ "id": "3", "action": "none" })] {
+        let event = Event::try_from(moderation_warning(warning.clone())?)?;
+
+        assert!(matches!(event, Event::TypeSafe(Notification { .. })));
+        assert!(event.is_notification());
+        let sent: serde_json::Value = serde_json::from_str(&event.to_json_string())?;
+        let payload: serde_json::Value = serde_json::from_str(sent["payload"].as_str().unwrap())?;
+        assert_eq!(payload["moderation_warning"], warning);
+    }
+    Ok(())
+}
+
+#[test]
+fn statuses_are_marked_local_by_their_authors_acct() -> Result<(), Box<dyn std::error::Error>> {
+    let remote: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    let mut local = remote.clone();
+    local["payload"]["account"]["acct"] = json!("federationbot");
+
+    for (status, is_local) in vec![(remote, false), (local, true)] {
+        let event = Event::try_from(status.to_string())?;
+        match event.marked_local() {
+            Some(Event::Dynamic(DynEvent { event, payload, .. })) => {
+                assert_eq!(event, "update");
+                assert_eq!(payload["_local"], json!(is_local));
+            }
+            other => panic!("expected a marked update, got {:?}", other),
+        }
+    }
+    let delete = Event::try_from(fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    assert_eq!(delete.marked_local(), None);
+    Ok(())
+}
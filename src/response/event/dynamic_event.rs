@@ -1,5 +1,5 @@
 use super::err;
-use super::Payload;
+use super::{days_since_epoch, plain_text, Payload};
 use crate::Id;
 
 use std::convert::TryFrom;
@@ -37,6 +37,12 @@ pub struct DynStatus {
     pub(crate) mentioned_users: HashSet<Id>,
     pub(crate) replied_to_user: Option<Id>,
     pub(crate) boosted_user: Option<Id>,
+    pub(crate) boosted_username: Option<String>,
+    pub(crate) local_only: bool,
+    pub(crate) created_day: Option<i64>,
+    pub(crate) followers: Option<i64>,
+    /// See `Payload::filterable_text`
+    pub(crate) filterable_text: Vec<String>,
 }
 
 type Result<T> = std::result::Result<T, err::Event>;
@@ -63,6 +69,19 @@ impl DynStatus {
             mentioned_users: HashSet::new(),
             replied_to_user: Id::try_from(&payload["in_reply_to_account_id"]).ok(),
             boosted_user: Id::try_from(&payload["reblog"]["account"]["id"]).ok(),
+            boosted_username: payload["reblog"]["account"]["acct"]
+                .as_str()
+                .map(String::from),
+            local_only: payload["local_only"].as_bool().unwrap_or(false),
+            created_day: payload["account"]["created_at"]
+                .as_str()
+                .and_then(days_since_epoch),
+            followers: payload["account"]["followers_count"].as_i64(),
+            filterable_text: [payload, &payload["reblog"]]
+                .iter()
+                .flat_map(|status| vec![&status["spoiler_text"], &status["content"]])
+                .filter_map(|text| text.as_str().map(plain_text))
+                .collect(),
         })
     }
 }
@@ -107,8 +126,32 @@ impl Payload for DynStatus {
         &self.id
     }
 
+    fn local_only(&self) -> bool {
+        self.local_only
+    }
+
+    fn author_created_day(&self) -> Option<i64> {
+        self.created_day
+    }
+
+    fn author_followers(&self) -> Option<i64> {
+        self.followers
+    }
+
     fn sent_from(&self) -> &str {
         let sender_username = &self.username;
         sender_username.split('@').nth(1).unwrap_or_default() // default occurs when sent from local instance
     }
+
+    fn sent_from_domains(&self) -> Vec<&str> {
+        std::iter::once(&self.username)
+            .chain(&self.boosted_username)
+            .filter_map(|username| username.split('@').nth(1))
+            .filter(|domain| !domain.is_empty())
+            .collect()
+    }
+
+    fn filterable_text(&self) -> Vec<String> {
+        self.filterable_text.clone()
+    }
 }
@@ -0,0 +1,44 @@
+use super::*;
+
+#[test]
+fn days_since_epoch_counts_from_1970() {
+    assert_eq!(days_since_epoch("1970-01-01T00:00:00.000Z"), Some(0));
+    assert_eq!(days_since_epoch("1969-12-31"), Some(-1));
+    assert_eq!(days_since_epoch("2019-09-10T00:00:00.000Z"), Some(18_149));
+}
+
+#[test]
+fn days_since_epoch_counts_leap_days() {
+    assert_eq!(days_since_epoch("2000-03-01"), Some(11_017));
+    assert_eq!(days_since_epoch("2020-02-29T23:59:59.999Z"), Some(18_321));
+    assert_eq!(days_since_epoch("2024-03-01"), Some(19_783));
+}
+
+#[test]
+fn days_since_epoch_rejects_invalid_dates() {
+    for invalid in &[
+        "",
+        "2020-13-01",
+        "2020-00-10",
+        "2020-01-32",
+        "2020-1-1",
+        "yesterday",
+    ] {
+        assert_eq!(days_since_epoch(invalid), None, "{}", invalid);
+    }
+}
+
+#[test]
+fn plain_text_drops_tags_and_breaks_lines() {
+    let html = r#"<p>First <a href="https://example.com" class="u-url">link</a></p><p>Second<br />line</p>"#;
+    assert_eq!(plain_text(html), "First link\nSecond\nline\n");
+    assert_eq!(plain_text("a < b"), "a < b");
+}
+
+#[test]
+fn plain_text_decodes_character_references() {
+    assert_eq!(plain_text("Fish &amp; chips"), "Fish & chips");
+    assert_eq!(plain_text("&lt;p&gt; &quot;&#39;&#x27;"), "<p> \"''");
+    assert_eq!(plain_text("AT&T; &bogus; &"), "AT&T; &bogus; &");
+    assert_eq!(plain_text("caf&eacute;&amp;é"), "caf&eacute;&é");
+}
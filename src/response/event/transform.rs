@@ -0,0 +1,12 @@
+use super::Event;
+use crate::request::Timeline;
+
+/// A change to make to events before they're delivered, such as a deployment-specific
+/// tweak to their payloads
+///
+/// The `Manager` applies its transforms, in the order they were added, to each event it
+/// delivers (once per timeline it's delivered to).  Events aren't filtered again afterwards,
+/// so transforms shouldn't change what clients would filter on.
+pub trait EventTransform: Send {
+    fn transform(&self, timeline: &Timeline, event: &mut Event);
+}
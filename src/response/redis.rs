@@ -1,11 +1,16 @@
 mod connection;
 mod manager;
 mod msg;
+mod source;
+mod tag_cache;
 
 pub(self) use super::{Event, EventErr};
 pub(self) use connection::RedisConn;
+pub use connection::RedisPing;
 pub use manager::Error;
-pub use manager::Manager;
+pub use manager::{DroppedEvents, Manager};
+pub use source::{MessageSource, RedisSource};
+pub(self) use tag_cache::TagCache;
 
 #[cfg(feature = "bench")]
 pub use msg::{RedisMsg, RedisParseOutput};
@@ -13,12 +18,30 @@ pub use msg::{RedisMsg, RedisParseOutput};
 use connection::RedisConnErr;
 use msg::RedisParseErr;
 
+/// Build a `SET` command that stores `value` at `key`
+fn set_cmd(key: &str, value: &str) -> Vec<u8> {
+    format!(
+        "*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+        key.len(),
+        key,
+        value.len(),
+        value
+    )
+    .into_bytes()
+}
+
 pub(crate) enum RedisCmd {
     Subscribe,
     Unsubscribe,
 }
 
 impl RedisCmd {
+    /// The bytes to send for this command on each of the two Redis connections.
+    ///
+    /// The primary connection is in PubSub mode, so it gets the `SUBSCRIBE` or `UNSUBSCRIBE`
+    /// itself.  A connection in PubSub mode can't run other commands, so the secondary gets an
+    /// `MSET` of the `subscribed:` key for each channel, which Mastodon checks before publishing
+    /// to that channel.  The secondary is only ever written to, never read from.
     fn into_sendable(self, timelines: &[String]) -> (Vec<u8>, Vec<u8>) {
         match self {
             RedisCmd::Subscribe => {
@@ -1,50 +1,229 @@
 mod err;
+#[cfg_attr(all(feature = "bench", not(test)), allow(dead_code))]
+mod stream;
+pub use connection::RedisPing;
 pub(super) use connection::*;
 pub use err::RedisConnErr;
 #[cfg(any(test, feature = "bench"))]
 pub(self) use mock_connection as connection;
 
+use super::msg::{parse_hello_reply, RedisParseErr};
+use crate::config::Redis;
+
+use socket2::{SockRef, TcpKeepalive};
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::str;
+
+#[cfg(test)]
+mod test;
+
+/// Enable TCP keepalive on `conn` (unless disabled in `redis_cfg`), so that the OS detects
+/// half-open connections that the application-level PINGs might not surface promptly.
+#[cfg_attr(all(feature = "bench", not(test)), allow(dead_code))]
+fn set_tcp_keepalive(conn: &TcpStream, redis_cfg: &Redis) -> io::Result<()> {
+    if let Some(idle) = *redis_cfg.keepalive_idle {
+        let keepalive = TcpKeepalive::new()
+            .with_time(idle)
+            .with_interval(*redis_cfg.keepalive_interval)
+            .with_retries(*redis_cfg.keepalive_probes);
+        SockRef::from(conn).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// Read one reply line (without its `\r\n`) from `conn`, along with any bytes that arrived
+/// after it.  Reading by line rather than by the expected reply's length means that extra
+/// bytes (e.g., from a proxy that coalesces packets) aren't left to corrupt the next read.
+#[cfg_attr(all(feature = "bench", not(test)), allow(dead_code))]
+fn read_reply_line<R: Read>(conn: &mut R) -> io::Result<(String, Vec<u8>)> {
+    const MAX_REPLY_LEN: usize = 4096;
+    let mut received = Vec::new();
+    let mut buffer = [0_u8; 256];
+    loop {
+        if let Some(end) = received.windows(2).position(|bytes| bytes == b"\r\n") {
+            let trailing = received.split_off(end + 2);
+            received.truncate(end);
+            return Ok((String::from_utf8_lossy(&received).to_string(), trailing));
+        }
+        if received.len() > MAX_REPLY_LEN {
+            // Not a Redis reply; return it all so that the caller can report what it was
+            return Ok((String::from_utf8_lossy(&received).to_string(), Vec::new()));
+        }
+        match conn.read(&mut buffer)? {
+            0 => Err(io::ErrorKind::UnexpectedEof)?,
+            n => received.extend_from_slice(&buffer[..n]),
+        }
+    }
+}
+
+/// Read the reply to an `AUTH` with the password `pass` from `conn` (which should have a read
+/// timeout), along with any bytes that arrived after it.  Any error reply (such as `-ERR` or
+/// `-WRONGPASS`) means the password was wrong.
+#[cfg_attr(all(feature = "bench", not(test)), allow(dead_code))]
+fn read_auth_reply<R: Read>(conn: &mut R, addr: &str, pass: &str) -> Result<Vec<u8>, RedisConnErr> {
+    use io::ErrorKind::{TimedOut, WouldBlock};
+    match read_reply_line(conn) {
+        Ok((reply, extra_input)) if reply == "+OK" => Ok(extra_input),
+        Ok((reply, _)) if reply.starts_with('-') => {
+            Err(RedisConnErr::IncorrectPassword(pass.to_string()))
+        }
+        Ok((reply, _)) => Err(RedisConnErr::InvalidRedisReply(reply)),
+        Err(e) if matches!(e.kind(), WouldBlock | TimedOut) => {
+            Err(RedisConnErr::AuthTimeout(addr.to_string()))
+        }
+        Err(e) => Err(RedisConnErr::with_addr(addr, e)),
+    }
+}
+
+/// A `PING` to send while subscribed, which Redis replies to with a `pong` among the messages
+const PUBSUB_PING: &[u8] = b"*1\r\n$4\r\nPING\r\n";
+
+/// Build a `PING` command with the message `tag`, which Redis echoes back as its reply
+#[cfg_attr(all(feature = "bench", not(test)), allow(dead_code))]
+fn ping_cmd(tag: &str) -> Vec<u8> {
+    format!("*2\r\n$4\r\nPING\r\n${}\r\n{}\r\n", tag.len(), tag).into_bytes()
+}
+
+/// Read from `conn` (which should have a read timeout) up to the reply to the `PING` tagged
+/// `tag` (see `ping_cmd`), skipping the replies to any earlier `PING`s that arrived too late.
+/// An error reply (such as `-LOADING`) fails the `PING`.
+#[cfg_attr(all(feature = "bench", not(test)), allow(dead_code))]
+fn read_pong<R: Read>(conn: &mut R, tag: &str) -> io::Result<()> {
+    const MAX_REPLY_LEN: usize = 4096;
+    let mut received = Vec::new();
+    let mut buffer = [0_u8; 256];
+    loop {
+        // Each reply is a line with the length of the tag and a line with the tag itself
+        while let Some(end) = received.windows(2).position(|bytes| bytes == b"\r\n") {
+            let line: Vec<u8> = received.drain(..end + 2).take(end).collect();
+            match line.first() {
+                Some(b'-') => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    String::from_utf8_lossy(&line[1..]).to_string(),
+                ))?,
+                _ if line == tag.as_bytes() => return Ok(()),
+                _ => (),
+            }
+        }
+        if received.len() > MAX_REPLY_LEN {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a reply to PING",
+            ))?;
+        }
+        match conn.read(&mut buffer)? {
+            0 => Err(io::ErrorKind::UnexpectedEof)?,
+            n => received.extend_from_slice(&buffer[..n]),
+        }
+    }
+}
+
+/// Take the complete replies out of `input` (the replies to the commands sent on the secondary
+/// connection, which are all one line, such as `+OK`), returning the errors among them
+#[cfg_attr(all(feature = "bench", not(test)), allow(dead_code))]
+fn error_replies(input: &mut Vec<u8>) -> Vec<String> {
+    let mut errors = Vec::new();
+    while let Some(end) = input.windows(2).position(|bytes| bytes == b"\r\n") {
+        let line: Vec<u8> = input.drain(..end + 2).take(end).collect();
+        if let Some(b'-') = line.first() {
+            errors.push(String::from_utf8_lossy(&line[1..]).to_string());
+        }
+    }
+    errors
+}
+
+/// Read the reply to a `HELLO 3` from `conn` (which should have a read timeout), along with any
+/// bytes that arrived after it.  Redis replies with a map that includes the protocol version
+/// it's now using, or with an error if it's older than Redis 6 and doesn't support RESP3.
+#[cfg_attr(all(feature = "bench", not(test)), allow(dead_code))]
+fn read_hello_reply<R: Read>(conn: &mut R, addr: &str) -> Result<Vec<u8>, RedisConnErr> {
+    let mut received = Vec::new();
+    let mut buffer = [0_u8; 256];
+    loop {
+        let utf8 = str::from_utf8(&received).unwrap_or_else(|e| {
+            str::from_utf8(&received[..e.valid_up_to()]).expect("guaranteed by `valid_up_to`")
+        });
+        match parse_hello_reply(utf8) {
+            Ok((3, leftover_input)) => {
+                return Ok(received[utf8.len() - leftover_input.len()..].to_vec())
+            }
+            Err(RedisParseErr::Incomplete) => (),
+            Ok(_) | Err(_) => {
+                let reply = utf8.lines().next().unwrap_or_default().to_string();
+                return Err(RedisConnErr::InvalidRedisReply(reply));
+            }
+        }
+        let n = match conn.read(&mut buffer) {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            read => read,
+        }
+        .map_err(|e| RedisConnErr::with_addr(addr, e))?;
+        received.extend_from_slice(&buffer[..n]);
+    }
+}
+
 #[cfg(not(any(test, feature = "bench")))]
 mod connection {
     use super::super::Error as ManagerErr;
-    use super::super::RedisCmd;
+    use super::super::{set_cmd, RedisCmd, TagCache};
     use super::err::RedisConnErr;
+    use super::read_reply_line;
+    use super::set_tcp_keepalive;
+    use super::stream::RedisStream;
+    use super::{error_replies, ping_cmd, read_auth_reply, read_hello_reply, read_pong};
     use crate::config::Redis;
     use crate::request::Timeline;
 
     use futures::{Async, Poll};
-    use lru::LruCache;
     use std::io::{self, Read, Write};
-    use std::net::TcpStream;
     use std::time::Duration;
 
     type Result<T> = std::result::Result<T, RedisConnErr>;
 
     #[derive(Debug)]
     pub struct RedisConn {
-        primary: TcpStream,
-        secondary: TcpStream,
+        primary: RedisStream,
+        /// The connection for commands other than (un)subscribing, opened on first use (and
+        /// never over RESP3, where those commands share the primary connection)
+        secondary: Option<RedisStream>,
+        /// Where and how to open the secondary connection
+        addr: String,
+        redis_cfg: Redis,
         pub(in super::super) namespace: Option<String>,
+        pub(in super::super) channel_root: String,
         // TODO: eventually, it might make sense to have Mastodon publish to timelines with
         //       the tag number instead of the tag name.  This would save us from dealing
         //       with a cache here and would be consistent with how lists/users are handled.
-        pub(in super::super) tag_name_cache: LruCache<i64, String>,
+        pub(in super::super) tag_name_cache: TagCache<i64, String>,
         pub(in super::super) input: Vec<u8>,
+        /// Whether to set the `subscribed:` keys on the secondary connection
+        advertise_subscriptions: bool,
+        /// Input that arrived with the primary connection's handshake replies, which is read
+        /// before anything else
+        pending_input: Vec<u8>,
+        /// Replies on the secondary connection that haven't fully arrived yet
+        secondary_input: Vec<u8>,
     }
 
     impl RedisConn {
         pub(in super::super) fn new(redis_cfg: &Redis) -> Result<Self> {
-            let addr = [&*redis_cfg.host, ":", &*redis_cfg.port.to_string()].concat();
-
-            let conn = Self::new_connection(&addr, redis_cfg.password.as_ref())?;
+            let addr = Self::addr(redis_cfg);
+            let (conn, pending_input) = Self::new_connection(&addr, redis_cfg)?;
             conn.set_nonblocking(true)
                 .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
             Ok(Self {
                 primary: conn,
-                secondary: Self::new_connection(&addr, redis_cfg.password.as_ref())?,
-                tag_name_cache: LruCache::new(1000),
+                secondary: None,
+                addr,
+                redis_cfg: redis_cfg.clone(),
+                tag_name_cache: TagCache::new(*redis_cfg.tag_cache_size),
                 namespace: redis_cfg.namespace.clone().0,
+                channel_root: redis_cfg.channel_root.clone().0,
                 input: vec![0; 4096 * 4],
+                advertise_subscriptions: *redis_cfg.advertise_subscriptions,
+                pending_input,
+                secondary_input: Vec::new(),
             })
         }
 
@@ -57,31 +236,54 @@ mod connection {
             }
 
             use Async::*;
+            if !self.pending_input.is_empty() {
+                let n = self.pending_input.len().min(BLOCK);
+                let pending: Vec<u8> = self.pending_input.drain(..n).collect();
+                self.input[i..i + n].copy_from_slice(&pending);
+                return Ok(Ready(Some(n)));
+            }
             match self.primary.read(&mut self.input[i..i + BLOCK]) {
-                Ok(n) if n == 0 => Ok(Ready(None)),
+                Ok(n) if n == 0 => {
+                    let closed = io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed");
+                    Err(RedisConnErr::UnknownRedisErr(closed))?
+                }
                 Ok(n) => Ok(Ready(Some(n))),
                 Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock) => Ok(NotReady),
-                Err(e) => {
-                    Ready(log::error!("{}", e));
-                    Ok(Ready(None))
-                }
+                Err(e) => Err(RedisConnErr::UnknownRedisErr(e))?,
             }
         }
 
-        pub(crate) fn send_cmd(&mut self, cmd: RedisCmd, timelines: &[Timeline]) -> Result<()> {
+        /// Send `cmd` for the `timelines`' channels, returning the names of the channels sent
+        pub(crate) fn send_cmd(
+            &mut self,
+            cmd: RedisCmd,
+            timelines: &[Timeline],
+        ) -> Result<Vec<String>> {
             let namespace = self.namespace.take();
             let timelines: Result<Vec<String>> = timelines
                 .iter()
                 .map(|tl| {
                     let hashtag = tl.tag().and_then(|id| self.tag_name_cache.get(&id));
+                    let raw_timeline = tl.to_redis_raw_timeline(&self.channel_root, hashtag)?;
                     match &namespace {
-                        Some(ns) => Ok(format!("{}:{}", ns, tl.to_redis_raw_timeline(hashtag)?)),
-                        None => Ok(tl.to_redis_raw_timeline(hashtag)?),
+                        Some(ns) => Ok(format!("{}:{}", ns, raw_timeline)),
+                        None => Ok(raw_timeline),
                     }
                 })
                 .collect();
 
-            let (primary_cmd, secondary_cmd) = cmd.into_sendable(&timelines?[..]);
+            let channels = timelines?;
+            self.send_channel_cmd(cmd, &channels)?;
+            Ok(channels)
+        }
+
+        /// Send `cmd` for the raw `channels` (which must already include any namespace)
+        pub(in super::super) fn send_channel_cmd(
+            &mut self,
+            cmd: RedisCmd,
+            channels: &[String],
+        ) -> Result<()> {
+            let (primary_cmd, secondary_cmd) = cmd.into_sendable(channels);
             self.primary.write_all(&primary_cmd)?;
 
             // We also need to set a key to tell the Puma server that we've subscribed or
@@ -89,24 +291,106 @@ mod connection {
             // no one is subscribed.
             // (Documented in [PR #3278](https://github.com/tootsuite/mastodon/pull/3278))
             // Question: why can't the Puma server just use NUMSUB for this?
-            self.secondary.write_all(&secondary_cmd)?;
+            if self.advertise_subscriptions {
+                self.command_conn()?.write_all(&secondary_cmd)?;
+                self.log_secondary_errors()?;
+            }
+            Ok(())
+        }
+
+        /// Store `value` at `key` using the secondary (non-PubSub) connection
+        pub(in super::super) fn set(&mut self, key: &str, value: &str) -> Result<()> {
+            self.command_conn()?.write_all(&set_cmd(key, value))?;
+            self.log_secondary_errors()
+        }
+
+        /// Send a `PING` on the PubSub connection, for Redis to reply to among the events
+        pub(in super::super) fn ping(&mut self) -> Result<()> {
+            self.primary.write_all(PUBSUB_PING)?;
             Ok(())
         }
 
-        fn new_connection(addr: &str, pass: Option<&String>) -> Result<TcpStream> {
-            let mut conn = TcpStream::connect(&addr)?;
-            if let Some(password) = pass {
-                Self::auth_connection(&mut conn, &addr, password)?;
+        /// Read the replies that have arrived on the secondary connection (without waiting for
+        /// more), logging any errors, such as a `SET` refused by a read-only replica.  Over
+        /// RESP3, these replies arrive among the events instead (see `RedisParseOutput`).
+        fn log_secondary_errors(&mut self) -> Result<()> {
+            let conn = match &mut self.secondary {
+                Some(conn) => conn,
+                None => return Ok(()),
+            };
+            conn.set_nonblocking(true)?;
+            let mut buffer = [0_u8; 1024];
+            let read = loop {
+                match conn.read(&mut buffer) {
+                    Ok(0) => break Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                    Ok(n) => self.secondary_input.extend_from_slice(&buffer[..n]),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                    Err(e) => break Err(e),
+                }
+            };
+            conn.set_nonblocking(false)?;
+            read?;
+            for error in error_replies(&mut self.secondary_input) {
+                log::error!("Redis refused a command: {}", error);
+            }
+            Ok(())
+        }
+
+        /// The connection to send commands other than (un)subscribing on, connecting the
+        /// secondary connection the first time it's needed
+        fn command_conn(&mut self) -> Result<&mut RedisStream> {
+            if self.secondary.is_none() && !*self.redis_cfg.resp3 {
+                log::info!("Opening the secondary Redis connection to {}", self.addr);
+                // Nothing has been sent on the secondary connection yet, so none of its extra
+                // input is a reply that `log_secondary_errors` should read
+                let (secondary, _) = Self::new_connection(&self.addr, &self.redis_cfg)?;
+                self.secondary = Some(secondary);
+            }
+            Ok(self.secondary.as_mut().unwrap_or(&mut self.primary))
+        }
+
+        /// The address of the Redis that `redis_cfg` names (see `RedisStream::connect`)
+        fn addr(redis_cfg: &Redis) -> String {
+            match &*redis_cfg.socket {
+                Some(path) => ["unix://", path].concat(),
+                None => [&*redis_cfg.host, ":", &*redis_cfg.port.to_string()].concat(),
+            }
+        }
+
+        /// Connect to Redis at `addr`, returning the connection and any input that arrived
+        /// after the handshake replies.  Each step of the handshake gives up after the
+        /// `REDIS_AUTH_TIMEOUT`, since reconnecting happens while polling (and so while the
+        /// `Manager` is locked).
+        fn new_connection(addr: &str, redis_cfg: &Redis) -> Result<(RedisStream, Vec<u8>)> {
+            let timeout = *redis_cfg.auth_timeout;
+            let mut conn = RedisStream::connect(&addr, timeout)
+                .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
+            if let Some(tcp) = conn.as_tcp() {
+                set_tcp_keepalive(tcp, redis_cfg).map_err(|e| RedisConnErr::with_addr(&addr, e))?;
+            }
+            if *redis_cfg.use_tls {
+                let ca_cert = redis_cfg.ca_cert.as_deref();
+                conn = conn.into_tls(&redis_cfg.host, ca_cert).map_err(|inner| {
+                    let addr = addr.to_string();
+                    RedisConnErr::TlsHandshake { addr, inner }
+                })?;
+            }
+            let mut extra_input = Vec::new();
+            if let Some(password) = &*redis_cfg.password {
+                extra_input = Self::auth_connection(&mut conn, &addr, password)?;
             }
 
-            Self::validate_connection(&mut conn, &addr)?;
+            if *redis_cfg.resp3 {
+                extra_input.extend(Self::switch_to_resp3(&mut conn, &addr)?);
+            }
+            extra_input.extend(Self::validate_connection(&mut conn, &addr)?);
             conn.set_read_timeout(Some(Duration::from_millis(10)))
                 .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            Self::set_connection_name(&mut conn, &addr)?;
-            Ok(conn)
+            extra_input.extend(Self::set_connection_name(&mut conn, &addr)?);
+            Ok((conn, extra_input))
         }
 
-        fn auth_connection(conn: &mut TcpStream, addr: &str, pass: &str) -> Result<()> {
+        fn auth_connection(conn: &mut RedisStream, addr: &str, pass: &str) -> Result<Vec<u8>> {
             conn.write_all(
                 &[
                     b"*2\r\n$4\r\nauth\r\n$",
@@ -118,73 +402,142 @@ mod connection {
                 .concat(),
             )
             .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            let mut buffer = vec![0_u8; 5];
-            conn.read_exact(&mut buffer)
+            read_auth_reply(conn, addr, pass)
+        }
+
+        fn switch_to_resp3(conn: &mut RedisStream, addr: &str) -> Result<Vec<u8>> {
+            conn.write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n")
                 .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            if String::from_utf8_lossy(&buffer) != "+OK\r\n" {
-                Err(RedisConnErr::IncorrectPassword(pass.to_string()))?
-            }
-            Ok(())
+            read_hello_reply(conn, addr)
         }
 
-        fn validate_connection(conn: &mut TcpStream, addr: &str) -> Result<()> {
+        fn validate_connection(conn: &mut RedisStream, addr: &str) -> Result<Vec<u8>> {
             conn.write_all(b"PING\r\n")
                 .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            let mut buffer = vec![0_u8; 100];
-            conn.read(&mut buffer)
-                .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            let reply = String::from_utf8_lossy(&buffer);
+            let (reply, extra_input) =
+                read_reply_line(conn).map_err(|e| RedisConnErr::with_addr(&addr, e))?;
             match &*reply {
-                r if r.starts_with("+PONG\r\n") => Ok(()),
+                "+PONG" => Ok(extra_input),
                 r if r.starts_with("-NOAUTH") => Err(RedisConnErr::MissingPassword),
                 r if r.starts_with("HTTP/1.") => Err(RedisConnErr::NotRedis(addr.to_string())),
-                _ => Err(RedisConnErr::InvalidRedisReply(reply.to_string())),
+                _ => Err(RedisConnErr::InvalidRedisReply(reply)),
             }
         }
 
-        fn set_connection_name(conn: &mut TcpStream, addr: &str) -> Result<()> {
+        fn set_connection_name(conn: &mut RedisStream, addr: &str) -> Result<Vec<u8>> {
             conn.write_all(b"*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$8\r\nflodgatt\r\n")
                 .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            let mut buffer = vec![0_u8; 100];
-            conn.read(&mut buffer)
-                .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            let reply = String::from_utf8_lossy(&buffer);
+            let (reply, extra_input) =
+                read_reply_line(conn).map_err(|e| RedisConnErr::with_addr(&addr, e))?;
             match &*reply {
-                r if r.starts_with("+OK\r\n") => Ok(()),
-                _ => Err(RedisConnErr::InvalidRedisReply(reply.to_string())),
+                "+OK" => Ok(extra_input),
+                _ => Err(RedisConnErr::InvalidRedisReply(reply)),
+            }
+        }
+    }
+
+    /// A Redis connection of its own for health checks, so that checking Redis never waits
+    /// for (or holds up) the `Manager`
+    #[derive(Debug)]
+    pub struct RedisPing {
+        /// Opened on the first check, and again after any check fails
+        conn: Option<RedisStream>,
+        addr: String,
+        redis_cfg: Redis,
+        /// How many `PING`s have been sent, which tags each one (see `ping_cmd`)
+        sent: u64,
+    }
+
+    impl RedisPing {
+        pub fn new(redis_cfg: &Redis) -> Self {
+            Self {
+                conn: None,
+                addr: RedisConn::addr(redis_cfg),
+                redis_cfg: redis_cfg.clone(),
+                sent: 0,
+            }
+        }
+
+        /// Check that Redis answers a `PING` within `timeout`, verifying the reply even over
+        /// RESP3 (since this connection never subscribes, replies don't arrive among events).
+        /// After a failure, the connection is replaced, so that a late reply can't be mistaken
+        /// for the next one.
+        pub fn ping(&mut self, timeout: Duration) -> Result<()> {
+            let result = self.try_ping(timeout);
+            if result.is_err() {
+                self.conn = None;
+            }
+            result
+        }
+
+        fn try_ping(&mut self, timeout: Duration) -> Result<()> {
+            if self.conn.is_none() {
+                let (conn, _) = RedisConn::new_connection(&self.addr, &self.redis_cfg)?;
+                self.conn = Some(conn);
             }
+            let conn = self.conn.as_mut().expect("connected above");
+            self.sent += 1;
+            let tag = self.sent.to_string();
+
+            conn.set_read_timeout(Some(timeout))?;
+            conn.write_all(&ping_cmd(&tag))?;
+            let pong = read_pong(conn, &tag);
+            conn.set_read_timeout(None)?;
+            Ok(pong?)
         }
     }
 }
 #[cfg(any(test, feature = "bench"))]
 mod mock_connection {
     use super::super::Error as ManagerErr;
-    use super::super::RedisCmd;
+    use super::super::{set_cmd, RedisCmd, TagCache};
     use super::err::RedisConnErr;
     use crate::config::Redis;
     use crate::request::Timeline;
 
     use futures::{Async, Poll};
-    use lru::LruCache;
     use std::collections::VecDeque;
+    use std::io;
+    use std::time::Duration;
 
     type Result<T> = std::result::Result<T, RedisConnErr>;
 
+    /// The outcome of one read from the mock connection
+    #[derive(Debug)]
+    pub enum ScriptedRead {
+        /// Redis sent these bytes (a read returns at most one block of them)
+        Bytes(Vec<u8>),
+        /// Nothing has arrived yet
+        WouldBlock,
+        /// The connection failed
+        Err(io::ErrorKind),
+    }
+
     #[derive(Debug)]
     pub struct RedisConn {
         pub(in super::super) namespace: Option<String>,
-        pub(in super::super) tag_name_cache: LruCache<i64, String>,
+        pub(in super::super) channel_root: String,
+        pub(in super::super) tag_name_cache: TagCache<i64, String>,
         pub(in super::super) input: Vec<u8>,
-        pub(in super::super) test_input: VecDeque<u8>,
+        pub(in super::super) test_input: VecDeque<ScriptedRead>,
+        /// Everything written to the primary (PubSub) connection
+        pub(in super::super) primary: Vec<u8>,
+        /// Everything written to the secondary connection
+        pub(in super::super) secondary: Vec<u8>,
+        advertise_subscriptions: bool,
     }
 
     impl RedisConn {
         pub(in super::super) fn new(redis_cfg: &Redis) -> Result<Self> {
             Ok(Self {
-                tag_name_cache: LruCache::new(1000),
+                tag_name_cache: TagCache::new(*redis_cfg.tag_cache_size),
                 namespace: redis_cfg.namespace.clone().0,
+                channel_root: redis_cfg.channel_root.clone().0,
                 input: vec![0; 4096 * 4],
                 test_input: VecDeque::new(),
+                primary: Vec::new(),
+                secondary: Vec::new(),
+                advertise_subscriptions: *redis_cfg.advertise_subscriptions,
             })
         }
 
@@ -195,32 +548,90 @@ mod mock_connection {
                 log::info!("Resizing input buffer to {} KiB.", self.input.len() / 1024);
             }
 
-            for i in 0..BLOCK {
-                if let Some(byte) = self.test_input.pop_front() {
-                    self.input[start + i] = byte;
-                } else if i > 0 {
-                    return Ok(Async::Ready(Some(i)));
-                } else {
-                    return Ok(Async::Ready(None));
+            match self.test_input.pop_front() {
+                Some(ScriptedRead::Bytes(mut bytes)) => {
+                    if bytes.len() > BLOCK {
+                        self.test_input
+                            .push_front(ScriptedRead::Bytes(bytes.split_off(BLOCK)));
+                    }
+                    self.input[start..start + bytes.len()].copy_from_slice(&bytes);
+                    Ok(Async::Ready(Some(bytes.len())))
+                }
+                Some(ScriptedRead::WouldBlock) => Ok(Async::NotReady),
+                Some(ScriptedRead::Err(kind)) => {
+                    Err(RedisConnErr::UnknownRedisErr(io::Error::from(kind)))?
                 }
+                None => Ok(Async::Ready(None)),
             }
-            Ok(Async::Ready(Some(BLOCK)))
         }
 
+        /// Make `input` available to read (as part of the last queued read, if that's also
+        /// bytes, as though they had arrived together)
         pub fn add(&mut self, input: &[u8]) {
-            for byte in input {
-                self.test_input.push_back(*byte)
+            if let Some(ScriptedRead::Bytes(bytes)) = self.test_input.back_mut() {
+                bytes.extend_from_slice(input);
+            } else {
+                self.test_input
+                    .push_back(ScriptedRead::Bytes(input.to_vec()));
             }
         }
-        pub(crate) fn send_cmd(&mut self, cmd: RedisCmd, timelines: &[Timeline]) -> Result<()> {
-            // stub - does nothing; silences some unused-code warnings
+
+        /// Queue `reads` to be returned one per `poll_redis`, after any already queued
+        pub fn script(&mut self, reads: Vec<ScriptedRead>) {
+            self.test_input.extend(reads)
+        }
+        pub(crate) fn send_cmd(
+            &mut self,
+            cmd: RedisCmd,
+            timelines: &[Timeline],
+        ) -> Result<Vec<String>> {
             let timelines: Result<Vec<String>> = timelines
                 .iter()
-                .map(|tl| Ok(tl.to_redis_raw_timeline(None).expect("test")))
+                .map(|tl| {
+                    let hashtag = tl.tag().and_then(|id| self.tag_name_cache.get(&id));
+                    Ok(tl.to_redis_raw_timeline(&self.channel_root, hashtag)?)
+                })
                 .collect();
 
-            let _ = cmd.into_sendable(&timelines?);
+            let channels = timelines?;
+            self.send_channel_cmd(cmd, &channels)?;
+            Ok(channels)
+        }
+
+        pub(in super::super) fn send_channel_cmd(
+            &mut self,
+            cmd: RedisCmd,
+            channels: &[String],
+        ) -> Result<()> {
+            let (primary_cmd, secondary_cmd) = cmd.into_sendable(channels);
+            self.primary.extend_from_slice(&primary_cmd);
+            if self.advertise_subscriptions {
+                self.secondary.extend_from_slice(&secondary_cmd);
+            }
+            Ok(())
+        }
+
+        pub(in super::super) fn set(&mut self, key: &str, value: &str) -> Result<()> {
+            self.secondary.extend_from_slice(&set_cmd(key, value));
+            Ok(())
+        }
+
+        pub(in super::super) fn ping(&mut self) -> Result<()> {
+            self.primary.extend_from_slice(PUBSUB_PING);
+            Ok(())
+        }
+    }
+
+    /// Health checks against the mock connection, which always succeed
+    #[derive(Debug)]
+    pub struct RedisPing;
+
+    impl RedisPing {
+        pub fn new(_redis_cfg: &Redis) -> Self {
+            Self
+        }
 
+        pub fn ping(&mut self, _timeout: Duration) -> Result<()> {
             Ok(())
         }
     }
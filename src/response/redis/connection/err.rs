@@ -4,9 +4,11 @@ use std::fmt;
 #[derive(Debug)]
 pub enum RedisConnErr {
     ConnectionErr { addr: String, inner: std::io::Error },
+    TlsHandshake { addr: String, inner: std::io::Error },
     InvalidRedisReply(String),
     UnknownRedisErr(std::io::Error),
     IncorrectPassword(String),
+    AuthTimeout(String),
     MissingPassword,
     NotRedis(String),
     TimelineErr(request::TimelineErr),
@@ -31,6 +33,11 @@ impl fmt::Display for RedisConnErr {
                  Connection Error: {}",
                 addr, inner
             ),
+            TlsHandshake { addr, inner } => format!(
+                "Error setting up TLS with Redis at {}.\n\
+                 TLS Error: {}",
+                addr, inner
+            ),
             InvalidRedisReply(unexpected_reply) => format!(
                 "Received and unexpected reply from Redis: `{}`",
                 unexpected_reply
@@ -43,6 +50,11 @@ impl fmt::Display for RedisConnErr {
                  Please supply correct password with REDIS_PASSWORD environmental variable.",
                 attempted_password
             ),
+            AuthTimeout(addr) => format!(
+                "Redis at {} did not reply to AUTH in time.  Please check that it's reachable, or \
+                 allow it longer with the REDIS_AUTH_TIMEOUT environmental variable.",
+                addr
+            ),
             MissingPassword => "Invalid authentication for Redis.  Redis is configured to require \
                                 a password, but you did not provide one. \n\
                                 Set a password using the REDIS_PASSWORD environmental variable."
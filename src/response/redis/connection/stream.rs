@@ -0,0 +1,135 @@
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// A connection to Redis: over TCP (optionally encrypted with TLS, as hosted Redis providers
+/// often require), or over a Unix socket for a Redis on the same host (which avoids the
+/// loopback's latency and keeps the connection off the TCP stack entirely)
+#[derive(Debug)]
+pub enum RedisStream {
+    Tcp(TcpStream),
+    Tls(SslStream<TcpStream>),
+    Unix(UnixStream),
+}
+
+impl RedisStream {
+    /// Connect to Redis at `addr`, which is either `host:port` or the path of a Unix socket
+    /// prefixed with `unix:` (as in `unix:///run/redis/redis.sock`).  Connecting over TCP gives
+    /// up after `timeout`, and every later read or write does too (until the timeouts are
+    /// changed), so that an unresponsive Redis can't stall the handshake indefinitely.
+    pub fn connect(addr: &str, timeout: Duration) -> io::Result<Self> {
+        let conn = match Self::socket_path(addr) {
+            Some(path) => Self::Unix(UnixStream::connect(path)?),
+            None => Self::Tcp(Self::connect_tcp(addr, timeout)?),
+        };
+        conn.set_read_timeout(Some(timeout))?;
+        conn.set_write_timeout(Some(timeout))?;
+        Ok(conn)
+    }
+
+    /// Connect to the first of the addresses `addr` resolves to that accepts within `timeout`
+    fn connect_tcp(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to");
+        for socket_addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_addr, timeout) {
+                Ok(conn) => return Ok(conn),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    /// The path of the Unix socket `addr` names, if it names one
+    pub fn socket_path(addr: &str) -> Option<&str> {
+        let path = addr.strip_prefix("unix:")?;
+        Some(path.strip_prefix("//").unwrap_or(path))
+    }
+
+    /// Encrypt this TCP connection with TLS, verifying that the server's certificate is valid
+    /// for the `domain` and signed by a trusted CA (the system's, plus the `ca_cert`, if any).
+    /// This does the whole handshake, so it should be called before anything else is sent.
+    pub fn into_tls(self, domain: &str, ca_cert: Option<&Path>) -> io::Result<Self> {
+        let conn = match self {
+            Self::Tcp(conn) => conn,
+            Self::Tls(_) => return Ok(self),
+            Self::Unix(_) => return Err(Self::tls_err("TLS is only supported over TCP")),
+        };
+        let mut connector = SslConnector::builder(SslMethod::tls()).map_err(Self::tls_err)?;
+        if let Some(ca_cert) = ca_cert {
+            connector.set_ca_file(ca_cert).map_err(Self::tls_err)?;
+        }
+        let conn = connector
+            .build()
+            .connect(domain, conn)
+            .map_err(Self::tls_err)?;
+        Ok(Self::Tls(conn))
+    }
+
+    fn tls_err(e: impl ToString) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, e.to_string())
+    }
+
+    /// The underlying `TcpStream`, if this is a TCP connection (encrypted or not)
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Tcp(conn) => Some(conn),
+            Self::Tls(conn) => Some(conn.get_ref()),
+            Self::Unix(_) => None,
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(conn) => conn.set_nonblocking(nonblocking),
+            Self::Tls(conn) => conn.get_ref().set_nonblocking(nonblocking),
+            Self::Unix(conn) => conn.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(conn) => conn.set_read_timeout(timeout),
+            Self::Tls(conn) => conn.get_ref().set_read_timeout(timeout),
+            Self::Unix(conn) => conn.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(conn) => conn.set_write_timeout(timeout),
+            Self::Tls(conn) => conn.get_ref().set_write_timeout(timeout),
+            Self::Unix(conn) => conn.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl Read for RedisStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(conn) => conn.read(buf),
+            Self::Tls(conn) => conn.read(buf),
+            Self::Unix(conn) => conn.read(buf),
+        }
+    }
+}
+
+impl Write for RedisStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(conn) => conn.write(buf),
+            Self::Tls(conn) => conn.write(buf),
+            Self::Unix(conn) => conn.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(conn) => conn.flush(),
+            Self::Tls(conn) => conn.flush(),
+            Self::Unix(conn) => conn.flush(),
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use super::*;
+use std::net::TcpListener;
+use std::time::Duration;
+
+fn connected_socket() -> (TcpListener, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bound listener");
+    let addr = listener.local_addr().expect("local address");
+    let conn = TcpStream::connect(addr).expect("connected socket");
+    (listener, conn)
+}
+
+#[test]
+fn tcp_keepalive_is_set_as_configured() {
+    let (_listener, conn) = connected_socket();
+    let mut redis_cfg = Redis::default();
+    redis_cfg.keepalive_idle.0 = Some(Duration::from_secs(45));
+
+    set_tcp_keepalive(&conn, &redis_cfg).expect("keepalive applied");
+
+    let socket = SockRef::from(&conn);
+    assert!(socket.keepalive().expect("SO_KEEPALIVE"));
+    assert_eq!(
+        socket.keepalive_time().expect("TCP_KEEPIDLE"),
+        Duration::from_secs(45)
+    );
+}
+
+#[test]
+fn tcp_keepalive_can_be_disabled() {
+    let (_listener, conn) = connected_socket();
+    let mut redis_cfg = Redis::default();
+    redis_cfg.keepalive_idle.0 = None;
+
+    set_tcp_keepalive(&conn, &redis_cfg).expect("nothing to apply");
+
+    assert!(!SockRef::from(&conn).keepalive().expect("SO_KEEPALIVE"));
+}
+
+#[test]
+fn reply_line_keeps_input_that_arrives_with_it() {
+    let pushed = "*3\r\n$7\r\nmessage\r\n$15\r\ntimeline:public\r\n$1\r\n1\r\n";
+    let mut conn = ["+PONG\r\n", pushed].concat().into_bytes();
+
+    let (reply, extra_input) = read_reply_line(&mut &conn[..]).expect("reply line");
+    assert_eq!(reply, "+PONG");
+    assert_eq!(extra_input, pushed.as_bytes());
+
+    conn.truncate("+PONG\r\n".len());
+    let (reply, extra_input) = read_reply_line(&mut &conn[..]).expect("reply line");
+    assert_eq!(reply, "+PONG");
+    assert!(extra_input.is_empty());
+}
+
+#[test]
+fn reply_line_is_assembled_across_reads() {
+    let mut conn = (&b"+PONG\r"[..]).chain(&b"\n+OK\r\n"[..]);
+
+    let (reply, extra_input) = read_reply_line(&mut conn).expect("reply line");
+    assert_eq!(reply, "+PONG");
+    assert_eq!(extra_input, b"+OK\r\n");
+}
+
+#[test]
+fn auth_reply_ok_keeps_input_that_arrives_with_it() {
+    let conn = b"+OK\r\n+PONG\r\n";
+
+    let extra_input = read_auth_reply(&mut &conn[..], "redis:6379", "pass").expect("authed");
+    assert_eq!(extra_input, b"+PONG\r\n");
+}
+
+#[test]
+fn auth_error_reply_is_an_incorrect_password() {
+    let conn = b"-WRONGPASS invalid username-password pair or user is disabled.\r\n";
+
+    let err = read_auth_reply(&mut &conn[..], "redis:6379", "pass").expect_err("wrong password");
+    assert!(matches!(err, RedisConnErr::IncorrectPassword(pass) if pass == "pass"));
+}
+
+#[test]
+fn truncated_auth_reply_is_a_connection_error() {
+    let conn = b"+O";
+
+    let err = read_auth_reply(&mut &conn[..], "redis:6379", "pass").expect_err("truncated");
+    assert!(matches!(
+        err,
+        RedisConnErr::ConnectionErr { inner, .. } if inner.kind() == io::ErrorKind::UnexpectedEof
+    ));
+}
+
+#[test]
+fn auth_reply_that_never_finishes_times_out() {
+    let (listener, mut conn) = connected_socket();
+    let (mut redis, _) = listener.accept().expect("accepted connection");
+    std::io::Write::write_all(&mut redis, b"+O").expect("partial reply");
+    conn.set_read_timeout(Some(Duration::from_millis(50)))
+        .expect("read timeout");
+
+    let err = read_auth_reply(&mut conn, "redis:6379", "pass").expect_err("timed out");
+    assert!(matches!(err, RedisConnErr::AuthTimeout(addr) if addr == "redis:6379"));
+}
+
+#[test]
+fn ping_is_tagged() {
+    assert_eq!(ping_cmd("12"), b"*2\r\n$4\r\nPING\r\n$2\r\n12\r\n");
+}
+
+#[test]
+fn pong_is_found_after_the_replies_to_earlier_pings() {
+    let conn = b"$1\r\n1\r\n$1\r\n2\r\n$1\r\n3\r\n";
+    read_pong(&mut &conn[..], "3").expect("pong");
+
+    let mut conn = (&b"$1\r\n1\r\n$2\r\n1"[..]).chain(&b"2\r\n"[..]);
+    read_pong(&mut conn, "12").expect("pong assembled across reads");
+}
+
+#[test]
+fn late_pong_is_not_mistaken_for_the_next_one() {
+    // The reply to an earlier `PING` that timed out arrives before the current one's
+    let conn = b"$1\r\n7\r\n";
+    let err = read_pong(&mut &conn[..], "8").expect_err("only a stale pong");
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn error_reply_to_a_ping_is_an_error() {
+    let conn = b"$1\r\n1\r\n-LOADING Redis is loading the dataset in memory\r\n";
+    let err = read_pong(&mut &conn[..], "2").expect_err("Redis is loading");
+    assert_eq!(
+        err.to_string(),
+        "LOADING Redis is loading the dataset in memory"
+    );
+}
+
+#[test]
+fn error_replies_are_taken_from_complete_replies() {
+    let mut input = b"+OK\r\n-READONLY You can't write against a read only replica.\r\n+O".to_vec();
+
+    let errors = error_replies(&mut input);
+
+    assert_eq!(
+        errors,
+        vec!["READONLY You can't write against a read only replica."]
+    );
+    assert_eq!(input, b"+O"); // the rest of the next reply is still to come
+}
+
+#[test]
+fn missing_pong_is_an_error() {
+    let conn = b"+OK\r\n+OK\r\n";
+    let err = read_pong(&mut &conn[..], "1").expect_err("no pong");
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+    let (listener, mut conn) = connected_socket();
+    let (mut redis, _) = listener.accept().expect("accepted connection");
+    std::io::Write::write_all(&mut redis, b"+OK\r\n").expect("earlier reply");
+    conn.set_read_timeout(Some(Duration::from_millis(50)))
+        .expect("read timeout");
+    let err = read_pong(&mut conn, "1").expect_err("timed out");
+    assert!(matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    ));
+}
+
+#[test]
+fn hello_reply_keeps_input_that_arrives_with_it() {
+    let hello = "%2\r\n$6\r\nserver\r\n$5\r\nredis\r\n$5\r\nproto\r\n:3\r\n";
+    let mut conn = (&hello.as_bytes()[..20])
+        .chain(&hello.as_bytes()[20..])
+        .chain(&b"+PONG\r\n"[..]);
+
+    let extra_input = read_hello_reply(&mut conn, "redis:6379").expect("switched to RESP3");
+    assert_eq!(extra_input, b"+PONG\r\n");
+}
+
+#[test]
+fn hello_error_reply_means_resp3_is_unsupported() {
+    let conn = b"-ERR unknown command `HELLO`, with args beginning with: `3`, \r\n";
+
+    let err = read_hello_reply(&mut &conn[..], "redis:6379").expect_err("Redis 5");
+    assert!(matches!(err, RedisConnErr::InvalidRedisReply(reply) if reply.starts_with("-ERR")));
+}
+
+#[test]
+fn unix_socket_addresses_are_recognized() {
+    use super::stream::RedisStream;
+
+    let path = RedisStream::socket_path;
+    assert_eq!(path("unix:///run/redis.sock"), Some("/run/redis.sock"));
+    assert_eq!(path("unix:/run/redis.sock"), Some("/run/redis.sock"));
+    assert_eq!(path("127.0.0.1:6379"), None);
+}
+
+#[test]
+fn handshake_replies_are_read_over_a_unix_socket() -> io::Result<()> {
+    use super::stream::RedisStream;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    let (conn, mut redis) = UnixStream::pair()?;
+    let mut conn = RedisStream::Unix(conn);
+    conn.set_read_timeout(Some(Duration::from_secs(1)))?;
+    assert!(conn.as_tcp().is_none());
+
+    redis.write_all(b"+OK\r\n+PONG\r\n")?;
+    let extra_input = read_auth_reply(&mut conn, "unix:///run/redis.sock", "pass")
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    assert_eq!(extra_input, b"+PONG\r\n");
+
+    conn.write_all(b"PING\r\n")?;
+    let mut ping = [0; 6];
+    std::io::Read::read_exact(&mut redis, &mut ping)?;
+    assert_eq!(&ping, b"PING\r\n");
+    Ok(())
+}
+
+#[test]
+fn tls_handshake_with_a_plaintext_server_fails() -> io::Result<()> {
+    use super::stream::RedisStream;
+    use std::io::Write;
+
+    let (listener, conn) = connected_socket();
+    let (mut redis, _) = listener.accept()?;
+    redis.write_all(b"-ERR unknown command\r\n")?;
+    drop(redis);
+
+    let handshake = RedisStream::Tcp(conn).into_tls("localhost", None);
+    assert!(handshake.is_err());
+    Ok(())
+}
+
+#[test]
+fn tls_is_refused_over_unix_sockets() -> io::Result<()> {
+    use super::stream::RedisStream;
+    use std::os::unix::net::UnixStream;
+
+    let (conn, _redis) = UnixStream::pair()?;
+    let handshake = RedisStream::Unix(conn).into_tls("localhost", None);
+    assert!(handshake.is_err());
+    Ok(())
+}
+
+#[test]
+fn handshake_gives_up_on_a_redis_that_never_replies() -> io::Result<()> {
+    use super::stream::RedisStream;
+    use std::io::Write;
+    use std::time::Instant;
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let timeout = Duration::from_millis(50);
+    let mut conn = RedisStream::connect(&addr, timeout)?;
+    let (_redis, _) = listener.accept()?; // accepted, but never answers
+
+    conn.write_all(b"PING\r\n")?;
+    let started = Instant::now();
+    let pong = read_reply_line(&mut conn);
+
+    assert!(pong.is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+    Ok(())
+}
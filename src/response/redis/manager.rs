@@ -4,182 +4,688 @@
 mod err;
 pub use err::Error;
 
-use super::msg::{RedisParseErr, RedisParseOutput};
-use super::{Event, RedisCmd, RedisConn};
+use super::source::{MessageSource, RedisSource};
+use super::{Event, RedisParseErr};
 use crate::config;
 use crate::request::{Subscription, Timeline};
+use crate::response::{EventTransform, WriteFailures};
 
 pub(self) use super::EventErr;
 
-use futures::{Async, Poll, Stream};
+use futures::{Async, Poll};
 use hashbrown::{HashMap, HashSet};
-use lru::LruCache;
-use std::convert::{TryFrom, TryInto};
-use std::str;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender;
 
 type Result<T> = std::result::Result<T, Error>;
-type EventChannel = Sender<Arc<Event>>;
+type EventChannel = Sender<(Timeline, Arc<Event>)>;
 
-/// The item that streams from Redis and is polled by the `ClientAgent`
-pub struct Manager {
-    pub redis_conn: RedisConn,
+/// The number of events meant for one client that were discarded before reaching its
+/// connection, so that the connection can number the events it sends without hiding the gaps
+#[derive(Clone, Debug, Default)]
+pub struct DroppedEvents(Arc<AtomicU64>);
+
+impl DroppedEvents {
+    pub(crate) fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Receives events from a `MessageSource` (Redis, by default) and delivers them to the
+/// `ClientAgent`s subscribed to each event's `Timeline`
+pub struct Manager<S = RedisSource> {
+    pub source: S,
     timelines: HashMap<Timeline, HashMap<u32, EventChannel>>,
     ping_time: Instant,
     channel_id: u32,
-    pub unread_idx: (usize, usize),
-    tag_id_cache: LruCache<String, i64>,
+    /// The events dropped for each client, by channel id
+    dropped: HashMap<u32, DroppedEvents>,
+    started_at: Instant,
+    parse_errors: usize,
+    stats_time: Instant,
+    stats_key: Option<String>,
+    stats_interval: Duration,
+    /// When anything (an event or a reply) last arrived from the source
+    last_input_at: Instant,
+    /// Whether the source has been pinged since input last arrived (see `ping_if_quiet`)
+    pinged: bool,
+    watchdog_timeout: Option<Duration>,
+    confirm_timeout: Option<Duration>,
+    source_healthy: bool,
+    polling_interval: Duration,
+    polling_overrides: HashMap<String, Duration>,
+    slow_start: Option<Duration>,
+    reconnected_at: Option<Instant>,
+    /// How long to wait before the next attempt to replace a dropped connection
+    reconnect_backoff: Duration,
+    /// When to next try to replace the source's connection, if it has dropped
+    reconnect_after: Option<Instant>,
+    polled_at: Instant,
+    delivered_at: HashMap<String, Instant>,
+    held: HashMap<Timeline, VecDeque<Arc<Event>>>,
+    held_max: usize,
+    /// When the oldest event in each of the `held` queues arrived
+    held_since: HashMap<Timeline, Instant>,
+    unsubscribe_linger: Option<Duration>,
+    backfill_max: usize,
+    emptied_at: HashMap<Timeline, Instant>,
+    /// The channels the source has been asked to subscribe to (and not since unsubscribed from)
+    subscribed: HashSet<Timeline>,
+    /// The channels clients need but that the source couldn't be asked to subscribe to, which
+    /// each `send_msgs` tries again
+    pending_subscribes: HashSet<Timeline>,
+    narrow_channels: bool,
+    trace_events: bool,
+    /// The most recent input that couldn't be parsed, oldest first
+    dead_letters: VecDeque<String>,
+    dead_letters_max: usize,
+    /// Applied to each event (in order) before it's delivered
+    transforms: Vec<Box<dyn EventTransform>>,
+    /// Shared with the clients' connections, which record their failed writes
+    write_failures: WriteFailures,
 }
 
-impl Stream for Manager {
-    type Item = (Timeline, Arc<Event>);
-    type Error = Error;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Error> {
-        let input = &self.redis_conn.input[self.unread_idx.0..self.unread_idx.1];
-        let (valid, invalid) = str::from_utf8(input)
-            .map(|v| (v, &b""[..]))
-            .unwrap_or_else(|e| {
-                // NOTE - this bounds check occurs more often than necessary; it could occur only when
-                // polling Redis.  However, benchmarking with Criterion shows it to be *very*
-                // inexpensive (<1 us) and thus not worth removing (doing so would require `unsafe`).
-                let (valid, invalid) = input.split_at(e.valid_up_to());
-                (str::from_utf8(valid).expect("split_at"), invalid)
-            });
+impl<S: MessageSource> Manager<S> {
+    /// The most input kept for each dead letter
+    const DEAD_LETTER_BYTES: usize = 1024;
+    /// How many times slower than usual we poll right after a reconnect (see `REDIS_SLOW_START`)
+    const SLOW_START_FACTOR: u32 = 4;
+    /// How long we wait to reconnect after the connection drops, doubling after each failure
+    const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+    const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
 
-        if !valid.is_empty() {
-            use RedisParseOutput::*;
-            match RedisParseOutput::try_from(valid) {
-                Ok(Msg(msg)) => {
-                    // If we get a message and it matches the redis_namespace, get the msg's
-                    // Event and send it to all channels matching the msg's Timeline
-                    if let Some(tl) = msg.timeline_matching_ns(&self.redis_conn.namespace) {
-                        self.unread_idx.0 =
-                            self.unread_idx.1 - msg.leftover_input.len() - invalid.len();
-
-                        let tl = Timeline::from_redis_text(tl, &mut self.tag_id_cache)?;
-                        let event: Arc<Event> = Arc::new(msg.event_txt.try_into()?);
-                        Ok(Async::Ready(Some((tl, event))))
-                    } else {
-                        Ok(Async::Ready(None))
-                    }
-                }
-                Ok(NonMsg(leftover_input)) => {
-                    self.unread_idx.0 = self.unread_idx.1 - leftover_input.len();
-                    Ok(Async::Ready(None))
-                }
-                Err(RedisParseErr::Incomplete) => {
-                    self.copy_partial_msg();
-                    Ok(Async::NotReady)
-                }
-                Err(e) => Err(Error::RedisParseErr(e, valid.to_string()))?,
-            }
-        } else {
-            self.unread_idx = (0, 0);
-            Ok(Async::NotReady)
-        }
-    }
-}
-
-impl Manager {
     // untested
     pub fn send_msgs(&mut self) -> Poll<(), Error> {
         if self.ping_time.elapsed() > Duration::from_secs(30) {
             self.send_pings()?
         }
+        if self.stats_key.is_some() && self.stats_time.elapsed() > self.stats_interval {
+            // The stats are only informational, so failing to store them mustn't hold up events
+            self.write_stats()
+                .unwrap_or_else(|e| log::error!("Could not write stats: {}", e));
+        }
+        if let Some(after) = self.reconnect_after {
+            if Instant::now() < after {
+                return Ok(Async::Ready(()));
+            }
+            self.reconnect_dropped();
+            if self.reconnect_after.is_some() {
+                return Ok(Async::Ready(()));
+            }
+        }
+        if !self.pending_subscribes.is_empty() {
+            let channels = self.pending_subscribes.iter().copied().collect();
+            self.subscribe_channels(channels);
+        }
+        if self.watchdog_fired() {
+            log::error!(
+                "Received nothing from Redis in {:?} despite active subscriptions.  \
+                 Reconnecting to Redis.",
+                self.last_input_at.elapsed()
+            );
+            self.connection_dropped();
+            return Ok(Async::Ready(()));
+        }
+        self.ping_if_quiet();
+        if !self.emptied_at.is_empty() {
+            self.unsubscribe_lingering()?
+        }
+        if self.slow_start_skips() {
+            return Ok(Async::Ready(()));
+        }
+        self.polled_at = Instant::now();
+        let due = self.due_categories();
+        self.deliver_held(&due);
+        let held_cap = self.held_cap(self.polled_at);
 
-        while let Ok(Async::Ready(Some(msg_len))) = self.redis_conn.poll_redis(self.unread_idx.1) {
-            self.unread_idx.1 += msg_len;
+        loop {
+            let msg = match self.source.poll_event() {
+                Ok(Async::Ready(msg)) => msg,
+                Ok(Async::NotReady) => break,
+                Err(e) => {
+                    log::error!("{}", e);
+                    match e {
+                        Error::RedisConnErr(_) => self.connection_dropped(),
+                        Error::OversizedInput(_) => self.reconnect()?,
+                        Error::RedisParseErr(RedisParseErr::LineTooLong(_), _) => {
+                            self.parse_errors += 1;
+                            self.keep_dead_letter(&e);
+                            self.reconnect()?
+                        }
+                        _ => {
+                            self.parse_errors += 1;
+                            self.keep_dead_letter(&e);
+                        }
+                    }
+                    break;
+                }
+            };
+            self.source_healthy = true;
+            self.last_input_at = Instant::now();
+            self.pinged = false;
 
-            while let Ok(Async::Ready(msg)) = self.poll() {
-                if let Some((tl, event)) = msg {
+            if let Some((channel_tl, event)) = msg {
+                if self.trace_events {
+                    event.trace(&format!("parsed from {:?}", channel_tl));
+                }
+                if !self.polling_overrides.is_empty() && !due.contains(channel_tl.category()) {
+                    if self.trace_events {
+                        event.trace(&format!("held until {} is due", channel_tl.category()));
+                    }
+                    let queue = self.held.entry(channel_tl).or_default();
+                    let overflow = queue.len().saturating_sub(held_cap - 1);
+                    let dropped: Vec<_> = queue.drain(..overflow).collect();
+                    queue.push_back(event);
+                    for oldest in dropped {
+                        self.record_dropped(channel_tl, &oldest);
+                    }
+                    self.held_since
+                        .entry(channel_tl)
+                        .or_insert_with(Instant::now);
+                    continue;
+                }
+                for tl in self.routed(channel_tl, &event) {
+                    let event = self.transformed(tl, &event);
                     for channel in self.timelines.entry(tl).or_default().values_mut() {
                         if let Ok(Async::NotReady) = channel.poll_ready() {
                             log::warn!("{:?} channel full\ncan't send:{:?}", tl, event);
-                            self.rewind_to_prev_msg();
+                            self.source.replay_last();
                             return Ok(Async::NotReady);
                         }
 
-                        let _ = channel.try_send(event.clone()); // err just means channel will be closed
+                        let _ = channel.try_send((tl, event.clone())); // err just means channel will be closed
                     }
                 }
             }
         }
+        // Checked after reading all available input, so confirmations that have arrived count
+        if self.confirmation_overdue() {
+            log::error!(
+                "Redis has not confirmed a subscribe or unsubscribe within {:?}.  \
+                 Reconnecting to Redis.",
+                self.confirm_timeout.unwrap_or_default()
+            );
+            self.reconnect()?
+        }
         Ok(Async::Ready(()))
     }
 
-    fn rewind_to_prev_msg(&mut self) {
-        self.unread_idx.0 = loop {
-            let input = &self.redis_conn.input[..self.unread_idx.0];
-            let input = str::from_utf8(input).unwrap_or_else(|e| {
-                str::from_utf8(input.split_at(e.valid_up_to()).0).expect("guaranteed by `split_at`")
-            });
+    /// How often `send_msgs` should be called: the fastest of `REDIS_FREQ` and any
+    /// per-category overrides.
+    pub fn poll_interval(&self) -> Duration {
+        self.polling_overrides
+            .values()
+            .fold(self.polling_interval, |fastest, &i| fastest.min(i))
+    }
 
-            let index = if let Some(i) = input.rfind("\r\n*") {
-                i + "\r\n".len()
-            } else {
-                0
-            };
-            self.unread_idx.0 = index;
+    /// How often `send_msgs` currently reads from the source: `poll_interval`, except during
+    /// the `REDIS_SLOW_START` window after a reconnect.  That window starts at
+    /// `SLOW_START_FACTOR` times `poll_interval` and ramps linearly back down to it.
+    pub fn effective_poll_interval(&self) -> Duration {
+        self.effective_poll_interval_at(Instant::now())
+    }
 
-            if let Ok(Async::Ready(Some(_))) = self.poll() {
-                break index;
+    /// The `effective_poll_interval` as of `now`
+    fn effective_poll_interval_at(&self, now: Instant) -> Duration {
+        let interval = self.poll_interval();
+        match (self.slow_start, self.reconnected_at) {
+            (Some(window), Some(at)) if now.saturating_duration_since(at) < window => {
+                let elapsed = now.saturating_duration_since(at);
+                let remaining = (window - elapsed).as_secs_f64() / window.as_secs_f64();
+                interval.mul_f64(1.0 + f64::from(Self::SLOW_START_FACTOR - 1) * remaining)
             }
+            _ => interval,
         }
     }
 
-    fn copy_partial_msg(&mut self) {
-        if self.unread_idx.0 == 0 {
-            // msg already first; no copying needed
-        } else if self.unread_idx.0 >= (self.unread_idx.1 - self.unread_idx.0) {
-            let (read, unread) =
-                self.redis_conn.input[..self.unread_idx.1].split_at_mut(self.unread_idx.0);
-            for (i, b) in unread.iter().enumerate() {
-                read[i] = *b;
+    /// The most events held for each timeline as of `now`: `REDIS_HELD_EVENTS_MAX`, cut by the
+    /// same factor as the poll interval is raised by during the `REDIS_SLOW_START` window, so
+    /// that less of the backlog after a reconnect piles up waiting for delivery
+    fn held_cap(&self, now: Instant) -> usize {
+        let (interval, effective) = (self.poll_interval(), self.effective_poll_interval_at(now));
+        let cap = self.held_max as u128 * interval.as_nanos() / effective.as_nanos().max(1);
+        usize::try_from(cap).unwrap_or(self.held_max).max(1)
+    }
+
+    /// Whether this poll comes too soon after the last one while slow-starting.  As in
+    /// `due_categories`, we allow half a poll of slack for timer jitter.
+    fn slow_start_skips(&self) -> bool {
+        let (interval, effective) = (self.poll_interval(), self.effective_poll_interval());
+        effective > interval && self.polled_at.elapsed() + interval / 2 < effective
+    }
+
+    /// The timeline categories whose events should be delivered on this poll.
+    ///
+    /// With no overrides configured, every category is always due.  Otherwise, each category
+    /// is due once its own interval (or `REDIS_FREQ`, absent an override) has elapsed; we allow
+    /// half a poll of slack so that timer jitter doesn't cost a category an entire poll.
+    fn due_categories(&mut self) -> HashSet<String> {
+        if self.polling_overrides.is_empty() {
+            return HashSet::new();
+        }
+        let slack = self.poll_interval() / 2;
+        let default_interval = self.polling_interval;
+        let mut due = HashSet::new();
+        for category in &Timeline::CATEGORIES {
+            let interval = self
+                .polling_overrides
+                .get(*category)
+                .copied()
+                .unwrap_or(default_interval);
+            let is_due = self
+                .delivered_at
+                .get(*category)
+                .map_or(true, |at| at.elapsed() + slack >= interval);
+            if is_due {
+                self.delivered_at
+                    .insert(category.to_string(), Instant::now());
+                due.insert(category.to_string());
+            }
+        }
+        due
+    }
+
+    /// Send any events that were held for the `due` categories to their subscribers.  Once a
+    /// subscriber's channel is full, the rest of its timeline's events stay held until the
+    /// next poll, as they would have stayed in Redis.
+    fn deliver_held(&mut self, due: &HashSet<String>) {
+        let ready: Vec<Timeline> = self
+            .held
+            .keys()
+            .filter(|tl| due.contains(tl.category()))
+            .copied()
+            .collect();
+        for channel_tl in ready {
+            let mut events = self.held.remove(&channel_tl).unwrap_or_default();
+            while let Some(event) = events.pop_front() {
+                let recipients = self.routed(channel_tl, &event);
+                if !self.channels_ready(&recipients) {
+                    log::warn!("{:?} channel full; holding its events", channel_tl);
+                    events.push_front(event);
+                    break;
+                }
+                for tl in recipients {
+                    let event = self.transformed(tl, &event);
+                    for (id, channel) in self.timelines.entry(tl).or_default().iter_mut() {
+                        if channel.try_send((tl, event.clone())).is_err() {
+                            log::warn!("{:?} channel closed\ncan't send:{:?}", tl, event);
+                            if let Some(dropped) = self.dropped.get(id) {
+                                dropped.record();
+                            }
+                        }
+                    }
+                }
             }
+            if events.is_empty() {
+                self.held_since.remove(&channel_tl);
+            } else {
+                self.held.insert(channel_tl, events);
+            }
+        }
+    }
+
+    /// Whether every channel of the `timelines` has room for another event.  A closed channel
+    /// counts as ready, since sending to it fails straight away.
+    fn channels_ready(&mut self, timelines: &[Timeline]) -> bool {
+        timelines.iter().all(|tl| {
+            self.timelines.get_mut(tl).map_or(true, |channels| {
+                channels
+                    .values_mut()
+                    .all(|channel| match channel.poll_ready() {
+                        Ok(Async::NotReady) => false,
+                        Ok(Async::Ready(())) | Err(_) => true,
+                    })
+            })
+        })
+    }
+
+    /// Count a discarded `event` from the `channel_tl` channel against each client it was for
+    fn record_dropped(&self, channel_tl: Timeline, event: &Event) {
+        for tl in self.recipients(channel_tl, event) {
+            let ids = self.timelines.get(&tl).into_iter().flat_map(HashMap::keys);
+            ids.filter_map(|id| self.dropped.get(id))
+                .for_each(DroppedEvents::record);
+        }
+    }
+
+    /// The timeline whose Redis channel carries `tl`'s events.  That's `tl` itself unless
+    /// Mastodon doesn't publish narrow channels, in which case `tl` is filtered from the
+    /// broader timeline's channel.
+    fn channel_for(&self, tl: Timeline) -> Timeline {
+        match tl.broader() {
+            Some(broader) if !self.narrow_channels => broader,
+            _ => tl,
+        }
+    }
+
+    /// The subscribed timelines that should receive an `event` from the `channel_tl` channel
+    fn recipients(&self, channel_tl: Timeline, event: &Event) -> Vec<Timeline> {
+        let mut recipients = vec![channel_tl];
+        if !self.narrow_channels {
+            let narrower = channel_tl.narrower().into_iter();
+            recipients.extend(narrower.filter(|tl| Self::accepts(*tl, event)));
+        }
+        recipients.retain(|tl| self.timelines.contains_key(tl));
+        recipients
+    }
+
+    /// The `recipients` of an `event`, which are logged when tracing events
+    fn routed(&self, channel_tl: Timeline, event: &Event) -> Vec<Timeline> {
+        let recipients = self.recipients(channel_tl, event);
+        if self.trace_events {
+            event.trace(&format!("routed to {:?}", recipients));
+        }
+        recipients
+    }
+
+    /// The `event` as it's delivered to `tl`'s subscribers, after any `EventTransform`s
+    fn transformed(&self, tl: Timeline, event: &Arc<Event>) -> Arc<Event> {
+        if self.transforms.is_empty() {
+            return event.clone();
+        }
+        let mut event = (**event).clone();
+        for transform in &self.transforms {
+            transform.transform(&tl, &mut event);
+        }
+        Arc::new(event)
+    }
+
+    /// Whether an `event` from the broader channel belongs on the narrow timeline `tl`
+    fn accepts(tl: Timeline, event: &Event) -> bool {
+        if tl.is_media_only() {
+            event.has_media()
+        } else if tl.is_notification_only() {
+            event.is_notification()
         } else {
-            // Less efficient, but should never occur in production
-            log::warn!("Moving partial input requires heap allocation");
-            self.redis_conn.input = self.redis_conn.input[self.unread_idx.0..].into();
+            true
         }
-        self.unread_idx = (0, self.unread_idx.1 - self.unread_idx.0);
-        &self.unread_idx;
     }
-    /// Create a new `Manager`, with its own Redis connections (but no active subscriptions).
-    pub fn try_from(redis_cfg: &config::Redis) -> Result<Self> {
-        Ok(Self {
-            redis_conn: RedisConn::new(redis_cfg)?,
+
+    /// Keep (a truncated copy of) the input that caused a parse error, dropping the oldest
+    /// kept input once `dead_letters_max` is reached
+    fn keep_dead_letter(&mut self, e: &Error) {
+        let input = match e {
+            Error::RedisParseErr(_, input) | Error::UnparseableEvent(_, input) => input,
+            _ => return,
+        };
+        if self.dead_letters_max == 0 {
+            return;
+        }
+        let mut end = input.len().min(Self::DEAD_LETTER_BYTES);
+        while !input.is_char_boundary(end) {
+            end -= 1;
+        }
+        if self.dead_letters.len() >= self.dead_letters_max {
+            self.dead_letters.pop_front();
+        }
+        self.dead_letters.push_back(input[..end].to_string());
+    }
+
+    /// Create a new `Manager` that receives events from `source`.  Other than the connection
+    /// settings, which `source` has already used, the `Manager` is configured by `redis_cfg`.
+    pub fn with_source(source: S, redis_cfg: &config::Redis) -> Self {
+        Self {
+            source,
             timelines: HashMap::new(),
             ping_time: Instant::now(),
             channel_id: 0,
-            unread_idx: (0, 0),
-            tag_id_cache: LruCache::new(1000),
-        })
+            dropped: HashMap::new(),
+            started_at: Instant::now(),
+            parse_errors: 0,
+            stats_time: Instant::now(),
+            stats_key: redis_cfg.stats_key.clone().0,
+            stats_interval: *redis_cfg.stats_interval,
+            last_input_at: Instant::now(),
+            pinged: false,
+            watchdog_timeout: *redis_cfg.watchdog_timeout,
+            confirm_timeout: *redis_cfg.confirm_timeout,
+            source_healthy: true,
+            polling_interval: *redis_cfg.polling_interval,
+            polling_overrides: redis_cfg.polling_overrides.clone().0,
+            slow_start: *redis_cfg.slow_start,
+            reconnected_at: None,
+            reconnect_backoff: Self::RECONNECT_BACKOFF_MIN,
+            reconnect_after: None,
+            polled_at: Instant::now(),
+            delivered_at: HashMap::new(),
+            held: HashMap::new(),
+            held_max: *redis_cfg.held_events_max,
+            held_since: HashMap::new(),
+            unsubscribe_linger: *redis_cfg.unsubscribe_linger,
+            backfill_max: *redis_cfg.backfill_max,
+            emptied_at: HashMap::new(),
+            subscribed: HashSet::new(),
+            pending_subscribes: HashSet::new(),
+            narrow_channels: *redis_cfg.narrow_channels,
+            trace_events: false,
+            dead_letters: VecDeque::new(),
+            dead_letters_max: *redis_cfg.dead_letters,
+            transforms: Vec::new(),
+            write_failures: WriteFailures::default(),
+        }
     }
 
     pub fn into_arc(self) -> Arc<Mutex<Self>> {
         Arc::new(Mutex::new(self))
     }
 
-    pub fn subscribe(&mut self, subscription: &Subscription, channel: EventChannel) {
+    /// Send the events for the `subscription`'s timelines down the `channel`.  The returned
+    /// `DroppedEvents` counts the ones that are discarded instead.
+    pub fn subscribe(
+        &mut self,
+        subscription: &Subscription,
+        channel: EventChannel,
+    ) -> DroppedEvents {
         let (tag, tl) = (subscription.hashtag_name.clone(), subscription.timeline);
         if let (Some(hashtag), Some(id)) = (tag, tl.tag()) {
-            self.tag_id_cache.put(hashtag.clone(), id);
-            self.redis_conn.tag_name_cache.put(id, hashtag);
+            self.source.register_tag(&hashtag, id);
         };
 
-        let channels = self.timelines.entry(tl).or_default();
-        channels.insert(self.channel_id, channel);
+        if subscription.backfill && self.backfill_max > 0 {
+            self.backfill(subscription, &channel);
+        }
+        let mut new_channels = Vec::new();
+        for tl in subscription.timelines() {
+            let channels = self.timelines.entry(tl).or_default();
+            channels.insert(self.channel_id, channel.clone());
+
+            // A timeline that's still lingering after its last client left is still subscribed;
+            // checking what the source was actually asked for (rather than inferring it from the
+            // client count) keeps a redundant SUBSCRIBE from ever being sent
+            self.emptied_at.remove(&tl);
+            let channel_tl = self.channel_for(tl);
+            let asked = [&self.subscribed, &self.pending_subscribes];
+            if !asked.iter().any(|channels| channels.contains(&channel_tl))
+                && !new_channels.contains(&channel_tl)
+            {
+                new_channels.push(channel_tl);
+            };
+        }
+        self.subscribe_channels(new_channels);
+        let dropped = DroppedEvents::default();
+        self.dropped.insert(self.channel_id, dropped.clone());
         self.channel_id += 1;
+        dropped
+    }
+
+    /// Ask the source to subscribe to the `channels`, all in one SUBSCRIBE so that they cost a
+    /// single write.  If that fails, they're kept in `pending_subscribes` for `send_msgs` to retry.
+    fn subscribe_channels(&mut self, channels: Vec<Timeline>) {
+        if channels.is_empty() {
+            return;
+        }
+        match self.source.subscribe(&channels) {
+            Ok(()) => {
+                log::info!("Subscribed to {:?}", channels);
+                for channel_tl in channels {
+                    self.pending_subscribes.remove(&channel_tl);
+                    self.subscribed.insert(channel_tl);
+                }
+            }
+            Err(e) => {
+                log::error!("Could not subscribe to {:?} (will retry): {}", channels, e);
+                self.pending_subscribes.extend(channels);
+            }
+        }
+    }
+
+    /// Send the most recent events from the `subscription`'s timelines down the `channel`
+    /// (before it's subscribed, so that they precede any live events).
+    fn backfill(&mut self, subscription: &Subscription, channel: &EventChannel) {
+        let mut channel = channel.clone();
+        for tl in subscription.timelines() {
+            let channel_tl = self.channel_for(tl);
+            let history = self.source.history(channel_tl, self.backfill_max);
+            for event in history.unwrap_or_else(|e| {
+                log::error!("Could not fetch the history of {:?}: {}", tl, e);
+                Vec::new()
+            }) {
+                if channel_tl != tl && !Self::accepts(tl, &event) {
+                    continue;
+                }
+                let event = self.transformed(tl, &event);
+                if channel.try_send((tl, event)).is_err() {
+                    log::warn!("{:?} channel full or closed; backfill truncated", tl);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Log each event under its trace id as it's parsed and routed (see `Event::trace_id`)
+    pub fn trace_events(&mut self, enabled: bool) {
+        self.trace_events = enabled;
+    }
+
+    /// Where clients' connections should count their failed writes, to be reported with the
+    /// `Manager`'s stats
+    pub fn write_failures(&self) -> WriteFailures {
+        self.write_failures.clone()
+    }
+
+    /// Apply `transform` to events before delivering them, after any transforms already added
+    pub fn add_transform(&mut self, transform: impl EventTransform + 'static) {
+        self.transforms.push(Box::new(transform));
+    }
+
+    /// Add the (name, id) pairs for hashtags that are likely to be requested soon to the caches,
+    /// so that their first messages don't miss the cache.
+    pub fn prewarm_tags(&mut self, tags: &[(String, i64)]) {
+        for (name, id) in tags {
+            self.source.register_tag(name, *id);
+        }
+    }
+
+    /// How long the oldest event still waiting to be delivered has been waiting, across all
+    /// the queues of held events (zero, if none are waiting)
+    pub fn max_lag(&self) -> Duration {
+        self.held_since
+            .values()
+            .map(Instant::elapsed)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Whether the source is reachable: false from a connection error until we've reconnected
+    pub fn is_healthy(&self) -> bool {
+        self.source_healthy
+    }
 
-        if channels.len() == 1 {
-            self.redis_conn
-                .send_cmd(RedisCmd::Subscribe, &[tl])
-                .unwrap_or_else(|e| log::error!("Could not subscribe to the Redis channel: {}", e));
-            log::info!("Subscribed to {:?}", tl);
+    /// Whether the Redis connection appears to be stuck: clients are subscribed and Redis
+    /// isn't reporting any errors, but we haven't received anything (not even the reply to
+    /// the `PING` from `ping_if_quiet`) in too long.
+    fn watchdog_fired(&self) -> bool {
+        match self.watchdog_timeout {
+            Some(timeout) => {
+                self.source_healthy
+                    && !self.timelines.is_empty()
+                    && self.last_input_at.elapsed() > timeout
+            }
+            None => false,
+        }
+    }
+
+    /// Ping the source once half the watchdog's timeout passes without any input, so that a
+    /// healthy connection that's just quiet has a reply to show for itself before it fires
+    fn ping_if_quiet(&mut self) {
+        let half_timeout = match self.watchdog_timeout {
+            Some(timeout) => timeout / 2,
+            None => return,
         };
+        if self.pinged
+            || !self.source_healthy
+            || self.timelines.is_empty()
+            || self.last_input_at.elapsed() < half_timeout
+        {
+            return;
+        }
+        self.pinged = true;
+        if let Err(e) = self.source.ping() {
+            log::error!("{}", e);
+            self.connection_dropped();
+        }
+    }
+
+    /// Whether the source has waited longer than the configured timeout for a subscribe or
+    /// unsubscribe to be confirmed, which means the connection is hung.
+    fn confirmation_overdue(&self) -> bool {
+        match (self.confirm_timeout, self.source.unconfirmed_since()) {
+            (Some(timeout), Some(sent_at)) => sent_at.elapsed() > timeout,
+            _ => false,
+        }
+    }
+
+    /// Replace the source's connection with a new one and resubscribe to all current timelines.
+    /// This runs while the `Manager` is locked, so every step of connecting is bounded by the
+    /// `REDIS_AUTH_TIMEOUT`.
+    fn reconnect(&mut self) -> Result<()> {
+        self.subscribed = self
+            .timelines
+            .keys()
+            .map(|tl| self.channel_for(*tl))
+            .collect();
+        let timelines: Vec<Timeline> = self.subscribed.iter().copied().collect();
+        self.source.reconnect(&timelines)?;
+        self.pending_subscribes.clear();
+        self.last_input_at = Instant::now();
+        self.pinged = false;
+        self.reconnected_at = Some(Instant::now());
+        self.source_healthy = true;
+        self.reconnect_backoff = Self::RECONNECT_BACKOFF_MIN;
+        self.reconnect_after = None;
+        log::info!("Resubscribed to {:?}", timelines);
+        Ok(())
+    }
+
+    /// Note that the source's connection has broken (Redis restarted, failed over, or closed
+    /// an idle connection), so that `send_msgs` stops reading from it and reconnects instead
+    fn connection_dropped(&mut self) {
+        self.source_healthy = false;
+        if self.reconnect_after.is_none() {
+            self.reconnect_after = Some(Instant::now() + self.reconnect_backoff);
+        }
+    }
+
+    /// Try to replace a dropped connection, backing off exponentially (up to
+    /// `RECONNECT_BACKOFF_MAX`) for as long as that fails
+    fn reconnect_dropped(&mut self) {
+        log::warn!(
+            "Lost the connection to Redis.  Reconnecting after waiting {:?}.",
+            self.reconnect_backoff
+        );
+        if let Err(e) = self.reconnect() {
+            self.reconnect_backoff = (self.reconnect_backoff * 2).min(Self::RECONNECT_BACKOFF_MAX);
+            self.reconnect_after = Some(Instant::now() + self.reconnect_backoff);
+            log::warn!("{}\nRetrying in {:?}.", e, self.reconnect_backoff);
+        }
     }
 
     fn send_pings(&mut self) -> Result<()> {
@@ -190,22 +696,94 @@ impl Manager {
 
         self.ping_time = Instant::now();
         let mut subscriptions_to_close = HashSet::new();
+        let (linger, emptied_at) = (self.unsubscribe_linger, &mut self.emptied_at);
         self.timelines.retain(|tl, channels| {
-            channels.retain(|_, chan| chan.try_send(Arc::new(Event::Ping)).is_ok());
+            channels.retain(|_, chan| chan.try_send((*tl, Arc::new(Event::Ping))).is_ok());
 
-            if channels.is_empty() {
-                subscriptions_to_close.insert(*tl);
-                false
-            } else {
-                true
+            match channels.is_empty() {
+                true if linger.is_some() => {
+                    emptied_at.entry(*tl).or_insert_with(Instant::now);
+                    true
+                }
+                true => {
+                    subscriptions_to_close.insert(*tl);
+                    false
+                }
+                false => true,
             }
         });
-        if !subscriptions_to_close.is_empty() {
-            let timelines: Vec<_> = subscriptions_to_close.into_iter().collect();
-            &self
-                .redis_conn
-                .send_cmd(RedisCmd::Unsubscribe, &timelines[..])?;
-            log::info!("Unsubscribed from {:?}", timelines);
+        // Forget the dropped events of clients that have disconnected
+        let open: HashSet<&u32> = self.timelines.values().flat_map(HashMap::keys).collect();
+        self.dropped.retain(|id, _| open.contains(id));
+        self.unsubscribe(subscriptions_to_close)
+    }
+
+    /// Unsubscribe from the channels for the `closed` timelines, except for any channel that
+    /// a remaining timeline is still filtered from.
+    fn unsubscribe(&mut self, closed: HashSet<Timeline>) -> Result<()> {
+        let needed: HashSet<Timeline> = self
+            .timelines
+            .keys()
+            .map(|tl| self.channel_for(*tl))
+            .collect();
+        let mut channels: Vec<Timeline> = closed
+            .into_iter()
+            .map(|tl| self.channel_for(tl))
+            .filter(|channel_tl| !needed.contains(channel_tl))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        // A channel the source never subscribed to needs no UNSUBSCRIBE
+        channels.retain(|channel_tl| !self.pending_subscribes.remove(channel_tl));
+        if channels.is_empty() {
+            return Ok(());
+        }
+
+        for channel_tl in &channels {
+            self.subscribed.remove(channel_tl);
+        }
+        self.source.unsubscribe(&channels[..])?;
+        log::info!("Unsubscribed from {:?}", channels);
+        Ok(())
+    }
+
+    /// Unsubscribe from the timelines that have had no clients for longer than the linger.
+    fn unsubscribe_lingering(&mut self) -> Result<()> {
+        let linger = self.unsubscribe_linger.unwrap_or_default();
+        let expired: Vec<Timeline> = self
+            .emptied_at
+            .iter()
+            .filter(|(_, emptied_at)| emptied_at.elapsed() >= linger)
+            .map(|(tl, _)| *tl)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        for tl in &expired {
+            self.emptied_at.remove(tl);
+            self.timelines.remove(tl);
+        }
+        self.unsubscribe(expired.into_iter().collect())
+    }
+
+    /// Store a JSON summary of the `Manager`'s current state at the configured Redis key.
+    ///
+    /// This lets dashboards that already read from Redis display Flodgatt's stats without
+    /// needing to scrape an HTTP endpoint.
+    fn write_stats(&mut self) -> Result<()> {
+        self.stats_time = Instant::now();
+        if let Some(key) = &self.stats_key {
+            let stats = serde_json::json!({
+                "connections": self.timelines.values().map(HashMap::len).sum::<usize>(),
+                "timelines": self.timelines.len(),
+                "parse_errors": self.parse_errors,
+                "max_lag_ms": u64::try_from(self.max_lag().as_millis()).unwrap_or(u64::MAX),
+                "write_failures": self.write_failures.counts(),
+                "tag_caches": self.source.tag_cache_stats(),
+                "uptime_secs": self.started_at.elapsed().as_secs(),
+            });
+            self.source.store(key, &stats.to_string())?;
         }
         Ok(())
     }
@@ -215,18 +793,106 @@ impl Manager {
         poisoned.into_inner()
     }
 
+    /// Discard all events being held for delivery and make every category due, so the next
+    /// poll reads from the source and delivers immediately (an operational reset).
+    pub fn flush(&mut self) -> String {
+        let held: Vec<_> = self.held.drain().collect();
+        self.held_since.clear();
+        let discarded: usize = held.iter().map(|(_, events)| events.len()).sum();
+        for (channel_tl, events) in &held {
+            for event in events {
+                self.record_dropped(*channel_tl, event);
+            }
+        }
+        self.delivered_at.clear();
+        log::warn!("Flushed {} held events", discarded);
+        format!("Discarded held events: {}", discarded)
+    }
+
+    /// Disconnect every client of `tl` (and of its narrower timelines, such as its media-only
+    /// variant), returning how many there were.
+    ///
+    /// Dropping a client's channel ends its stream, so each of those clients is disconnected
+    /// entirely, even from the other timelines it shares the connection with.  Channels that no
+    /// remaining client needs are unsubscribed from straight away, without any linger.
+    pub fn drain(&mut self, tl: Timeline) -> Result<usize> {
+        let mut closed: HashSet<Timeline> = std::iter::once(tl)
+            .chain(tl.narrower())
+            .filter(|tl| self.timelines.contains_key(tl))
+            .collect();
+        let ids: HashSet<u32> = closed
+            .iter()
+            .filter_map(|tl| self.timelines.get(tl))
+            .flat_map(HashMap::keys)
+            .copied()
+            .collect();
+
+        for (other_tl, channels) in &mut self.timelines {
+            let before = channels.len();
+            channels.retain(|id, _| !ids.contains(id));
+            if channels.is_empty() && before > 0 {
+                closed.insert(*other_tl);
+            }
+        }
+        for tl in &closed {
+            self.timelines.remove(tl);
+            self.emptied_at.remove(tl);
+        }
+        self.dropped.retain(|id, _| !ids.contains(id));
+        log::warn!("Drained {} clients from {:?}", ids.len(), tl);
+        self.unsubscribe(closed)?;
+        Ok(ids.len())
+    }
+
+    /// Who is subscribed to what: the timelines of each client (by channel id) and the number
+    /// of clients on each timeline
+    pub fn subscription_graph(&self) -> serde_json::Value {
+        let mut clients: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+        let mut clients_per_timeline = BTreeMap::new();
+        for (tl, channels) in &self.timelines {
+            let tl_txt = format!("{:?}", tl);
+            for id in channels.keys() {
+                clients.entry(*id).or_default().push(tl_txt.clone());
+            }
+            clients_per_timeline.insert(tl_txt, channels.len());
+        }
+        for timelines in clients.values_mut() {
+            timelines.sort();
+        }
+        serde_json::json!({
+            "clients": clients,
+            "clients_per_timeline": clients_per_timeline,
+        })
+    }
+
+    /// The input behind the most recent parse errors, as a JSON array (oldest first)
+    pub fn dead_letters(&self) -> String {
+        serde_json::json!(self.dead_letters).to_string()
+    }
+
+    /// The number of connections to each category of timeline.
+    ///
+    /// Categories rather than individual timelines keep the number of distinct labels small
+    /// and fixed, however many hashtags or lists are being streamed.
+    pub fn connections_by_category(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts: BTreeMap<_, _> = Timeline::CATEGORIES.iter().map(|c| (*c, 0)).collect();
+        for (tl, channels) in &self.timelines {
+            if let Some(count) = counts.get_mut(tl.category()) {
+                *count += channels.len();
+            }
+        }
+        counts
+    }
+
     pub fn count(&self) -> String {
-        format!(
-            "Current connections: {}",
-            self.timelines.values().map(HashMap::len).sum::<usize>()
-        )
+        format!("Current connections: {}", self.client_count())
     }
 
-    pub fn backpresure(&self) -> String {
-        format!(
-            "Input buffer size: {} KiB",
-            (self.unread_idx.1 - self.unread_idx.0) / 1024
-        )
+    /// How many clients are connected, counting a client subscribed to several timelines
+    /// (such as a firehose) once
+    fn client_count(&self) -> usize {
+        let clients: HashSet<&u32> = self.timelines.values().flat_map(HashMap::keys).collect();
+        clients.len()
     }
 
     pub fn list(&self) -> String {
@@ -247,5 +913,185 @@ impl Manager {
     }
 }
 
+impl Manager<RedisSource> {
+    /// Create a new `Manager`, with its own Redis connections (but no active subscriptions).
+    pub fn try_from(redis_cfg: &config::Redis) -> Result<Self> {
+        Ok(Self::with_source(
+            RedisSource::try_from(redis_cfg)?,
+            redis_cfg,
+        ))
+    }
+
+    /// Warn if the configured Redis namespace doesn't seem to match Mastodon's, after listening
+    /// for up to `wait` (see `RedisSource::check_namespace`).  Call before any subscriptions.
+    pub fn check_namespace(&mut self, wait: Duration) -> Result<()> {
+        if let Some(warning) = self.source.check_namespace(wait)? {
+            log::error!("{}", warning);
+        }
+        Ok(())
+    }
+
+    /// The names of all hashtags that currently have at least one subscriber
+    pub fn subscribed_tags(&mut self) -> Vec<String> {
+        let ids: HashSet<i64> = self.timelines.keys().filter_map(Timeline::tag).collect();
+        ids.into_iter()
+            .filter_map(|id| self.source.redis_conn.tag_name_cache.peek(&id).cloned())
+            .collect()
+    }
+
+    /// Compare the cached hashtag ids with the `current_ids` (typically fresh from Postgres)
+    /// and correct any that have diverged.
+    ///
+    /// Hashtag ids are very stable, but Postgres can reassign them (e.g., after two tags are
+    /// merged).  When that happens, incoming messages for the tag would be matched to an id
+    /// that no one is subscribed to, so we also move the existing subscribers to the new id.
+    pub fn revalidate_tags(&mut self, current_ids: &[(String, i64)]) {
+        for (name, new_id) in current_ids {
+            let old_id = match self.source.tag_id_cache.peek(name) {
+                Some(old_id) if old_id != new_id => *old_id,
+                Some(_) | None => continue,
+            };
+            log::warn!(
+                "Hashtag `#{}` changed id from {} to {}; updating cache",
+                name,
+                old_id,
+                new_id
+            );
+            self.source.tag_id_cache.put(name.clone(), *new_id);
+            self.source.redis_conn.tag_name_cache.pop(&old_id);
+            self.source
+                .redis_conn
+                .tag_name_cache
+                .put(*new_id, name.clone());
+
+            let stale: Vec<Timeline> = self
+                .timelines
+                .keys()
+                .filter(|tl| tl.tag() == Some(old_id))
+                .copied()
+                .collect();
+            for tl in stale {
+                if let Some(channels) = self.timelines.remove(&tl) {
+                    let updated = self.timelines.entry(tl.with_tag(*new_id)).or_default();
+                    updated.extend(channels);
+                }
+                // Redis channels are named after the tag, so the subscription itself carries over
+                if self.subscribed.remove(&tl) {
+                    self.subscribed.insert(tl.with_tag(*new_id));
+                }
+                if self.pending_subscribes.remove(&tl) {
+                    self.pending_subscribes.insert(tl.with_tag(*new_id));
+                }
+            }
+        }
+    }
+
+    /// Disconnect every client of the timeline with the Redis channel `name` (such as
+    /// `hashtag:spam`; see `drain`), describing the outcome
+    pub fn drain_named(&mut self, name: &str) -> String {
+        let tl = match Timeline::from_redis_text(name, &mut self.source.tag_id_cache) {
+            Ok(tl) => tl,
+            Err(e) => return format!("Could not drain `{}`: {}", name, e),
+        };
+        match self.drain(tl) {
+            Ok(drained) => format!("Drained clients: {}", drained),
+            Err(e) => format!("Drained clients, but could not unsubscribe: {}", e),
+        }
+    }
+
+    /// Counters and gauges in the Prometheus text format, for scraping.  Per-timeline gauges are
+    /// labeled with the timeline's Redis channel (such as `timeline:hashtag:rust`); per-category
+    /// gauges with the category (such as `hashtag`), which keeps their cardinality fixed.
+    pub fn metrics(&self) -> String {
+        let label = |tl: &Timeline| {
+            let conn = &self.source.redis_conn;
+            let hashtag = tl.tag().and_then(|id| conn.tag_name_cache.peek(&id));
+            let channel = tl
+                .to_redis_raw_timeline(&conn.channel_root, hashtag)
+                .unwrap_or_else(|_| format!("{:?}", tl));
+            channel.replace('\\', "\\\\").replace('"', "\\\"")
+        };
+        let mut clients: Vec<_> = self
+            .timelines
+            .iter()
+            .map(|(tl, channels)| (label(tl), channels.len()))
+            .collect();
+        clients.sort();
+        let mut held_ages: Vec<_> = self
+            .held_since
+            .iter()
+            .map(|(tl, since)| (label(tl), since.elapsed().as_secs_f64()))
+            .collect();
+        held_ages.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut metrics = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            metrics.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n",
+                name, help, name, kind
+            ));
+            for (labels, value) in samples {
+                metrics.push_str(&format!("{}{} {}\n", name, labels, value));
+            }
+        };
+        let unlabeled = |value: usize| vec![(String::new(), value.to_string())];
+        let by_timeline = |tl: String| format!("{{timeline=\"{}\"}}", tl);
+        let by_category = |category: &str| format!("{{category=\"{}\"}}", category);
+        metric(
+            "flodgatt_connected_clients",
+            "gauge",
+            "Clients currently connected.",
+            unlabeled(self.client_count()),
+        );
+        metric(
+            "flodgatt_category_clients",
+            "gauge",
+            "Clients currently connected, by timeline category.",
+            self.connections_by_category()
+                .into_iter()
+                .map(|(category, n)| (by_category(category), n.to_string()))
+                .collect(),
+        );
+        metric(
+            "flodgatt_timeline_clients",
+            "gauge",
+            "Clients currently connected, by timeline.",
+            clients
+                .into_iter()
+                .map(|(tl, n)| (by_timeline(tl), n.to_string()))
+                .collect(),
+        );
+        metric(
+            "flodgatt_held_queues",
+            "gauge",
+            "Queues of events held until their timeline's category is next polled.",
+            unlabeled(self.held.len()),
+        );
+        metric(
+            "flodgatt_held_queue_oldest_seconds",
+            "gauge",
+            "Age of the oldest event in each held queue, by timeline.",
+            held_ages
+                .into_iter()
+                .map(|(tl, age)| (by_timeline(tl), age.to_string()))
+                .collect(),
+        );
+        metric(
+            "flodgatt_redis_parse_errors_total",
+            "counter",
+            "Input from Redis that could not be parsed.",
+            unlabeled(self.parse_errors),
+        );
+        metrics
+    }
+
+    pub fn backpresure(&self) -> String {
+        format!(
+            "Input buffer size: {} KiB",
+            (self.source.unread_idx.1 - self.source.unread_idx.0) / 1024
+        )
+    }
+}
+
 #[cfg(test)]
 mod test;
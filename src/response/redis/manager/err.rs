@@ -1,6 +1,6 @@
 use super::super::{RedisConnErr, RedisParseErr};
 use super::{Event, EventErr};
-use crate::request::TimelineErr;
+use crate::request::{Timeline, TimelineErr};
 
 use std::fmt;
 use std::sync::Arc;
@@ -10,9 +10,11 @@ pub enum Error {
     InvalidId,
     TimelineErr(TimelineErr),
     EventErr(EventErr),
+    UnparseableEvent(EventErr, String),
     RedisParseErr(RedisParseErr, String),
     RedisConnErr(RedisConnErr),
-    ChannelSendErr(tokio::sync::mpsc::error::TrySendError<Arc<Event>>),
+    OversizedInput(usize),
+    ChannelSendErr(tokio::sync::mpsc::error::TrySendError<(Timeline, Arc<Event>)>),
 }
 
 impl std::error::Error for Error {}
@@ -26,8 +28,14 @@ impl fmt::Display for Error {
                 "tried to access a timeline/channel subscription that does not exist"
             ),
             EventErr(inner) => write!(f, "{}", inner),
+            UnparseableEvent(inner, input) => write!(f, "error parsing event {}\n{}", input, inner),
             RedisParseErr(inner, input) => write!(f, "error parsing {}\n{}", input, inner),
             RedisConnErr(inner) => write!(f, "{}", inner),
+            OversizedInput(len) => write!(
+                f,
+                "discarded {} bytes of input from Redis that never formed a complete message",
+                len
+            ),
             TimelineErr(inner) => write!(f, "{}", inner),
             ChannelSendErr(inner) => write!(f, "{}", inner),
         }?;
@@ -35,8 +43,8 @@ impl fmt::Display for Error {
     }
 }
 
-impl From<tokio::sync::mpsc::error::TrySendError<Arc<Event>>> for Error {
-    fn from(error: tokio::sync::mpsc::error::TrySendError<Arc<Event>>) -> Self {
+impl From<tokio::sync::mpsc::error::TrySendError<(Timeline, Arc<Event>)>> for Error {
+    fn from(error: tokio::sync::mpsc::error::TrySendError<(Timeline, Arc<Event>)>) -> Self {
         Self::ChannelSendErr(error)
     }
 }
@@ -1,3 +1,5 @@
+use super::super::connection::ScriptedRead;
+use super::super::RedisConnErr;
 use super::*;
 use crate::config;
 use crate::response::event::checked_event::{
@@ -9,8 +11,13 @@ use crate::response::event::checked_event::{
     CheckedEvent::*,
 };
 use crate::Id;
+use futures::{Future, Stream};
+use lru::LruCache;
 use serde_json::json;
+use std::collections::VecDeque;
 use std::fs;
+use std::io;
+use tokio::sync::mpsc::{self, Receiver};
 
 type TestResult = std::result::Result<(), Box<dyn std::error::Error>>;
 
@@ -50,16 +57,52 @@ fn output(i: usize) -> Arc<Event> {
         .clone()
 }
 
+/// Run `f` inside a task, as sending to or polling a channel requires
+fn in_task<T>(f: impl FnOnce() -> T) -> T {
+    futures::future::lazy(|| Ok::<_, ()>(f()))
+        .wait()
+        .expect("infallible")
+}
+
+fn received(rx: &mut Receiver<(Timeline, Arc<Event>)>) -> bool {
+    in_task(|| match rx.poll() {
+        Ok(Async::Ready(Some(_))) => true,
+        _ => false,
+    })
+}
+
+/// The message Redis sends subscribers of `timeline` (such as `public` or `78`) when `payload`
+/// is published to it
+fn redis_msg(timeline: &str, payload: &str) -> String {
+    let channel = format!("timeline:{}", timeline);
+    format!(
+        "*3\r\n$7\r\nmessage\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+        channel.len(),
+        channel,
+        payload.len(),
+        payload
+    )
+}
+
+/// Publish `payload` to `timeline` (see `redis_msg`) and deliver it to the `manager`'s clients
+fn publish(manager: &mut Manager, timeline: &str, payload: &str) -> Result<()> {
+    manager
+        .source
+        .redis_conn
+        .add(redis_msg(timeline, payload).as_bytes());
+    in_task(|| manager.send_msgs()).map(|_| ())
+}
+
 #[test]
 fn manager_poll_matches_six_events() -> TestResult {
-    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let mut source = RedisSource::try_from(&config::Redis::default())?;
     for i in 1..=6 {
-        manager.redis_conn.add(&input(i));
+        source.redis_conn.add(&input(i));
     }
     let mut i = 0;
-    while let Ok(Async::Ready(Some(len))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
-        manager.unread_idx = (0, manager.unread_idx.1 + len);
-        while let Ok(Async::Ready(Some((_tl, event)))) = manager.poll() {
+    while let Ok(Async::Ready(Some(len))) = source.redis_conn.poll_redis(source.unread_idx.1) {
+        source.unread_idx = (0, source.unread_idx.1 + len);
+        while let Ok(Async::Ready(Some((_tl, event)))) = source.poll() {
             println!("Parsing Event #{:03}", i + 1);
             assert_eq!(event, output(i));
             i += 1;
@@ -70,7 +113,7 @@ fn manager_poll_matches_six_events() -> TestResult {
 
 #[test]
 fn manager_poll_handles_non_utf8() -> TestResult {
-    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let mut source = RedisSource::try_from(&config::Redis::default())?;
     let mut input_txt = Vec::new();
     for i in 1..=6 {
         input_txt.extend_from_slice(&input(i))
@@ -83,23 +126,23 @@ fn manager_poll_handles_non_utf8() -> TestResult {
         .len()
         + 1;
 
-    manager.redis_conn.add(&input_txt[..invalid_idx]);
+    source.redis_conn.add(&input_txt[..invalid_idx]);
 
     let mut i = 0;
-    while let Ok(Async::Ready(Some(len))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
-        manager.unread_idx.1 += len;
-        while let Ok(Async::Ready(Some((_tl, event)))) = manager.poll() {
+    while let Ok(Async::Ready(Some(len))) = source.redis_conn.poll_redis(source.unread_idx.1) {
+        source.unread_idx.1 += len;
+        while let Ok(Async::Ready(Some((_tl, event)))) = source.poll() {
             println!("Parsing Event #{:03}", i + 1);
             assert_eq!(event, output(i));
             i += 1;
         }
     }
 
-    manager.redis_conn.add(&input_txt[invalid_idx..]);
+    source.redis_conn.add(&input_txt[invalid_idx..]);
 
-    while let Ok(Async::Ready(Some(len))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
-        manager.unread_idx.1 += len;
-        while let Ok(Async::Ready(Some((_tl, event)))) = manager.poll() {
+    while let Ok(Async::Ready(Some(len))) = source.redis_conn.poll_redis(source.unread_idx.1) {
+        source.unread_idx.1 += len;
+        while let Ok(Async::Ready(Some((_tl, event)))) = source.poll() {
             println!("Parsing Event #{:03}", i + 1);
             assert_eq!(event, output(i));
             i += 1;
@@ -111,14 +154,14 @@ fn manager_poll_handles_non_utf8() -> TestResult {
 
 #[test]
 fn manager_poll_matches_six_events_in_batches() -> TestResult {
-    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let mut source = RedisSource::try_from(&config::Redis::default())?;
     for i in 1..=3 {
-        manager.redis_conn.add(&input(i))
+        source.redis_conn.add(&input(i))
     }
     let mut i = 0;
-    while let Ok(Async::Ready(Some(len))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
-        manager.unread_idx.1 += len;
-        while let Ok(Async::Ready(Some((_tl, event)))) = manager.poll() {
+    while let Ok(Async::Ready(Some(len))) = source.redis_conn.poll_redis(source.unread_idx.1) {
+        source.unread_idx.1 += len;
+        while let Ok(Async::Ready(Some((_tl, event)))) = source.poll() {
             println!("Parsing Event #{:03}", i + 1);
             assert_eq!(event, output(i));
             i += 1;
@@ -126,11 +169,11 @@ fn manager_poll_matches_six_events_in_batches() -> TestResult {
     }
 
     for i in 4..=6 {
-        manager.redis_conn.add(&input(i));
+        source.redis_conn.add(&input(i));
     }
-    while let Ok(Async::Ready(Some(len))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
-        manager.unread_idx.1 += len;
-        while let Ok(Async::Ready(Some((_tl, event)))) = manager.poll() {
+    while let Ok(Async::Ready(Some(len))) = source.redis_conn.poll_redis(source.unread_idx.1) {
+        source.unread_idx.1 += len;
+        while let Ok(Async::Ready(Some((_tl, event)))) = source.poll() {
             println!("Parsing Event #{:03}", i + 1);
             assert_eq!(event, output(i));
             i += 1;
@@ -141,18 +184,18 @@ fn manager_poll_matches_six_events_in_batches() -> TestResult {
 
 #[test]
 fn manager_poll_handles_non_events() -> TestResult {
-    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let mut source = RedisSource::try_from(&config::Redis::default())?;
     for i in 1..=6 {
-        manager.redis_conn.add(&input(i));
-        manager
+        source.redis_conn.add(&input(i));
+        source
             .redis_conn
             .add(b"*3\r\n$9\r\nsubscribe\r\n$12\r\ntimeline:308\r\n:1\r\n");
     }
     let mut i = 0;
 
-    while let Ok(Async::Ready(Some(len))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
-        manager.unread_idx.1 += len;
-        while let Ok(Async::Ready(msg)) = manager.poll() {
+    while let Ok(Async::Ready(Some(len))) = source.redis_conn.poll_redis(source.unread_idx.1) {
+        source.unread_idx.1 += len;
+        while let Ok(Async::Ready(msg)) = source.poll() {
             if let Some((_tl, event)) = msg {
                 println!("Parsing Event #{:03}", i + 1);
                 assert_eq!(event, output(i));
@@ -165,16 +208,16 @@ fn manager_poll_handles_non_events() -> TestResult {
 
 #[test]
 fn manager_poll_handles_partial_events() -> TestResult {
-    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let mut source = RedisSource::try_from(&config::Redis::default())?;
     for i in 1..=3 {
-        manager.redis_conn.add(&input(i));
+        source.redis_conn.add(&input(i));
     }
-    manager.redis_conn.add(&input(4)[..50]);
+    source.redis_conn.add(&input(4)[..50]);
     let mut i = 0;
 
-    while let Ok(Async::Ready(Some(len))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
-        manager.unread_idx.1 += len;
-        while let Ok(Async::Ready(msg)) = manager.poll() {
+    while let Ok(Async::Ready(Some(len))) = source.redis_conn.poll_redis(source.unread_idx.1) {
+        source.unread_idx.1 += len;
+        while let Ok(Async::Ready(msg)) = source.poll() {
             if let Some((_tl, event)) = msg {
                 println!("Parsing Event #{:03}", i + 1);
                 assert_eq!(event, output(i));
@@ -184,12 +227,12 @@ fn manager_poll_handles_partial_events() -> TestResult {
     }
     assert_eq!(i, 3);
 
-    manager.redis_conn.add(&input(4)[50..]);
-    manager.redis_conn.add(&input(5));
-    manager.redis_conn.add(&input(6));
-    while let Ok(Async::Ready(Some(len))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
-        manager.unread_idx.1 += len;
-        while let Ok(Async::Ready(msg)) = manager.poll() {
+    source.redis_conn.add(&input(4)[50..]);
+    source.redis_conn.add(&input(5));
+    source.redis_conn.add(&input(6));
+    while let Ok(Async::Ready(Some(len))) = source.redis_conn.poll_redis(source.unread_idx.1) {
+        source.unread_idx.1 += len;
+        while let Ok(Async::Ready(msg)) = source.poll() {
             if let Some((_tl, event)) = msg {
                 println!("Parsing Event #{:03}", i + 1);
                 assert_eq!(event, output(i));
@@ -203,15 +246,15 @@ fn manager_poll_handles_partial_events() -> TestResult {
 
 #[test]
 fn manager_poll_handles_full_channel() -> TestResult {
-    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let mut source = RedisSource::try_from(&config::Redis::default())?;
     for i in 1..=6 {
-        manager.redis_conn.add(&input(i));
+        source.redis_conn.add(&input(i));
     }
     let (mut i, channel_full) = (0, 3);
     'outer: loop {
-        while let Ok(Async::Ready(Some(n))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
-            manager.unread_idx.1 += n;
-            while let Ok(Async::Ready(msg)) = manager.poll() {
+        while let Ok(Async::Ready(Some(n))) = source.redis_conn.poll_redis(source.unread_idx.1) {
+            source.unread_idx.1 += n;
+            while let Ok(Async::Ready(msg)) = source.poll() {
                 if let Some((_tl, event)) = msg {
                     println!("Parsing Event #{:03}", i + 1);
                     assert_eq!(event, output(i));
@@ -226,13 +269,13 @@ fn manager_poll_handles_full_channel() -> TestResult {
     }
 
     let _rewind = (|| {
-        manager.rewind_to_prev_msg();
+        source.rewind_to_prev_msg();
         i -= 1;
     })();
 
-    while let Ok(Async::Ready(Some(len))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
-        manager.unread_idx.1 += len;
-        while let Ok(Async::Ready(msg)) = manager.poll() {
+    while let Ok(Async::Ready(Some(len))) = source.redis_conn.poll_redis(source.unread_idx.1) {
+        source.unread_idx.1 += len;
+        while let Ok(Async::Ready(msg)) = source.poll() {
             if let Some((_tl, event)) = msg {
                 println!("Parsing Event #{:03}", i + 1);
                 assert_eq!(event, output(i));
@@ -243,3 +286,1581 @@ fn manager_poll_handles_full_channel() -> TestResult {
 
     Ok(assert_eq!(i, 6))
 }
+
+#[test]
+fn manager_writes_stats_to_configured_key() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.stats_key.0 = Some("flodgatt:stats".to_string());
+    let mut manager = Manager::try_from(&redis_cfg)?;
+
+    manager.write_stats()?;
+
+    let sent = String::from_utf8(manager.source.redis_conn.secondary.clone())?;
+    assert!(sent.starts_with("*3\r\n$3\r\nSET\r\n$14\r\nflodgatt:stats\r\n"));
+    assert!(sent.contains(r#""parse_errors":0"#));
+    Ok(())
+}
+
+#[test]
+fn manager_does_not_write_stats_without_a_key() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+
+    manager.write_stats()?;
+
+    assert!(manager.source.redis_conn.secondary.is_empty());
+    Ok(())
+}
+
+#[test]
+fn manager_delivers_events_when_the_stats_cannot_be_written() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.stats_key.0 = Some("flodgatt:stats".to_string());
+    redis_cfg.stats_interval.0 = Duration::from_millis(0);
+    let source = MemorySource {
+        failing_store: true,
+        ..MemorySource::default()
+    };
+    let mut manager = Manager::with_source(source, &redis_cfg);
+    let public = Timeline::from_redis_text("public", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: public,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    manager.source.events.push_back((public, output(0)));
+    in_task(|| manager.send_msgs())?;
+
+    assert!(received(&mut rx));
+    Ok(())
+}
+
+/// What's written to the primary and secondary connections when a client subscribes
+fn commands_sent_on_subscribe(
+    redis_cfg: &config::Redis,
+) -> std::result::Result<(String, String), Box<dyn std::error::Error>> {
+    let mut manager = Manager::try_from(redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    let conn = &manager.source.redis_conn;
+    let as_text = |sent: &[u8]| String::from_utf8_lossy(sent).to_string();
+    Ok((as_text(&conn.primary), as_text(&conn.secondary)))
+}
+
+#[test]
+fn manager_advertises_subscriptions_on_the_secondary_connection() -> TestResult {
+    let (primary, secondary) = commands_sent_on_subscribe(&config::Redis::default())?;
+
+    assert!(primary.starts_with("*2\r\n$9\r\nsubscribe\r\n$15\r\ntimeline:public\r\n"));
+    assert!(secondary.starts_with("*2\r\n$4\r\nMSET\r\n$26\r\nsubscribed:timeline:public\r\n"));
+    Ok(())
+}
+
+#[test]
+fn manager_only_subscribes_on_the_primary_connection_when_not_advertising() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.advertise_subscriptions.0 = false;
+    let (primary, secondary) = commands_sent_on_subscribe(&redis_cfg)?;
+
+    assert!(primary.starts_with("*2\r\n$9\r\nsubscribe\r\n$15\r\ntimeline:public\r\n"));
+    assert!(secondary.is_empty());
+    Ok(())
+}
+
+#[test]
+fn manager_revalidation_updates_changed_tag_ids() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let timeline = Timeline::from_redis_text("hashtag:5", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline,
+        hashtag_name: Some("rust".to_string()),
+        ..Subscription::default()
+    };
+    let (tx, _rx) = tokio::sync::mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+    assert_eq!(manager.subscribed_tags(), vec!["rust".to_string()]);
+
+    // Postgres now reports a different id for `#rust`
+    manager.revalidate_tags(&[("rust".to_string(), 6)]);
+
+    assert_eq!(
+        manager.source.tag_id_cache.get(&"rust".to_string()),
+        Some(&6)
+    );
+    assert_eq!(
+        manager.source.redis_conn.tag_name_cache.get(&6),
+        Some(&"rust".to_string())
+    );
+    assert!(manager.timelines.contains_key(&timeline.with_tag(6)));
+    assert!(!manager.timelines.contains_key(&timeline));
+    Ok(())
+}
+
+#[test]
+fn manager_revalidation_ignores_unchanged_tag_ids() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let timeline = Timeline::from_redis_text("hashtag:5", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline,
+        hashtag_name: Some("rust".to_string()),
+        ..Subscription::default()
+    };
+    let (tx, _rx) = tokio::sync::mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    manager.revalidate_tags(&[("rust".to_string(), 5)]);
+
+    assert_eq!(
+        manager.source.tag_id_cache.get(&"rust".to_string()),
+        Some(&5)
+    );
+    assert!(manager.timelines.contains_key(&timeline));
+    Ok(())
+}
+
+#[test]
+fn manager_watchdog_reconnects_when_poll_stalls() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.watchdog_timeout.0 = Some(Duration::from_millis(5));
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = tokio::sync::mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+    assert!(!manager.watchdog_fired());
+
+    // Nothing arrives before the timeout
+    std::thread::sleep(Duration::from_millis(10));
+    assert!(manager.watchdog_fired());
+    manager.send_msgs()?;
+    assert!(!manager.is_healthy());
+    assert!(manager.reconnected_at.is_none()); // backing off, as for any dropped connection
+
+    // The new connection resubscribed to the existing timeline
+    std::thread::sleep(Duration::from_millis(110));
+    manager.send_msgs()?;
+    let sent = String::from_utf8(manager.source.redis_conn.primary.clone())?;
+    assert!(sent.starts_with("*2\r\n$9\r\nsubscribe\r\n"));
+    assert!(!manager.watchdog_fired());
+    Ok(())
+}
+
+#[test]
+fn manager_watchdog_keeps_a_quiet_connection_that_answers_pings() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.watchdog_timeout.0 = Some(Duration::from_millis(100));
+    let mut manager = Manager::with_source(MemorySource::default(), &redis_cfg);
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    // No events arrive, but each PING sent after half the timeout is answered
+    for _ in 0..4 {
+        std::thread::sleep(Duration::from_millis(60));
+        in_task(|| manager.send_msgs())?;
+    }
+    assert_eq!(manager.source.pings, 4);
+    assert!(!manager.watchdog_fired());
+    assert!(manager.is_healthy());
+    assert!(manager.reconnected_at.is_none());
+    Ok(())
+}
+
+#[test]
+fn manager_pings_a_quiet_redis_connection() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.watchdog_timeout.0 = Some(Duration::from_millis(10));
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+    manager.source.redis_conn.primary.clear();
+
+    std::thread::sleep(Duration::from_millis(6));
+    manager.send_msgs()?;
+    manager.send_msgs()?; // only once until input arrives
+
+    let sent = String::from_utf8(manager.source.redis_conn.primary.clone())?;
+    assert_eq!(sent, "*1\r\n$4\r\nPING\r\n");
+    Ok(())
+}
+
+#[test]
+fn manager_watchdog_ignores_idle_manager() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.watchdog_timeout.0 = Some(Duration::from_millis(5));
+    let manager = Manager::try_from(&redis_cfg)?;
+
+    std::thread::sleep(Duration::from_millis(10));
+    assert!(!manager.watchdog_fired());
+    Ok(())
+}
+
+#[test]
+fn manager_polls_slowly_for_the_slow_start_window_after_a_reconnect() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.slow_start.0 = Some(Duration::from_millis(50));
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let normal = manager.poll_interval();
+    assert_eq!(manager.effective_poll_interval(), normal);
+
+    manager.reconnect()?;
+    let at = manager.reconnected_at.expect("just reconnected");
+    let interval_after = |ms| manager.effective_poll_interval_at(at + Duration::from_millis(ms));
+    assert!(interval_after(0) > normal * 3 && interval_after(0) <= normal * 4);
+    assert!(interval_after(25) > normal * 2 && interval_after(25) < normal * 3);
+    assert!(interval_after(45) > normal && interval_after(45) < normal * 2);
+    assert_eq!(interval_after(50), normal);
+    assert_eq!(interval_after(60), normal);
+    Ok(())
+}
+
+#[test]
+fn manager_reconnects_when_a_subscribe_goes_unconfirmed() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.confirm_timeout.0 = Some(Duration::from_millis(5));
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+    assert!(!manager.confirmation_overdue());
+
+    // Redis never confirms the SUBSCRIBE
+    std::thread::sleep(Duration::from_millis(10));
+    assert!(manager.confirmation_overdue());
+    manager.send_msgs()?;
+
+    // The new connection resubscribed, and is waiting on its own confirmation
+    let sent = String::from_utf8(manager.source.redis_conn.primary.clone())?;
+    assert!(sent.starts_with("*2\r\n$9\r\nsubscribe\r\n"));
+    assert!(!manager.confirmation_overdue());
+    Ok(())
+}
+
+#[test]
+fn manager_keeps_the_connection_when_a_subscribe_is_confirmed() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.confirm_timeout.0 = Some(Duration::from_millis(5));
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    let confirmation = b"*3\r\n$9\r\nsubscribe\r\n$15\r\ntimeline:public\r\n:1\r\n";
+    manager.source.redis_conn.add(confirmation);
+    in_task(|| manager.send_msgs())?;
+
+    std::thread::sleep(Duration::from_millis(10));
+    assert!(!manager.confirmation_overdue());
+    Ok(())
+}
+
+#[test]
+fn manager_delivers_overridden_category_on_its_own_cadence() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg
+        .polling_overrides
+        .0
+        .insert("public".to_string(), Duration::from_millis(200));
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    assert_eq!(manager.poll_interval(), Duration::from_millis(100));
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    // The first poll is always due
+    manager.source.redis_conn.add(&input(1));
+    in_task(|| manager.send_msgs())?;
+    assert!(received(&mut rx));
+
+    // Events arriving before the override elapses are held...
+    manager.source.redis_conn.add(&input(2));
+    in_task(|| manager.send_msgs())?;
+    assert!(!received(&mut rx));
+
+    // ...and delivered once it has
+    std::thread::sleep(Duration::from_millis(200));
+    in_task(|| manager.send_msgs())?;
+    assert!(received(&mut rx));
+    Ok(())
+}
+
+/// A `Manager` whose public timeline is delivered every 200ms, with the first poll (which is
+/// always due) behind it and a client subscribed through a channel with room for `capacity`
+fn manager_holding_public_events(
+    redis_cfg: &mut config::Redis,
+    capacity: usize,
+) -> std::result::Result<
+    (
+        Manager<MemorySource>,
+        Timeline,
+        Receiver<(Timeline, Arc<Event>)>,
+        DroppedEvents,
+    ),
+    Box<dyn std::error::Error>,
+> {
+    redis_cfg
+        .polling_overrides
+        .0
+        .insert("public".to_string(), Duration::from_millis(200));
+    let mut manager = Manager::with_source(MemorySource::default(), redis_cfg);
+    let public = Timeline::from_redis_text("public", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: public,
+        ..Subscription::default()
+    };
+    let (tx, rx) = mpsc::channel(capacity);
+    let dropped = manager.subscribe(&subscription, tx);
+    in_task(|| manager.send_msgs())?;
+    Ok((manager, public, rx, dropped))
+}
+
+#[test]
+fn manager_drops_the_oldest_held_event_once_the_queue_is_full() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.held_events_max.0 = 2;
+    let (mut manager, public, _rx, dropped) = manager_holding_public_events(&mut redis_cfg, 10)?;
+
+    for i in 0..3 {
+        manager.source.events.push_back((public, output(i)));
+    }
+    in_task(|| manager.send_msgs())?;
+
+    let held: Vec<_> = manager.held[&public].iter().cloned().collect();
+    assert_eq!(held, vec![output(1), output(2)]);
+    assert_eq!(dropped.count(), 1);
+    Ok(())
+}
+
+#[test]
+fn manager_holds_fewer_events_during_the_slow_start_window() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.held_events_max.0 = 8;
+    redis_cfg.slow_start.0 = Some(Duration::from_secs(60));
+    let (mut manager, public, _rx, dropped) = manager_holding_public_events(&mut redis_cfg, 10)?;
+    manager.reconnect()?;
+    let at = manager.reconnected_at.expect("just reconnected");
+    assert_eq!(manager.held_cap(at), 2);
+    assert_eq!(manager.held_cap(at + Duration::from_secs(60)), 8);
+
+    manager.polled_at -= Duration::from_secs(1); // so that this poll isn't skipped
+    for i in 0..8 {
+        manager.source.events.push_back((public, output(i % 3)));
+    }
+    in_task(|| manager.send_msgs())?;
+
+    assert_eq!(manager.held[&public].len(), 2);
+    assert_eq!(dropped.count(), 6);
+    Ok(())
+}
+
+#[test]
+fn manager_keeps_held_events_that_a_full_channel_cannot_take() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    let (mut manager, public, mut rx, dropped) = manager_holding_public_events(&mut redis_cfg, 1)?;
+    manager.source.events.push_back((public, output(0)));
+    manager.source.events.push_back((public, output(1)));
+    in_task(|| manager.send_msgs())?;
+
+    manager.delivered_at.clear(); // make the public timeline due
+    in_task(|| manager.send_msgs())?;
+    assert!(received(&mut rx));
+    assert!(!received(&mut rx));
+    assert_eq!(manager.held[&public].len(), 1);
+
+    manager.delivered_at.clear();
+    in_task(|| manager.send_msgs())?;
+    assert!(received(&mut rx));
+    assert!(manager.held.is_empty());
+    assert_eq!(dropped.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn manager_lag_grows_while_an_event_is_held() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg
+        .polling_overrides
+        .0
+        .insert("public".to_string(), Duration::from_millis(200));
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+    in_task(|| manager.send_msgs())?; // the first poll is always due
+    assert_eq!(manager.max_lag(), Duration::from_millis(0));
+
+    manager.source.redis_conn.add(&input(1));
+    in_task(|| manager.send_msgs())?;
+    let held_lag = manager.max_lag();
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(manager.max_lag() >= held_lag + Duration::from_millis(20));
+
+    // Delivering the event clears its lag
+    std::thread::sleep(Duration::from_millis(200));
+    in_task(|| manager.send_msgs())?;
+    assert!(received(&mut rx));
+    assert_eq!(manager.max_lag(), Duration::from_millis(0));
+    Ok(())
+}
+
+/// A `MessageSource` that delivers events from memory rather than from a message broker
+#[derive(Default)]
+struct MemorySource {
+    events: VecDeque<(Timeline, Arc<Event>)>,
+    last: Option<(Timeline, Arc<Event>)>,
+    subscribed: Vec<Timeline>,
+    history: Vec<Arc<Event>>,
+    /// How many of the next reconnects fail
+    failing_reconnects: usize,
+    /// How many of the next subscribes fail
+    failing_subscribes: usize,
+    /// Whether `store` fails
+    failing_store: bool,
+    /// How many times the source has been pinged
+    pings: usize,
+    /// The replies to pings that haven't been polled yet
+    pongs: usize,
+}
+
+impl MessageSource for MemorySource {
+    fn subscribe(&mut self, timelines: &[Timeline]) -> Result<()> {
+        if self.failing_subscribes > 0 {
+            self.failing_subscribes -= 1;
+            let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+            return Err(Error::RedisConnErr(RedisConnErr::UnknownRedisErr(reset)));
+        }
+        self.subscribed.extend_from_slice(timelines);
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, timelines: &[Timeline]) -> Result<()> {
+        self.subscribed.retain(|tl| !timelines.contains(tl));
+        Ok(())
+    }
+
+    fn poll_event(&mut self) -> Poll<Option<(Timeline, Arc<Event>)>, Error> {
+        if self.pongs > 0 {
+            self.pongs -= 1;
+            return Ok(Async::Ready(None));
+        }
+        self.last = self.events.pop_front();
+        match &self.last {
+            Some(msg) => Ok(Async::Ready(Some(msg.clone()))),
+            None => Ok(Async::NotReady),
+        }
+    }
+
+    fn replay_last(&mut self) {
+        if let Some(msg) = self.last.take() {
+            self.events.push_front(msg);
+        }
+    }
+
+    fn reconnect(&mut self, timelines: &[Timeline]) -> Result<()> {
+        if self.failing_reconnects > 0 {
+            self.failing_reconnects -= 1;
+            let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+            return Err(Error::RedisConnErr(RedisConnErr::UnknownRedisErr(refused)));
+        }
+        self.subscribed = timelines.to_vec();
+        Ok(())
+    }
+
+    fn history(&mut self, _timeline: Timeline, limit: usize) -> Result<Vec<Arc<Event>>> {
+        let skipped = self.history.len().saturating_sub(limit);
+        Ok(self.history[skipped..].to_vec())
+    }
+
+    fn store(&mut self, _key: &str, _value: &str) -> Result<()> {
+        if self.failing_store {
+            let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+            return Err(Error::RedisConnErr(RedisConnErr::UnknownRedisErr(refused)));
+        }
+        Ok(())
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        self.pings += 1;
+        self.pongs += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn manager_delivers_events_from_any_message_source() -> TestResult {
+    let mut manager = Manager::with_source(MemorySource::default(), &config::Redis::default());
+    let public = Timeline::from_redis_text("public", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: public,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+    assert_eq!(manager.source.subscribed, vec![public]);
+
+    manager.source.events.push_back((public, output(0)));
+    in_task(|| manager.send_msgs())?;
+
+    match in_task(|| rx.poll()) {
+        Ok(Async::Ready(Some((tl, event)))) => {
+            assert_eq!(tl, public);
+            assert_eq!(event, output(0));
+        }
+        other => panic!("Expected an event, but got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn manager_prewarming_populates_both_tag_caches() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+
+    manager.prewarm_tags(&[("rust".to_string(), 5), ("mastodon".to_string(), 8)]);
+
+    let source = &mut manager.source;
+    assert_eq!(source.tag_id_cache.get(&"rust".to_string()), Some(&5));
+    assert_eq!(source.tag_id_cache.get(&"mastodon".to_string()), Some(&8));
+    assert_eq!(
+        source.redis_conn.tag_name_cache.get(&5),
+        Some(&"rust".to_string())
+    );
+    assert_eq!(
+        source.redis_conn.tag_name_cache.get(&8),
+        Some(&"mastodon".to_string())
+    );
+    Ok(())
+}
+
+#[test]
+fn manager_discards_oversized_incomplete_input_and_reconnects() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.max_message_bytes.0 = 1_000;
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    // A message that claims to be far larger than the limit and never completes
+    let mut input = b"*3\r\n$7\r\nmessage\r\n$15\r\ntimeline:public\r\n$100000\r\n".to_vec();
+    input.extend_from_slice(&[b'a'; 5_000]);
+    manager.source.redis_conn.add(&input);
+    in_task(|| manager.send_msgs())?;
+
+    assert_eq!(manager.source.unread_idx, (0, 0));
+    // The fresh connection resubscribed to the existing timeline
+    let sent = String::from_utf8(manager.source.redis_conn.primary.clone())?;
+    assert!(sent.starts_with("*2\r\n$9\r\nsubscribe\r\n"));
+    Ok(())
+}
+
+#[test]
+fn manager_delivers_status_counts_to_the_timelines_subscribers() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let public = Timeline::from_redis_text("public", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: public,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    let txt = r#"{"event":"status.counts","payload":{"id":"42","reblogs_count":3}}"#;
+    publish(&mut manager, "public", &txt)?;
+
+    match in_task(|| rx.poll()) {
+        Ok(Async::Ready(Some((tl, event)))) => {
+            assert_eq!(tl, public);
+            assert_eq!(
+                event.to_json_string(),
+                r#"{"event":"status.counts","payload":"{\"id\":\"42\",\"reblogs_count\":3}"}"#
+            );
+        }
+        other => panic!("Expected an event, but got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn manager_delivers_announcement_reactions_to_the_users_timeline() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let user = Timeline::from_redis_text("78", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: user,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    let txt = r#"{"event":"announcement.reaction","payload":{"name":"blobcat","count":2,"announcement_id":"8"}}"#;
+    publish(&mut manager, "78", &txt)?;
+
+    match in_task(|| rx.poll()) {
+        Ok(Async::Ready(Some((tl, event)))) => {
+            assert_eq!(tl, user);
+            assert_eq!(
+                event.to_json_string(),
+                r#"{"event":"announcement.reaction","payload":"{\"announcement_id\":\"8\",\"count\":2,\"name\":\"blobcat\"}"}"#
+            );
+        }
+        other => panic!("Expected an event, but got {:?}", other),
+    }
+    Ok(())
+}
+
+/// Marks the payloads of deletions, and records the timelines it transformed events for
+struct RecordingTransform(Arc<Mutex<Vec<Timeline>>>);
+
+impl EventTransform for RecordingTransform {
+    fn transform(&self, timeline: &Timeline, event: &mut Event) {
+        self.0.lock().expect("not poisoned").push(*timeline);
+        if let Event::TypeSafe(Delete { payload }) = event {
+            payload.push_str("-transformed");
+        }
+    }
+}
+
+#[test]
+fn manager_delivers_transformed_events() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    manager.add_transform(RecordingTransform(recorded.clone()));
+    let public = Timeline::from_redis_text("public", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: public,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    let txt = r#"{"event":"delete","payload":"1038647"}"#;
+    publish(&mut manager, "public", &txt)?;
+
+    match in_task(|| rx.poll()) {
+        Ok(Async::Ready(Some((_tl, event)))) => assert_eq!(
+            event.to_json_string(),
+            r#"{"event":"delete","payload":"1038647-transformed"}"#
+        ),
+        other => panic!("Expected an event, but got {:?}", other),
+    }
+    assert_eq!(*recorded.lock().expect("not poisoned"), vec![public]);
+    Ok(())
+}
+
+#[test]
+fn manager_lingering_timeline_survives_a_quick_reconnect() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.unsubscribe_linger.0 = Some(Duration::from_secs(60));
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+
+    let (tx, rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+    drop(rx); // the client disconnects...
+    in_task(|| manager.send_pings())?;
+    in_task(|| manager.send_msgs())?;
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx); // ...and promptly reconnects
+
+    let sent = String::from_utf8(manager.source.redis_conn.primary.clone())?;
+    assert_eq!(sent.matches("\r\nsubscribe\r\n").count(), 1);
+    assert!(!sent.contains("unsubscribe"));
+    Ok(())
+}
+
+#[test]
+fn manager_drains_only_the_targeted_timeline() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let spam = Subscription {
+        timeline: Timeline::from_redis_text("hashtag:42", &mut LruCache::new(1))?,
+        hashtag_name: Some("spam".to_string()),
+        ..Subscription::default()
+    };
+    let public = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (spam_tx, mut spam_rx) = mpsc::channel(10);
+    let (public_tx, mut public_rx) = mpsc::channel(10);
+    manager.subscribe(&spam, spam_tx);
+    manager.subscribe(&public, public_tx);
+
+    assert_eq!(manager.drain_named("hashtag:spam"), "Drained clients: 1");
+
+    assert!(in_task(|| matches!(spam_rx.poll(), Ok(Async::Ready(None)))));
+    assert!(!manager.timelines.contains_key(&spam.timeline));
+    let sent = String::from_utf8(manager.source.redis_conn.primary.clone())?;
+    assert!(sent.contains("unsubscribe\r\n$21\r\ntimeline:hashtag:spam\r\n"));
+    assert_eq!(sent.matches("unsubscribe").count(), 1);
+
+    assert_eq!(manager.timelines[&public.timeline].len(), 1);
+    in_task(|| manager.send_pings())?;
+    assert!(received(&mut public_rx));
+    Ok(())
+}
+
+#[test]
+fn manager_unsubscribes_once_the_linger_elapses() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.unsubscribe_linger.0 = Some(Duration::from_millis(5));
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+
+    let (tx, rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+    drop(rx);
+    in_task(|| manager.send_pings())?;
+    std::thread::sleep(Duration::from_millis(10));
+    in_task(|| manager.send_msgs())?;
+
+    let sent = String::from_utf8(manager.source.redis_conn.primary.clone())?;
+    assert!(sent.contains("unsubscribe"));
+    assert!(manager.timelines.is_empty());
+    Ok(())
+}
+
+#[test]
+fn manager_flush_discards_held_events_and_polls_immediately() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg
+        .polling_overrides
+        .0
+        .insert("public".to_string(), Duration::from_secs(60));
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+    manager.source.redis_conn.add(&input(1));
+    in_task(|| manager.send_msgs())?;
+    assert!(received(&mut rx));
+
+    // Held until the override elapses...
+    manager.source.redis_conn.add(&input(2));
+    in_task(|| manager.send_msgs())?;
+    assert!(!received(&mut rx));
+
+    // ...unless the manager is flushed, which drops it and makes the next poll deliver
+    assert_eq!(manager.flush(), "Discarded held events: 1");
+    manager.source.redis_conn.add(&input(3));
+    in_task(|| manager.send_msgs())?;
+    match in_task(|| rx.poll()) {
+        Ok(Async::Ready(Some((_tl, event)))) => assert_eq!(event, output(2)),
+        other => panic!("Expected an event, but got {:?}", other),
+    }
+    assert!(!received(&mut rx));
+    Ok(())
+}
+
+#[test]
+fn manager_counts_the_held_events_it_discards_for_each_client() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg
+        .polling_overrides
+        .0
+        .insert("public".to_string(), Duration::from_secs(60));
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    let dropped = manager.subscribe(&subscription, tx);
+    manager.source.redis_conn.add(&input(1));
+    in_task(|| manager.send_msgs())?;
+    assert!(received(&mut rx));
+
+    manager.source.redis_conn.add(&input(2));
+    in_task(|| manager.send_msgs())?;
+    assert_eq!(dropped.count(), 0);
+
+    manager.flush();
+    assert_eq!(dropped.count(), 1);
+    Ok(())
+}
+
+#[test]
+fn manager_counts_connections_by_timeline_category() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    for (tag, name) in &[("hashtag:5", "rust"), ("hashtag:6", "mastodon")] {
+        let subscription = Subscription {
+            timeline: Timeline::from_redis_text(tag, &mut LruCache::new(1))?,
+            hashtag_name: Some(name.to_string()),
+            ..Subscription::default()
+        };
+        let (tx, _rx) = tokio::sync::mpsc::channel(10);
+        manager.subscribe(&subscription, tx);
+    }
+
+    let counts = manager.connections_by_category();
+    assert_eq!(counts.get("hashtag"), Some(&2));
+    assert_eq!(counts.get("public"), Some(&0));
+    assert_eq!(counts.len(), 5);
+    let metrics = manager.metrics();
+    assert!(metrics.contains("flodgatt_category_clients{category=\"hashtag\"} 2\n"));
+    assert!(!metrics.contains("category=\"hashtag:"));
+    Ok(())
+}
+
+#[test]
+fn manager_delivers_poll_results_to_the_timelines_subscribers() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let public = Timeline::from_redis_text("public", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: public,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    let mut json: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    json["event"] = json!("status.update");
+    json["payload"]["poll"] = json!({
+        "id": "34830",
+        "expires_at": "2019-09-12T18:42:19.000Z",
+        "expired": false,
+        "multiple": false,
+        "votes_count": 10,
+        "options": [{ "title": "yes", "votes_count": 7 }, { "title": "no", "votes_count": 3 }],
+        "emojis": []
+    });
+    let txt = json.to_string();
+    publish(&mut manager, "public", &txt)?;
+
+    match in_task(|| rx.poll()) {
+        Ok(Async::Ready(Some((tl, event)))) => {
+            assert_eq!(tl, public);
+            let poll = event
+                .update_payload()
+                .and_then(|status| status.poll.as_ref())
+                .expect("poll results");
+            assert_eq!(poll.options[0].votes_count, Some(7));
+        }
+        other => panic!("Expected an event, but got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn manager_backfill_precedes_live_events() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.backfill_max.0 = 2;
+    let mut manager = Manager::with_source(MemorySource::default(), &redis_cfg);
+    manager.source.history = vec![output(1), output(2), output(3)];
+    let public = Timeline::from_redis_text("public", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: public,
+        backfill: true,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    manager.source.events.push_back((public, output(0)));
+    in_task(|| manager.send_msgs())?;
+
+    let mut delivered = Vec::new();
+    while let Ok(Async::Ready(Some((_tl, event)))) = in_task(|| rx.poll()) {
+        delivered.push(event);
+    }
+    assert_eq!(delivered, vec![output(2), output(3), output(0)]);
+    Ok(())
+}
+
+#[test]
+fn redis_source_keeps_the_most_recent_events_for_backfill() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.backfill_max.0 = 2;
+    let mut source = RedisSource::try_from(&redis_cfg)?;
+    for i in 1..=3 {
+        source.redis_conn.add(&input(i));
+    }
+    let public = Timeline::from_redis_text("public", &mut LruCache::new(1))?;
+
+    let mut polled = 0;
+    while polled < 3 {
+        if let Async::Ready(Some(_)) = source.poll_event()? {
+            polled += 1;
+        }
+    }
+    // An event that couldn't be delivered is polled again, but only recorded once
+    source.replay_last();
+    assert!(matches!(source.poll_event()?, Async::Ready(Some(_))));
+
+    assert_eq!(source.history(public, 10)?, vec![output(1), output(2)]);
+    assert_eq!(source.history(public, 1)?, vec![output(2)]);
+    source.unsubscribe(&[public])?;
+    assert!(source.history(public, 10)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn manager_only_backfills_when_asked() -> TestResult {
+    let mut manager = Manager::with_source(MemorySource::default(), &config::Redis::default());
+    manager.source.history = vec![output(1)];
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    assert!(!received(&mut rx));
+    Ok(())
+}
+
+#[test]
+fn manager_redundant_subscribe_is_not_sent_to_the_source() -> TestResult {
+    let mut manager = Manager::with_source(MemorySource::default(), &config::Redis::default());
+    let public = Timeline::from_redis_text("public", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: public,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    // The bookkeeping drifts: the timeline loses its clients without being unsubscribed
+    manager.timelines.clear();
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    assert_eq!(manager.source.subscribed, vec![public]);
+    assert_eq!(manager.count(), "Current connections: 1");
+    Ok(())
+}
+
+#[test]
+fn manager_retries_a_subscribe_that_failed() -> TestResult {
+    let source = MemorySource {
+        failing_subscribes: 1,
+        ..MemorySource::default()
+    };
+    let mut manager = Manager::with_source(source, &config::Redis::default());
+    let public = Timeline::from_redis_text("public", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: public,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+
+    manager.subscribe(&subscription, tx.clone());
+    assert!(manager.source.subscribed.is_empty());
+
+    // Neither the next poll nor the next client of the timeline subscribes to it twice
+    in_task(|| manager.send_msgs())?;
+    manager.subscribe(&subscription, tx);
+    in_task(|| manager.send_msgs())?;
+    assert_eq!(manager.source.subscribed, vec![public]);
+    Ok(())
+}
+
+#[test]
+fn manager_filters_narrow_timelines_from_the_broad_channel() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.narrow_channels.0 = false;
+    let mut manager = Manager::with_source(MemorySource::default(), &redis_cfg);
+    let mut cache = LruCache::new(1);
+    let (public, media) = (
+        Timeline::from_redis_text("public", &mut cache)?,
+        Timeline::from_redis_text("public:media", &mut cache)?,
+    );
+    let subscription = Subscription {
+        timeline: media,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+    assert_eq!(manager.source.subscribed, vec![public]);
+
+    manager.source.events.push_back((public, output(0)));
+    manager.source.events.push_back((public, output(4)));
+    in_task(|| manager.send_msgs())?;
+
+    let mut delivered = Vec::new();
+    while let Ok(Async::Ready(Some(msg))) = in_task(|| rx.poll()) {
+        delivered.push(msg);
+    }
+    assert_eq!(delivered, vec![(media, output(4))]);
+    Ok(())
+}
+
+#[test]
+fn trace_id_is_consistent_from_parse_to_route() -> TestResult {
+    let mut source = RedisSource::try_from(&config::Redis::default())?;
+    source.redis_conn.add(&input(1));
+    let mut parsed = None;
+    while let Ok(Async::Ready(Some(len))) = source.redis_conn.poll_redis(source.unread_idx.1) {
+        source.unread_idx.1 += len;
+        if let Ok(Async::Ready(Some(msg))) = source.poll() {
+            parsed = Some(msg);
+        }
+    }
+    let (tl, event) = parsed.ok_or("no event parsed")?;
+    let trace_id = |line: String| line.split(']').next().map(String::from);
+
+    let parse_line = event.trace_line(&format!("parsed from {:?}", tl));
+    let route_line = event.trace_line(&format!("routed to {:?}", vec![tl]));
+    assert_eq!(trace_id(parse_line), trace_id(route_line));
+    // The id is derived from the event rather than assigned to it, so copies share it
+    assert_eq!(event.trace_id(), output(0).trace_id());
+    assert_ne!(event.trace_id(), output(1).trace_id());
+    Ok(())
+}
+
+/// The JSON delivered to a subscriber for an event type Flodgatt doesn't know, if any
+fn unknown_event_delivered(
+    passthrough_unknown_events: bool,
+) -> std::result::Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.passthrough_unknown_events.0 = passthrough_unknown_events;
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    let txt = r#"{"event":"notifications_merged"}"#;
+    publish(&mut manager, "public", &txt)?;
+    Ok(match in_task(|| rx.poll()) {
+        Ok(Async::Ready(Some((_tl, event)))) => Some(event.to_json_string()),
+        _ => None,
+    })
+}
+
+#[test]
+fn manager_passes_through_unknown_events_only_when_configured() -> TestResult {
+    assert_eq!(
+        unknown_event_delivered(true)?,
+        Some(r#"{"event":"notifications_merged"}"#.to_string())
+    );
+    assert_eq!(unknown_event_delivered(false)?, None);
+    Ok(())
+}
+
+/// The update from `test_data/msg.event_txt_001.txt`, with its status and author ids sent as
+/// JSON numbers rather than strings
+fn update_with_numeric_ids() -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let txt = fs::read_to_string("test_data/msg.event_txt_001.txt")?;
+    let mut event: serde_json::Value = serde_json::from_str(&txt)?;
+    event["payload"]["id"] = json!(102_775_370_117_886_890_i64);
+    event["payload"]["in_reply_to_id"] = json!(102_775_346_916_917_099_i64);
+    event["payload"]["account"]["id"] = json!(78);
+    Ok(event.to_string())
+}
+
+#[test]
+fn numeric_ids_are_delivered_as_strings_when_configured() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.stringify_ids.0 = true;
+    let mut source = RedisSource::try_from(&redis_cfg)?;
+    let txt = update_with_numeric_ids()?;
+    source.redis_conn.add(redis_msg("public", &txt).as_bytes());
+
+    let events = events_until_blocked(&mut source)?;
+
+    assert_eq!(events.len(), 1);
+    let sent: serde_json::Value = serde_json::from_str(&events[0].to_json_string())?;
+    let payload = sent["payload"].as_str().ok_or("payload is not a string")?;
+    let payload: serde_json::Value = serde_json::from_str(payload)?;
+    assert_eq!(payload["id"], json!("102775370117886890"));
+    assert_eq!(payload["in_reply_to_id"], json!("102775346916917099"));
+    assert_eq!(payload["account"]["id"], json!("78"));
+    Ok(())
+}
+
+#[test]
+fn string_ids_are_not_stringified_again() {
+    let txt = r#"{"event":"update","payload":{"id":"1","account":{"id":"78"}}}"#;
+    assert_eq!(Event::stringify_ids(txt), None);
+}
+
+fn public_msg(channel: &str) -> String {
+    let txt = r#"{"event":"delete","payload":"1"}"#;
+    format!(
+        "*3\r\n$7\r\nmessage\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+        channel.len(),
+        channel,
+        txt.len(),
+        txt
+    )
+}
+
+#[test]
+fn namespace_check_warns_when_only_bare_channels_get_messages() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.namespace.0 = Some("mastodon".to_string());
+    let mut source = RedisSource::try_from(&redis_cfg)?;
+    let bare = public_msg("timeline:public");
+    source.redis_conn.add(bare.as_bytes());
+
+    let warning = source.check_namespace(Duration::from_millis(30))?;
+
+    assert!(warning.ok_or("no warning")?.contains("REDIS_NAMESPACE"));
+    let sent = String::from_utf8(source.redis_conn.primary.clone())?;
+    assert!(sent.contains("mastodon:timeline:public") && sent.contains("unsubscribe"));
+    Ok(())
+}
+
+#[test]
+fn namespace_check_passes_when_the_namespace_gets_messages() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.namespace.0 = Some("mastodon".to_string());
+    let mut source = RedisSource::try_from(&redis_cfg)?;
+    let (bare, namespaced) = (
+        public_msg("timeline:public"),
+        public_msg("mastodon:timeline:public"),
+    );
+    source.redis_conn.add(bare.as_bytes());
+    source.redis_conn.add(namespaced.as_bytes());
+
+    assert_eq!(source.check_namespace(Duration::from_millis(30))?, None);
+    Ok(())
+}
+
+#[test]
+fn manager_delivers_move_notifications_to_the_users_timeline() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let user = Timeline::from_redis_text("1", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: user,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    let status: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    let mut account = status["payload"]["account"].clone();
+    account["moved"] = json!({ "id": "99", "acct": "federationbot@example.com" });
+    let txt = json!({
+        "event": "notification",
+        "payload": {
+            "id": "7",
+            "type": "move",
+            "created_at": "2020-03-01T12:00:00.000Z",
+            "account": account
+        }
+    })
+    .to_string();
+    publish(&mut manager, "1", &txt)?;
+
+    match in_task(|| rx.poll()) {
+        Ok(Async::Ready(Some((tl, event)))) => {
+            assert_eq!(tl, user);
+            assert!(matches!(*event, Event::TypeSafe(Notification { .. })));
+            assert!(event.to_json_string().contains(r#"\"type\":\"move\""#));
+        }
+        other => panic!("Expected an event, but got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn manager_delivers_moderation_warnings_to_the_users_timeline() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let user = Timeline::from_redis_text("1", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: user,
+        ..Subscription::default()
+    };
+    let (tx, mut rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    let status: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    let txt = json!({
+        "event": "notification",
+        "payload": {
+            "id": "8",
+            "type": "moderation_warning",
+            "created_at": "2024-03-01T12:00:00.000Z",
+            "account": status["payload"]["account"],
+            "moderation_warning": { "id": "3", "action": "silence", "text": "" }
+        }
+    })
+    .to_string();
+    publish(&mut manager, "1", &txt)?;
+
+    match in_task(|| rx.poll()) {
+        Ok(Async::Ready(Some((tl, event)))) => {
+            assert_eq!(tl, user);
+            assert!(matches!(*event, Event::TypeSafe(Notification { .. })));
+            assert!(event.to_json_string().contains(r#"\"action\":\"silence\""#));
+        }
+        other => panic!("Expected an event, but got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn manager_keeps_the_most_recent_unparseable_input_as_dead_letters() -> TestResult {
+    let mut redis_cfg = config::Redis::default();
+    redis_cfg.dead_letters.0 = 2;
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+
+    let long = format!(r#"{{"event":"{}"}}"#, "x".repeat(2000));
+    let unparseable = [
+        r#"{"event":"first"}"#,
+        r#"{"event":"second"}"#,
+        long.as_str(),
+    ];
+    for txt in &unparseable {
+        publish(&mut manager, "public", &txt)?;
+    }
+
+    let dead_letters: Vec<String> = serde_json::from_str(&manager.dead_letters())?;
+    assert_eq!(dead_letters, vec![r#"{"event":"second"}"#, &long[..1024]]);
+    Ok(())
+}
+
+/// Every event `source` yields before it runs out of input
+fn events_until_blocked(source: &mut RedisSource) -> Result<Vec<Arc<Event>>> {
+    let mut events = Vec::new();
+    while let Async::Ready(msg) = source.poll_event()? {
+        events.extend(msg.map(|(_tl, event)| event));
+    }
+    Ok(events)
+}
+
+/// Redis's confirmation that the connection has subscribed to `channel`
+fn subscribe_confirmation(channel: &str) -> String {
+    format!(
+        "*3\r\n$9\r\nsubscribe\r\n${}\r\n{}\r\n:1\r\n",
+        channel.len(),
+        channel
+    )
+}
+
+#[test]
+fn confirmations_only_settle_their_own_channel() -> TestResult {
+    let mut source = RedisSource::try_from(&config::Redis::default())?;
+    let public = Timeline::from_redis_text("public", &mut LruCache::new(1))?;
+    let local = Timeline::from_redis_text("public:local", &mut LruCache::new(1))?;
+    source.subscribe(&[public, local])?;
+
+    // Out of order, and with a confirmation for a channel that was never subscribed to
+    source
+        .redis_conn
+        .add(subscribe_confirmation("timeline:public:local").as_bytes());
+    source
+        .redis_conn
+        .add(subscribe_confirmation("timeline:hashtag:rust").as_bytes());
+    events_until_blocked(&mut source)?;
+    assert!(source.unconfirmed_since().is_some());
+
+    source
+        .redis_conn
+        .add(subscribe_confirmation("timeline:public").as_bytes());
+    events_until_blocked(&mut source)?;
+    assert!(source.unconfirmed_since().is_none());
+    Ok(())
+}
+
+#[test]
+fn scripted_reads_assemble_a_message_split_by_a_gap() -> TestResult {
+    let mut source = RedisSource::try_from(&config::Redis::default())?;
+    let msg = input(1);
+    let (head, tail) = msg.split_at(msg.len() / 2);
+    source.redis_conn.script(vec![
+        ScriptedRead::Bytes(head.to_vec()),
+        ScriptedRead::WouldBlock,
+        ScriptedRead::Bytes(tail.to_vec()),
+    ]);
+
+    assert!(events_until_blocked(&mut source)?.is_empty());
+    assert_eq!(events_until_blocked(&mut source)?, vec![output(0)]);
+    Ok(())
+}
+
+#[test]
+fn scripted_reads_assemble_a_character_split_by_a_gap() -> TestResult {
+    let mut source = RedisSource::try_from(&config::Redis::default())?;
+    let msgs: Vec<u8> = (1..=6).flat_map(input).collect();
+    let split = str::from_utf8(&msgs)?
+        .char_indices()
+        .find(|(_, c)| c.len_utf8() > 1)
+        .map(|(i, _)| i + 1)
+        .expect("a multi-byte character");
+    source.redis_conn.script(vec![
+        ScriptedRead::Bytes(msgs[..split].to_vec()),
+        ScriptedRead::WouldBlock,
+        ScriptedRead::Bytes(msgs[split..].to_vec()),
+    ]);
+
+    let mut events = events_until_blocked(&mut source)?;
+    assert!(events.len() < 6);
+    events.extend(events_until_blocked(&mut source)?);
+    assert_eq!(events, (0..6).map(output).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn scripted_read_errors_surface_as_connection_errors() -> TestResult {
+    let mut source = RedisSource::try_from(&config::Redis::default())?;
+    source.redis_conn.add(&input(1));
+    source
+        .redis_conn
+        .script(vec![ScriptedRead::Err(io::ErrorKind::ConnectionReset)]);
+
+    assert!(matches!(source.poll_event(), Ok(Async::Ready(None))));
+    assert!(matches!(source.poll_event(), Ok(Async::Ready(Some(_)))));
+    assert!(matches!(source.poll_event(), Err(Error::RedisConnErr(_))));
+    Ok(())
+}
+
+#[test]
+fn manager_resubscribes_after_the_connection_drops() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+    manager
+        .source
+        .redis_conn
+        .script(vec![ScriptedRead::Err(io::ErrorKind::ConnectionReset)]);
+
+    manager.send_msgs()?;
+    assert!(!manager.is_healthy());
+    assert!(manager.reconnected_at.is_none()); // still backing off
+
+    std::thread::sleep(Duration::from_millis(110));
+    manager.send_msgs()?;
+    assert!(manager.is_healthy());
+    let sent = String::from_utf8(manager.source.redis_conn.primary.clone())?;
+    assert_eq!(sent, "*2\r\n$9\r\nsubscribe\r\n$15\r\ntimeline:public\r\n");
+    Ok(())
+}
+
+#[test]
+fn manager_backs_off_exponentially_while_reconnecting_fails() -> TestResult {
+    let mut manager = Manager::with_source(MemorySource::default(), &config::Redis::default());
+    let public = Timeline::from_redis_text("public", &mut LruCache::new(1))?;
+    let subscription = Subscription {
+        timeline: public,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&subscription, tx);
+    manager.source.failing_reconnects = 3;
+
+    manager.connection_dropped();
+    let mut waits = vec![manager.reconnect_backoff];
+    for _ in 0..3 {
+        manager.reconnect_dropped();
+        waits.push(manager.reconnect_backoff);
+    }
+    let ms = |ms| Duration::from_millis(ms);
+    assert_eq!(waits, vec![ms(100), ms(200), ms(400), ms(800)]);
+    assert!(manager.reconnect_after.is_some());
+
+    manager.reconnect_dropped();
+    assert!(manager.reconnect_after.is_none());
+    assert_eq!(manager.reconnect_backoff, ms(100));
+    assert_eq!(manager.source.subscribed, vec![public]);
+    assert!(manager.is_healthy());
+    Ok(())
+}
+
+#[test]
+fn manager_caps_the_reconnect_backoff() {
+    let mut manager = Manager::with_source(MemorySource::default(), &config::Redis::default());
+    manager.source.failing_reconnects = 20;
+
+    manager.connection_dropped();
+    for _ in 0..20 {
+        manager.reconnect_dropped();
+    }
+    assert_eq!(manager.reconnect_backoff, Duration::from_secs(30));
+}
+
+#[test]
+fn metrics_count_clients_by_redis_channel() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let subscriptions = vec![
+        Subscription {
+            timeline: Timeline::from_redis_text("hashtag:5", &mut LruCache::new(1))?,
+            hashtag_name: Some("rust".to_string()),
+            ..Subscription::default()
+        },
+        Subscription {
+            timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+            ..Subscription::default()
+        },
+        Subscription {
+            timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+            ..Subscription::default()
+        },
+    ];
+    for subscription in &subscriptions {
+        let (tx, _rx) = mpsc::channel(10);
+        manager.subscribe(subscription, tx);
+    }
+    manager.parse_errors = 2;
+
+    let metrics = manager.metrics();
+    let samples: Vec<&str> = metrics.lines().filter(|l| !l.starts_with('#')).collect();
+    assert_eq!(
+        samples,
+        vec![
+            "flodgatt_connected_clients 3",
+            "flodgatt_category_clients{category=\"direct\"} 0",
+            "flodgatt_category_clients{category=\"hashtag\"} 1",
+            "flodgatt_category_clients{category=\"list\"} 0",
+            "flodgatt_category_clients{category=\"public\"} 2",
+            "flodgatt_category_clients{category=\"user\"} 0",
+            "flodgatt_timeline_clients{timeline=\"timeline:hashtag:rust\"} 1",
+            "flodgatt_timeline_clients{timeline=\"timeline:public\"} 2",
+            "flodgatt_held_queues 0",
+            "flodgatt_redis_parse_errors_total 2",
+        ]
+    );
+    assert!(metrics.contains("# TYPE flodgatt_redis_parse_errors_total counter\n"));
+    Ok(())
+}
+
+#[test]
+fn metrics_count_a_firehose_client_once() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let firehose = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        firehose: true,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&firehose, tx);
+
+    assert!(manager.timelines.len() > 1);
+    assert!(manager
+        .metrics()
+        .contains("\nflodgatt_connected_clients 1\n"));
+    assert_eq!(manager.count(), "Current connections: 1");
+    Ok(())
+}
+
+#[test]
+fn manager_subscribes_to_all_new_channels_in_one_command() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let firehose = Subscription {
+        timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+        firehose: true,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&firehose, tx);
+
+    let sent = String::from_utf8(manager.source.redis_conn.primary.clone())?;
+    assert!(sent.starts_with("*5\r\n$9\r\nsubscribe\r\n"));
+    assert_eq!(sent.matches("subscribe").count(), 1);
+    for channel in &["public", "public:media", "public:local", "public:remote"] {
+        assert!(sent.contains(&format!("\r\ntimeline:{}\r\n", channel)));
+    }
+
+    // Only the channels that weren't already subscribed are sent
+    let local = Subscription {
+        timeline: Timeline::from_redis_text("public:local:media", &mut LruCache::new(1))?,
+        ..Subscription::default()
+    };
+    let (tx, _rx) = mpsc::channel(10);
+    manager.subscribe(&local, tx);
+    let sent = String::from_utf8(manager.source.redis_conn.primary.clone())?;
+    assert!(sent.ends_with("*2\r\n$9\r\nsubscribe\r\n$27\r\ntimeline:public:local:media\r\n"));
+    Ok(())
+}
+
+#[test]
+fn subscription_graph_matches_the_subscriptions() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let subscriptions = vec![
+        Subscription {
+            timeline: Timeline::from_redis_text("hashtag:5", &mut LruCache::new(1))?,
+            hashtag_name: Some("rust".to_string()),
+            ..Subscription::default()
+        },
+        Subscription {
+            timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+            ..Subscription::default()
+        },
+        Subscription {
+            timeline: Timeline::from_redis_text("public", &mut LruCache::new(1))?,
+            firehose: true,
+            ..Subscription::default()
+        },
+    ];
+    for subscription in &subscriptions {
+        let (tx, _rx) = mpsc::channel(10);
+        manager.subscribe(subscription, tx);
+    }
+
+    let public = "Timeline(Public, Federated, All)";
+    let media = "Timeline(Public, Federated, Media)";
+    let local = "Timeline(Public, Local, All)";
+    let remote = "Timeline(Public, Remote, All)";
+    assert_eq!(
+        manager.subscription_graph(),
+        json!({
+            "clients": {
+                "0": ["Timeline(Hashtag(5), Federated, All)"],
+                "1": [public],
+                "2": [public, media, local, remote],
+            },
+            "clients_per_timeline": {
+                "Timeline(Hashtag(5), Federated, All)": 1,
+                public: 2,
+                local: 1,
+                media: 1,
+                remote: 1,
+            },
+        })
+    );
+    Ok(())
+}
@@ -19,6 +19,10 @@
 //! Read that as: an array with three elements: the first element is a bulk string with
 //! three characters, the second is a bulk string with ten characters, and the third is a
 //! bulk string with 1,386 characters.
+//!
+//! Over RESP3 (see `REDIS_RESP3`), the same messages arrive as push frames, which are laid out
+//! exactly like arrays but start with `>` rather than `*`.  Since the connection is then also
+//! used for other commands, their replies (such as `+OK\r\n`) are interleaved with the messages.
 use self::RedisParseOutput::*;
 pub use err::RedisParseErr;
 use std::convert::{TryFrom, TryInto};
@@ -29,7 +33,12 @@ mod err;
 #[derive(Debug, Clone, PartialEq)]
 pub enum RedisParseOutput<'a> {
     Msg(RedisMsg<'a>),
-    NonMsg(&'a str),
+    /// A subscribe or unsubscribe confirmation
+    NonMsg(RedisConfirmation<'a>),
+    /// The reply to some other command, which only arrives alongside messages over RESP3
+    Reply(&'a str),
+    /// An error reply to some other command (such as a `SET` that Redis refused)
+    ErrorReply(RedisErrorReply<'a>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -39,22 +48,41 @@ pub struct RedisMsg<'a> {
     pub(crate) leftover_input: &'a str,
 }
 
+/// Redis's confirmation of a subscribe or unsubscribe command, one per channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedisConfirmation<'a> {
+    /// The channel confirmed (including its namespace, if any)
+    pub channel: &'a str,
+    pub(crate) leftover_input: &'a str,
+}
+
+/// An error reply (such as `-READONLY You can't write against a read only replica.`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedisErrorReply<'a> {
+    /// The error, without its leading `-`
+    pub message: &'a str,
+    pub(crate) leftover_input: &'a str,
+}
+
 impl<'a> RedisMsg<'a> {
+    /// The channel name (including its root, but not the namespace), if it's in `namespace`
     pub(super) fn timeline_matching_ns(&self, namespace: &Option<String>) -> Option<&str> {
         match namespace {
             Some(ns) if self.timeline_txt.starts_with(ns) => {
-                Some(&self.timeline_txt[ns.len() + ":timeline:".len()..])
+                Some(&self.timeline_txt[ns.len() + ":".len()..])
             }
-            None => Some(&self.timeline_txt["timeline:".len()..]),
+            None => Some(self.timeline_txt),
             Some(_non_matching_ns) => None,
         }
     }
 }
 
-impl<'a> TryFrom<&'a str> for RedisParseOutput<'a> {
-    type Error = RedisParseErr;
-    fn try_from(utf8: &'a str) -> Result<RedisParseOutput<'a>, Self::Error> {
-        let (structured_txt, leftover_utf8) = utf8_to_redis_data(utf8)?;
+impl<'a> RedisParseOutput<'a> {
+    /// Parse `utf8`, failing with `LineTooLong` (rather than waiting for more input) as soon as
+    /// a type-and-length line runs past `max_line_len` bytes without ending.  Those lines are
+    /// only a type character and a number, so a long one means the framing is corrupt.
+    pub(crate) fn parse(utf8: &'a str, max_line_len: usize) -> Result<Self, RedisParseErr> {
+        let (structured_txt, leftover_utf8) = utf8_to_redis_data(utf8, max_line_len)?;
         let structured_txt = RedisStructuredText {
             structured_txt,
             leftover_input: leftover_utf8,
@@ -63,6 +91,13 @@ impl<'a> TryFrom<&'a str> for RedisParseOutput<'a> {
     }
 }
 
+impl<'a> TryFrom<&'a str> for RedisParseOutput<'a> {
+    type Error = RedisParseErr;
+    fn try_from(utf8: &'a str) -> Result<RedisParseOutput<'a>, Self::Error> {
+        Self::parse(utf8, usize::MAX)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 struct RedisStructuredText<'a> {
     structured_txt: RedisData<'a>,
@@ -72,6 +107,8 @@ struct RedisStructuredText<'a> {
 enum RedisData<'a> {
     RedisArray(Vec<RedisData<'a>>),
     BulkString(&'a str),
+    SimpleString(&'a str),
+    Error(&'a str),
     Integer(usize),
     Uninitilized,
 }
@@ -79,15 +116,21 @@ enum RedisData<'a> {
 use RedisData::*;
 use RedisParseErr::*;
 type RedisParser<'a, Item> = Result<Item, RedisParseErr>;
-fn utf8_to_redis_data<'a>(s: &'a str) -> Result<(RedisData, &'a str), RedisParseErr> {
+fn utf8_to_redis_data<'a>(
+    s: &'a str,
+    max_line_len: usize,
+) -> Result<(RedisData, &'a str), RedisParseErr> {
     if s.len() < 4 {
         Err(Incomplete)?
     };
     let (first_char, s) = s.split_at(1);
     match first_char {
-        ":" => parse_redis_int(s),
-        "$" => parse_redis_bulk_string(s),
-        "*" => parse_redis_array(s),
+        ":" => parse_redis_int(s, max_line_len),
+        "$" => parse_redis_bulk_string(s, max_line_len),
+        "+" => parse_redis_line(s, max_line_len).map(|(line, rest)| (SimpleString(line), rest)),
+        "-" => parse_redis_line(s, max_line_len).map(|(line, rest)| (Error(line), rest)),
+        "*" | ">" => parse_redis_array(s, max_line_len),
+        "%" => parse_redis_map(s, max_line_len),
         e => Err(InvalidLineStart(e.to_string())),
     }
 }
@@ -99,33 +142,74 @@ fn skip_line(s: &str, len: usize) -> RedisParser<&str> {
     Ok(s.get(len + "\r\n".len()..).ok_or(Incomplete)?)
 }
 
-fn parse_number_at<'a>(s: &'a str) -> RedisParser<(usize, &'a str)> {
-    let len = s.chars().position(|c| !c.is_numeric()).ok_or(Incomplete)?;
+/// Parse the number at the start of `s` (the rest of a line that began with a type character),
+/// which ends its line.  The line, including its type character, may not be longer than
+/// `max_line_len`.
+fn parse_number_at<'a>(s: &'a str, max_line_len: usize) -> RedisParser<(usize, &'a str)> {
+    let max_len = max_line_len.saturating_sub(":".len());
+    let len = match s
+        .chars()
+        .take(max_len.saturating_add(1))
+        .position(|c| !c.is_numeric())
+    {
+        Some(len) if len <= max_len => len,
+        _ if s.len() > max_len => Err(LineTooLong(max_line_len))?,
+        _ => Err(Incomplete)?,
+    };
     Ok((s[..len].parse()?, skip_line(s, len)?))
 }
 
 /// Parse a Redis bulk string and return the content of that string and the unparsed remainder.
 ///
 /// All bulk strings have the format `$[LENGTH_OF_ITEM_BODY]\r\n[ITEM_BODY]\r\n`
-fn parse_redis_bulk_string<'a>(s: &'a str) -> RedisParser<(RedisData, &'a str)> {
-    let (len, rest) = parse_number_at(s)?;
+fn parse_redis_bulk_string<'a>(
+    s: &'a str,
+    max_line_len: usize,
+) -> RedisParser<(RedisData, &'a str)> {
+    let (len, rest) = parse_number_at(s, max_line_len)?;
     let content = rest.get(..len).ok_or(Incomplete)?;
     Ok((BulkString(content), skip_line(rest, len)?))
 }
 
-fn parse_redis_int<'a>(s: &'a str) -> RedisParser<(RedisData, &'a str)> {
-    let (number, rest) = parse_number_at(s)?;
+/// Parse a Redis simple string or error (the rest of its line), and return its content and the
+/// unparsed remainder
+fn parse_redis_line<'a>(s: &'a str, max_line_len: usize) -> RedisParser<(&'a str, &'a str)> {
+    let max_len = max_line_len.saturating_sub("+".len());
+    match s.find("\r\n") {
+        Some(len) if len <= max_len => Ok((&s[..len], &s[len + "\r\n".len()..])),
+        Some(_) => Err(LineTooLong(max_line_len)),
+        None if s.len() > max_len => Err(LineTooLong(max_line_len)),
+        None => Err(Incomplete),
+    }
+}
+
+fn parse_redis_int<'a>(s: &'a str, max_line_len: usize) -> RedisParser<(RedisData, &'a str)> {
+    let (number, rest) = parse_number_at(s, max_line_len)?;
     Ok((Integer(number), rest))
 }
 
-fn parse_redis_array<'a>(s: &'a str) -> RedisParser<(RedisData, &'a str)> {
-    let (number_of_elements, mut rest) = parse_number_at(s)?;
+fn parse_redis_array<'a>(s: &'a str, max_line_len: usize) -> RedisParser<(RedisData, &'a str)> {
+    let (number_of_elements, rest) = parse_number_at(s, max_line_len)?;
+    parse_redis_elements(number_of_elements, rest, max_line_len)
+}
+
+/// Parse a RESP3 map (which only the reply to `HELLO` uses) as an array of its keys and values,
+/// in turn
+fn parse_redis_map<'a>(s: &'a str, max_line_len: usize) -> RedisParser<(RedisData, &'a str)> {
+    let (number_of_pairs, rest) = parse_number_at(s, max_line_len)?;
+    parse_redis_elements(number_of_pairs * 2, rest, max_line_len)
+}
 
+fn parse_redis_elements<'a>(
+    number_of_elements: usize,
+    mut rest: &'a str,
+    max_line_len: usize,
+) -> RedisParser<(RedisData, &'a str)> {
     let mut inner = Vec::with_capacity(number_of_elements);
     inner.resize(number_of_elements, RedisData::Uninitilized);
 
     for i in (0..number_of_elements).rev() {
-        let (next_el, new_rest) = utf8_to_redis_data(rest)?;
+        let (next_el, new_rest) = utf8_to_redis_data(rest, max_line_len)?;
         rest = new_rest;
         inner[i] = next_el;
     }
@@ -153,7 +237,10 @@ impl<'a> TryFrom<RedisStructuredText<'a>> for RedisParseOutput<'a> {
                 // subscription statuses look like:
                 // $14\r\ntimeline:local\r\n
                 // :47\r\n
-                "subscribe" | "unsubscribe" => Ok(NonMsg(input.leftover_input)),
+                "subscribe" | "unsubscribe" => Ok(NonMsg(RedisConfirmation {
+                    channel: redis_strings.pop().ok_or(MissingField)?.try_into()?,
+                    leftover_input: input.leftover_input,
+                })),
                 // Messages look like;
                 // $10\r\ntimeline:4\r\n
                 // $1386\r\n{\"event\":\"update\",\"payload\"...\"queued_at\":1569623342825}\r\n
@@ -162,13 +249,40 @@ impl<'a> TryFrom<RedisStructuredText<'a>> for RedisParseOutput<'a> {
                     event_txt: redis_strings.pop().ok_or(MissingField)?.try_into()?,
                     leftover_input: input.leftover_input,
                 })),
+                // replies to a PING while subscribed look like:
+                // $0\r\n\r\n
+                "pong" => Ok(Reply(input.leftover_input)),
                 _cmd => Err(Incomplete),
             }
+        } else if let RedisData::SimpleString(_) | RedisData::Integer(_) = input.structured_txt {
+            Ok(Reply(input.leftover_input))
+        } else if let RedisData::Error(message) = input.structured_txt {
+            Ok(ErrorReply(RedisErrorReply {
+                message,
+                leftover_input: input.leftover_input,
+            }))
         } else {
             Err(IncorrectRedisType)
         }
     }
 }
 
+/// The protocol version in `utf8`'s reply to a `HELLO` (a map with a `proto` entry), and the
+/// input after it.  An error reply (from a Redis too old to know `HELLO`) is `IncorrectRedisType`.
+pub(super) fn parse_hello_reply(utf8: &str) -> Result<(usize, &str), RedisParseErr> {
+    let (reply, leftover_input) = utf8_to_redis_data(utf8, usize::MAX)?;
+    let mut fields = match reply {
+        RedisArray(fields) => fields,
+        _ => Err(IncorrectRedisType)?,
+    };
+    // elements are stored last-first, so popping yields each key and then its value
+    while let (Some(key), Some(value)) = (fields.pop(), fields.pop()) {
+        if let (BulkString("proto"), Integer(proto)) = (key, value) {
+            return Ok((proto, leftover_input));
+        }
+    }
+    Err(MissingField)
+}
+
 #[cfg(test)]
 mod test;
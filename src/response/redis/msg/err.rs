@@ -6,6 +6,7 @@ pub enum RedisParseErr {
     InvalidNumber(std::num::ParseIntError),
     InvalidLineStart(String),
     InvalidLineEnd(usize, String),
+    LineTooLong(usize),
     IncorrectRedisType,
     MissingField,
 }
@@ -32,6 +33,10 @@ impl fmt::Display for RedisParseErr {
                 "A Redis line did not have the promised length of {}.  The line is: {}",
                 len, line
             ),
+            LineTooLong(max_len) => format!(
+                "A Redis line ran past {} bytes without ending, so the input is not valid RESP.",
+                max_len
+            ),
             IncorrectRedisType => "Received a Redis type that is not supported in this context.  \
                 Flodgatt expects each message from Redis to be a Redis array consisting of bulk \
                 strings or integers."
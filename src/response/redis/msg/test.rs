@@ -6,11 +6,14 @@ fn parse_redis_subscribe() -> Result<(), RedisParseErr> {
     let input = "*3\r\n$9\r\nsubscribe\r\n$15\r\ntimeline:public\r\n:1\r\n";
 
     let r_subscribe = match RedisParseOutput::try_from(input) {
-        Ok(NonMsg(leftover)) => leftover,
+        Ok(NonMsg(confirmation)) => confirmation,
         Ok(Msg(msg)) => panic!("unexpectedly got a msg: {:?}", msg),
+        Ok(Reply(leftover)) => panic!("unexpectedly got a reply: {:?}", leftover),
+        Ok(ErrorReply(reply)) => panic!("unexpectedly got an error reply: {:?}", reply),
         Err(e) => panic!("Error in parsing subscribe command: {}", e),
     };
-    assert!(r_subscribe.is_empty());
+    assert_eq!(r_subscribe.channel, "timeline:public");
+    assert!(r_subscribe.leftover_input.is_empty());
 
     Ok(())
 }
@@ -21,30 +24,71 @@ fn parse_redis_detects_non_newline() -> Result<(), RedisParseErr> {
         "*3QQ$7\r\nmessage\r\n$12\r\ntimeline:308\r\n$38\r\n{\"event\":\"delete\",\"payload\":\"1038647\"}\r\n";
 
     match RedisParseOutput::try_from(input) {
-        Ok(NonMsg(leftover)) => panic!(
-            "Parsed an invalid msg as a non-msg.\nInput `{}` parsed to NonMsg({})",
-            &input, leftover
+        Ok(NonMsg(confirmation)) => panic!(
+            "Parsed an invalid msg as a non-msg.\nInput `{}` parsed to NonMsg({:?})",
+            &input, confirmation
         ),
         Ok(Msg(msg)) => panic!(
             "Parsed an invalid msg as a msg.\nInput `{}` parsed to {:?}",
             &input, msg
         ),
+        Ok(Reply(leftover)) => panic!(
+            "Parsed an invalid msg as a reply.\nInput `{}` parsed to Reply({})",
+            &input, leftover
+        ),
+        Ok(ErrorReply(reply)) => panic!(
+            "Parsed an invalid msg as an error reply.\nInput `{}` parsed to {:?}",
+            &input, reply
+        ),
         Err(_) => (), // should err
     };
 
     Ok(())
 }
 
+#[test]
+fn over_length_line_without_crlf_is_a_parse_error() {
+    let input = "*3\r\n$7\r\nmessage\r\n$1234567890123456789";
+
+    match RedisParseOutput::parse(input, 16) {
+        Err(RedisParseErr::LineTooLong(16)) => (),
+        other => panic!("Expected the line to be too long, got {:?}", other),
+    };
+    // Without a limit, the parser waits for the rest of the line
+    assert!(matches!(
+        RedisParseOutput::try_from(input),
+        Err(RedisParseErr::Incomplete)
+    ));
+}
+
+#[test]
+fn unfinished_line_within_the_max_length_is_incomplete() {
+    let input = "*3\r\n$7\r\nmessage\r\n$12345";
+
+    assert!(matches!(
+        RedisParseOutput::parse(input, 16),
+        Err(RedisParseErr::Incomplete)
+    ));
+}
+
 #[test]
 fn parse_redis_msg() -> Result<(), RedisParseErr> {
     let input =
         "*3\r\n$7\r\nmessage\r\n$12\r\ntimeline:308\r\n$38\r\n{\"event\":\"delete\",\"payload\":\"1038647\"}\r\n";
 
     let r_msg = match RedisParseOutput::try_from(input) {
-        Ok(NonMsg(leftover)) => panic!(
+        Ok(NonMsg(confirmation)) => panic!(
             "Parsed a msg as a non-msg.\nInput `{}` parsed to NonMsg({:?})",
+            &input, confirmation
+        ),
+        Ok(Reply(leftover)) => panic!(
+            "Parsed a msg as a reply.\nInput `{}` parsed to Reply({:?})",
             &input, leftover
         ),
+        Ok(ErrorReply(reply)) => panic!(
+            "Parsed a msg as an error reply.\nInput `{}` parsed to {:?}",
+            &input, reply
+        ),
         Ok(Msg(msg)) => msg,
         Err(e) => panic!("Error in parsing subscribe command: {}", e),
     };
@@ -66,10 +110,18 @@ fn parse_long_redis_msg() -> Result<(), Box<dyn std::error::Error>> {
         test_num += 1;
 
         let r_msg = match RedisParseOutput::try_from(input.as_str()) {
-            Ok(NonMsg(leftover)) => panic!(
+            Ok(NonMsg(confirmation)) => panic!(
                 "Parsed a msg as a non-msg.\nInput `{}` parsed to NonMsg({:?})",
+                &input, confirmation
+            ),
+            Ok(Reply(leftover)) => panic!(
+                "Parsed a msg as a reply.\nInput `{}` parsed to Reply({:?})",
                 &input, leftover
             ),
+            Ok(ErrorReply(reply)) => panic!(
+                "Parsed a msg as an error reply.\nInput `{}` parsed to {:?}",
+                &input, reply
+            ),
             Ok(Msg(msg)) => msg,
             Err(e) => panic!("Error in parsing Redis input: {}", e),
         };
@@ -82,3 +134,100 @@ fn parse_long_redis_msg() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn resp3_push_msg_parses_like_a_resp2_msg() -> Result<(), RedisParseErr> {
+    let event = r#"{"event":"delete","payload":"1038647"}"#;
+    let input = [
+        ">3\r\n$7\r\nmessage\r\n$12\r\ntimeline:308\r\n$38\r\n",
+        event,
+        "\r\n",
+    ]
+    .concat();
+
+    let resp2_input = input.replacen('>', "*", 1);
+    assert_eq!(
+        RedisParseOutput::try_from(input.as_str())?,
+        RedisParseOutput::try_from(resp2_input.as_str())?
+    );
+    match RedisParseOutput::try_from(input.as_str())? {
+        Msg(msg) => assert_eq!((msg.timeline_txt, msg.event_txt), ("timeline:308", event)),
+        other => panic!("Expected a msg, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn resp3_push_subscribe_is_a_non_msg() -> Result<(), RedisParseErr> {
+    let input = ">3\r\n$9\r\nsubscribe\r\n$15\r\ntimeline:public\r\n:1\r\n";
+
+    let confirmation = RedisConfirmation {
+        channel: "timeline:public",
+        leftover_input: "",
+    };
+    assert_eq!(RedisParseOutput::try_from(input)?, NonMsg(confirmation));
+    Ok(())
+}
+
+#[test]
+fn replies_to_other_commands_are_skipped() -> Result<(), RedisParseErr> {
+    let msg = "*3\r\n$7\r\nmessage\r\n$12\r\ntimeline:308\r\n$2\r\n{}\r\n";
+    let input = ["+OK\r\n", msg].concat();
+
+    assert_eq!(RedisParseOutput::try_from(input.as_str())?, Reply(msg));
+    assert_eq!(RedisParseOutput::try_from("+PONG\r\n")?, Reply(""));
+    assert_eq!(RedisParseOutput::try_from(":12\r\n")?, Reply(""));
+    assert!(matches!(
+        RedisParseOutput::try_from("+PON"),
+        Err(RedisParseErr::Incomplete)
+    ));
+    Ok(())
+}
+
+#[test]
+fn error_replies_are_parsed_with_their_message() -> Result<(), RedisParseErr> {
+    let msg = "*3\r\n$7\r\nmessage\r\n$12\r\ntimeline:308\r\n$2\r\n{}\r\n";
+    let input = [
+        "-READONLY You can't write against a read only replica.\r\n",
+        msg,
+    ]
+    .concat();
+
+    let reply = RedisErrorReply {
+        message: "READONLY You can't write against a read only replica.",
+        leftover_input: msg,
+    };
+    assert_eq!(
+        RedisParseOutput::try_from(input.as_str())?,
+        ErrorReply(reply)
+    );
+    Ok(())
+}
+
+#[test]
+fn pong_while_subscribed_is_a_reply() -> Result<(), RedisParseErr> {
+    let msg = "*3\r\n$7\r\nmessage\r\n$12\r\ntimeline:308\r\n$2\r\n{}\r\n";
+    let input = ["*2\r\n$4\r\npong\r\n$0\r\n\r\n", msg].concat();
+
+    assert_eq!(RedisParseOutput::try_from(input.as_str())?, Reply(msg));
+    Ok(())
+}
+
+#[test]
+fn hello_reply_reports_the_protocol() -> Result<(), RedisParseErr> {
+    let reply = "%7\r\n$6\r\nserver\r\n$5\r\nredis\r\n$7\r\nversion\r\n$5\r\n6.2.6\r\n\
+                 $5\r\nproto\r\n:3\r\n$2\r\nid\r\n:10\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n\
+                 $4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n";
+    let input = [reply, "+PONG\r\n"].concat();
+
+    assert_eq!(parse_hello_reply(&input)?, (3, "+PONG\r\n"));
+    assert!(matches!(
+        parse_hello_reply(&reply[..reply.len() - 6]),
+        Err(RedisParseErr::Incomplete)
+    ));
+    assert!(matches!(
+        parse_hello_reply("-ERR unknown command `HELLO`, with args beginning with: `3`, \r\n"),
+        Err(RedisParseErr::IncorrectRedisType)
+    ));
+    Ok(())
+}
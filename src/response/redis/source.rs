@@ -0,0 +1,426 @@
+//! The `MessageSource` abstraction that the `Manager` receives events through, and its
+//! default Redis implementation.
+use super::msg::{RedisConfirmation, RedisErrorReply, RedisParseErr, RedisParseOutput};
+use super::{Error, Event, RedisCmd, RedisConn, TagCache};
+use crate::config;
+use crate::request::Timeline;
+
+use futures::{Async, Poll, Stream};
+use hashbrown::HashMap;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::str;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Something the `Manager` can subscribe to `Timeline`s with and poll for `Event`s.
+///
+/// Redis is the default (and, for now, only production) source, but the `Manager` relies on
+/// nothing else, so an alternate message broker can reuse all of the delivery machinery.
+pub trait MessageSource {
+    /// Start receiving events for each of the `timelines`
+    fn subscribe(&mut self, timelines: &[Timeline]) -> Result<()>;
+
+    /// Stop receiving events for each of the `timelines`
+    fn unsubscribe(&mut self, timelines: &[Timeline]) -> Result<()>;
+
+    /// Poll for the next event.  `Ready(None)` means the source made progress without producing
+    /// an event (and should be polled again); `NotReady` means no more input is available yet.
+    fn poll_event(&mut self) -> Poll<Option<(Timeline, Arc<Event>)>, Error>;
+
+    /// Arrange for the next `poll_event` to return the most recently polled event again (used
+    /// when that event couldn't be delivered)
+    fn replay_last(&mut self);
+
+    /// Replace the underlying connection with a new one subscribed to `timelines`
+    fn reconnect(&mut self, timelines: &[Timeline]) -> Result<()>;
+
+    /// Record that the hashtag `name` has the id `id`, for sources that address hashtags by name
+    fn register_tag(&mut self, _name: &str, _id: i64) {}
+
+    /// Up to `limit` of the most recent events on `timeline`, oldest first, for sources that
+    /// keep a history (others have none).
+    fn history(&mut self, _timeline: Timeline, _limit: usize) -> Result<Vec<Arc<Event>>> {
+        Ok(Vec::new())
+    }
+
+    /// When the oldest subscribe or unsubscribe that hasn't been confirmed yet was sent, for
+    /// sources that confirm them (others are never waiting on a confirmation)
+    fn unconfirmed_since(&self) -> Option<Instant> {
+        None
+    }
+
+    /// How the source's hashtag caches are faring, for sources that have them
+    fn tag_cache_stats(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Store `value` at `key`, for sources that support it (others ignore it)
+    fn store(&mut self, _key: &str, _value: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Ask for a reply that `poll_event` will read like any other input, so that a quiet
+    /// connection can be told from a stuck one (for sources that can be asked)
+    fn ping(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A `MessageSource` that reads from Redis PubSub.
+pub struct RedisSource {
+    pub redis_conn: RedisConn,
+    pub unread_idx: (usize, usize),
+    pub(super) tag_id_cache: TagCache<String, i64>,
+    max_message_bytes: usize,
+    max_line_len: usize,
+    passthrough_unknown_events: bool,
+    stringify_ids: bool,
+    /// Each channel still awaiting a subscribe or unsubscribe confirmation, and when its command
+    /// was sent, oldest first (Redis confirms each channel of a command separately)
+    unconfirmed: VecDeque<(String, Instant)>,
+    /// The most recent events on each subscribed channel, oldest first and up to the configured
+    /// `backfill_max`.  Mastodon only caches the ids of (some) timelines' statuses in Redis, not
+    /// the statuses themselves, so this is the only history there is: the events received since
+    /// the channel was last subscribed.
+    history: HashMap<Timeline, VecDeque<Arc<Event>>>,
+    /// Whether the next event polled is one already polled (and recorded in the `history`)
+    replaying: bool,
+    redis_cfg: config::Redis,
+}
+
+impl Stream for RedisSource {
+    type Item = (Timeline, Arc<Event>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Error> {
+        let input = &self.redis_conn.input[self.unread_idx.0..self.unread_idx.1];
+        let (valid, invalid) = str::from_utf8(input)
+            .map(|v| (v, &b""[..]))
+            .unwrap_or_else(|e| {
+                // NOTE - this bounds check occurs more often than necessary; it could occur only when
+                // polling Redis.  However, benchmarking with Criterion shows it to be *very*
+                // inexpensive (<1 us) and thus not worth removing (doing so would require `unsafe`).
+                let (valid, invalid) = input.split_at(e.valid_up_to());
+                (str::from_utf8(valid).expect("split_at"), invalid)
+            });
+
+        if !valid.is_empty() {
+            use RedisParseOutput::*;
+            match RedisParseOutput::parse(valid, self.max_line_len) {
+                Ok(Msg(msg)) => {
+                    // If we get a message and it matches the redis_namespace, get the msg's
+                    // Event and send it to all channels matching the msg's Timeline
+                    if let Some(tl) = msg.timeline_matching_ns(&self.redis_conn.namespace) {
+                        self.unread_idx.0 =
+                            self.unread_idx.1 - msg.leftover_input.len() - invalid.len();
+
+                        let root = &self.redis_conn.channel_root;
+                        let tl =
+                            Timeline::from_redis_raw_timeline(tl, root, &mut self.tag_id_cache)?;
+                        let stringified = if self.stringify_ids {
+                            Event::stringify_ids(msg.event_txt)
+                        } else {
+                            None
+                        };
+                        let txt = stringified.as_deref().unwrap_or(msg.event_txt);
+                        let unparseable = |e| Error::UnparseableEvent(e, txt.to_string());
+                        let event = match Event::try_from(txt) {
+                            Ok(event) => event,
+                            Err(e) if self.passthrough_unknown_events => {
+                                Event::passthrough(txt).ok_or_else(|| unparseable(e))?
+                            }
+                            Err(e) => Err(unparseable(e))?,
+                        };
+                        let event = Arc::new(event);
+                        Ok(Async::Ready(Some((tl, event))))
+                    } else {
+                        Ok(Async::Ready(None))
+                    }
+                }
+                Ok(NonMsg(confirmation)) => {
+                    // The only non-messages we parse are subscribe/unsubscribe confirmations
+                    self.confirm(confirmation.channel);
+                    self.unread_idx.0 = self.unread_idx.1 - confirmation.leftover_input.len();
+                    Ok(Async::Ready(None))
+                }
+                Ok(Reply(leftover_input)) => {
+                    self.unread_idx.0 = self.unread_idx.1 - leftover_input.len();
+                    Ok(Async::Ready(None))
+                }
+                Ok(ErrorReply(reply)) => {
+                    log::error!("Redis refused a command: {}", reply.message);
+                    self.unread_idx.0 = self.unread_idx.1 - reply.leftover_input.len();
+                    Ok(Async::Ready(None))
+                }
+                Err(RedisParseErr::Incomplete) => {
+                    self.copy_partial_msg();
+                    Ok(Async::NotReady)
+                }
+                Err(e @ RedisParseErr::LineTooLong(_)) => {
+                    // The framing is lost, so none of the buffered input can be trusted
+                    self.unread_idx = (0, 0);
+                    Err(Error::RedisParseErr(e, valid.to_string()))?
+                }
+                Err(e) => Err(Error::RedisParseErr(e, valid.to_string()))?,
+            }
+        } else {
+            self.unread_idx = (0, 0);
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl MessageSource for RedisSource {
+    fn subscribe(&mut self, timelines: &[Timeline]) -> Result<()> {
+        let channels = self.redis_conn.send_cmd(RedisCmd::Subscribe, timelines)?;
+        self.await_confirmations(channels);
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, timelines: &[Timeline]) -> Result<()> {
+        for tl in timelines {
+            self.history.remove(tl);
+        }
+        let channels = self.redis_conn.send_cmd(RedisCmd::Unsubscribe, timelines)?;
+        self.await_confirmations(channels);
+        Ok(())
+    }
+
+    fn poll_event(&mut self) -> Poll<Option<(Timeline, Arc<Event>)>, Error> {
+        match self.poll()? {
+            Async::Ready(Some((tl, event))) => {
+                if !std::mem::replace(&mut self.replaying, false) {
+                    self.record(tl, &event);
+                }
+                Ok(Async::Ready(Some((tl, event))))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => {
+                // Whatever is left can't be parsed until more input arrives; if there's too
+                // much of it, the stream is malformed and we'd just keep buffering forever
+                let unparsed = self.unread_idx.1 - self.unread_idx.0;
+                if unparsed > self.max_message_bytes {
+                    self.unread_idx = (0, 0);
+                    Err(Error::OversizedInput(unparsed))?
+                }
+                match self.redis_conn.poll_redis(self.unread_idx.1)? {
+                    Async::Ready(Some(msg_len)) => {
+                        self.unread_idx.1 += msg_len;
+                        Ok(Async::Ready(None))
+                    }
+                    Async::Ready(None) | Async::NotReady => Ok(Async::NotReady),
+                }
+            }
+        }
+    }
+
+    fn replay_last(&mut self) {
+        self.rewind_to_prev_msg();
+        self.replaying = true;
+    }
+
+    fn reconnect(&mut self, timelines: &[Timeline]) -> Result<()> {
+        let mut conn = RedisConn::new(&self.redis_cfg)?;
+        std::mem::swap(
+            &mut conn.tag_name_cache,
+            &mut self.redis_conn.tag_name_cache,
+        );
+        self.redis_conn = conn;
+        self.unread_idx = (0, 0);
+        self.unconfirmed.clear();
+
+        if !timelines.is_empty() {
+            self.subscribe(timelines)?;
+        }
+        Ok(())
+    }
+
+    fn register_tag(&mut self, name: &str, id: i64) {
+        self.tag_id_cache.put(name.to_string(), id);
+        self.redis_conn.tag_name_cache.put(id, name.to_string());
+    }
+
+    fn history(&mut self, timeline: Timeline, limit: usize) -> Result<Vec<Arc<Event>>> {
+        let history = match self.history.get(&timeline) {
+            Some(history) => history,
+            None => return Ok(Vec::new()),
+        };
+        let skipped = history.len().saturating_sub(limit);
+        Ok(history.iter().skip(skipped).cloned().collect())
+    }
+
+    fn unconfirmed_since(&self) -> Option<Instant> {
+        self.unconfirmed.front().map(|(_channel, sent_at)| *sent_at)
+    }
+
+    fn tag_cache_stats(&self) -> Option<serde_json::Value> {
+        Some(serde_json::json!({
+            "ids": self.tag_id_cache.stats(),
+            "names": self.redis_conn.tag_name_cache.stats(),
+        }))
+    }
+
+    fn store(&mut self, key: &str, value: &str) -> Result<()> {
+        Ok(self.redis_conn.set(key, value)?)
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        Ok(self.redis_conn.ping()?)
+    }
+}
+
+impl RedisSource {
+    /// Create a new `RedisSource`, with its own Redis connections (but no active subscriptions).
+    pub fn try_from(redis_cfg: &config::Redis) -> Result<Self> {
+        Ok(Self {
+            redis_conn: RedisConn::new(redis_cfg)?,
+            unread_idx: (0, 0),
+            tag_id_cache: TagCache::new(*redis_cfg.tag_cache_size),
+            max_message_bytes: *redis_cfg.max_message_bytes,
+            max_line_len: *redis_cfg.max_line_length,
+            passthrough_unknown_events: *redis_cfg.passthrough_unknown_events,
+            stringify_ids: *redis_cfg.stringify_ids,
+            unconfirmed: VecDeque::new(),
+            history: HashMap::new(),
+            replaying: false,
+            redis_cfg: redis_cfg.clone(),
+        })
+    }
+
+    /// Listen on the public timeline's channel, both with and without the configured namespace,
+    /// for up to `wait`.  If only the channel without the namespace receives messages, Mastodon
+    /// is almost certainly using a different namespace (or none), so this returns a warning.
+    ///
+    /// This must be called before subscribing to any timelines, since it discards the input.
+    pub(super) fn check_namespace(&mut self, wait: Duration) -> Result<Option<String>> {
+        let namespace = match &self.redis_conn.namespace {
+            Some(namespace) => namespace.clone(),
+            None => return Ok(None), // with no namespace, there's nothing to check against
+        };
+        let bare = [&self.redis_conn.channel_root, ":public"].concat();
+        let namespaced = [&namespace, ":", &bare].concat();
+        let channels = [namespaced.clone(), bare.clone()];
+        self.redis_conn
+            .send_channel_cmd(RedisCmd::Subscribe, &channels)?;
+
+        let (mut namespaced_msgs, mut bare_msgs) = (0, 0);
+        let started = Instant::now();
+        while started.elapsed() < wait && namespaced_msgs == 0 {
+            match self.redis_conn.poll_redis(self.unread_idx.1)? {
+                Async::Ready(Some(len)) => self.unread_idx.1 += len,
+                Async::Ready(None) | Async::NotReady => thread::sleep(Duration::from_millis(10)),
+            }
+            let input = &self.redis_conn.input[self.unread_idx.0..self.unread_idx.1];
+            let mut valid = str::from_utf8(input).unwrap_or_else(|e| {
+                str::from_utf8(&input[..e.valid_up_to()]).expect("guaranteed by `valid_up_to`")
+            });
+            let unread_len = valid.len();
+            while let Ok(output) = RedisParseOutput::parse(valid, self.max_line_len) {
+                valid = match output {
+                    RedisParseOutput::Msg(msg) => {
+                        match msg.timeline_txt {
+                            channel if channel == namespaced => namespaced_msgs += 1,
+                            channel if channel == bare => bare_msgs += 1,
+                            _ => (),
+                        }
+                        msg.leftover_input
+                    }
+                    RedisParseOutput::NonMsg(RedisConfirmation { leftover_input, .. })
+                    | RedisParseOutput::ErrorReply(RedisErrorReply { leftover_input, .. })
+                    | RedisParseOutput::Reply(leftover_input) => leftover_input,
+                };
+            }
+            self.unread_idx.0 += unread_len - valid.len();
+        }
+
+        self.redis_conn
+            .send_channel_cmd(RedisCmd::Unsubscribe, &channels)?;
+        self.unread_idx = (0, 0);
+        Ok(match (namespaced_msgs, bare_msgs) {
+            (0, n) if n > 0 => Some(format!(
+                "Received {} messages on `{}` but none on `{}` in {:?}.  REDIS_NAMESPACE is \
+                 probably set incorrectly: it should match Mastodon's REDIS_NAMESPACE (and be \
+                 unset if Mastodon doesn't use one).",
+                n, bare, namespaced, wait
+            )),
+            _ => None,
+        })
+    }
+
+    /// Add the `event` to the `history` of `timeline`, dropping the oldest event once it's full
+    fn record(&mut self, timeline: Timeline, event: &Arc<Event>) {
+        let max = *self.redis_cfg.backfill_max;
+        if max == 0 {
+            return;
+        }
+        let history = self.history.entry(timeline).or_default();
+        if history.len() == max {
+            history.pop_front();
+        }
+        history.push_back(event.clone());
+    }
+
+    /// Expect a confirmation for each of the `channels` just sent a command
+    fn await_confirmations(&mut self, channels: Vec<String>) {
+        let sent_at = Instant::now();
+        let channels = channels.into_iter().map(|channel| (channel, sent_at));
+        self.unconfirmed.extend(channels);
+    }
+
+    /// Record Redis's confirmation for the `channel`, which settles the oldest command still
+    /// awaiting one for that channel.  A confirmation for a channel no command is awaiting
+    /// (such as the channels `check_namespace` listens on) settles nothing.
+    fn confirm(&mut self, channel: &str) {
+        match self
+            .unconfirmed
+            .iter()
+            .position(|(sent, _)| sent == channel)
+        {
+            Some(i) => {
+                self.unconfirmed.remove(i);
+            }
+            None => log::debug!("Ignoring an unexpected confirmation for `{}`", channel),
+        }
+    }
+
+    pub(super) fn rewind_to_prev_msg(&mut self) {
+        self.unread_idx.0 = loop {
+            let input = &self.redis_conn.input[..self.unread_idx.0];
+            let input = str::from_utf8(input).unwrap_or_else(|e| {
+                str::from_utf8(input.split_at(e.valid_up_to()).0).expect("guaranteed by `split_at`")
+            });
+
+            let index = if let Some(i) = input.rfind("\r\n*") {
+                i + "\r\n".len()
+            } else {
+                0
+            };
+            self.unread_idx.0 = index;
+
+            if let Ok(Async::Ready(Some(_))) = self.poll() {
+                break index;
+            }
+        }
+    }
+
+    fn copy_partial_msg(&mut self) {
+        if self.unread_idx.0 == 0 {
+            // msg already first; no copying needed
+        } else if self.unread_idx.0 >= (self.unread_idx.1 - self.unread_idx.0) {
+            let (read, unread) =
+                self.redis_conn.input[..self.unread_idx.1].split_at_mut(self.unread_idx.0);
+            for (i, b) in unread.iter().enumerate() {
+                read[i] = *b;
+            }
+        } else {
+            // Less efficient, but should never occur in production
+            log::warn!("Moving partial input requires heap allocation");
+            self.redis_conn.input = self.redis_conn.input[self.unread_idx.0..].into();
+        }
+        self.unread_idx = (0, self.unread_idx.1 - self.unread_idx.0);
+    }
+}
@@ -0,0 +1,75 @@
+//! The LRU caches that translate between hashtag names and ids, with counts of how their
+//! lookups fare so that operators can tell whether they're big enough.
+use crate::request::TagIds;
+
+use lru::LruCache;
+use std::hash::Hash;
+
+#[cfg(test)]
+mod test;
+
+/// An `LruCache` that counts its hits, misses and evictions
+#[derive(Debug)]
+pub(crate) struct TagCache<K: Hash + Eq, V> {
+    cache: LruCache<K, V>,
+    hits: usize,
+    misses: usize,
+    evictions: usize,
+}
+
+impl<K: Hash + Eq, V> TagCache<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            cache: LruCache::new(capacity),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Look up `key`, counting the lookup as a hit or a miss
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        let value = self.cache.get(key);
+        if value.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        value
+    }
+
+    /// Look up `key` without counting the lookup or marking the entry as recently used (for
+    /// bookkeeping that shouldn't skew the counts)
+    pub(crate) fn peek(&self, key: &K) -> Option<&V> {
+        self.cache.peek(key)
+    }
+
+    /// Insert `value` at `key`, counting the entry this evicts (if any)
+    pub(crate) fn put(&mut self, key: K, value: V) {
+        if self.cache.len() == self.cache.cap() && !self.cache.contains(&key) {
+            self.evictions += 1;
+        }
+        self.cache.put(key, value);
+    }
+
+    pub(crate) fn pop(&mut self, key: &K) -> Option<V> {
+        self.cache.pop(key)
+    }
+
+    /// The counts since startup, along with the cache's size
+    pub(crate) fn stats(&self) -> serde_json::Value {
+        serde_json::json!({
+            "hits": self.hits,
+            "misses": self.misses,
+            "evictions": self.evictions,
+            "len": self.cache.len(),
+            "capacity": self.cache.cap(),
+        })
+    }
+}
+
+impl TagIds for TagCache<String, i64> {
+    fn tag_id(&mut self, name: &str) -> Option<i64> {
+        self.get(&name.to_string()).copied()
+    }
+}
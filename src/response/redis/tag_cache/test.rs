@@ -0,0 +1,37 @@
+use super::*;
+
+#[test]
+fn a_miss_then_a_hit_are_counted() {
+    let mut cache = TagCache::new(10);
+
+    assert_eq!(cache.get(&5), None);
+    cache.put(5, "rust".to_string());
+    assert_eq!(cache.get(&5), Some(&"rust".to_string()));
+
+    assert_eq!(cache.stats()["misses"], 1);
+    assert_eq!(cache.stats()["hits"], 1);
+}
+
+#[test]
+fn evictions_are_counted_only_when_the_cache_is_full() {
+    let mut cache = TagCache::new(2);
+    cache.put(1, "one");
+    cache.put(2, "two");
+    cache.put(2, "deux"); // replaces an entry, rather than evicting one
+    assert_eq!(cache.stats()["evictions"], 0);
+
+    cache.put(3, "three");
+    assert_eq!(cache.stats()["evictions"], 1);
+    assert_eq!(cache.stats()["len"], 2);
+}
+
+#[test]
+fn peeking_is_not_counted() {
+    let mut cache = TagCache::new(2);
+    cache.put(1, "one");
+
+    assert_eq!(cache.peek(&1), Some(&"one"));
+    assert_eq!(cache.peek(&2), None);
+    assert_eq!(cache.stats()["hits"], 0);
+    assert_eq!(cache.stats()["misses"], 0);
+}
@@ -1,7 +1,21 @@
-pub use sse::Sse;
-pub use ws::Ws;
+pub use local::LocalSubscriber;
+pub use socket::{bind_with_backlog, with_send_buffer};
+pub use sse::{Sse, SseOptions};
+use summary::Summary;
+use throttle::Throttle;
+pub use write_failures::WriteFailures;
+pub use ws::{Ws, WsOptions};
 
-pub(self) use super::{Event, Payload};
+pub(self) use super::{DroppedEvents, Event, EventIds, Payload};
+use filter::{skip_reason, RecentIds};
+use ordering::{DeliveryOrder, FairOrder};
 
+mod filter;
+mod local;
+mod ordering;
+mod socket;
 mod sse;
+mod summary;
+mod throttle;
+mod write_failures;
 mod ws;
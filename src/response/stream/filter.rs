@@ -0,0 +1,86 @@
+//! The checks that decide which events a client is sent, shared by both kinds of stream
+use super::{Event, Payload};
+use crate::request::{Subscription, Timeline};
+use crate::Id;
+
+use std::collections::VecDeque;
+
+/// The ids of the statuses most recently sent to a client of several merged timelines (such as
+/// the `firehose`), since the same status is often published to more than one of them
+#[derive(Debug)]
+pub(super) struct RecentIds(VecDeque<Id>);
+
+impl RecentIds {
+    /// How many recently sent status ids to remember
+    const CAPACITY: usize = 100;
+
+    /// The ids to check for a client of the `subscription`, if it merges several timelines
+    pub(super) fn for_subscription(subscription: &Subscription) -> Option<Self> {
+        Some(Self(VecDeque::with_capacity(Self::CAPACITY))).filter(|_| subscription.firehose)
+    }
+
+    /// Whether `event` is a status that has already been sent
+    pub(super) fn repeats(&self, event: &Event) -> bool {
+        event.status_id().map_or(false, |id| self.0.contains(&id))
+    }
+
+    /// Remember that `event` (if it's a status) has been sent
+    pub(super) fn sent(&mut self, event: &Event) {
+        if let Some(id) = event.status_id() {
+            if self.0.len() == Self::CAPACITY {
+                self.0.pop_front();
+            }
+            self.0.push_back(id);
+        }
+    }
+}
+
+/// Why the `update` from `timeline` shouldn't be sent to a client of the `subscription`, if it
+/// shouldn't be
+pub(super) fn skip_reason(
+    subscription: &Subscription,
+    timeline: Timeline,
+    update: &impl Payload,
+) -> Option<&'static str> {
+    let (blocks, filters) = (&subscription.blocks, &subscription.filters);
+    match subscription.timeline {
+        tl if tl.is_public()
+            && !update.language_unset()
+            && !subscription.allows_language(&update.language()) =>
+        {
+            Some("disallowed language")
+        }
+        _ if timeline.is_federated_public() && update.local_only() => Some("local-only status"),
+        tl if tl.is_public()
+            && subscription.is_from_unestablished_account(
+                update.author_created_day(),
+                update.author_followers(),
+            ) =>
+        {
+            Some("from new or little-followed account")
+        }
+        tl if subscription.exclude_self && tl.user_id() == Some(*update.author()) => {
+            Some("user's own status")
+        }
+        _ if subscription.is_from_non_member(update.author()) => Some("from non-member of list"),
+        _ if subscription.is_from_unlisted_account(update.author()) => {
+            Some("from unlisted account")
+        }
+        _ if !blocks.blocked_users.is_disjoint(&update.involved_users()) => {
+            Some("involves blocked user")
+        }
+        _ if blocks.blocking_users.contains(update.author()) => Some("from blocking user"),
+        _ if update
+            .sent_from_domains()
+            .iter()
+            .any(|domain| blocks.blocked_domains.contains(*domain)) =>
+        {
+            Some("from blocked domain")
+        }
+        _ if filters.match_any(&update.filterable_text()) => Some("matches keyword filter"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test;
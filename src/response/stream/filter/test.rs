@@ -0,0 +1,35 @@
+use super::*;
+use std::convert::TryFrom;
+use std::fs;
+
+fn update_event() -> Event {
+    let txt = fs::read_to_string("test_data/msg.event_txt_001.txt").expect("test input");
+    Event::try_from(txt).expect("valid event")
+}
+
+#[test]
+fn only_merged_timelines_check_for_repeats() {
+    let firehose = Subscription {
+        firehose: true,
+        ..Subscription::default()
+    };
+
+    assert!(RecentIds::for_subscription(&firehose).is_some());
+    assert!(RecentIds::for_subscription(&Subscription::default()).is_none());
+}
+
+#[test]
+fn statuses_repeat_once_sent() {
+    let firehose = Subscription {
+        firehose: true,
+        ..Subscription::default()
+    };
+    let mut recent_ids = RecentIds::for_subscription(&firehose).expect("merged timelines");
+    let (update, ping) = (update_event(), Event::Ping);
+
+    assert!(!recent_ids.repeats(&update));
+    recent_ids.sent(&update);
+    recent_ids.sent(&ping);
+    assert!(recent_ids.repeats(&update));
+    assert!(!recent_ids.repeats(&ping));
+}
@@ -0,0 +1,146 @@
+use super::Event;
+use crate::request::Timeline;
+
+use futures::{Future, Stream};
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Receiver;
+
+type EventRx = Receiver<(Timeline, Arc<Event>)>;
+
+/// Writes a timeline's events to a local Unix socket (such as an indexer's), as
+/// newline-delimited JSON
+///
+/// Writes (and connects) never block.  Output the reader hasn't taken yet is buffered (up to
+/// `MAX_UNSENT` bytes, beyond which events are dropped), and if the reader goes away, its unsent
+/// output is discarded and the socket is reconnected, with a growing backoff between attempts
+/// during which events are dropped.  Either way, this keeps taking events from the `Manager`,
+/// so a stuck or missing reader never holds up any other client.
+pub struct LocalSubscriber {
+    path: PathBuf,
+    socket: Option<UnixStream>,
+    unsent: Vec<u8>,
+    /// When to next try to connect, after a failure
+    retry_at: Option<Instant>,
+    /// How long to wait after the next failure to connect or write
+    backoff: Duration,
+    /// When a failure was last logged, and how many events have been dropped since
+    warned_at: Option<Instant>,
+    dropped: usize,
+}
+
+impl LocalSubscriber {
+    /// The most output to buffer while the reader is falling behind
+    const MAX_UNSENT: usize = 1024 * 1024;
+    /// How long we wait to reconnect after a failure, doubling after each failure in a row
+    const BACKOFF_MIN: Duration = Duration::from_millis(100);
+    const BACKOFF_MAX: Duration = Duration::from_secs(30);
+    /// How often to log that events are being dropped, at most
+    const WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            socket: None,
+            unsent: Vec::new(),
+            retry_at: None,
+            backoff: Self::BACKOFF_MIN,
+            warned_at: None,
+            dropped: 0,
+        }
+    }
+
+    /// Write the events from `event_rx` to the socket, until the `Manager` closes the channel
+    pub fn send_events(mut self, event_rx: EventRx) -> impl Future<Item = (), Error = ()> {
+        event_rx
+            .map_err(|e| log::error!("{}", e))
+            .for_each(move |(_timeline, event)| {
+                if !matches!(*event, Event::Ping) {
+                    self.send(&event, Instant::now());
+                }
+                Ok(())
+            })
+    }
+
+    /// Send `event` (at `now`), unless the reader is falling behind or we're waiting to
+    /// reconnect to it
+    fn send(&mut self, event: &Event, now: Instant) {
+        if self.retry_at.map_or(false, |at| now < at) {
+            return self.drop_event(now, "waiting to reconnect");
+        }
+        if self.unsent.len() < Self::MAX_UNSENT {
+            self.unsent.extend(event.to_json_string().as_bytes());
+            self.unsent.push(b'\n');
+        } else {
+            self.drop_event(now, "falling behind");
+        }
+        match self.flush() {
+            Ok(()) => {
+                self.retry_at = None;
+                self.backoff = Self::BACKOFF_MIN;
+            }
+            Err(e) => {
+                log::debug!("Could not write to {}: {}", self.path.display(), e);
+                self.unsent.clear();
+                self.drop_event(now, &e.to_string());
+                self.retry_at = Some(now + self.backoff);
+                self.backoff = (self.backoff * 2).min(Self::BACKOFF_MAX);
+            }
+        }
+    }
+
+    /// Count a dropped event, logging (at most every `WARN_INTERVAL`) why events are dropped
+    fn drop_event(&mut self, now: Instant, why: &str) {
+        self.dropped += 1;
+        if self
+            .warned_at
+            .map_or(true, |at| now >= at + Self::WARN_INTERVAL)
+        {
+            log::warn!(
+                "Dropped {} events for the local subscriber at {} ({})",
+                self.dropped,
+                self.path.display(),
+                why
+            );
+            self.warned_at = Some(now);
+            self.dropped = 0;
+        }
+    }
+
+    /// Write as much of the unsent output as the socket takes without blocking, connecting
+    /// first if need be.  After an error, the socket is dropped (to be reconnected next time).
+    fn flush(&mut self) -> io::Result<()> {
+        let mut socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => self.connect()?,
+        };
+        while !self.unsent.is_empty() {
+            match socket.write(&self.unsent) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.unsent.drain(..written);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    /// Connect to the reader without blocking: if it isn't accepting connections (say, its
+    /// backlog is full), this fails rather than waiting for it.
+    fn connect(&self) -> io::Result<UnixStream> {
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        socket.set_nonblocking(true)?;
+        socket.connect(&SockAddr::unix(&self.path)?)?;
+        Ok(socket.into())
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,124 @@
+use super::*;
+use futures::future::lazy;
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader};
+use std::os::unix::net::UnixListener;
+
+type TestResult = std::result::Result<(), Box<dyn std::error::Error>>;
+
+/// A channel holding the `events`, which is closed once they've been received
+fn event_rx(events: Vec<Event>) -> std::result::Result<EventRx, Box<dyn std::error::Error>> {
+    let (mut event_tx, event_rx) = tokio::sync::mpsc::channel(10);
+    lazy(move || {
+        for event in events {
+            event_tx.try_send((Timeline::empty(), Arc::new(event)))?;
+        }
+        Ok::<_, tokio::sync::mpsc::error::TrySendError<_>>(())
+    })
+    .wait()?;
+    Ok(event_rx)
+}
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("flodgatt-{}-{}.sock", name, std::process::id()))
+}
+
+#[test]
+fn events_are_written_to_the_socket_one_per_line() -> TestResult {
+    let path = socket_path("local-subscriber");
+    std::fs::remove_file(&path).unwrap_or_default();
+    let listener = UnixListener::bind(&path)?;
+    let event = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+
+    let events = event_rx(vec![event.clone(), Event::Ping, event.clone()])?;
+    LocalSubscriber::new(&path)
+        .send_events(events)
+        .wait()
+        .map_err(|()| "sending failed")?;
+
+    let (socket, _) = listener.accept()?;
+    let lines = BufReader::new(socket)
+        .lines()
+        .collect::<io::Result<Vec<_>>>()?;
+    assert_eq!(lines, vec![event.to_json_string(), event.to_json_string()]);
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn events_are_taken_even_without_a_reader() -> TestResult {
+    let path = socket_path("missing-subscriber");
+    let event = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+
+    let events = event_rx(vec![event.clone(), event])?;
+    LocalSubscriber::new(&path)
+        .send_events(events)
+        .wait()
+        .map_err(|()| "sending failed")?;
+    Ok(())
+}
+
+#[test]
+fn reconnects_only_once_the_backoff_has_passed() -> TestResult {
+    let path = socket_path("late-subscriber");
+    std::fs::remove_file(&path).unwrap_or_default();
+    let event = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    let mut subscriber = LocalSubscriber::new(&path);
+    let start = Instant::now();
+
+    subscriber.send(&event, start);
+    assert_eq!(
+        subscriber.retry_at,
+        Some(start + LocalSubscriber::BACKOFF_MIN)
+    );
+
+    let listener = UnixListener::bind(&path)?;
+    listener.set_nonblocking(true)?;
+    subscriber.send(&event, start + LocalSubscriber::BACKOFF_MIN / 2);
+    let not_connected = listener
+        .accept()
+        .expect_err("no connection during the backoff");
+    assert_eq!(not_connected.kind(), io::ErrorKind::WouldBlock);
+
+    subscriber.send(&event, start + LocalSubscriber::BACKOFF_MIN);
+    listener.accept()?;
+    assert_eq!(subscriber.retry_at, None);
+    assert_eq!(subscriber.backoff, LocalSubscriber::BACKOFF_MIN);
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn backoff_doubles_up_to_its_maximum() -> TestResult {
+    let event = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    let mut subscriber = LocalSubscriber::new(socket_path("absent-subscriber"));
+    let mut now = Instant::now();
+
+    let mut backoffs = Vec::new();
+    for _ in 0..12 {
+        subscriber.send(&event, now);
+        now = subscriber.retry_at.expect("the connection failed");
+        backoffs.push(subscriber.backoff);
+    }
+    assert_eq!(backoffs[0], LocalSubscriber::BACKOFF_MIN * 2);
+    assert_eq!(backoffs[1], LocalSubscriber::BACKOFF_MIN * 4);
+    assert_eq!(backoffs[11], LocalSubscriber::BACKOFF_MAX);
+    Ok(())
+}
+
+#[test]
+fn dropped_events_are_logged_at_most_once_per_interval() {
+    let mut subscriber = LocalSubscriber::new(socket_path("quiet-subscriber"));
+    let start = Instant::now();
+
+    subscriber.drop_event(start, "test");
+    assert_eq!((subscriber.warned_at, subscriber.dropped), (Some(start), 0));
+
+    subscriber.drop_event(start + Duration::from_secs(1), "test");
+    subscriber.drop_event(start + Duration::from_secs(2), "test");
+    assert_eq!((subscriber.warned_at, subscriber.dropped), (Some(start), 2));
+
+    let later = start + LocalSubscriber::WARN_INTERVAL;
+    subscriber.drop_event(later, "test");
+    assert_eq!((subscriber.warned_at, subscriber.dropped), (Some(later), 0));
+}
@@ -0,0 +1,160 @@
+//! The order in which each client receives the events that are waiting for it
+use futures::{Async, Poll, Stream};
+use std::collections::VecDeque;
+
+/// Yields the items of `inner` either in the order they arrived (the default) or, for clients
+/// that want to catch up to the latest events first, newest-first.
+///
+/// Newest-first works in batches: whenever nothing is buffered, everything `inner` has ready is
+/// taken at once and then yielded in reverse.
+pub(super) struct DeliveryOrder<S: Stream> {
+    inner: S,
+    newest_first: bool,
+    batch: Vec<S::Item>,
+    inner_done: bool,
+}
+
+impl<S: Stream> DeliveryOrder<S> {
+    pub(super) fn new(inner: S, newest_first: bool) -> Self {
+        Self {
+            inner,
+            newest_first,
+            batch: Vec::new(),
+            inner_done: false,
+        }
+    }
+}
+
+impl<S: Stream> Stream for DeliveryOrder<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if !self.newest_first {
+            return self.inner.poll();
+        }
+
+        if self.batch.is_empty() && !self.inner_done {
+            loop {
+                match self.inner.poll()? {
+                    Async::Ready(Some(item)) => self.batch.push(item),
+                    Async::Ready(None) => {
+                        self.inner_done = true;
+                        break;
+                    }
+                    Async::NotReady => break,
+                }
+            }
+        }
+        match self.batch.pop() {
+            Some(item) => Ok(Async::Ready(Some(item))),
+            None if self.inner_done => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Yields the items of `inner`, which are tagged with the timeline (or other key) they came
+/// from, taking turns between timelines so that a busy timeline can't hold back a quiet one
+/// sharing the same connection (as with the firehose).
+///
+/// Like newest-first, this works in batches: whenever nothing is buffered, everything `inner`
+/// has ready is taken at once and then yielded round-robin, in order within each timeline.
+/// Each round visits the timelines in the order given to `in_order_of` (and any others in the
+/// order they arrived), so an event published to two of them is always yielded for the same
+/// one first, no matter which channel Redis delivered it on first.  When not `fair`, items are
+/// yielded in the order they arrived.
+pub(super) struct FairOrder<S, K, T> {
+    inner: S,
+    fair: bool,
+    order: Vec<K>,
+    queues: Vec<(K, VecDeque<T>)>,
+    next: usize,
+    inner_done: bool,
+}
+
+impl<S, K: PartialEq, T> FairOrder<S, K, T> {
+    pub(super) fn new(inner: S, fair: bool) -> Self {
+        Self {
+            inner,
+            fair,
+            order: Vec::new(),
+            queues: Vec::new(),
+            next: 0,
+            inner_done: false,
+        }
+    }
+
+    /// Take turns between the timelines (or other keys) in the order of `keys`
+    pub(super) fn in_order_of(mut self, keys: Vec<K>) -> Self {
+        self.order = keys;
+        self
+    }
+
+    /// The position of `key` in the turn order (keys without one go last)
+    fn rank(&self, key: &K) -> usize {
+        let position = self.order.iter().position(|k| k == key);
+        position.unwrap_or_else(|| self.order.len())
+    }
+
+    fn enqueue(&mut self, key: K, item: T) {
+        match self.queues.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, queue)) => queue.push_back(item),
+            None => {
+                let rank = self.rank(&key);
+                let after = self.queues.iter().position(|(k, _)| self.rank(k) > rank);
+                let idx = after.unwrap_or_else(|| self.queues.len());
+                self.queues.insert(idx, (key, VecDeque::from(vec![item])));
+            }
+        }
+    }
+}
+
+impl<S, K, T> Stream for FairOrder<S, K, T>
+where
+    S: Stream<Item = (K, T)>,
+    K: PartialEq + Copy,
+{
+    type Item = (K, T);
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if !self.fair {
+            return self.inner.poll();
+        }
+
+        if self.queues.is_empty() && !self.inner_done {
+            loop {
+                match self.inner.poll()? {
+                    Async::Ready(Some((key, item))) => self.enqueue(key, item),
+                    Async::Ready(None) => {
+                        self.inner_done = true;
+                        break;
+                    }
+                    Async::NotReady => break,
+                }
+            }
+            self.next = 0;
+        }
+        if self.queues.is_empty() {
+            return match self.inner_done {
+                true => Ok(Async::Ready(None)),
+                false => Ok(Async::NotReady),
+            };
+        }
+
+        let turn = self.next % self.queues.len();
+        let (key, queue) = &mut self.queues[turn];
+        let (key, item) = (*key, queue.pop_front().expect("empty queues are removed"));
+        if queue.is_empty() {
+            self.queues.remove(turn);
+            self.next = turn;
+        } else {
+            self.next = turn + 1;
+        }
+        Ok(Async::Ready(Some((key, item))))
+    }
+}
+
+#[cfg(test)]
+mod test;
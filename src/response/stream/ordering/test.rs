@@ -0,0 +1,74 @@
+use super::*;
+use futures::{stream, Future};
+
+#[test]
+fn newest_first_delivers_most_recent_event_first() {
+    let queued = stream::iter_ok::<_, ()>(vec!["first", "second", "third"]);
+
+    let delivered = DeliveryOrder::new(queued, true).collect().wait();
+
+    assert_eq!(delivered, Ok(vec!["third", "second", "first"]));
+}
+
+#[test]
+fn events_are_delivered_oldest_first_by_default() {
+    let queued = stream::iter_ok::<_, ()>(vec!["first", "second", "third"]);
+
+    let delivered = DeliveryOrder::new(queued, false).collect().wait();
+
+    assert_eq!(delivered, Ok(vec!["first", "second", "third"]));
+}
+
+#[test]
+fn fair_order_delivers_a_quiet_timeline_between_busy_ones_events() {
+    let queued = stream::iter_ok::<_, ()>(vec![
+        ("busy", 1),
+        ("busy", 2),
+        ("busy", 3),
+        ("quiet", 1),
+        ("busy", 4),
+    ]);
+
+    let delivered = FairOrder::new(queued, true).collect().wait();
+
+    assert_eq!(
+        delivered,
+        Ok(vec![
+            ("busy", 1),
+            ("quiet", 1),
+            ("busy", 2),
+            ("busy", 3),
+            ("busy", 4)
+        ])
+    );
+}
+
+#[test]
+fn unfair_order_delivers_events_as_they_arrived() {
+    let queued = stream::iter_ok::<_, ()>(vec![("busy", 1), ("busy", 2), ("quiet", 1)]);
+
+    let delivered = FairOrder::new(queued, false).collect().wait();
+
+    assert_eq!(delivered, Ok(vec![("busy", 1), ("busy", 2), ("quiet", 1)]));
+}
+
+#[test]
+fn event_on_two_timelines_is_delivered_for_each_in_a_stable_order() {
+    let delivered = |arrived: Vec<(&'static str, &'static str)>| {
+        let queued = stream::iter_ok::<_, ()>(arrived);
+        FairOrder::new(queued, true)
+            .in_order_of(vec!["public", "hashtag"])
+            .collect()
+            .wait()
+    };
+    let expected = Ok(vec![("public", "status"), ("hashtag", "status")]);
+
+    assert_eq!(
+        delivered(vec![("public", "status"), ("hashtag", "status")]),
+        expected
+    );
+    assert_eq!(
+        delivered(vec![("hashtag", "status"), ("public", "status")]),
+        expected
+    );
+}
@@ -0,0 +1,51 @@
+use futures::Stream;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use tokio::net::TcpListener;
+use tokio::reactor::Handle;
+
+#[cfg(test)]
+mod test;
+
+/// Request an OS send buffer of `bytes` for `socket`, bounding the kernel memory a slow
+/// client can tie up
+fn set_send_buffer<S: AsRawFd>(socket: &S, bytes: usize) -> io::Result<()> {
+    SockRef::from(socket).set_send_buffer_size(bytes)
+}
+
+/// Apply `set_send_buffer` to each socket accepted from `incoming` (if `bytes` is set).
+/// Failing to set the size only means the OS default is used, so it isn't fatal.
+pub fn with_send_buffer<I, S>(
+    incoming: I,
+    bytes: Option<usize>,
+) -> impl Stream<Item = S, Error = io::Error>
+where
+    I: Stream<Item = S, Error = io::Error>,
+    S: AsRawFd,
+{
+    incoming.map(move |socket| {
+        if let Some(bytes) = bytes {
+            if let Err(e) = set_send_buffer(&socket, bytes) {
+                log::warn!("Could not set the send buffer for a client socket: {}", e);
+            }
+        }
+        socket
+    })
+}
+
+/// Listen on `addr` with a queue of up to `backlog` connections waiting to be accepted (the
+/// standard library always asks for 128).  Like the standard library, this reuses the address.
+pub fn bind_with_backlog(addr: &SocketAddr, backlog: i32) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(backlog)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into(), &Handle::default())
+}
@@ -0,0 +1,50 @@
+use super::*;
+use futures::{stream, Future};
+use std::net::{TcpListener, TcpStream};
+
+type TestResult = std::result::Result<(), Box<dyn std::error::Error>>;
+
+fn client_socket() -> io::Result<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    TcpStream::connect(listener.local_addr()?)
+}
+
+#[test]
+fn send_buffer_is_applied_to_accepted_sockets() -> TestResult {
+    let bytes = 256 * 1024;
+    let sockets = with_send_buffer(stream::iter_ok(vec![client_socket()?]), Some(bytes));
+
+    for socket in sockets.wait() {
+        // Linux doubles the requested size to leave room for bookkeeping
+        assert!(SockRef::from(&socket?).send_buffer_size()? >= bytes);
+    }
+    Ok(())
+}
+
+#[test]
+fn send_buffer_is_left_alone_when_unset() -> TestResult {
+    let socket = client_socket()?;
+    let default = SockRef::from(&socket).send_buffer_size()?;
+
+    for socket in with_send_buffer(stream::iter_ok(vec![socket]), None).wait() {
+        assert_eq!(SockRef::from(&socket?).send_buffer_size()?, default);
+    }
+    Ok(())
+}
+
+#[test]
+fn listener_with_a_backlog_accepts_connections() -> TestResult {
+    let listener = bind_with_backlog(&"127.0.0.1:0".parse()?, 1024)?;
+    let client = TcpStream::connect(listener.local_addr()?)?;
+
+    let (accepted, _) = listener
+        .incoming()
+        .into_future()
+        .wait()
+        .map_err(|(e, _)| e)?;
+    assert_eq!(
+        accepted.expect("a connection").peer_addr()?,
+        client.local_addr()?
+    );
+    Ok(())
+}
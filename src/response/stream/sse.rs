@@ -1,55 +1,398 @@
-use super::{Event, Payload};
-use crate::request::Subscription;
+use super::Throttle;
+use super::{skip_reason, DeliveryOrder, Event, EventIds, FairOrder, RecentIds, Summary};
+use crate::request::{Subscription, Timeline};
 
-use futures::stream::Stream;
+use flate2::{write::GzEncoder, Compression};
+use futures::stream::{self, Stream};
+use rand::distributions::{Distribution, Uniform};
+use rand::thread_rng;
+use std::io::Write;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::RecvError;
 use tokio::sync::mpsc::Receiver;
+use tokio::timer::Interval;
+use warp::http::header::{HeaderValue, CONNECTION};
+use warp::http::Response;
+use warp::reject::Rejection;
 use warp::reply::Reply;
-use warp::sse::Sse as WarpSse;
+use warp::sse::{ServerSentEvent as _, Sse as WarpSse};
+use warp::Filter;
 
-type EventRx = Receiver<Arc<Event>>;
+type EventRx = Receiver<(Timeline, Arc<Event>)>;
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
-pub struct Sse(Subscription);
+/// The next thing a batching stream has to handle (see `Sse::batched`)
+enum Batching {
+    Event(Arc<Event>),
+    Tick,
+    End,
+}
+
+/// How an `Sse` stream is set up, apart from its `Subscription`
+#[derive(Clone, Default)]
+pub struct SseOptions {
+    /// How long to advise clients to wait before reconnecting, if at all
+    pub retry: Option<Duration>,
+    /// The most to add to `retry`, at random, so that clients don't all reconnect at once
+    pub retry_jitter: Duration,
+    /// Where to get each event's `id` from, if sending them
+    pub event_ids: Option<EventIds>,
+    /// Whether to log whether each event was delivered or filtered
+    pub trace_events: bool,
+}
+
+pub struct Sse {
+    subscription: Subscription,
+    /// The reconnection interval sent with the first event, if any
+    retry: Option<Duration>,
+    event_ids: Option<EventIds>,
+    trace_events: bool,
+    /// How often to send a `presence` event, if the client asked for them
+    presence: Option<Duration>,
+    /// The counts for the summary sent once the events end, if sending one
+    summary: Option<Arc<Summary>>,
+}
 
 impl Sse {
-    pub fn new(subscription: Subscription) -> Self {
-        Self(subscription)
+    /// The payload of `presence` events.  It's only there because `EventSource` doesn't
+    /// dispatch events without any data.
+    const PRESENCE_DATA: &'static str = "{}";
+
+    /// Create a new `Sse` for the `subscription`, set up as described by its `options`
+    pub fn new(subscription: Subscription, options: SseOptions) -> Self {
+        let jitter = options.retry_jitter;
+        Self {
+            subscription,
+            retry: options.retry.map(|base| Self::retry_interval(base, jitter)),
+            event_ids: options.event_ids,
+            trace_events: options.trace_events,
+            presence: None,
+            summary: None,
+        }
     }
 
+    /// Send a `presence` event every `interval`, if the client's subscription asked for them.
+    /// Unlike the `thump` comments, these are events that clients act on.
+    pub fn with_presence(mut self, interval: Duration) -> Self {
+        self.presence = Some(interval).filter(|_| self.subscription.presence);
+        self
+    }
+
+    /// Count the events delivered to (and filtered from) the client, if `enabled`, and send a
+    /// `summary` event with those counts and the connection's duration once the events end.
+    pub fn with_summary(mut self, enabled: bool) -> Self {
+        self.summary = Some(Arc::new(Summary::default())).filter(|_| enabled);
+        self
+    }
+
+    /// The header naming the `Timeline` the client's request resolved to
+    const TIMELINE_HEADER: &'static str = "x-flodgatt-timeline";
+
+    /// Respond to the client with the events from `event_rx`, gzipped if `gzip` (see
+    /// `gzip_accepted`).  The response is complete: nothing needs to be added to its headers.
+    pub fn respond(self, sse: WarpSse, gzip: bool, event_rx: EventRx) -> Response<hyper::Body> {
+        match gzip {
+            true => self.send_gzipped_events(event_rx),
+            false => self.send_events(sse, event_rx).into_response(),
+        }
+    }
+
+    /// Send the events from `event_rx` to the client.  The `retry:` field (if any) only needs
+    /// to reach the client once, so it's sent with the first event.
     pub fn send_events(self, sse: WarpSse, event_rx: EventRx) -> impl Reply {
-        let event_stream = event_rx.filter_map(move |event| {
-            match (event.update_payload(), event.dyn_update_payload()) {
-                (Some(update), _) if self.update_not_filtered(update) => event.to_warp_reply(),
-                (_, Some(update)) if self.update_not_filtered(update) => event.to_warp_reply(),
-                (_, _) => event.to_warp_reply(), // send all non-updates
-            }
-        });
-
-        sse.reply(
+        let mut retry = self.retry;
+        let (limit, presence, batch, ids) = (
+            self.subscription.limit,
+            self.presence,
+            self.subscription.batch,
+            self.event_ids.clone(),
+        );
+        let timeline = self.timeline_header();
+        let summary = Self::summary(self.summary.clone());
+        let events = self.deliverable_events(event_rx);
+        let event_stream = Self::batched(events, batch)
+            .filter_map(move |event| {
+                let id = ids.as_ref().and_then(|ids| ids.id_for(&event));
+                event.to_warp_reply().map(|(event, data)| (event, data, id))
+            })
+            .map(move |(event, data, id)| match (retry.take(), id) {
+                (Some(interval), Some(id)) => {
+                    (warp::sse::retry(interval), warp::sse::id(id), event, data)
+                        .into_a()
+                        .into_a()
+                }
+                (Some(interval), None) => {
+                    (warp::sse::retry(interval), event, data).into_b().into_a()
+                }
+                (None, Some(id)) => (warp::sse::id(id), event, data).into_a().into_b(),
+                (None, None) => (event, data).into_b().into_b(),
+            })
+            .map(|event| Some(event.into_a()))
+            .chain(summary.map(|summary| {
+                let (event, data) = (warp::sse::event("summary"), summary);
+                Some((event, warp::sse::data(data)).into_b())
+            }))
+            // Presence events never end, so `None` marks the end of the events
+            .chain(stream::once(Ok(None)))
+            .select(Self::ticks(presence).map(|_| {
+                let (event, data) = (warp::sse::event("presence"), Self::PRESENCE_DATA);
+                Some((event, warp::sse::data(data.to_string())).into_b())
+            }))
+            .take_while(|event| Ok(event.is_some()))
+            .filter_map(|event| event);
+
+        let reply = sse.reply(
             warp::sse::keep_alive()
                 .interval(Duration::from_secs(30))
                 .text("thump".to_string())
                 .stream(event_stream),
-        )
-    }
-
-    fn update_not_filtered(&self, update: &impl Payload) -> bool {
-        let blocks = &self.0.blocks;
-        let allowed_langs = &self.0.allowed_langs;
-
-        match self.0.timeline {
-            tl if tl.is_public()
-                && !update.language_unset()
-                && !allowed_langs.is_empty()
-                && !allowed_langs.contains(&update.language()) =>
-            {
-                false
-            }
-            _ if !blocks.blocked_users.is_disjoint(&update.involved_users()) => false,
-            _ if blocks.blocking_users.contains(update.author()) => false,
-            _ if blocks.blocked_domains.contains(update.sent_from()) => false,
-            _ => true,
+        );
+        let response = Self::with_connection_header(limit, reply.into_response());
+        Self::with_timeline_header(timeline, response)
+    }
+
+    /// Like `send_events`, but with the stream gzipped.
+    ///
+    /// `warp::sse` can't compress its output, so this writes the SSE frames itself.  The encoder
+    /// is flushed after every frame, which lets clients decode each event as soon as it arrives.
+    pub fn send_gzipped_events(self, event_rx: EventRx) -> Response<hyper::Body> {
+        let mut retry = self
+            .retry
+            .map(|interval| format!("retry:{}\n", interval.as_millis()))
+            .unwrap_or_default();
+        let (limit, presence, batch, ids) = (
+            self.subscription.limit,
+            self.presence,
+            self.subscription.batch,
+            self.event_ids.clone(),
+        );
+        let timeline = self.timeline_header();
+        let summary = Self::summary(self.summary.clone());
+        let events = self.deliverable_events(event_rx);
+        let events = Self::batched(events, batch)
+            .filter_map(move |event| {
+                let frame = event.to_sse_frame();
+                match ids.as_ref().and_then(|ids| ids.id_for(&event)) {
+                    Some(id) => frame.map(|frame| format!("id:{}\n{}", id, frame)),
+                    None => frame,
+                }
+            })
+            .map(move |frame| Some([std::mem::take(&mut retry), frame].concat()))
+            .chain(summary.map(|summary| Some(format!("event:summary\ndata:{}\n\n", summary))))
+            // The keep-alives never end, so `None` marks the end of the events
+            .chain(stream::once(Ok(None)))
+            .map_err(BoxError::from);
+        let keep_alive = Interval::new_interval(Duration::from_secs(30))
+            .map(|_| Some(":thump\n\n".to_string()))
+            .map_err(BoxError::from);
+        let presence = Self::ticks(presence)
+            .map(|_| Some(format!("event:presence\ndata:{}\n\n", Self::PRESENCE_DATA)));
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        let body = events
+            .select(keep_alive)
+            .select(presence)
+            .take_while(|frame| Ok(frame.is_some()))
+            .and_then(move |frame| {
+                gzip.write_all(frame.unwrap_or_default().as_bytes())?;
+                gzip.flush()?;
+                Ok::<_, BoxError>(std::mem::replace(gzip.get_mut(), Vec::new()))
+            });
+
+        let response = Response::builder()
+            .header("content-type", "text/event-stream")
+            .header("cache-control", "no-cache")
+            .header("content-encoding", "gzip")
+            .body(hyper::Body::wrap_stream(body))
+            .expect("static headers are valid");
+        let response = Self::with_connection_header(limit, response);
+        Self::with_timeline_header(timeline, response)
+    }
+
+    /// The events from `event_rx` that should reach the client, in the order they should be
+    /// sent and (for a stream with a `limit` or `max_events_per_sec`) only as many as it allows
+    fn deliverable_events(
+        self,
+        event_rx: EventRx,
+    ) -> impl Stream<Item = Arc<Event>, Error = RecvError> {
+        let (limit, mark_local) = (self.subscription.limit, self.subscription.mark_local);
+        let mut throttle = self.subscription.max_events_per_sec.map(Throttle::new);
+        let mut recent_ids = RecentIds::for_subscription(&self.subscription);
+        let ordered = DeliveryOrder::new(event_rx, self.subscription.newest_first);
+        FairOrder::new(ordered, self.fair())
+            .in_order_of(self.subscription.timelines())
+            .filter_map(move |(timeline, event)| {
+                let subscription = &self.subscription;
+                let delivered = match (event.update_payload(), event.dyn_update_payload()) {
+                    _ if matches!(*event, Event::Ping) || !self.receives_kind(&event) => false,
+                    (Some(update), _) => skip_reason(subscription, timeline, update).is_none(),
+                    (_, Some(update)) => skip_reason(subscription, timeline, update).is_none(),
+                    (None, None) => !subscription.blocks.blocks_notification(&event), // send all other non-updates
+                };
+                let repeated = recent_ids.as_ref().map_or(false, |ids| ids.repeats(&event));
+                let delivered = delivered
+                    && !repeated
+                    && throttle.as_mut().map_or(true, |t| t.allows(Instant::now()));
+                if let Some(recent_ids) = recent_ids.as_mut().filter(|_| delivered) {
+                    recent_ids.sent(&event);
+                }
+                self.trace(&event, delivered);
+                self.count(&event, delivered);
+                let marked = if mark_local && delivered {
+                    event.marked_local()
+                } else {
+                    None
+                };
+                Some(marked.map_or(event, Arc::new)).filter(|_| delivered)
+            })
+            .take(limit.map_or(u64::MAX, u64::from))
+    }
+
+    /// Group `events` into one `batch` event per `window` (skipping windows without any
+    /// events), or pass them through unchanged without a `window`.  Once the events end, any
+    /// that are still waiting for their window to close are sent straight away.
+    fn batched<E>(
+        events: impl Stream<Item = Arc<Event>, Error = E>,
+        window: Option<Duration>,
+    ) -> impl Stream<Item = Arc<Event>, Error = E> {
+        let mut batch = Vec::new();
+        events
+            .map(Batching::Event)
+            .chain(stream::once(Ok(Batching::End)))
+            .select(Self::ticks(window).map(|_| Batching::Tick))
+            .map(move |next| {
+                let end = matches!(next, Batching::End);
+                let ready = match next {
+                    Batching::Event(event) if window.is_none() => Some(event),
+                    Batching::Event(event) => {
+                        batch.push(event);
+                        None
+                    }
+                    Batching::Tick | Batching::End if batch.is_empty() => None,
+                    Batching::Tick | Batching::End => {
+                        Some(Arc::new(Event::batch(&std::mem::take(&mut batch))))
+                    }
+                };
+                // `None` marks the end of the events, since the ticks never end
+                let end = if end { Some(None) } else { None };
+                stream::iter_ok(ready.map(Some).into_iter().chain(end))
+            })
+            .flatten()
+            .take_while(|event| Ok(event.is_some()))
+            .filter_map(|event| event)
+    }
+
+    /// The connection's `summary` as JSON, once polled after the events end (or nothing, if
+    /// it's not being sent)
+    fn summary<E>(summary: Option<Arc<Summary>>) -> impl Stream<Item = String, Error = E> {
+        stream::iter_ok(summary).map(|summary| summary.to_json())
+    }
+
+    /// Ask to keep the connection alive, except for a stream with a `limit`: that ends once
+    /// that many events have been sent, so the client (and any proxies) shouldn't reuse it.
+    fn with_connection_header(
+        limit: Option<u32>,
+        mut response: Response<hyper::Body>,
+    ) -> Response<hyper::Body> {
+        let connection = match limit {
+            Some(_) => HeaderValue::from_static("close"),
+            None => HeaderValue::from_static("keep-alive"),
+        };
+        response.headers_mut().insert(CONNECTION, connection);
+        response
+    }
+
+    /// The value of the `TIMELINE_HEADER`: the `Timeline` the client's request resolved to, as
+    /// JSON, if it asked for it (with `debug_timeline`)
+    fn timeline_header(&self) -> Option<HeaderValue> {
+        if !self.subscription.debug_timeline {
+            return None;
         }
+        let timeline = serde_json::to_string(&self.subscription.timeline).ok()?;
+        HeaderValue::from_str(&timeline).ok()
+    }
+
+    fn with_timeline_header(
+        timeline: Option<HeaderValue>,
+        mut response: Response<hyper::Body>,
+    ) -> Response<hyper::Body> {
+        if let Some(timeline) = timeline {
+            response
+                .headers_mut()
+                .insert(Self::TIMELINE_HEADER, timeline);
+        }
+        response
+    }
+
+    /// A filter that extracts whether the request should get a gzipped SSE stream: whether it
+    /// accepts gzip encoding, when `enabled` by the config.  It never rejects, so that one SSE
+    /// route can serve both kinds of stream.
+    pub fn gzip_accepted(
+        enabled: bool,
+    ) -> impl Filter<Extract = (bool,), Error = Rejection> + Clone {
+        warp::header::optional::<String>("accept-encoding").map(move |encodings: Option<String>| {
+            let gzip = encodings.map_or(false, |encodings| {
+                encodings
+                    .split(',')
+                    .any(|encoding| encoding.trim().starts_with("gzip"))
+            });
+            enabled && gzip
+        })
+    }
+
+    /// A tick every `interval` (or nothing, if `interval` is `None`), for sending presence
+    /// events or batches.  Timer errors are logged and skipped, so the stream never fails.
+    fn ticks<E>(interval: Option<Duration>) -> impl Stream<Item = (), Error = E> {
+        let ticks = interval.map(|interval| Interval::new(Instant::now() + interval, interval));
+        stream::iter_ok::<_, tokio::timer::Error>(ticks)
+            .flatten()
+            .then(|tick| match tick {
+                Ok(_) => Ok(Some(())),
+                Err(e) => {
+                    log::warn!("SSE timer error: {}", e);
+                    Ok(None)
+                }
+            })
+            .filter_map(|tick| tick)
+    }
+
+    /// Pick a reconnection interval between `base` and `base + jitter`.
+    ///
+    /// Each connection gets its own interval so that, when many clients are disconnected at
+    /// once, they don't all try to reconnect at the same instant.
+    fn retry_interval(base: Duration, jitter: Duration) -> Duration {
+        base + Uniform::new_inclusive(Duration::from_millis(0), jitter).sample(&mut thread_rng())
+    }
+
+    /// Whether this subscription's timelines should take turns (see `FairOrder`)
+    fn fair(&self) -> bool {
+        self.subscription.timelines().len() > 1
+    }
+
+    /// Log whether `event` was `delivered` (if tracing events)
+    fn trace(&self, event: &Event, delivered: bool) {
+        if self.trace_events {
+            let outcome = if delivered { "delivered" } else { "filtered" };
+            let tl = self.subscription.timeline;
+            event.trace(&format!("{} to an SSE client of {:?}", outcome, tl));
+        }
+    }
+
+    /// Count whether `event` was `delivered` (if sending a summary)
+    fn count(&self, event: &Event, delivered: bool) {
+        if let Some(summary) = &self.summary {
+            summary.record(event, delivered);
+        }
+    }
+
+    /// Whether `event` is of a kind this subscription receives at all (regardless of content)
+    fn receives_kind(&self, event: &Event) -> bool {
+        let of_type = |event_type: &String| event.is_of_type(event_type);
+        (!self.subscription.only_relationships || event.is_relationship_notification())
+            && self.subscription.event_type.as_ref().map_or(true, of_type)
     }
 }
+
+#[cfg(test)]
+mod test;
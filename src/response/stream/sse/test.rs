@@ -0,0 +1,607 @@
+use super::*;
+use tokio::runtime::current_thread::Runtime;
+
+#[test]
+fn retry_interval_falls_within_configured_range() {
+    let (base, jitter) = (Duration::from_millis(5_000), Duration::from_millis(2_000));
+    for _ in 0..1_000 {
+        let retry = Sse::retry_interval(base, jitter);
+        assert!(
+            retry >= base,
+            "{:?} is shorter than the base interval",
+            retry
+        );
+        assert!(retry <= base + jitter, "{:?} exceeds base + jitter", retry);
+    }
+}
+
+#[test]
+fn retry_interval_without_jitter_is_the_base() {
+    let base = Duration::from_millis(3_000);
+    assert_eq!(Sse::retry_interval(base, Duration::from_millis(0)), base);
+}
+
+#[test]
+fn retry_is_only_set_when_configured() {
+    let options = SseOptions {
+        retry_jitter: Duration::from_millis(500),
+        ..SseOptions::default()
+    };
+    let sse = Sse::new(Subscription::default(), options.clone());
+    assert_eq!(sse.retry, None);
+
+    let base = Duration::from_millis(1_000);
+    let options = SseOptions {
+        retry: Some(base),
+        ..options
+    };
+    let sse = Sse::new(Subscription::default(), options);
+    let retry = sse.retry.expect("retry configured");
+    assert!(retry >= base && retry <= base + Duration::from_millis(500));
+}
+
+#[test]
+fn gzip_is_only_accepted_when_enabled_and_requested() {
+    let request = || warp::test::request().header("accept-encoding", "deflate, gzip;q=1.0");
+    assert_eq!(request().filter(&Sse::gzip_accepted(true)).ok(), Some(true));
+    assert_eq!(
+        request().filter(&Sse::gzip_accepted(false)).ok(),
+        Some(false)
+    );
+
+    let identity = warp::test::request().header("accept-encoding", "identity");
+    assert_eq!(identity.filter(&Sse::gzip_accepted(true)).ok(), Some(false));
+    let no_encoding = warp::test::request().filter(&Sse::gzip_accepted(true));
+    assert_eq!(no_encoding.ok(), Some(false));
+}
+
+#[test]
+fn gzipped_events_decode_to_sse_frames() -> Result<(), Box<dyn std::error::Error>> {
+    use flate2::write::GzDecoder;
+    use futures::future::lazy;
+    use std::convert::TryFrom;
+
+    let event = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    let (mut event_tx, event_rx) = tokio::sync::mpsc::channel(10);
+    let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+    runtime.block_on(lazy(move || {
+        event_tx.try_send((Timeline::empty(), Arc::new(event)))
+    }))?;
+
+    let response =
+        Sse::new(Subscription::default(), SseOptions::default()).send_gzipped_events(event_rx);
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+    // Each event is flushed in its own chunk, so the first chunk decodes to a complete frame
+    let (chunk, _body) = runtime
+        .block_on(response.into_body().into_future())
+        .map_err(|(e, _)| e)?;
+    let mut decoder = GzDecoder::new(Vec::new());
+    decoder.write_all(&chunk.expect("a chunk"))?;
+    decoder.flush()?;
+    assert_eq!(
+        String::from_utf8(decoder.get_ref().clone())?,
+        "event:delete\ndata:104061222412800865\n\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn retry_is_only_sent_with_the_first_frame() -> Result<(), Box<dyn std::error::Error>> {
+    use flate2::write::GzDecoder;
+    use futures::future::lazy;
+    use std::convert::TryFrom;
+
+    let event = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    let (mut event_tx, event_rx) = tokio::sync::mpsc::channel(10);
+    let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+    runtime.block_on(lazy(move || {
+        event_tx.try_send((Timeline::empty(), Arc::new(event.clone())))?;
+        event_tx.try_send((Timeline::empty(), Arc::new(event)))
+    }))?;
+
+    let options = SseOptions {
+        retry: Some(Duration::from_millis(1_000)),
+        ..SseOptions::default()
+    };
+    let response = Sse::new(Subscription::default(), options).send_gzipped_events(event_rx);
+
+    let mut decoder = GzDecoder::new(Vec::new());
+    let mut frames = Vec::new();
+    let mut body = response.into_body();
+    for _ in 0..2 {
+        let (chunk, rest) = runtime.block_on(body.into_future()).map_err(|(e, _)| e)?;
+        decoder.write_all(&chunk.expect("a chunk"))?;
+        decoder.flush()?;
+        frames.push(String::from_utf8(std::mem::take(decoder.get_mut()))?);
+        body = rest;
+    }
+    assert_eq!(
+        frames,
+        vec![
+            "retry:1000\nevent:delete\ndata:104061222412800865\n\n",
+            "event:delete\ndata:104061222412800865\n\n"
+        ]
+    );
+    Ok(())
+}
+
+type Responded = (Response<hyper::Body>, Runtime);
+
+/// The response `respond` makes for an `Sse` of the `subscription` sent `events`, and the
+/// runtime to poll its body in
+fn respond_to_events(
+    subscription: Subscription,
+    events: Vec<Event>,
+    respond: impl FnOnce(Sse, EventRx) -> Response<hyper::Body>,
+) -> Result<Responded, Box<dyn std::error::Error>> {
+    let events = events.into_iter().map(|event| (Timeline::empty(), event));
+    respond_to_timeline_events(subscription, events.collect(), respond)
+}
+
+/// Like `respond_to_events`, for `events` from the timelines they're paired with
+fn respond_to_timeline_events(
+    subscription: Subscription,
+    events: Vec<(Timeline, Event)>,
+    respond: impl FnOnce(Sse, EventRx) -> Response<hyper::Body>,
+) -> Result<Responded, Box<dyn std::error::Error>> {
+    use futures::future::lazy;
+
+    let (mut event_tx, event_rx) = tokio::sync::mpsc::channel(10);
+    let mut runtime = Runtime::new()?;
+    runtime.block_on(lazy(move || {
+        for (timeline, event) in events {
+            event_tx.try_send((timeline, Arc::new(event)))?;
+        }
+        Ok::<_, tokio::sync::mpsc::error::TrySendError<_>>(())
+    }))?;
+
+    let sse = Sse::new(subscription, SseOptions::default());
+    Ok((respond(sse, event_rx), runtime))
+}
+
+/// The chunks of the stream an `Sse` for the `subscription` sends for `events`
+fn sent_chunks(
+    subscription: Subscription,
+    events: Vec<Event>,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let events = events.into_iter().map(|event| (Timeline::empty(), event));
+    sent_timeline_chunks(subscription, events.collect())
+}
+
+/// Like `sent_chunks`, for `events` from the timelines they're paired with
+fn sent_timeline_chunks(
+    subscription: Subscription,
+    events: Vec<(Timeline, Event)>,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let warp_sse = warp::test::request()
+        .filter(&warp::sse())
+        .map_err(|_| "not an SSE request")?;
+    let (response, mut runtime) = respond_to_timeline_events(subscription, events, |sse, rx| {
+        sse.send_events(warp_sse, rx).into_response()
+    })?;
+
+    let mut body = response.into_body();
+    let mut chunks = Vec::new();
+    while let (Some(chunk), rest) = runtime.block_on(body.into_future()).map_err(|(e, _)| e)? {
+        chunks.push(String::from_utf8(chunk.to_vec())?);
+        body = rest;
+    }
+    Ok(chunks)
+}
+
+#[test]
+fn events_are_streamed_in_chunks_of_one_event() -> Result<(), Box<dyn std::error::Error>> {
+    use hyper::body::Payload as _;
+    use std::convert::TryFrom;
+
+    let event = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    let warp_sse = warp::test::request()
+        .filter(&warp::sse())
+        .map_err(|_| "not an SSE request")?;
+    let events = vec![event.clone(), event];
+    let (response, mut runtime) = respond_to_events(Subscription::default(), events, |sse, rx| {
+        sse.send_events(warp_sse, rx).into_response()
+    })?;
+
+    // Without a length, HTTP/1.1 responses use chunked transfer encoding
+    assert!(response.headers().get("content-length").is_none());
+    let connection = response.headers().get("connection");
+    assert_eq!(
+        connection.map(|value| value.as_bytes()),
+        Some(&b"keep-alive"[..])
+    );
+    assert_eq!(response.body().content_length(), None);
+
+    let mut body = response.into_body();
+    for _ in 0..2 {
+        let (chunk, rest) = runtime.block_on(body.into_future()).map_err(|(e, _)| e)?;
+        assert_eq!(
+            &chunk.expect("a chunk")[..],
+            &b"event:delete\ndata:104061222412800865\n\n"[..]
+        );
+        body = rest;
+    }
+    Ok(())
+}
+
+#[test]
+fn gzipped_events_are_streamed_without_a_length() -> Result<(), Box<dyn std::error::Error>> {
+    use hyper::body::Payload as _;
+    use std::convert::TryFrom;
+
+    let event = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    let (response, _runtime) = respond_to_events(
+        Subscription::default(),
+        vec![event],
+        Sse::send_gzipped_events,
+    )?;
+
+    assert!(response.headers().get("content-length").is_none());
+    assert_eq!(response.body().content_length(), None);
+    Ok(())
+}
+
+#[test]
+fn limited_stream_closes_after_its_events() -> Result<(), Box<dyn std::error::Error>> {
+    use std::convert::TryFrom;
+
+    let event = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    let warp_sse = warp::test::request()
+        .filter(&warp::sse())
+        .map_err(|_| "not an SSE request")?;
+    let subscription = Subscription {
+        limit: Some(1),
+        ..Subscription::default()
+    };
+    let events = vec![event.clone(), event];
+    let (response, mut runtime) = respond_to_events(subscription, events, |sse, rx| {
+        sse.send_events(warp_sse, rx).into_response()
+    })?;
+
+    let connection = response.headers().get("connection");
+    assert_eq!(
+        connection.map(|value| value.as_bytes()),
+        Some(&b"close"[..])
+    );
+
+    let body = response.into_body();
+    let (chunk, rest) = runtime.block_on(body.into_future()).map_err(|(e, _)| e)?;
+    assert_eq!(
+        &chunk.expect("a chunk")[..],
+        &b"event:delete\ndata:104061222412800865\n\n"[..]
+    );
+    // The second event is never sent: the body ends after the first
+    let (end, _) = runtime.block_on(rest.into_future()).map_err(|(e, _)| e)?;
+    assert!(end.is_none());
+    Ok(())
+}
+
+/// A route that, like `main`'s, streams `events` to each SSE request for `subscription`
+fn sse_route(
+    subscription: Subscription,
+    events: Vec<Event>,
+) -> impl Filter<Extract = (Response<hyper::Body>,), Error = Rejection> + Clone {
+    warp::sse()
+        .and(Sse::gzip_accepted(true))
+        .map(move |sse: WarpSse, gzip: bool| {
+            let (mut event_tx, event_rx) = tokio::sync::mpsc::channel(10);
+            for event in events.clone() {
+                event_tx
+                    .try_send((Timeline::empty(), Arc::new(event)))
+                    .expect("room for every event");
+            }
+            Sse::new(subscription.clone(), SseOptions::default()).respond(sse, gzip, event_rx)
+        })
+}
+
+#[test]
+fn connection_header_reaches_the_client_through_the_route() -> Result<(), Box<dyn std::error::Error>>
+{
+    use std::convert::TryFrom;
+
+    let event = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    let limited = Subscription {
+        limit: Some(1),
+        ..Subscription::default()
+    };
+    let connection = |subscription: &Subscription, encoding: &str| {
+        let route = sse_route(subscription.clone(), vec![event.clone(), event.clone()]);
+        let response = warp::test::request()
+            .header("accept-encoding", encoding)
+            .reply(&route);
+        response.headers().get("connection").cloned()
+    };
+
+    for encoding in &["identity", "gzip"] {
+        assert_eq!(
+            connection(&limited, encoding),
+            Some(HeaderValue::from_static("close"))
+        );
+        assert_eq!(
+            connection(&Subscription::default(), encoding),
+            Some(HeaderValue::from_static("keep-alive"))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn event_type_from_the_path_limits_the_events_sent() -> Result<(), Box<dyn std::error::Error>> {
+    use std::convert::TryFrom;
+
+    let update = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    let delete = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    let subscription = Subscription {
+        event_type: Some("update".to_string()),
+        ..Subscription::default()
+    };
+
+    let chunks = sent_chunks(subscription, vec![delete, update.clone(), update])?;
+
+    assert_eq!(chunks.len(), 2);
+    let is_update = |chunk: &String| chunk.starts_with("event:update\n");
+    assert!(chunks.iter().all(is_update));
+    Ok(())
+}
+
+#[test]
+fn resolved_timeline_is_sent_as_a_header_on_request() -> Result<(), Box<dyn std::error::Error>> {
+    let timeline = |debug_timeline| -> Result<_, Box<dyn std::error::Error>> {
+        let subscription = Subscription {
+            timeline: Timeline::from_redis_text("hashtag:42", &mut lru::LruCache::new(1))?,
+            debug_timeline,
+            ..Subscription::default()
+        };
+        let (response, _runtime) =
+            respond_to_events(subscription, Vec::new(), Sse::send_gzipped_events)?;
+        Ok(response.headers().get("x-flodgatt-timeline").cloned())
+    };
+
+    assert_eq!(
+        timeline(true)?.as_ref().map(|value| value.as_bytes()),
+        Some(&br#"{"stream":{"hashtag":42},"reach":"federated","content":"all"}"#[..])
+    );
+    assert_eq!(timeline(false)?, None);
+    Ok(())
+}
+
+#[test]
+fn events_within_the_batch_window_are_sent_as_one_frame() -> Result<(), Box<dyn std::error::Error>>
+{
+    use std::convert::TryFrom;
+
+    let event = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    let subscription = Subscription {
+        batch: Some(Duration::from_millis(50)),
+        ..Subscription::default()
+    };
+
+    let chunks = sent_chunks(subscription, vec![event.clone(), event])?;
+
+    let delete = r#"{"event":"delete","payload":"104061222412800865"}"#;
+    assert_eq!(
+        chunks,
+        vec![format!("event:batch\ndata:[{},{}]\n\n", delete, delete)]
+    );
+    Ok(())
+}
+
+#[test]
+fn updates_from_blocked_and_blocking_users_are_not_sent() -> Result<(), Box<dyn std::error::Error>>
+{
+    use crate::request::Blocks;
+    use crate::Id;
+    use std::convert::TryFrom;
+
+    let update = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    let delete = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    let author = || vec![Id(78)].into_iter().collect();
+    for blocks in vec![
+        Blocks {
+            blocked_users: author(),
+            ..Blocks::default()
+        },
+        Blocks {
+            blocking_users: author(),
+            ..Blocks::default()
+        },
+    ] {
+        let subscription = Subscription {
+            blocks,
+            ..Subscription::default()
+        };
+
+        let chunks = sent_chunks(subscription, vec![update.clone(), delete.clone()])?;
+
+        // Only the delete is sent
+        assert_eq!(chunks, vec!["event:delete\ndata:104061222412800865\n\n"]);
+    }
+    Ok(())
+}
+
+#[test]
+fn updates_from_blocked_domains_are_not_sent() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::request::Blocks;
+    use std::convert::TryFrom;
+
+    let txt = std::fs::read_to_string("test_data/msg.event_txt_001.txt")?;
+    let mut update: serde_json::Value = serde_json::from_str(&txt)?;
+    // A local account boosting a status by `author@original.example`
+    let mut boosted_status = update["payload"].clone();
+    boosted_status["account"]["acct"] = "author@original.example".into();
+    update["payload"]["reblog"] = boosted_status;
+    update["payload"]["account"]["acct"] = "alice".into();
+    let boost = Event::try_from(update.to_string())?;
+    let sent = |domains: &[&str]| -> Result<bool, Box<dyn std::error::Error>> {
+        let subscription = Subscription {
+            timeline: Timeline::from_redis_text("public", &mut lru::LruCache::new(1))?,
+            blocks: Blocks {
+                blocked_domains: domains.iter().copied().map(String::from).collect(),
+                ..Blocks::default()
+            },
+            ..Subscription::default()
+        };
+        Ok(!sent_chunks(subscription, vec![boost.clone()])?.is_empty())
+    };
+
+    assert!(!sent(&["original.example"])?);
+    assert!(sent(&["example"])?);
+    assert!(sent(&["", "mastodon.host"])?); // the booster is local, so has no domain
+    Ok(())
+}
+
+#[test]
+fn public_statuses_are_only_sent_in_allowed_languages() -> Result<(), Box<dyn std::error::Error>> {
+    use std::convert::TryFrom;
+
+    let txt = std::fs::read_to_string("test_data/msg.event_txt_001.txt")?;
+    let english = Event::try_from(txt.clone())?;
+    let mut unset: serde_json::Value = serde_json::from_str(&txt)?;
+    unset["payload"]["language"] = serde_json::Value::Null;
+    let unset = Event::try_from(unset.to_string())?;
+
+    let sent = |langs: &[&str], event: &Event| -> Result<bool, Box<dyn std::error::Error>> {
+        let subscription = Subscription {
+            timeline: Timeline::from_redis_text("public", &mut lru::LruCache::new(1))?,
+            allowed_langs: langs.iter().copied().map(String::from).collect(),
+            ..Subscription::default()
+        };
+        Ok(!sent_chunks(subscription, vec![event.clone()])?.is_empty())
+    };
+
+    assert!(sent(&[], &english)?);
+    assert!(sent(&["en", "de"], &english)?);
+    assert!(!sent(&["de"], &english)?);
+    assert!(sent(&["de"], &unset)?);
+    Ok(())
+}
+
+#[test]
+fn filtered_updates_are_not_sent() -> Result<(), Box<dyn std::error::Error>> {
+    use std::convert::TryFrom;
+
+    let update = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    let delete = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    // The update is by account 78, who asked not to be sent their own statuses
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("78", &mut lru::LruCache::new(1))?,
+        exclude_self: true,
+        ..Subscription::default()
+    };
+
+    let chunks = sent_chunks(subscription, vec![update, delete])?;
+
+    assert_eq!(chunks, vec!["event:delete\ndata:104061222412800865\n\n"]);
+    Ok(())
+}
+
+#[test]
+fn firehose_sends_a_status_published_to_several_timelines_once(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::convert::TryFrom;
+
+    let update = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    let timeline = |txt| Timeline::from_redis_text(txt, &mut lru::LruCache::new(1));
+    let subscription = Subscription {
+        timeline: timeline("public")?,
+        firehose: true,
+        ..Subscription::default()
+    };
+    let events = vec![
+        (timeline("public")?, update.clone()),
+        (timeline("public:remote")?, update),
+    ];
+
+    let chunks = sent_timeline_chunks(subscription, events)?;
+
+    assert_eq!(chunks.len(), 1);
+    assert!(chunks[0].starts_with("event:update\n"));
+    Ok(())
+}
+
+#[test]
+fn firehose_only_sends_local_only_statuses_from_local_timelines(
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::convert::TryFrom;
+
+    let txt = std::fs::read_to_string("test_data/msg.event_txt_001.txt")?;
+    let txt = txt.replacen(r#""payload":{"#, r#""payload":{"local_only":true,"#, 1);
+    let local_only = Event::try_from(txt)?;
+    let timeline = |txt| Timeline::from_redis_text(txt, &mut lru::LruCache::new(1));
+    let subscription = || -> Result<Subscription, Box<dyn std::error::Error>> {
+        Ok(Subscription {
+            timeline: timeline("public")?,
+            firehose: true,
+            ..Subscription::default()
+        })
+    };
+
+    let federated = vec![(timeline("public")?, local_only.clone())];
+    assert!(sent_timeline_chunks(subscription()?, federated)?.is_empty());
+    let local = vec![(timeline("public:local")?, local_only)];
+    assert_eq!(sent_timeline_chunks(subscription()?, local)?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn updates_matching_a_keyword_filter_are_not_sent() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::request::Filters;
+    use std::convert::TryFrom;
+
+    // The content is `<p>Trending tags:<br><a href="…" class="mention hashtag" …>`
+    let update = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    let sent = |phrase: &str| -> Result<bool, Box<dyn std::error::Error>> {
+        let subscription = Subscription {
+            timeline: Timeline::from_redis_text("public", &mut lru::LruCache::new(1))?,
+            filters: Filters::new(&[(phrase.to_string(), true)]),
+            ..Subscription::default()
+        };
+        Ok(!sent_chunks(subscription, vec![update.clone()])?.is_empty())
+    };
+
+    assert!(!sent("trending tags")?);
+    assert!(sent("trend")?);
+    assert!(sent("href")?); // only in the markup
+    assert!(sent("mention hashtag")?);
+    Ok(())
+}
+
+#[test]
+fn summary_counts_the_events_delivered_and_filtered() -> Result<(), Box<dyn std::error::Error>> {
+    use std::convert::TryFrom;
+
+    let update = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_001.txt")?)?;
+    let delete = Event::try_from(std::fs::read_to_string("test_data/msg.event_txt_004.txt")?)?;
+    let warp_sse = warp::test::request()
+        .filter(&warp::sse())
+        .map_err(|_| "not an SSE request")?;
+    let subscription = Subscription {
+        event_type: Some("update".to_string()),
+        ..Subscription::default()
+    };
+    let events = vec![delete, update.clone(), Event::Ping, update];
+    let (response, mut runtime) = respond_to_events(subscription, events, |sse, rx| {
+        sse.with_summary(true)
+            .send_events(warp_sse, rx)
+            .into_response()
+    })?;
+
+    let mut body = response.into_body();
+    let mut chunks = Vec::new();
+    while let (Some(chunk), rest) = runtime.block_on(body.into_future()).map_err(|(e, _)| e)? {
+        chunks.push(String::from_utf8(chunk.to_vec())?);
+        body = rest;
+    }
+    assert_eq!(chunks.len(), 3);
+    let summary = chunks[2]
+        .strip_prefix("event:summary\ndata:")
+        .and_then(|summary| summary.strip_suffix("\n\n"))
+        .ok_or("not a summary")?;
+    let summary: serde_json::Value = serde_json::from_str(summary)?;
+    assert_eq!(summary["delivered"], 2);
+    assert_eq!(summary["filtered"], 1);
+    assert!(summary["duration_ms"].is_u64());
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use super::Event;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// What a connection's client was sent, for the summary sent just before the connection closes
+/// (see `Deployment::connection_summaries`)
+#[derive(Debug)]
+pub struct Summary {
+    started: Instant,
+    delivered: AtomicU64,
+    filtered: AtomicU64,
+}
+
+/// An empty `Summary` of a connection starting now
+impl Default for Summary {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            delivered: AtomicU64::new(0),
+            filtered: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Summary {
+    /// Count an `event` that was either `delivered` to the client or filtered from it.  Pings
+    /// aren't events the client ever sees, so they're not counted either way.
+    pub fn record(&self, event: &Event, delivered: bool) {
+        if matches!(*event, Event::Ping) {
+            return;
+        }
+        let count = if delivered {
+            &self.delivered
+        } else {
+            &self.filtered
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The summary so far, as `{"delivered":3,"filtered":1,"duration_ms":1520}` (for example)
+    pub fn to_json(&self) -> String {
+        let duration_ms = u64::try_from(self.started.elapsed().as_millis()).unwrap_or(u64::MAX);
+        serde_json::json!({
+            "delivered": self.delivered.load(Ordering::Relaxed),
+            "filtered": self.filtered.load(Ordering::Relaxed),
+            "duration_ms": duration_ms,
+        })
+        .to_string()
+    }
+}
@@ -0,0 +1,46 @@
+//! Per-connection flow control, for clients that can't keep up with a busy timeline
+use std::time::{Duration, Instant};
+
+/// Caps the number of events a client is sent per second
+///
+/// Each window starts with the first event after the previous window ended, and lasts a
+/// second.  Events beyond the cap in a window are dropped rather than queued, since queuing
+/// them would only hand the client the same overload later.
+#[derive(Debug)]
+pub(super) struct Throttle {
+    max_per_sec: u32,
+    window_start: Option<Instant>,
+    sent: u32,
+}
+
+impl Throttle {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    pub(super) fn new(max_per_sec: u32) -> Self {
+        Self {
+            max_per_sec,
+            window_start: None,
+            sent: 0,
+        }
+    }
+
+    /// Whether an event that's ready to send `now` may be sent (counting it if so)
+    pub(super) fn allows(&mut self, now: Instant) -> bool {
+        match self.window_start {
+            Some(start) if now.saturating_duration_since(start) < Self::WINDOW => (),
+            _ => {
+                self.window_start = Some(now);
+                self.sent = 0;
+            }
+        }
+        if self.sent < self.max_per_sec {
+            self.sent += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test;
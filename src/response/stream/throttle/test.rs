@@ -0,0 +1,41 @@
+use super::*;
+
+#[test]
+fn a_burst_is_capped_at_the_rate() {
+    let mut throttle = Throttle::new(10);
+    let start = Instant::now();
+
+    // 100 events spread over half a second
+    let sent = (0..100)
+        .map(|i| start + Duration::from_millis(i * 5))
+        .filter(|&now| throttle.allows(now))
+        .count();
+    assert_eq!(sent, 10);
+}
+
+#[test]
+fn the_cap_resets_once_the_window_ends() {
+    let mut throttle = Throttle::new(2);
+    let start = Instant::now();
+    let at = |ms| start + Duration::from_millis(ms);
+
+    assert!(throttle.allows(at(0)));
+    assert!(throttle.allows(at(10)));
+    assert!(!throttle.allows(at(999)));
+    assert!(throttle.allows(at(1_000)));
+    assert!(throttle.allows(at(1_500)));
+    assert!(!throttle.allows(at(1_999)));
+}
+
+#[test]
+fn a_sustained_burst_is_delivered_at_the_rate() {
+    let mut throttle = Throttle::new(5);
+    let start = Instant::now();
+
+    // 1,000 events a second for three seconds
+    let sent = (0..3_000)
+        .map(|i| start + Duration::from_millis(i))
+        .filter(|&now| throttle.allows(now))
+        .count();
+    assert_eq!(sent, 15);
+}
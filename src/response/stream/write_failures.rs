@@ -0,0 +1,89 @@
+//! Counts of failed writes to clients, by cause, so that flaky client populations can be told
+//! apart from clients that disconnect cleanly.
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[cfg(test)]
+mod test;
+
+/// Why a write to a client failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Cause {
+    Reset,
+    Timeout,
+    Closed,
+    Other,
+}
+
+impl Cause {
+    const ALL: [Self; 4] = [Self::Reset, Self::Timeout, Self::Closed, Self::Other];
+
+    /// The cause of the write `error`: the kind of the first IO error in its chain of sources
+    /// (or `Other`, if there isn't one)
+    pub(crate) fn of(error: &(dyn Error + 'static)) -> Self {
+        let mut next = Some(error);
+        while let Some(error) = next {
+            if let Some(io_error) = error.downcast_ref::<io::Error>() {
+                return Self::of_kind(io_error.kind());
+            }
+            next = error.source();
+        }
+        Self::Other
+    }
+
+    fn of_kind(kind: io::ErrorKind) -> Self {
+        use io::ErrorKind::*;
+        match kind {
+            ConnectionReset => Self::Reset,
+            // a send timeout on a socket fails with `WouldBlock` (`EAGAIN`) on Unix
+            TimedOut | WouldBlock => Self::Timeout,
+            BrokenPipe | ConnectionAborted | NotConnected | UnexpectedEof => Self::Closed,
+            _ => Self::Other,
+        }
+    }
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Self::Reset => "reset",
+            Self::Timeout => "timeout",
+            Self::Closed => "closed",
+            Self::Other => "other",
+        }
+    }
+
+    /// Whether this is how a client that simply went away fails (rather than a real error)
+    pub(crate) fn is_disconnect(self) -> bool {
+        matches!(self, Self::Reset | Self::Closed)
+    }
+}
+
+/// The number of failed writes to clients, by `Cause`.  Clones share their counts, so the
+/// `Manager` can report the failures its clients' connections record.
+#[derive(Clone, Debug, Default)]
+pub struct WriteFailures(Arc<[AtomicUsize; 4]>);
+
+impl WriteFailures {
+    /// Count a failed write to a client and return its cause
+    pub(crate) fn record(&self, error: &(dyn Error + 'static)) -> Cause {
+        let cause = Cause::of(error);
+        self.0[cause as usize].fetch_add(1, Ordering::Relaxed);
+        log::debug!("Write to client failed ({}): {}", cause.label(), error);
+        cause
+    }
+
+    /// The number of failed writes to clients so far, by cause
+    pub(crate) fn counts(&self) -> BTreeMap<&'static str, usize> {
+        Cause::ALL
+            .iter()
+            .map(|&cause| {
+                (
+                    cause.label(),
+                    self.0[cause as usize].load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,56 @@
+use super::*;
+use std::fmt;
+
+/// An error that wraps an IO error, as Warp's errors do
+#[derive(Debug)]
+struct Wrapped(io::Error);
+
+impl fmt::Display for Wrapped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "wrapped: {}", self.0)
+    }
+}
+
+impl Error for Wrapped {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+#[test]
+fn write_errors_are_classified_by_io_error_kind() {
+    let cause = |kind: io::ErrorKind| Cause::of(&io::Error::from(kind));
+
+    assert_eq!(cause(io::ErrorKind::ConnectionReset), Cause::Reset);
+    assert_eq!(cause(io::ErrorKind::TimedOut), Cause::Timeout);
+    assert_eq!(cause(io::ErrorKind::WouldBlock), Cause::Timeout);
+    assert_eq!(cause(io::ErrorKind::BrokenPipe), Cause::Closed);
+    assert_eq!(cause(io::ErrorKind::ConnectionAborted), Cause::Closed);
+    assert_eq!(cause(io::ErrorKind::InvalidData), Cause::Other);
+}
+
+#[test]
+fn io_errors_are_found_among_the_sources() {
+    let reset = Wrapped(io::Error::from(io::ErrorKind::ConnectionReset));
+    assert_eq!(Cause::of(&reset), Cause::Reset);
+
+    // the text doesn't matter, only the kind
+    let described = io::Error::new(io::ErrorKind::Other, "connection reset by peer");
+    assert_eq!(Cause::of(&described), Cause::Other);
+    assert_eq!(Cause::of(&fmt::Error), Cause::Other);
+}
+
+#[test]
+fn recording_a_write_error_counts_it_under_its_cause() {
+    let failures = WriteFailures::default();
+    let shared = failures.clone();
+
+    let cause = shared.record(&io::Error::from(io::ErrorKind::TimedOut));
+
+    assert_eq!(cause, Cause::Timeout);
+    let counts = failures.counts();
+    assert_eq!(
+        counts.into_iter().collect::<Vec<_>>(),
+        vec![("closed", 0), ("other", 0), ("reset", 0), ("timeout", 1)]
+    );
+}
@@ -1,19 +1,107 @@
-use super::{Event, Payload};
-use crate::request::Subscription;
+use super::WriteFailures;
+use super::{skip_reason, DeliveryOrder, DroppedEvents, Event, EventIds, FairOrder, Payload};
+use super::{RecentIds, Throttle};
+use crate::request::{Subscription, Timeline};
 
 use futures::future::Future;
-use futures::stream::Stream;
+use futures::stream::{self, Stream};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Receiver;
+use tokio::timer::Interval;
 use warp::ws::{Message, WebSocket};
 
-type EventRx = Receiver<Arc<Event>>;
+type EventRx = Receiver<(Timeline, Arc<Event>)>;
 
-pub struct Ws(Subscription);
+/// How a `Ws` stream is set up, apart from its `Subscription`
+#[derive(Clone, Default)]
+pub struct WsOptions {
+    /// How often to send a keepalive text frame (for clients behind proxies that strip Ping
+    /// frames), if at all
+    pub keepalive: Option<Duration>,
+    /// Where to get each event's `id` from, if including them
+    pub event_ids: Option<EventIds>,
+    /// Whether to keep the internal fields from Mastodon (such as `queued_at`) in events
+    pub retain_internal_fields: bool,
+    /// Whether to log whether each event was delivered or filtered (under its trace id)
+    pub trace_events: bool,
+    /// Where to count failed writes to the client
+    pub write_failures: WriteFailures,
+}
+
+pub struct Ws {
+    subscription: Subscription,
+    /// The statuses sent most recently, if merging several timelines
+    recent_ids: Option<RecentIds>,
+    keepalive: Option<Duration>,
+    event_ids: Option<EventIds>,
+    retain_internal_fields: bool,
+    trace_events: bool,
+    write_failures: WriteFailures,
+    /// The number of events sent so far and the count of those dropped, if numbering events
+    sequence: Option<(u64, DroppedEvents)>,
+    /// How often to send a `presence` event, if the client asked for them
+    presence: Option<Duration>,
+    /// Caps the events sent per second, if the client asked for a cap
+    throttle: Option<Throttle>,
+}
 
 impl Ws {
-    pub fn new(subscription: Subscription) -> Self {
-        Self(subscription)
+    /// The text frame sent as an application-level keepalive
+    const KEEPALIVE: &'static str = r#"{"event":"keepalive"}"#;
+    /// The text frame sent to clients that asked to be kept present on their `user` timeline
+    const PRESENCE: &'static str = r#"{"event":"presence"}"#;
+
+    /// Create a new `Ws` for the `subscription`, set up as described by its `options`
+    pub fn new(subscription: Subscription, options: WsOptions) -> Self {
+        let throttle = subscription.max_events_per_sec.map(Throttle::new);
+        let recent_ids = RecentIds::for_subscription(&subscription);
+        Self {
+            subscription,
+            recent_ids,
+            keepalive: options.keepalive,
+            event_ids: options.event_ids,
+            retain_internal_fields: options.retain_internal_fields,
+            trace_events: options.trace_events,
+            write_failures: options.write_failures,
+            sequence: None,
+            presence: None,
+            throttle,
+        }
+    }
+
+    /// Send a `presence` event every `interval`, if the client's subscription asked for them.
+    /// Unlike keepalives, these are events that clients act on rather than connection upkeep.
+    pub fn with_presence(mut self, interval: Duration) -> Self {
+        self.presence = Some(interval).filter(|_| self.subscription.presence);
+        self
+    }
+
+    /// Number each event sent to the client with a `seq` that increases by one per event, and
+    /// that also counts the client's `dropped` events, so that the client can spot gaps.
+    pub fn numbered(mut self, dropped: DroppedEvents) -> Self {
+        self.sequence = Some((0, dropped));
+        self
+    }
+
+    /// Whether the throttle (if any) lets another event be sent now.  One it holds back is
+    /// counted as dropped, so that the sequence numbers account for it.
+    fn allowed_by_throttle(&mut self) -> bool {
+        let allowed = self
+            .throttle
+            .as_mut()
+            .map_or(true, |throttle| throttle.allows(Instant::now()));
+        if let (false, Some((_, dropped))) = (allowed, &self.sequence) {
+            dropped.record();
+        }
+        allowed
+    }
+
+    /// The sequence number of the next event sent, if numbering events
+    fn next_seq(&mut self) -> Option<u64> {
+        let (sent, dropped) = self.sequence.as_mut()?;
+        *sent += 1;
+        Some(*sent + dropped.count())
     }
 
     pub fn send_to(
@@ -22,55 +110,153 @@ impl Ws {
         event_rx: EventRx,
     ) -> impl Future<Item = (), Error = ()> {
         let (transmit_to_ws, _receive_from_ws) = ws.split();
-        event_rx
-            .filter_map(move |event| {
-                if matches!(*event, Event::Ping) {
-                    Some(Message::text(&event.to_json_string()))
-                } else {
-                    match (event.update_payload(), event.dyn_update_payload()) {
-                        (Some(update), _) if !self.filtered(update) => {
-                            Some(Message::text(&event.to_json_string()))
-                        }
-                        (None, None) => Some(Message::text(&event.to_json_string())), // send all non-updates
-                        (_, Some(dyn_update)) if !self.filtered(dyn_update) => {
-                            Some(Message::text(&event.to_json_string()))
-                        }
-                        _ => None,
-                    }
-                }
+        let timeline = stream::iter_ok(self.timeline_message());
+        let keepalive = Self::keepalive_messages(self.keepalive);
+        let presence = Self::presence_messages(self.presence);
+        let ordered = DeliveryOrder::new(event_rx, self.subscription.newest_first);
+        let fair = self.subscription.timelines().len() > 1;
+        let write_failures = self.write_failures.clone();
+        let events = FairOrder::new(ordered, fair)
+            .in_order_of(self.subscription.timelines())
+            .filter_map(move |(timeline, event)| {
+                let msg = self.to_message(timeline, &event);
+                self.trace(&event, msg.is_some());
+                msg
             })
-            .map_err(|_| -> warp::Error { unreachable!() })
+            .map_err(|_| -> warp::Error { unreachable!() });
+        timeline
+            .chain(events)
+            .map(Some)
+            // Keepalives never end, so `None` marks the end of the events (and the connection)
+            .chain(stream::once(Ok(None)))
+            .select(keepalive.map(Some))
+            .select(presence.map(Some))
+            .take_while(|msg| Ok(msg.is_some()))
+            .filter_map(|msg| msg)
             .forward(transmit_to_ws)
             .map(|_r| ())
-            // ignore errors that indicate normal disconnects.  TODO - once we upgrade our
-            // Warp version, we should stop matching on text, which is fragile.
-            .map_err(|e| match e.to_string().as_ref() {
-                "IO error: Broken pipe (os error 32)"
-                | "IO error: Connection reset by peer (os error 104)" => (),
-                e => log::warn!("WebSocket send error: {}", e),
+            .map_err(move |e| {
+                // errors that indicate normal disconnects are only counted, not warned about
+                if !write_failures.record(&e).is_disconnect() {
+                    log::warn!("WebSocket send error: {}", e);
+                }
+            })
+    }
+
+    /// A keepalive `Message` every `interval` (or nothing, if `interval` is `None`)
+    fn keepalive_messages(
+        interval: Option<Duration>,
+    ) -> impl Stream<Item = Message, Error = warp::Error> {
+        Self::repeated(Self::KEEPALIVE, interval)
+    }
+
+    /// A presence `Message` every `interval` (or nothing, if `interval` is `None`)
+    fn presence_messages(
+        interval: Option<Duration>,
+    ) -> impl Stream<Item = Message, Error = warp::Error> {
+        Self::repeated(Self::PRESENCE, interval)
+    }
+
+    /// The `text` as a `Message` every `interval` (or nothing, if `interval` is `None`)
+    fn repeated(
+        text: &'static str,
+        interval: Option<Duration>,
+    ) -> impl Stream<Item = Message, Error = warp::Error> {
+        let ticks = interval.map(|interval| Interval::new(Instant::now() + interval, interval));
+        stream::iter_ok::<_, tokio::timer::Error>(ticks)
+            .flatten()
+            .then(move |tick| match tick {
+                Ok(_) => Ok::<_, warp::Error>(Some(Message::text(text))),
+                Err(e) => {
+                    log::warn!("WebSocket timer error sending {}: {}", text, e);
+                    Ok(None)
+                }
             })
+            .filter_map(|msg| msg)
+    }
+
+    /// A `timeline` event naming the `Timeline` the client's request resolved to, if it asked
+    /// for one (with `debug_timeline`).  Like other events, its payload is a JSON string.
+    fn timeline_message(&self) -> Option<Message> {
+        if !self.subscription.debug_timeline {
+            return None;
+        }
+        let timeline = serde_json::to_string(&self.subscription.timeline).ok()?;
+        let msg = serde_json::json!({ "event": "timeline", "payload": timeline });
+        Some(Message::text(msg.to_string()))
+    }
+
+    /// Log whether `event` was `delivered` (if tracing events)
+    fn trace(&self, event: &Event, delivered: bool) {
+        if self.trace_events {
+            let outcome = if delivered { "delivered" } else { "filtered" };
+            let tl = self.subscription.timeline;
+            event.trace(&format!("{} to a WebSocket client of {:?}", outcome, tl));
+        }
     }
-    fn filtered<T: std::fmt::Debug + Payload>(&mut self, update: &T) -> bool {
-        let (blocks, allowed_langs) = (&self.0.blocks, &self.0.allowed_langs);
-        let skip = |msg| {
-            // Some(log::info!("{:?} msg skipped - {}\n{:?}", self.0.timeline, msg, update)).is_some()
-            Some(log::info!("{:?} msg skipped - {}", self.0.timeline, msg)).is_some()
+
+    /// The `Message` to send to the client for an `Event` from `timeline`, if any
+    fn to_message(&mut self, timeline: Timeline, event: &Event) -> Option<Message> {
+        if matches!(*event, Event::Ping) {
+            return Some(Message::text(&event.to_json_string()));
+        }
+        if self.subscription.only_relationships && !event.is_relationship_notification() {
+            return None;
+        }
+        let send = match (event.update_payload(), event.dyn_update_payload()) {
+            (Some(update), _) => !self.filtered(timeline, update),
+            (None, None) => !self.subscription.blocks.blocks_notification(event), // send all other non-updates
+            (_, Some(dyn_update)) => !self.filtered(timeline, dyn_update),
+        };
+        if !send {
+            return None;
+        }
+        let marked = if self.subscription.mark_local {
+            event.marked_local()
+        } else {
+            None
         };
+        let event = marked.as_ref().unwrap_or(event);
 
-        match self.0.timeline {
-            tl if tl.is_public()
-                && !update.language_unset()
-                && !allowed_langs.is_empty()
-                && !allowed_langs.contains(&update.language()) =>
-            {
-                skip("disallowed language")
-            }
-            _ if !blocks.blocked_users.is_disjoint(&update.involved_users()) => {
-                skip("involves blocked user")
-            }
-            _ if blocks.blocking_users.contains(update.author()) => skip("from blocking user"),
-            _ if blocks.blocked_domains.contains(update.sent_from()) => skip("from blocked domain"),
-            _ => false,
+        let id = self.event_ids.as_ref().and_then(|ids| ids.id_for(event));
+        let queued_at = event.queued_at().filter(|_| self.retain_internal_fields);
+        let repeated = self
+            .recent_ids
+            .as_ref()
+            .map_or(false, |ids| ids.repeats(event));
+        if repeated || !self.allowed_by_throttle() {
+            return None;
+        }
+        if let Some(recent_ids) = &mut self.recent_ids {
+            recent_ids.sent(event);
+            let stream = timeline.to_redis_text(None).ok()?;
+            let seq = self.next_seq();
+            Some(Message::text(&event.to_json_string_from(
+                Some(vec![&stream]),
+                id.as_deref(),
+                queued_at,
+                seq,
+            )))
+        } else {
+            let seq = self.next_seq();
+            Some(Message::text(&event.to_json_string_from(
+                None,
+                id.as_deref(),
+                queued_at,
+                seq,
+            )))
         }
     }
+
+    /// Whether the `update` from `timeline` is filtered out (logging why, if so)
+    fn filtered(&self, timeline: Timeline, update: &impl Payload) -> bool {
+        let reason = skip_reason(&self.subscription, timeline, update);
+        if let Some(reason) = reason {
+            log::info!("{:?} msg skipped - {}", self.subscription.timeline, reason);
+        }
+        reason.is_some()
+    }
 }
+
+#[cfg(test)]
+mod test;
@@ -113,6 +113,7 @@ Event::TypeSafe(
                     reblogged: Some(false),
                     muted: Some(false),
                     bookmarked: None,
+                    local_only: None,
                     pinned: None
                 },
                 queued_at: Some(1568227693541) })
\ No newline at end of file
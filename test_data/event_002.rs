@@ -93,6 +93,7 @@ Event::TypeSafe(
             reblogged: None,
             muted: None,
             bookmarked: None,
+            local_only: None,
             pinned: None,
         },
         queued_at: None,